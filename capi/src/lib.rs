@@ -0,0 +1,5 @@
+//! The C API of the buddy allocator as a shared and a static library.
+//!
+//! Every `#[no_mangle]` function of the Rust crate is exported from here, the
+//! Rust crate itself is kept an rlib so it can be built without std.
+pub use buddy::*;
@@ -18,13 +18,85 @@ constexpr static const uint16_t BLOCK_RESERVED = 0;
 
 constexpr static const uint16_t BLOCK_UNUSED = 3;
 
+constexpr static const uint16_t BLOCK_ALIGNED = 2;
+
+constexpr static const uint16_t BLOCK_SENTINEL = 4;
+
+/// Place a sentinel record right after the end of the managed region
+constexpr static const uint32_t INIT_END_SENTINEL = (1 << 0);
+
+/// Map the region MAP_SHARED instead of MAP_PRIVATE so forked children share it
+constexpr static const uint32_t INIT_SHARED = (1 << 1);
+
+/// Keep the header of reserved blocks at their end so user data starts on the block
+constexpr static const uint32_t INIT_TRAILING_HEADER = (1 << 2);
+
+/// Back the region with huge pages on Linux, falling back to normal pages
+constexpr static const uint32_t INIT_HUGEPAGES = (1 << 3);
+
+/// Coalesce freed blocks with their free buddies
+constexpr static const uint32_t POLICY_COALESCE = (1 << 0);
+
+/// Postpone coalescing until an allocation can not be satisfied otherwise
+constexpr static const uint32_t POLICY_DEFERRED = (1 << 1);
+
+/// Never coalesce freed blocks of the smallest class so small allocations stay cheap
+constexpr static const uint32_t POLICY_SMALL_FAST = (1 << 2);
+
+/// Verify block headers in buddy_malloc and buddy_free and report violations to the assert handler
+constexpr static const uint32_t POLICY_CHECKED = (1 << 3);
+
+/// With POLICY_DEFERRED, try to assemble the requested class from free buddies before splitting a larger block
+constexpr static const uint32_t POLICY_EAGER = (1 << 4);
+
+/// Policy flags of a freshly initialized pool
+constexpr static const uint32_t POLICY_DEFAULT = POLICY_COALESCE;
+
+/// Freed blocks are pushed to the front of their list and reused first
+constexpr static const uint32_t STRATEGY_LIFO = 0;
+
+/// Freed blocks are appended to the back of their list and reused last
+constexpr static const uint32_t STRATEGY_FIFO = 1;
+
+/// Like LIFO, but when a block has to be split the most recently freed larger
+/// block is split instead of the smallest one, see buddy_set_policy
+constexpr static const uint32_t STRATEGY_CACHE_HOT = 2;
+
+/// Freed blocks are kept sorted by address so the lowest free block is reused
+/// first, at the cost of walking the list on every insert, see buddy_set_policy
+constexpr static const uint32_t STRATEGY_ADDRESS = 3;
+
+/// Number of bytes used by a single block record in an exported layout.
+constexpr static const uintptr_t LAYOUT_RECORD_SIZE = 2;
+
+/// Why the pool could not hand out or take back memory
+enum BuddyError {
+  BuddyError_Ok = 0,
+  BuddyError_NullPool = 1,
+  BuddyError_NullPtr = 2,
+  BuddyError_InvalidPointer = 3,
+  BuddyError_DoubleFree = 4,
+  BuddyError_OutOfMemory = 5,
+  BuddyError_ZeroSize = 6,
+  BuddyError_Throttled = 7,
+  BuddyError_TooLarge = 8,
+};
+
 /// Struct to represent the table of all available blocks do not reorder members
 /// of this struct because internal calculations depend on the ordering.
 struct Avail {
   uint16_t tag;
   uint16_t kval;
+  uint32_t owner;
   Avail *next;
   Avail *prev;
+  uint64_t stamp;
+};
+
+/// The tuning knobs of a pool that can be changed at any time
+struct BuddyPolicy {
+  uint32_t flags;
+  uint32_t strategy;
 };
 
 /// The Buddy Memory Pool
@@ -33,12 +105,81 @@ struct BuddyPool {
   uintptr_t numbytes;
   void *base;
   Avail avail[MAX_K];
+  bool owns_mapping;
+  BuddyPolicy policy;
+  Avail *sentinel;
+  uint64_t alloc_clock;
+  uint64_t free_clock;
+  bool (*rate_callback)(uint64_t count, void *user);
+  uint64_t rate_threshold;
+  uint64_t rate_count;
+  void *rate_user;
+  uint16_t max_split_depth;
+  uint64_t split_count;
+  uintptr_t live_count;
+  uint64_t free_mask;
+  uintptr_t highest_free_k;
+  int32_t map_fd;
+  int32_t map_flags;
+  int32_t map_prot;
+  uint64_t coalesce_blocked;
+  uint64_t split_limit;
+  bool trailing_header;
+  int32_t oom_errno;
+  uintptr_t free_bytes;
+  uintptr_t headerless_count;
+  uint64_t *free_bits;
+  void (*on_alloc)(uintptr_t size, void *ptr);
+  void (*on_free)(void *ptr);
+};
+
+/// How the memory of a pool is backed
+struct MappingInfo {
+  int32_t fd;
+  int32_t flags;
+  int32_t prot;
+  uint8_t owns_mapping;
+};
+
+/// Running totals of the work done by a pool
+struct BuddyCounters {
+  uint64_t allocs;
+  uint64_t frees;
+  uint64_t live;
+  uint64_t splits;
+  uint64_t coalesce_blocked;
+};
+
+/// Snapshot of how the memory of a pool is split between free and used blocks
+struct BuddyStats {
+  uintptr_t total_bytes;
+  uintptr_t free_bytes;
+  uintptr_t used_bytes;
+  uintptr_t largest_free_block;
+  uintptr_t num_free_blocks;
+};
+
+/// Options for buddy_init_with
+struct BuddyInitOptions {
+  uint32_t flags;
+  uint32_t map_retries;
+  uint32_t retry_delay_us;
+};
+
+/// Describes a single block of the pool in address order.
+struct BlockInfo {
+  uintptr_t offset;
+  uint16_t kval;
+  uint16_t tag;
 };
 
 extern "C" {
 
 /// Converts bytes to its equivalent K value defined as bytes <= 2^K
 ///
+/// Sizes above the largest power of two a usize can hold saturate to
+/// usize::BITS - 1 instead of overflowing.
+///
 /// ## Parameters
 ///
 /// - bytes `usize` The number of bytes needed
@@ -50,6 +191,10 @@ uintptr_t btok(uintptr_t bytes);
 
 /// Find the buddy of a given pointer and kval relative to the base address we got from mmap
 ///
+/// A block of class kval_m spans the whole pool and has no buddy, for such a
+/// block the block itself is returned. Use buddy_has_buddy to tell the cases
+/// apart.
+///
 /// ## Parameters
 /// - pool `*mut BuddyPool` The memory pool to work on (needed for the base addresses)
 /// - buddy `*mut Avail` The memory block that we want to find the buddy for
@@ -59,10 +204,126 @@ uintptr_t btok(uintptr_t bytes);
 ///  - A pointer to the buddy. Type = `*mut Avail`
 Avail *buddy_calc(BuddyPool *pool, Avail *buddy);
 
+/// Returns whether block has a buddy, i.e. whether it is smaller than the
+/// whole pool. Only a block of class kval_m has none, and buddy_calc returns
+/// such a block unchanged.
+///
+/// If pool or block is NULL, the return value will be false
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool the block belongs to
+/// - block `*mut Avail` The block to check
+///
+/// ## Returns
+///
+/// - True if the block has a buddy. Type = `bool`
+bool buddy_has_buddy(BuddyPool *pool, Avail *block);
+
 /// Helper function.
 ///
-/// Removes a block from the free list.
-void remove_block(Avail *block);
+/// Removes a block from the free list and returns whether the list is empty
+/// afterwards, so callers can keep track of empty classes without looking at
+/// the list again.
+///
+/// # Safety
+///
+/// `block` must point to a valid block that is currently linked into a free
+/// list. It must not be one of the avail sentinels (tagged BLOCK_UNUSED),
+/// unlinking a sentinel cuts its whole list loose, debug builds abort on it.
+bool remove_block(Avail *block);
+
+/// Removes a free block from its avail list like remove_block, after making
+/// sure the block is a free block inside the pool. The avail sentinels in
+/// particular are refused, unlinking one of them would cut its whole list
+/// loose for good.
+///
+/// The block is left marked available but is on no list anymore, it is up
+/// to the caller to use it or hand it back.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool the block belongs to
+/// - block `*mut Avail` The free block to unlink
+///
+/// ## Returns
+///
+/// - 0 if the block was unlinked, EINVAL if pool or block is NULL or block is not a free block of the pool. Type = `i32`
+int32_t buddy_remove_block(BuddyPool *pool,
+                           Avail *block);
+
+/// Merges every pair of free buddies in the pool in a single sweep from the
+/// smallest class upward, whatever the policy. Pools that free with
+/// coalescing switched off or deferred end up as coalesced as their live
+/// allocations allow, at the cost of one pass over the free lists rather than
+/// a merge loop per freed block.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to coalesce
+///
+/// ## Returns
+///
+/// - The largest class with a free block afterwards, 0 if there is none or pool is NULL. Type = `u16`
+uint16_t buddy_coalesce_all(BuddyPool *pool);
+
+/// Returns the current policy of the pool.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - The policy of the pool, or the default policy if pool is NULL. Type = `BuddyPolicy`
+BuddyPolicy buddy_get_policy(BuddyPool *pool);
+
+/// Changes the policy of the pool. The new policy applies to every following
+/// call to buddy_malloc and buddy_free, blocks already on the free lists are
+/// left where they are.
+///
+/// When coalescing is switched off (POLICY_COALESCE cleared) freed blocks are
+/// never merged. With POLICY_DEFERRED freed blocks are merged in one sweep the
+/// next time an allocation would otherwise fail, and with POLICY_EAGER as well
+/// also the classes below one that would have to be split from a larger
+/// block, in case merging them yields a block of the class. POLICY_SMALL_FAST
+/// keeps freed
+/// blocks of the smallest class unmerged so they can be handed out again
+/// without splitting.
+///
+/// STRATEGY_CACHE_HOT reuses the most recently freed memory first, even when
+/// that means splitting a larger block than necessary. A block freed a few
+/// operations ago is likely still in the L1 or L2 cache, where a hit costs
+/// a few nanoseconds against roughly 100 for a trip to main memory, so for
+/// workloads that free and soon allocate again most first touches of the
+/// returned memory avoid a cache miss.
+///
+/// STRATEGY_ADDRESS keeps every free list sorted by address, so allocations
+/// pack towards the start of the pool and free blocks stay clustered where
+/// they can merge. Inserting walks the list, making frees O(n) in the length
+/// of the list instead of O(1), which pays off for long lived pools that
+/// fragment under LIFO reuse.
+///
+/// POLICY_CHECKED verifies the headers of blocks taken
+/// from the free lists and of pointers being freed, and that freeing the last
+/// allocation merges the pool back into one block, see buddy_set_assert_handler.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to configure
+/// - policy `BuddyPolicy` The new policy
+void buddy_set_policy(BuddyPool *pool, BuddyPolicy policy);
+
+/// Selects the errno value allocations from the pool set when it runs out of
+/// memory, for callers whose error handling expects something other than
+/// ENOMEM, e.g. EAGAIN for a pool that is worth retrying later. Passing 0
+/// restores the default of ENOMEM.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to configure
+/// - errno_value `i32` The errno to report, 0 for ENOMEM
+void buddy_set_oom_errno(BuddyPool *pool, int32_t errno_value);
 
 /// Allocates a block of size bytes of memory, returning a pointer to
 /// the beginning of the block. The content of the newly allocated block
@@ -70,6 +331,7 @@ void remove_block(Avail *block);
 ///
 /// If size is zero, the return value will be NULL
 /// If pool is NULL, the return value will be NULL
+/// If the block would be larger than the pool, errno is set to ENOMEM and the return value will be NULL
 ///
 /// ## Parameters
 ///
@@ -79,7 +341,169 @@ void remove_block(Avail *block);
 /// ## Returns
 ///
 /// - A pointer to the memory block. Type = `*mut c_void`
-void *buddy_malloc(BuddyPool *pool, uintptr_t size);
+void *buddy_malloc(BuddyPool *pool,
+                   uintptr_t size);
+
+/// Allocates a block of size bytes of memory like buddy_malloc, and stores
+/// in reason why it could not when it returns NULL, or BuddyError::Ok when
+/// it does not. Unlike errno this tells a NULL pool, a zero size and a
+/// request the pool could never hold apart from the pool being full.
+///
+/// A destroyed pool, or one whose initialization failed, holds nothing and
+/// reports BuddyError::TooLarge for every size.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - size `usize` The size of the user requested memory block in bytes
+/// - reason `*mut BuddyError` Receives the outcome, may be NULL
+///
+/// ## Returns
+///
+/// - A pointer to the memory block. Type = `*mut c_void`
+void *buddy_malloc_explained(BuddyPool *pool, uintptr_t size, BuddyError *reason);
+
+/// Allocates a block of size bytes of memory like buddy_malloc and records
+/// owner in its header, so the memory held by each subsystem can be added up
+/// with buddy_bytes_by_tag. Blocks from every other allocation function have
+/// owner 0.
+///
+/// If size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - size `usize` The size of the user requested memory block in bytes
+/// - owner `u32` The tag to attribute the block to
+///
+/// ## Returns
+///
+/// - A pointer to the memory block. Type = `*mut c_void`
+void *buddy_malloc_tagged(BuddyPool *pool, uintptr_t size, uint32_t owner);
+
+/// Allocates a block of size bytes of memory like buddy_malloc, but only if
+/// it takes at most max_splits splits to carve it out of the free blocks.
+/// Every split is work on the allocation path, so this bounds the latency of
+/// the call and lets the caller fall back to memory it set aside instead.
+///
+/// If size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
+/// If the allocation would need more than max_splits splits, errno is set to EAGAIN and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - size `usize` The size of the user requested memory block in bytes
+/// - max_splits `usize` The largest number of splits the allocation may perform
+///
+/// ## Returns
+///
+/// - A pointer to the memory block. Type = `*mut c_void`
+void *buddy_malloc_budget(BuddyPool *pool,
+                          uintptr_t size,
+                          uintptr_t max_splits);
+
+/// Allocates a block of exactly class k, splitting a larger free block down
+/// to k if needed. Unlike buddy_malloc the class is chosen by the caller
+/// rather than derived from a size, so allocations of the same class always
+/// sit on the same grid of 1 << k byte blocks, e.g. for slabs. The caller may
+/// use buddy_usable_size bytes of the block, its size minus the header.
+///
+/// k is clamped to the classes of the pool, SMALLEST_K up to its kval_m.
+///
+/// If pool is NULL, the return value will be NULL
+/// If the pool has no free block of class k or larger, errno is set to ENOMEM and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - k `usize` The class of the block to allocate
+///
+/// ## Returns
+///
+/// - A pointer to the memory block. Type = `*mut c_void`
+void *buddy_alloc_class(BuddyPool *pool,
+                        uintptr_t k);
+
+/// Allocates the block of the class fitting size bytes that starts exactly
+/// offset bytes from base, so callers can control where allocations land,
+/// e.g. to lay out a memory mapped format the same way on every run. The
+/// free block covering offset is split down until the requested block is
+/// carved out, with every other half going back to the free lists.
+///
+/// The returned pointer is the user pointer of that block, for pools without
+/// INIT_TRAILING_HEADER it is offset + size_of::<Avail>() bytes from base.
+///
+/// If pool is NULL or size is zero, the return value will be NULL
+/// If offset is not a multiple of the block size or the block would not fit in the pool, errno is set to EINVAL and the return value will be NULL
+/// If any part of the block is not free, errno is set to ENOMEM and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - offset `usize` The offset of the block from base in bytes
+/// - size `usize` The size of the user requested memory block in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the memory block. Type = `*mut c_void`
+void *buddy_malloc_at(BuddyPool *pool,
+                      uintptr_t offset,
+                      uintptr_t size);
+
+/// Registers a callback that watches the allocation rate of the pool. Every
+/// call to buddy_malloc counts towards the current window and once more than
+/// threshold allocations were made in it the callback is called with that
+/// count. If the callback returns false the allocation is denied, buddy_malloc
+/// sets errno to EAGAIN and returns NULL, and the callback keeps being asked
+/// on every following allocation. If it returns true a new window starts.
+///
+/// Windows also restart on buddy_reset_rate_window, so calling that on a timer
+/// turns the threshold into an allocations-per-interval limit. Passing a NULL
+/// callback disables rate tracking.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to watch
+/// - callback `Option<extern "C" fn(u64, *mut c_void) -> bool>` The callback, or NULL to disable it
+/// - threshold `u64` The number of allocations allowed per window
+/// - user `*mut c_void` User data passed to the callback
+void buddy_set_rate_callback(BuddyPool *pool,
+                             bool (*callback)(uint64_t count, void *user),
+                             uint64_t threshold,
+                             void *user);
+
+/// Registers hooks that observe every allocation and free of the pool, e.g.
+/// to build a histogram of allocation sizes without touching the call sites.
+/// on_alloc is called once for every successful allocation, whichever
+/// function made it, with the size that function was asked for and the
+/// pointer it returned, so aligned allocations report the aligned pointer.
+/// buddy_calloc reports the product of its arguments and buddy_alloc_class
+/// the size of the block. on_free is called with the pointer after every
+/// successful buddy_free and buddy_free_headerless. buddy_realloc reports
+/// the old pointer as freed and the new one as allocated, also when the block
+/// is resized in place. Failed calls are not reported. Without hooks the
+/// cost is a single branch per call.
+///
+/// Hooks run inside the allocator, so they must not allocate from or free to
+/// the same pool. Passing NULL removes a hook.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to observe
+/// - on_alloc `Option<extern "C" fn(usize, *mut c_void)>` Called after each allocation, or NULL
+/// - on_free `Option<extern "C" fn(*mut c_void)>` Called after each free, or NULL
+void buddy_set_hooks(BuddyPool *pool,
+                     void (*on_alloc)(uintptr_t size, void *ptr),
+                     void (*on_free)(void *ptr));
+
+/// Starts a new rate window, see buddy_set_rate_callback.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool
+void buddy_reset_rate_window(BuddyPool *pool);
 
 /// A block of memory previously allocated by a call to malloc,
 /// calloc or realloc is deallocated, making it available again
@@ -92,41 +516,1339 @@ void *buddy_malloc(BuddyPool *pool, uintptr_t size);
 /// Notice that this function does not change the value of ptr itself,
 /// hence it still points to the same (now invalid) location.
 ///
+/// A pointer outside the pool and a block that is already free are caught
+/// and reported instead of corrupting the free lists. So is a pointer into
+/// the middle of a block, as long as the bytes in front of it do not happen
+/// to look like the header of a block aligned to its own size. A block that
+/// was freed and handed out again can not be told from a live one, though.
+///
 /// ## Parameters
 ///
 /// - pool `*mut BuddyPool` The memory pool
 /// - ptr `*mut c_void` Pointer to the memory block to free
-uint8_t buddy_free(BuddyPool *pool, void *ptr);
+///
+/// ## Returns
+///
+/// - BuddyError::Ok if the block was freed, why it was not otherwise. Type = `BuddyError`
+BuddyError buddy_free(BuddyPool *pool, void *ptr);
 
-/// Initialize a new memory pool using the buddy algorithm. Internally,
-/// this function uses mmap to get a block of memory to manage so should be
-/// portable to any system that implements mmap. This function will round
-/// up to the nearest power of two. So if the user requests 503MiB
-/// it will be rounded up to 512MiB.
+/// Frees a block like buddy_free and records caller_id, any number the
+/// caller picks to identify the call site such as a return address, in the
+/// freed block. Freeing the block again through buddy_free_tagged returns
+/// BuddyError::DoubleFree and leaves the block alone, and if an assert
+/// handler is installed it is told the caller_id of both frees. The id of
+/// the first one can also be looked up with buddy_freed_by.
 ///
-/// Note that if a 0 is passed as an argument then it initializes
-/// the memory pool to be of the default size of DEFAULT_K. If the caller
-/// specifies an unreasonably small size, then the buddy system may
-/// not be able to satisfy any requests.
+/// The caller_id lives in the free memory right behind the header, so the
+/// report is only as good as that memory: once the block has been handed
+/// out again a second free can no longer be told apart from a valid one.
 ///
-/// NOTE: Memory pools returned by this function can not be intermingled.
-/// Calling buddy_malloc with pool A and then calling buddy_free with
-/// pool B will result in undefined behavior.
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool
+/// - ptr `*mut c_void` Pointer to the memory block to free
+/// - caller_id `u64` The id of the code freeing the block
+///
+/// ## Returns
+///
+/// - BuddyError::Ok if the block was freed, why it was not otherwise. Type = `BuddyError`
+BuddyError buddy_free_tagged(BuddyPool *pool, void *ptr, uint64_t caller_id);
+
+/// Returns the caller_id buddy_free_tagged recorded when it freed the
+/// allocation at ptr, e.g. to find out who freed a block that is freed twice.
+/// Like the id itself this is only meaningful until the block is handed out
+/// again.
+///
+/// If pool or ptr is NULL, or ptr is not a freed allocation of the pool, the return value will be 0
 ///
 /// ## Parameters
 ///
-/// - pool `*mut BuddyPool` A pointer to the pool to initialize
-/// - size `usize` The size of the pool in bytes.
-void buddy_init(BuddyPool *pool, uintptr_t size);
+/// - pool `*mut BuddyPool` The memory pool ptr was allocated from
+/// - ptr `*mut c_void` Pointer to the freed allocation
+///
+/// ## Returns
+///
+/// - The caller_id of the free. Type = `u64`
+uint64_t buddy_freed_by(BuddyPool *pool, void *ptr);
 
-/// Inverse of buddy_init.
+/// Allocates a block of at least size bytes without keeping a header in it,
+/// so all 2^k bytes of the block belong to the caller. This suits power of
+/// two sized buffers, which would otherwise need a block twice their size to
+/// fit the header. The pointer returned is the start of the block.
 ///
-/// Notice that this function does not change the value of pool itself,
-/// hence it still points to the same (now invalid) location.
+/// Since nothing in the block records its size, the caller has to keep track
+/// of it and hand the same size to buddy_free_headerless, the block must not
+/// be freed with buddy_free or resized with buddy_realloc. Functions walking
+/// the blocks in address order, such as buddy_check_layout, cannot tell a
+/// live headerless block apart from the data in it, so they should not be
+/// used while headerless blocks are live.
+///
+/// If size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
 ///
 /// ## Parameters
 ///
-/// - pool `*mut BuddyPool` The memory pool to destroy
-void buddy_destroy(BuddyPool *pool);
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - size `usize` The size of the user requested memory block in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the start of the memory block. Type = `*mut c_void`
+void *buddy_malloc_headerless(BuddyPool *pool, uintptr_t size);
+
+/// Frees a block allocated by buddy_malloc_headerless, coalescing it with
+/// its buddies like buddy_free does.
+///
+/// If ptr is not in the pool, or is not aligned to a block of size bytes that fits it, BuddyError::InvalidPointer is returned
+/// If the block is free already, or no headerless block is live, BuddyError::DoubleFree is returned
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool
+/// - ptr `*mut c_void` Pointer returned by buddy_malloc_headerless
+/// - size `usize` The size the block was allocated with
+///
+/// ## Returns
+///
+/// - BuddyError::Ok if the block was freed, why it was not otherwise. Type = `BuddyError`
+BuddyError buddy_free_headerless(BuddyPool *pool,
+                                 void *ptr,
+                                 uintptr_t size);
+
+/// Returns whether ptr is the start of a live allocation of the pool, as
+/// returned by buddy_malloc or one of its variants, rather than a pointer into
+/// the middle of one or into free memory. The header in front of ptr must be
+/// reserved and sit at a multiple of its own block size from base, which only
+/// a real block header does.
+///
+/// If pool or ptr is NULL, the return value will be false
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to check against
+/// - ptr `*mut c_void` The pointer to check
+///
+/// ## Returns
+///
+/// - True if ptr starts an allocation of the pool. Type = `bool`
+bool buddy_is_block_start(BuddyPool *pool, void *ptr);
+
+/// Installs a handler that is called with a description of any invariant
+/// violation found by the checks of POLICY_CHECKED, instead of panicking.
+/// The message is not NUL terminated, its length is passed along with it.
+/// The handler may log and return, in which case the failing call returns an
+/// error, or it may never return (abort, longjmp).
+///
+/// Without a handler, or after passing NULL, violations panic, which aborts
+/// the process when it happens inside an extern function. The handler is
+/// shared by every pool in the process.
+///
+/// ## Parameters
+///
+/// - handler `Option<extern "C" fn(*const u8, usize)>` The handler, or NULL for the default
+void buddy_set_assert_handler(void (*handler)(const uint8_t *msg, uintptr_t len));
+
+/// Frees every allocation of the pool for which pred returns true and returns
+/// how many were freed. Reserved blocks are visited in address order and pred
+/// is called with the user pointer of the block, the number of usable bytes
+/// in it and user. All matching blocks are collected before any of them is
+/// freed, so pred always sees the pool as it was when the call started.
+///
+/// For allocations made through the aligned path pred receives the start of
+/// the block's user data rather than the aligned pointer.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool
+/// - pred `extern "C" fn(*mut c_void, usize, *mut c_void) -> bool` Returns true for blocks to free
+/// - user `*mut c_void` User data passed to pred
+///
+/// ## Returns
+///
+/// - The number of allocations freed. Type = `usize`
+uintptr_t buddy_free_matching(BuddyPool *pool,
+                              bool (*pred)(void *ptr, uintptr_t usable, void *user),
+                              void *user);
+
+/// Allocates memory for an array of nmemb elements of size bytes each and
+/// sets every usable byte of the block to zero. The header of the block is
+/// left alone.
+///
+/// Memory returned by this function is released with buddy_free.
+///
+/// If nmemb or size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
+/// If nmemb * size overflows or the pool is exhausted, errno is set to ENOMEM and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - nmemb `usize` The number of elements
+/// - size `usize` The size of each element in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the zeroed memory. Type = `*mut c_void`
+void *buddy_calloc(BuddyPool *pool,
+                   uintptr_t nmemb,
+                   uintptr_t size);
+
+/// Allocates size bytes for a single object and sets every usable byte of
+/// the block to zero, including the slack left by rounding size up to a
+/// power of two. Unlike buddy_calloc there is no element count to multiply.
+///
+/// Memory returned by this function is released with buddy_free.
+///
+/// If size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
+/// If the pool is exhausted, errno is set to ENOMEM and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - size `usize` The size of the user requested memory block in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the zeroed memory. Type = `*mut c_void`
+void *buddy_alloc_zeroed(BuddyPool *pool, uintptr_t size);
+
+/// Changes the size of the memory block pointed to by ptr to size bytes,
+/// returning a pointer to the resized block. The contents are preserved up
+/// to the lesser of the old and new sizes. If the block already has the right
+/// size class ptr is returned unchanged. A block that shrinks is split in
+/// place, handing the halves it no longer needs back to the free lists, and
+/// a block that grows absorbs the free buddies above it if they add up to
+/// the new class. Only when that is not possible is a new block allocated,
+/// the contents copied and the old block freed.
+///
+/// Allocations made through the aligned path, such as those of
+/// buddy_aligned_alloc, buddy_aligned_calloc and buddy_posix_memalign, keep
+/// their alignment, the new block is allocated with the same alignment as
+/// the old one.
+///
+/// If ptr is NULL this behaves like buddy_malloc(pool, size)
+/// If size is zero the block is freed and the return value will be NULL
+/// If the new block can not be allocated, NULL is returned and the old block is left untouched
+/// If ptr is not a live allocation of the pool, errno is set to EINVAL, NULL is returned and nothing is changed
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool ptr was allocated from
+/// - ptr `*mut c_void` Pointer to the memory block to resize
+/// - size `usize` The new size of the memory block in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the resized memory block. Type = `*mut c_void`
+void *buddy_realloc(BuddyPool *pool,
+                    void *ptr,
+                    uintptr_t size);
+
+/// Resizes the array at ptr to nmemb elements of size bytes each, like
+/// reallocarray from the BSDs and glibc. The multiplication is checked, so a
+/// count that would wrap around fails instead of shrinking the array.
+///
+/// If nmemb * size overflows, errno is set to ENOMEM, NULL is returned and the old block is left untouched
+/// Otherwise this behaves like buddy_realloc(pool, ptr, nmemb * size)
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool ptr was allocated from
+/// - ptr `*mut c_void` Pointer to the array to resize, may be NULL
+/// - nmemb `usize` The new number of elements
+/// - size `usize` The size of each element in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the resized array. Type = `*mut c_void`
+void *buddy_reallocarray(BuddyPool *pool,
+                         void *ptr,
+                         uintptr_t nmemb,
+                         uintptr_t size);
+
+/// Allocates size bytes whose address is a multiple of alignment, such as a
+/// cache line for SIMD data or a page for DMA. Blocks are only aligned to
+/// their own size, with the header in front of the user data, so the block
+/// is over-allocated and a record right before the aligned pointer links
+/// back to the block header.
+///
+/// Memory returned by this function is released with buddy_free and resized
+/// with buddy_realloc like any other allocation.
+///
+/// If size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
+/// If alignment is not a power of two, errno is set to EINVAL and the return value will be NULL
+/// If the pool is exhausted, errno is set to ENOMEM and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - alignment `usize` The required alignment of the returned pointer, a power of two
+/// - size `usize` The size of the user requested memory block in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the aligned memory block. Type = `*mut c_void`
+void *buddy_aligned_alloc(BuddyPool *pool, uintptr_t alignment, uintptr_t size);
+
+/// Allocates size bytes whose address is a multiple of alignment and stores
+/// the pointer in *memptr, with the signature of posix_memalign so C code
+/// written against it can use the pool. Errors are returned instead of set in
+/// errno, and on failure *memptr is not modified.
+///
+/// Memory returned by this function is released with buddy_free.
+///
+/// If size is zero, *memptr is set to NULL and the return value will be 0
+/// If pool or memptr is NULL, the return value will be EINVAL
+/// If alignment is not a power of two multiple of size_of::<*mut c_void>(), the return value will be EINVAL
+/// If the pool is exhausted, the return value will be ENOMEM
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - memptr `*mut *mut c_void` Where to store the pointer to the aligned memory block
+/// - alignment `usize` The required alignment of the returned pointer
+/// - size `usize` The size of the user requested memory block in bytes
+///
+/// ## Returns
+///
+/// - 0 on success, EINVAL or ENOMEM on failure. Type = `i32`
+int32_t buddy_posix_memalign(BuddyPool *pool,
+                             void **memptr,
+                             uintptr_t alignment,
+                             uintptr_t size);
+
+/// Allocates memory for an array of nmemb elements of size bytes each whose
+/// address is a multiple of alignment, and zeroes every usable byte of it.
+/// This is meant for buffers that must be both aligned and clean, like DMA or
+/// SIMD buffers.
+///
+/// Memory returned by this function is released with buddy_free.
+///
+/// If nmemb or size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
+/// If alignment is not a power of two, errno is set to EINVAL and the return value will be NULL
+/// If nmemb * size overflows or the pool is exhausted, errno is set to ENOMEM and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - alignment `usize` The required alignment of the returned pointer, a power of two
+/// - nmemb `usize` The number of elements
+/// - size `usize` The size of each element in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the zeroed memory. Type = `*mut c_void`
+void *buddy_aligned_calloc(BuddyPool *pool,
+                           uintptr_t alignment,
+                           uintptr_t nmemb,
+                           uintptr_t size);
+
+/// Returns whether buddy_malloc(pool, size) could be satisfied right now,
+/// i.e. whether any avail list at or above the class of the request holds a
+/// block. Nothing is removed or split and errno is left alone, so a scheduler
+/// can check before committing to a large allocation.
+///
+/// The pool is not changed, so blocks a POLICY_DEFERRED pool has yet to merge
+/// do not count, and the rate callback and split limits are not consulted.
+///
+/// If pool is NULL or size is zero, the return value will be false
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+/// - size `usize` The size of the user requested memory block in bytes
+///
+/// ## Returns
+///
+/// - True if the allocation would succeed. Type = `bool`
+bool buddy_can_alloc(BuddyPool *pool, uintptr_t size);
+
+/// Returns whether an allocation of size bytes aligned to alignment, as made
+/// by buddy_aligned_calloc, could be satisfied right now. The aligned path
+/// over-allocates to find an aligned address with room for its record, so
+/// it can need a block twice the size a plain allocation of size bytes would
+/// use, and fail where buddy_malloc would not.
+///
+/// The pool is not changed, so blocks a POLICY_DEFERRED pool has yet to merge
+/// do not count, and the rate callback and split limits are not consulted.
+///
+/// If pool is NULL, size is zero or alignment is not a power of two, the return value will be false
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+/// - size `usize` The size of the user requested memory block in bytes
+/// - alignment `usize` The required alignment of the memory, a power of two
+///
+/// ## Returns
+///
+/// - True if the aligned allocation would succeed. Type = `bool`
+bool buddy_can_alloc_aligned(BuddyPool *pool, uintptr_t size, uintptr_t alignment);
+
+/// Allocates size bytes that are guaranteed not to cross a multiple of
+/// boundary, i.e. [ptr, ptr + size) lies within a single boundary aligned
+/// window. This is meant for devices that can not DMA across such boundaries.
+///
+/// Blocks of the pool are naturally aligned to their size, so any block no
+/// larger than boundary stays inside one window. When the header pushes the
+/// request over boundary the aligned path is used instead, with the pointer
+/// aligned to boundary itself.
+///
+/// Memory returned by this function is released with buddy_free.
+///
+/// If size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
+/// If boundary is not a power of two or is smaller than size, errno is set to EINVAL and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - size `usize` The size of the user requested memory block in bytes
+/// - boundary `usize` The boundary the memory must not cross, a power of two
+///
+/// ## Returns
+///
+/// - A pointer to the memory block. Type = `*mut c_void`
+void *buddy_malloc_noboundary(BuddyPool *pool,
+                              uintptr_t size,
+                              uintptr_t boundary);
+
+/// Reports whether an allocation of size bytes made right now would hand out
+/// memory that is not resident, i.e. whether touching it would page fault.
+/// The block buddy_malloc would pick is looked up without allocating it and
+/// the residency of its pages is checked with mincore. This lets latency
+/// sensitive callers prefault memory or take another path first. Free blocks
+/// whose merging the policy postponed are merged first, as the allocation
+/// would do.
+///
+/// If the allocation could not be satisfied at all false is returned. On
+/// platforms without mincore the answer is always true.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+/// - size `usize` The size of the allocation in bytes
+///
+/// ## Returns
+///
+/// - true if the allocation would touch non-resident pages. Type = `bool`
+bool buddy_would_fault(BuddyPool *pool, uintptr_t size);
+
+/// Prepares the pool for allocations of the given classes so the first ones
+/// made at each of them neither split a block nor fault a page in. For every
+/// class whose free list is empty a larger block is split down ahead of time,
+/// then the pages of the first free block of the class are touched so they
+/// are backed by memory.
+///
+/// Classes are block classes as returned by btok, a class that is already
+/// warm is left alone.
+///
+/// If pool or classes is NULL, EINVAL is returned
+/// If a class is outside of SMALLEST_K..=kval_m, EINVAL is returned and no class is warmed
+/// If there is no free block left to split for a class, ENOMEM is returned
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to warm
+/// - classes `*const usize` The classes to warm
+/// - count `usize` The number of entries in classes
+///
+/// ## Returns
+///
+/// - 0 on success, an errno value otherwise. Type = `i32`
+int32_t buddy_warm_classes(BuddyPool *pool, const uintptr_t *classes, uintptr_t count);
+
+/// Splits free blocks ahead of time until the free list of every class k
+/// holds at least counts[k] blocks, so the first allocations of each class
+/// are served without splitting. Classes are handled from the largest down,
+/// and a class is only split into smaller ones while it holds more blocks
+/// than asked for, always taking the smallest such class.
+///
+/// The splits are planned before any is made, so the pool is only changed if
+/// the whole distribution fits. The blocks stay split until a freed buddy
+/// coalesces through them, set a policy without POLICY_COALESCE to keep the
+/// shape for good.
+///
+/// If pool or counts is NULL, EINVAL is returned
+/// If a class outside of SMALLEST_K..=kval_m asks for blocks, EINVAL is returned
+/// If the free blocks can not be split into the distribution, ENOMEM is returned and the pool is left untouched
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to shape
+/// - counts `*const usize` The number of free blocks wanted for each class, indexed by class
+/// - len `usize` The number of entries in counts
+///
+/// ## Returns
+///
+/// - 0 on success, an errno value otherwise. Type = `i32`
+int32_t buddy_prewarm(BuddyPool *pool,
+                      const uintptr_t *counts,
+                      uintptr_t len);
+
+/// Splits every free block of the pool down to the class that fits
+/// object_size, turning the free memory into a grid of equal slots on a
+/// single free list, and returns how many slots that list holds. Allocations
+/// of object_size are then served straight off the list without splitting,
+/// which makes the pool an object pool for objects of that size.
+///
+/// Freed slots are coalesced again as usual, set a policy without
+/// POLICY_COALESCE to keep the grid.
+///
+/// If pool is NULL, object_size is zero or no block fits it, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to split up
+/// - object_size `usize` The size of the objects the slots hold in bytes
+///
+/// ## Returns
+///
+/// - The number of free slots of the class. Type = `usize`
+uintptr_t buddy_slabify(BuddyPool *pool, uintptr_t object_size);
+
+/// Returns how many bytes the pool could hand out in total if all of it were
+/// carved into blocks of class assumed_block_k. Every block loses its header,
+/// so this is less than numbytes and shrinks as the blocks get smaller,
+/// which makes it the capacity to plan with for a workload of uniform
+/// allocations.
+///
+/// If pool is NULL or assumed_block_k is not a class of the pool, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+/// - assumed_block_k `usize` The class every block is assumed to have
+///
+/// ## Returns
+///
+/// - The number of usable bytes. Type = `usize`
+uintptr_t buddy_usable_capacity(BuddyPool *pool, uintptr_t assumed_block_k);
+
+/// Hands the pages of free blocks back to the system until at least
+/// target_bytes have been released, for instance when the process is under
+/// memory pressure. Free blocks are visited from the largest class down and
+/// every whole page behind their header is advised away with MADV_DONTNEED,
+/// or decommitted on Windows.
+/// The blocks stay on the free lists, their pages are faulted back in, zeroed
+/// for private mappings, when they are used again.
+///
+/// Pages are counted whether they were resident or not, so the bytes released
+/// are an upper bound on the memory actually returned.
+///
+/// If pool is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to shrink
+/// - target_bytes `usize` The number of bytes to release
+///
+/// ## Returns
+///
+/// - The number of bytes released. Type = `usize`
+uintptr_t buddy_reclaim(BuddyPool *pool, uintptr_t target_bytes);
+
+/// Hands every whole page of every free block back to the system, like
+/// buddy_reclaim without a target, so a pool that once peaked high stops
+/// holding on to memory it no longer uses. The first page of each free block
+/// is kept for its header and the mapping itself stays in place, so the pool
+/// works as before and trimmed pages are faulted back in when reused.
+///
+/// If pool is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to trim
+///
+/// ## Returns
+///
+/// - The number of bytes released. Type = `usize`
+uintptr_t buddy_trim(BuddyPool *pool);
+
+/// Returns the largest number of blocks of class k the pool could ever hold,
+/// which is the number of blocks left if the whole pool were split down to
+/// that class. Comparing it with the number of free blocks of the class gives
+/// its utilization.
+///
+/// If pool is NULL or k is not a valid class of the pool, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+/// - k `usize` The class to query
+///
+/// ## Returns
+///
+/// - The number of class k blocks that fit in the pool. Type = `usize`
+uintptr_t buddy_capacity(BuddyPool *pool, uintptr_t k);
+
+/// Returns the number of usable bytes of the largest free block of the pool,
+/// the largest allocation buddy_malloc can currently satisfy. The answer is
+/// read from an index of non-empty classes, so it takes constant time no
+/// matter how many classes the pool has.
+///
+/// If pool is NULL or it has no free block, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - The usable size of the largest free block in bytes. Type = `usize`
+uintptr_t buddy_largest_available(BuddyPool *pool);
+
+/// Same as buddy_largest_available: the usable size of the largest free
+/// block, (1 << k) - size_of::<Avail>() for the highest class k with a free
+/// block, or 0 if the pool is fully allocated. The pool is not changed.
+///
+/// If pool is NULL or it has no free block, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - The usable size of the largest free block in bytes. Type = `usize`
+uintptr_t buddy_largest_free(BuddyPool *pool);
+
+/// Returns how many classes, from SMALLEST_K to kval_m, currently have at
+/// least one free block. A fresh pool has a single one, its top block, and the
+/// more classes the free memory is spread over the more fragmented it tends
+/// to be. This is read from the index of non-empty classes, in constant time.
+///
+/// If pool is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - The number of classes with a free block. Type = `usize`
+uintptr_t buddy_active_classes(BuddyPool *pool);
+
+/// Allocates the largest free block of the pool whole and stores its usable
+/// size in size. This hands out as much contiguous memory as the pool can
+/// give without splitting anything.
+///
+/// If pool or size is NULL, the return value will be NULL
+/// If the pool has no free block, errno is set to ENOMEM and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - size `*mut usize` Receives the usable size of the returned block
+///
+/// ## Returns
+///
+/// - A pointer to the memory block. Type = `*mut c_void`
+void *buddy_malloc_largest(BuddyPool *pool, uintptr_t *size);
+
+/// Describes how the memory of the pool is backed: the file descriptor, the
+/// mmap flags and the protection of its region and whether the pool maps it
+/// itself. Pools on a caller supplied buffer report an fd of -1 and no flags
+/// or protection, since they never map anything.
+///
+/// If pool or info is NULL, EINVAL is returned
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+/// - info `*mut MappingInfo` Receives the description of the mapping
+///
+/// ## Returns
+///
+/// - 0 on success, EINVAL otherwise. Type = `i32`
+int32_t buddy_mapping_info(BuddyPool *pool, MappingInfo *info);
+
+/// Reads the running totals of the pool: allocations and frees made, live
+/// allocations, blocks split and frees whose merging was cut short by a
+/// reserved buddy. A coalesce_blocked count that keeps growing means long
+/// lived allocations are pinning the pool in a fragmented state.
+///
+/// If pool or counters is NULL, EINVAL is returned
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+/// - counters `*mut BuddyCounters` Receives the counters
+///
+/// ## Returns
+///
+/// - 0 on success, EINVAL otherwise. Type = `i32`
+int32_t buddy_counters(BuddyPool *pool, BuddyCounters *counters);
+
+/// Adds up the free lists of the pool into a BuddyStats: how many bytes and
+/// blocks are free, the largest free block, and the rest of the pool as used.
+/// Sizes are those of whole blocks, so the rounding of every allocation up to
+/// a power of two shows up as used memory. The pool is not modified.
+///
+/// If pool is NULL, every field of the result will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - The statistics of the pool. Type = `BuddyStats`
+BuddyStats buddy_stats(BuddyPool *pool);
+
+/// Returns the number of free blocks of class k, the length of a single
+/// avail list, without filling in a whole BuddyStats.
+///
+/// If pool is NULL or k is larger than the largest class of the pool, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+/// - k `usize` The class to count the free blocks of
+///
+/// ## Returns
+///
+/// - The number of free blocks of class k. Type = `usize`
+uintptr_t buddy_free_count(BuddyPool *pool, uintptr_t k);
+
+/// Measures the external fragmentation of the pool as one minus the share of
+/// the free memory held by the largest free block. 0.0 means all free memory
+/// is a single block, values near 1.0 mean it is scattered over many small
+/// blocks and large requests fail even though the pool is far from full. A
+/// value that keeps rising over time means coalescing is not keeping up. The
+/// pool is not modified.
+///
+/// If pool is NULL or it has no free memory, the return value will be 0.0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - The fragmentation ratio between 0.0 and 1.0. Type = `f64`
+double buddy_fragmentation(BuddyPool *pool);
+
+/// Returns whether the pool can be destroyed without pulling memory out from
+/// under a caller, i.e. whether every allocation made from it was freed.
+///
+/// If pool is NULL, the return value will be false
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - True if no allocation of the pool is outstanding. Type = `bool`
+bool buddy_can_destroy(BuddyPool *pool);
+
+/// Caps the number of splits the pool may perform over its whole lifetime.
+/// Once split_count has reached max, allocations that would have to split a
+/// block fail with EAGAIN, while those served straight from a free list still
+/// succeed. An allocation is also refused if its splits would take the pool
+/// past the limit. This bounds the structural work a tenant of the pool can
+/// cause.
+///
+/// Passing 0 removes the limit, which is the default.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to limit
+/// - max `u64` The largest split_count the pool may reach, 0 for no limit
+void buddy_set_lifetime_split_limit(BuddyPool *pool, uint64_t max);
+
+/// Returns the number of levels of the buddy tree of the pool below its top
+/// block, kval_m - SMALLEST_K. This is the most splits one allocation or
+/// merges one free can ever take, and the number of classes below the top
+/// one that per class bookkeeping has to cover.
+///
+/// If pool is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - The depth of the buddy tree. Type = `usize`
+uintptr_t buddy_depth(BuddyPool *pool);
+
+/// Returns the largest number of splits a single buddy_malloc call has needed
+/// since the pool was initialized. Every split is one step of the allocation
+/// path, so this bounds the worst case allocation latency seen so far.
+///
+/// If pool is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - The deepest split chain performed by one allocation. Type = `u16`
+uint16_t buddy_max_split_depth(BuddyPool *pool);
+
+/// Returns the natural alignment of the block behind ptr, 1 << kval. Blocks
+/// of class k start at a multiple of 2^k, because the region itself is
+/// aligned to its size, so this is the alignment the block is guaranteed to
+/// have for free.
+///
+/// Notice that it is the block that has this alignment, not ptr: the user
+/// data starts after the block header, at block + size_of::<Avail>(). Callers
+/// that need the large alignment can round ptr up to it inside the block, or
+/// create the pool with INIT_TRAILING_HEADER so ptr is the block itself.
+///
+/// If pool or ptr is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool ptr was allocated from
+/// - ptr `*mut c_void` Pointer to a live allocation
+///
+/// ## Returns
+///
+/// - The alignment of the start of the block in bytes. Type = `usize`
+uintptr_t buddy_natural_alignment(BuddyPool *pool, void *ptr);
+
+/// Returns how many bytes starting at ptr the caller may use. Requests are
+/// rounded up to a power of two block, so this is usually more than was asked
+/// for, and the caller is free to grow into the rest of the block without
+/// calling buddy_realloc. This is the counterpart of malloc_usable_size.
+///
+/// If pool or ptr is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool ptr was allocated from
+/// - ptr `*mut c_void` Pointer to a live allocation
+///
+/// ## Returns
+///
+/// - The number of usable bytes of the allocation. Type = `usize`
+uintptr_t buddy_usable_size(BuddyPool *pool, void *ptr);
+
+/// Returns the offset from the start of the pool of the block holding the
+/// allocation at ptr. The offset stays the same for as long as the allocation
+/// lives, so it can key metadata kept outside of the pool.
+///
+/// If pool or ptr is NULL or ptr is not inside the pool, the return value will be usize::MAX
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool ptr was allocated from
+/// - ptr `*mut c_void` Pointer to a live allocation
+///
+/// ## Returns
+///
+/// - The offset of the block in bytes. Type = `usize`
+uintptr_t buddy_offset_of(BuddyPool *pool, void *ptr);
+
+/// Returns the index of the block holding the allocation at ptr among the
+/// smallest blocks of the pool, i.e. its offset divided by the smallest block
+/// size. No two live allocations share an index and every index is below
+/// the pool size divided by the smallest block size, so a flat array of that
+/// length can hold per allocation metadata.
+///
+/// If pool or ptr is NULL or ptr is not inside the pool, the return value will be usize::MAX
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool ptr was allocated from
+/// - ptr `*mut c_void` Pointer to a live allocation
+///
+/// ## Returns
+///
+/// - The index of the block. Type = `usize`
+uintptr_t buddy_block_index(BuddyPool *pool, void *ptr);
+
+/// Returns how many allocations were made from the pool after the one that
+/// returned ptr. The most recent allocation has an age of 0, so comparing
+/// ages tells which of several live allocations is the oldest, e.g. for a
+/// cache deciding what to evict.
+///
+/// If pool or ptr is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool ptr was allocated from
+/// - ptr `*mut c_void` Pointer to a live allocation
+///
+/// ## Returns
+///
+/// - The age of the allocation. Type = `u64`
+uint64_t buddy_alloc_age(BuddyPool *pool, void *ptr);
+
+/// Initialize a new memory pool using the buddy algorithm. Internally,
+/// this function uses mmap to get a block of memory to manage so should be
+/// portable to any system that implements mmap. On Windows the memory comes
+/// from VirtualAlloc instead. This function will round
+/// up to the nearest power of two. So if the user requests 503MiB
+/// it will be rounded up to 512MiB.
+///
+/// The region is placed at an address that is a multiple of its own size, so
+/// every block of the pool is naturally aligned to its size.
+///
+/// Note that if a 0 is passed as an argument then it initializes
+/// the memory pool to be of the default size of DEFAULT_K. If the caller
+/// specifies an unreasonably small size, then the buddy system may
+/// not be able to satisfy any requests.
+///
+/// If mmap fails this function panics, unless buddy_set_abort_on_error(false)
+/// was called, in which case the pool is left zeroed and the errno reported
+/// by mmap is returned.
+///
+/// NOTE: Memory pools returned by this function can not be intermingled.
+/// Calling buddy_malloc with pool A and then calling buddy_free with
+/// pool B will result in undefined behavior.
+/// BuddyRegistry finds the right pool for a pointer when several are in use.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` A pointer to the pool to initialize
+/// - size `usize` The size of the pool in bytes.
+///
+/// ## Returns
+///
+/// - 0 on success or the errno of the failed mapping. Type = `i32`
+int32_t buddy_init(BuddyPool *pool, uintptr_t size);
+
+/// Same as buddy_init but with extra options for how the pool is set up.
+///
+/// With INIT_END_SENTINEL a few extra bytes are mapped past the end of the
+/// region to hold a BLOCK_SENTINEL record. Address-order walks over the pool
+/// then have to land exactly on that record, which buddy_check_layout uses to
+/// detect corrupted headers.
+///
+/// With INIT_SHARED the region is mapped MAP_SHARED | MAP_ANONYMOUS. A private
+/// region is copied on write into a child after fork, while a shared one stays
+/// the same memory in the parent and all of its children, so bytes written by
+/// one process are seen by the others. Only the region is shared, the
+/// BuddyPool itself is as private as the memory it lives in. Processes that
+/// allocate from the same pool must place it in shared memory too, use offsets
+/// from base rather than raw pointers if the region may be mapped elsewhere,
+/// and serialize their calls with a lock of their own. Windows has no fork,
+/// so there INIT_SHARED fails with EINVAL.
+///
+/// With INIT_TRAILING_HEADER reserved blocks keep their header in their last
+/// bytes instead of their first ones, so the pointer handed out is the start
+/// of the block and shares its natural alignment, e.g. a 64 byte block is
+/// cache line aligned. Free blocks still keep their header at the start for
+/// the free lists. To find the header again, buddy_free tries every class
+/// the pointer is aligned to and accepts the reserved header that links back
+/// to it, so user data must never be written past the usable size.
+///
+/// With INIT_HUGEPAGES the region is mapped with MAP_HUGETLB on Linux, backed
+/// by 2 MiB huge pages so a large pool needs far fewer TLB entries. Huge pages
+/// have to be reserved by the administrator first (vm.nr_hugepages), and when
+/// the mapping fails the pool falls back to normal pages instead. Whether huge
+/// pages were used shows in map_flags, see buddy_mapping_info. The size should
+/// be a multiple of the huge page size, a smaller pool still takes a whole
+/// huge page. Other systems ignore the flag.
+///
+/// If mmap fails with ENOMEM or EAGAIN it is retried up to map_retries times,
+/// sleeping retry_delay_us microseconds before the first retry and twice as
+/// long before each one after it. The error is only reported once every retry
+/// failed.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` A pointer to the pool to initialize
+/// - size `usize` The size of the pool in bytes.
+/// - options `*const BuddyInitOptions` The options to use, NULL for the defaults
+///
+/// ## Returns
+///
+/// - 0 on success or the errno of the failed mapping. Type = `i32`
+int32_t buddy_init_with(BuddyPool *pool, uintptr_t size, const BuddyInitOptions *options);
+
+/// Walks every block of the pool in address order and checks that the walk
+/// covers exactly the whole region. For pools created with INIT_END_SENTINEL
+/// the walk must also finish on the sentinel record. A false result means a
+/// block header was corrupted.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to check
+///
+/// ## Returns
+///
+/// - true if the layout of the pool is consistent. Type = `bool`
+bool buddy_check_layout(BuddyPool *pool);
+
+/// Reports every allocation of the pool with its owner, to find out which
+/// subsystem holds memory that should have been released. Reserved blocks are
+/// visited in address order and visit is called with the user pointer of the
+/// block, the number of usable bytes in it, the owner recorded by
+/// buddy_malloc_tagged (0 for every other allocation) and user. Free blocks
+/// are skipped. visit must not allocate from or free to the pool.
+///
+/// For allocations made through the aligned path visit receives the start of
+/// the block's user data rather than the aligned pointer.
+///
+/// If pool is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to walk
+/// - visit `extern "C" fn(*mut c_void, usize, u32, *mut c_void)` Called for every allocation
+/// - user `*mut c_void` User data passed to visit
+///
+/// ## Returns
+///
+/// - The number of allocations reported. Type = `usize`
+uintptr_t buddy_walk_allocated(BuddyPool *pool, void (*visit)(void *ptr,
+                                                              uintptr_t usable,
+                                                              uint32_t owner,
+                                                              void *user), void *user);
+
+/// Checks the structure of the avail lists, so tests and debug builds can
+/// assert that a pool is healthy. Every list must be a consistent doubly
+/// linked ring through its sentinel, with an empty list pointing at itself,
+/// and hold only BLOCK_AVAIL blocks of its class that lie inside the pool,
+/// aligned to their size. Lists below SMALLEST_K must be empty, free_mask
+/// must match which lists are not, and the kval_m list can hold at most one
+/// block, the whole pool at base.
+///
+/// Unlike buddy_check_layout the blocks are not walked in address order, so
+/// this also works on pools whose reserved headers were overwritten.
+///
+/// If pool is NULL, the return value will be false
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to check
+///
+/// ## Returns
+///
+/// - true if every avail list is consistent. Type = `bool`
+bool buddy_validate(BuddyPool *pool);
+
+/// Finds the block of the pool that addr falls into, for instance the
+/// faulting address of a crash, and describes it in info. The blocks are
+/// walked in address order, so the answer is only as good as the headers in
+/// front of addr.
+///
+/// If pool or info is NULL or addr is outside the pool, the return value will be false
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to search
+/// - addr `*const c_void` The address to look up
+/// - info `*mut BlockInfo` Receives the block containing addr
+///
+/// ## Returns
+///
+/// - True if a block containing addr was found. Type = `bool`
+bool buddy_block_at_address(BuddyPool *pool, const void *addr, BlockInfo *info);
+
+/// Returns the total size of the reserved blocks whose owner is tag, walking
+/// the pool in address order. Whole blocks are counted, header included, so
+/// the totals of all tags add up to the memory in use. Tag 0 covers every
+/// allocation not made with buddy_malloc_tagged.
+///
+/// If pool is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to walk
+/// - tag `u32` The owner tag to add up
+///
+/// ## Returns
+///
+/// - The bytes held by blocks with this owner. Type = `usize`
+uintptr_t buddy_bytes_by_tag(BuddyPool *pool, uint32_t tag);
+
+/// Initialize a new memory pool using the buddy algorithm on top of a region
+/// of memory supplied by the caller instead of one obtained from mmap. This
+/// allows placing the pool in a static buffer or a shared memory segment.
+///
+/// The size is rounded down to the nearest power of two and only that many
+/// bytes from base are managed. The base must be aligned to that rounded size,
+/// i.e. the size of the largest block, so that every block of the pool is
+/// naturally aligned to its own size. If base is misaligned, or the region is
+/// too small to hold a block of size 2^SMALLEST_K, the pool is left untouched
+/// and EINVAL is returned. Such a block is the least that fits the header of
+/// the top block, which is checked when the crate is compiled.
+///
+/// The memory stays owned by the caller: buddy_destroy will not unmap it and
+/// it must outlive the pool.
+///
+/// The free lists hold absolute addresses, so to share a pool between
+/// processes the BuddyPool itself must be placed in the shared segment too and
+/// every process must map the segment at the same address.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` A pointer to the pool to initialize
+/// - base `*mut c_void` The start of the memory region to manage
+/// - size `usize` The size of the memory region in bytes
+///
+/// ## Returns
+///
+/// - 0 on success or EINVAL if the region can not be used. Type = `i32`
+int32_t buddy_init_from_buffer(BuddyPool *pool, void *base, uintptr_t size);
+
+/// Inverse of buddy_init.
+///
+/// Pools initialized with buddy_init_from_buffer do not own their memory, so
+/// it is left for the caller to release.
+///
+/// If munmap fails this function panics, unless buddy_set_abort_on_error(false)
+/// was called, in which case the pool is left intact and the errno reported by
+/// munmap is returned.
+///
+/// Notice that this function does not change the value of pool itself,
+/// hence it still points to the same (now invalid) location.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to destroy
+///
+/// ## Returns
+///
+/// - 0 on success or the errno of the failed unmapping. Type = `i32`
+int32_t buddy_destroy(BuddyPool *pool);
+
+/// Frees every allocation of the pool by handing its region back to the
+/// system and mapping a fresh one of the same size in its place. Unlike
+/// freeing everything by hand, the new pages are guaranteed to be zeroed and
+/// not resident until they are touched. The policy, callbacks and counters of
+/// the pool are kept.
+///
+/// The new region usually lives at a different address, so every pointer
+/// into the old one, including those held by the caller, is invalidated.
+///
+/// If pool is NULL or it does not own its mapping, EINVAL is returned
+/// If mapping the new region or unmapping the old one fails, the behavior
+/// follows buddy_set_abort_on_error. If the new region can not be mapped the
+/// pool is left untouched.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to recycle
+///
+/// ## Returns
+///
+/// - 0 on success, an errno value otherwise. Type = `i32`
+int32_t buddy_recycle(BuddyPool *pool);
+
+/// Shrinks the pool to new_size bytes, rounded up to a power of two and to
+/// at least 2^MIN_K, and hands the memory past the new end back to the
+/// system for good. Unlike buddy_reclaim, which only drops the physical pages
+/// of free blocks, the address range is unmapped and kval_m and numbytes
+/// shrink with it. This only works if everything past the new end is free,
+/// i.e. the upper half of the pool, the upper half of the lower half and so
+/// on down to the new size can each merge into a single free block. Live
+/// allocations below the new end stay where they are.
+///
+/// On Windows the tail is decommitted rather than released, its address
+/// range stays reserved until the pool is destroyed. Pools registered with a
+/// BuddyRegistry have to be registered again to update their range.
+///
+/// If pool is NULL or it does not own its mapping, EINVAL is returned
+/// If new_size is not smaller than the pool, nothing happens and 0 is returned
+/// If any memory past the new end is in use, EBUSY is returned and the pool is left as it was
+/// If unmapping the tail fails, the behavior follows buddy_set_abort_on_error
+/// and the pool is left as it was.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to shrink
+/// - new_size `usize` The size to shrink the pool to in bytes
+///
+/// ## Returns
+///
+/// - 0 on success, an errno value otherwise. Type = `i32`
+int32_t buddy_shrink(BuddyPool *pool, uintptr_t new_size);
+
+/// Frees every allocation of the pool at once by putting it back in the state
+/// buddy_init left it in, a single free block of size 2^kval_m at base. The
+/// region is reused as is, without a call to mmap or munmap, which makes this
+/// much cheaper than destroying and recreating the pool when it is used as an
+/// arena. The policy, callbacks and counters of the pool are kept and the
+/// contents of the memory are left as they were.
+///
+/// Every pointer previously returned by the pool is invalidated.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to reset
+void buddy_reset(BuddyPool *pool);
+
+/// Exchanges everything two pools hold, their regions, free lists, policies
+/// and counters, so a caller holding a pointer to a keeps using it and now
+/// sees the memory of b, and the other way around. This lets a new pool be
+/// built and filled in the background and then swapped in place of a live
+/// one.
+///
+/// The pools must be separate, each with its own region, and no allocation
+/// or free may be in progress on either of them during the swap. The swap is
+/// not synchronized, guarding it against other threads is up to the caller.
+///
+/// If a or b is NULL, or they are the same pool, EINVAL is returned
+///
+/// ## Parameters
+///
+/// - a `*mut BuddyPool` The first pool
+/// - b `*mut BuddyPool` The second pool
+///
+/// ## Returns
+///
+/// - 0 on success, EINVAL otherwise. Type = `i32`
+int32_t buddy_swap(BuddyPool *a, BuddyPool *b);
+
+/// Reports what buddy_destroy would do to the pool without doing it: the
+/// number of bytes it would unmap and the number of bytes still held by live
+/// allocations, which would be pulled out from under their owners. Teardown
+/// code can log these or refuse to go ahead. The pool is not changed.
+///
+/// Pools initialized with buddy_init_from_buffer report 0 bytes to unmap,
+/// since buddy_destroy leaves their memory alone.
+///
+/// If pool, would_unmap_bytes or outstanding_bytes is NULL, EINVAL is returned
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to inspect
+/// - would_unmap_bytes `*mut usize` Receives the number of bytes buddy_destroy would unmap
+/// - outstanding_bytes `*mut usize` Receives the number of bytes in reserved blocks
+///
+/// ## Returns
+///
+/// - 0 on success, EINVAL otherwise. Type = `i32`
+int32_t buddy_destroy_dry_run(BuddyPool *pool,
+                              uintptr_t *would_unmap_bytes,
+                              uintptr_t *outstanding_bytes);
+
+/// Selects how failures of the backing memory (mmap and munmap) are handled
+/// by buddy_init, buddy_destroy and buddy_recycle. When abort is true, the
+/// default, they panic. When abort is false they return the errno of the
+/// failure instead, which is usually what a C caller wants.
+///
+/// This setting is shared by every pool in the process.
+///
+/// ## Parameters
+///
+/// - abort `bool` Whether failures should panic
+void buddy_set_abort_on_error(bool abort);
+
+/// Returns whether failures of the backing memory panic, see buddy_set_abort_on_error.
+///
+/// ## Returns
+///
+/// - true if failures panic. Type = `bool`
+bool buddy_get_abort_on_error();
+
+/// Returns the errno value left by the last call that failed. With the std
+/// feature this is errno of the calling thread. Without it there is no libc
+/// to keep errno, so the value is shared by the whole program and only
+/// meaningful right after the failed call.
+///
+/// ## Returns
+///
+/// - The errno of the last failure. Type = `i32`
+int32_t buddy_errno();
+
+/// Writes a compact description of the shape of the pool into the buffer out.
+/// Every block of the pool is visited in address order and recorded as a
+/// `LAYOUT_RECORD_SIZE` byte record holding its tag followed by its kval. The
+/// user data stored in the pool is never copied.
+///
+/// If out is NULL nothing is written and the number of bytes the layout needs
+/// is returned, so callers can size their buffer. If the layout does not fit
+/// in len bytes, or the walk finds a corrupted header, nothing is written and
+/// 0 is returned.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to capture
+/// - out `*mut u8` The buffer to write the layout into
+/// - len `usize` The size of the buffer in bytes
+///
+/// ## Returns
+///
+/// - The number of bytes written. Type = `usize`
+uintptr_t buddy_export_layout(BuddyPool *pool, uint8_t *out, uintptr_t len);
+
+/// Compares two layouts captured with buddy_export_layout from the same pool
+/// and reports every block of layout b that does not appear with the same
+/// offset, kval and tag in layout a, i.e. the blocks that changed between the
+/// two captures.
+///
+/// At most cap changes are written to out, but the total number of changed
+/// blocks is always returned. Passing a NULL out only counts the changes.
+///
+/// ## Parameters
+///
+/// - a `*const u8` The earlier layout
+/// - a_len `usize` The size of the earlier layout in bytes
+/// - b `*const u8` The later layout
+/// - b_len `usize` The size of the later layout in bytes
+/// - out `*mut BlockInfo` Buffer receiving the changed blocks of b
+/// - cap `usize` The number of entries out can hold
+///
+/// ## Returns
+///
+/// - The number of blocks that changed. Type = `usize`
+uintptr_t buddy_layout_diff(const uint8_t *a,
+                            uintptr_t a_len,
+                            const uint8_t *b,
+                            uintptr_t b_len,
+                            BlockInfo *out,
+                            uintptr_t cap);
+
+/// Recomputes the counters the pool keeps up to date incrementally, the free
+/// byte count, the live allocation count and the index of non-empty classes,
+/// from the free lists and a walk over the region, and compares them with the
+/// stored values. A mismatch means a bug or memory corruption made the
+/// accounting drift. If fix is true the stored values are replaced by the
+/// recomputed ones.
+///
+/// If pool is NULL, the return value will be false
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to audit
+/// - fix `bool` Whether to repair counters that drifted
+///
+/// ## Returns
+///
+/// - True if every counter matched. Type = `bool`
+bool buddy_audit_counters(BuddyPool *pool, bool fix);
+
+/// Writes the statistics of the pool into the buffer out as text in the
+/// Prometheus exposition format, ready to be served to a scraper. The metrics
+/// are buddy_total_bytes, buddy_free_bytes, buddy_live_allocations,
+/// buddy_fragmentation_ratio and buddy_free_blocks, which has one sample per
+/// class labelled with its kval. The fragmentation ratio is one minus the
+/// share of free memory held by the largest free block. The text is not NUL
+/// terminated.
+///
+/// If out is NULL nothing is written and the number of bytes the text needs
+/// is returned, so callers can size their buffer. If the text does not fit in
+/// len bytes nothing is written and 0 is returned.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to report on
+/// - out `*mut u8` The buffer to write the metrics into
+/// - len `usize` The size of the buffer in bytes
+///
+/// ## Returns
+///
+/// - The number of bytes written. Type = `usize`
+uintptr_t buddy_export_prometheus(BuddyPool *pool, uint8_t *out, uintptr_t len);
+
+/// Prints the free lists of the pool to stderr, see buddy_dump_to_string for
+/// the format. Going to stderr keeps the dump out of data written to stdout.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to dump
+void buddy_dump(BuddyPool *pool);
 
 }  // extern "C"
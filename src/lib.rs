@@ -1,27 +1,268 @@
-use libc::{memset, mmap, munmap, MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_READ, PROT_WRITE, __errno_location, ENOMEM};
-use std::ptr;
-use std::ffi::c_void;
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(feature = "std")]
+use libc::{EAGAIN, EBUSY, EINVAL, ENOMEM};
+#[cfg(all(unix, feature = "std"))]
+use libc::{madvise, mmap, munmap, sysconf, MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, MAP_SHARED, PROT_READ, PROT_WRITE, _SC_PAGESIZE, MADV_DONTNEED, __errno_location};
+#[cfg(all(target_os = "linux", feature = "std"))]
+use libc::{mincore, MAP_HUGETLB};
+use core::ptr;
+#[cfg(feature = "std")]
+use core::ptr::NonNull;
+use core::ffi::c_void;
+#[cfg(feature = "std")]
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(not(feature = "std"))]
+use core::sync::atomic::AtomicI32;
+#[cfg(feature = "std")]
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+#[cfg(feature = "std")]
+use std::time::Duration;
+
+// Without libc the errno values are those of Linux
+#[cfg(not(feature = "std"))]
+const EAGAIN: i32 = 11;
+#[cfg(not(feature = "std"))]
+const ENOMEM: i32 = 12;
+#[cfg(not(feature = "std"))]
+const EINVAL: i32 = 22;
 
 pub const DEFAULT_K: usize = 30;
 pub const MIN_K: usize = 20;
 pub const MAX_K: usize = 48;
 pub const SMALLEST_K: usize = 6;
 
+// Every block, down to the smallest and up to the smallest pool, must have room for its header
+const _: () = assert!(1 << SMALLEST_K >= core::mem::size_of::<Avail>() && SMALLEST_K <= MIN_K && MIN_K < MAX_K);
+
 pub const BLOCK_AVAIL: u16 = 1;
 pub const BLOCK_RESERVED: u16 = 0;
 pub const BLOCK_UNUSED: u16 = 3;
+pub const BLOCK_ALIGNED: u16 = 2;
+pub const BLOCK_SENTINEL: u16 = 4;
+
+/// Place a sentinel record right after the end of the managed region
+pub const INIT_END_SENTINEL: u32 = 1 << 0;
+
+/// Map the region MAP_SHARED instead of MAP_PRIVATE so forked children share it
+pub const INIT_SHARED: u32 = 1 << 1;
+
+/// Keep the header of reserved blocks at their end so user data starts on the block
+pub const INIT_TRAILING_HEADER: u32 = 1 << 2;
+
+/// Back the region with huge pages on Linux, falling back to normal pages
+pub const INIT_HUGEPAGES: u32 = 1 << 3;
+
+/// Size of the huge pages INIT_HUGEPAGES maps, the default on x86-64 and arm64
+#[cfg(all(target_os = "linux", feature = "std"))]
+const HUGE_PAGE_SIZE: usize = 1 << 21;
+
+/// Coalesce freed blocks with their free buddies
+pub const POLICY_COALESCE: u32 = 1 << 0;
+/// Postpone coalescing until an allocation can not be satisfied otherwise
+pub const POLICY_DEFERRED: u32 = 1 << 1;
+/// Never coalesce freed blocks of the smallest class so small allocations stay cheap
+pub const POLICY_SMALL_FAST: u32 = 1 << 2;
+/// Verify block headers in buddy_malloc and buddy_free and report violations to the assert handler
+pub const POLICY_CHECKED: u32 = 1 << 3;
+/// With POLICY_DEFERRED, try to assemble the requested class from free buddies before splitting a larger block
+pub const POLICY_EAGER: u32 = 1 << 4;
+/// Policy flags of a freshly initialized pool
+pub const POLICY_DEFAULT: u32 = POLICY_COALESCE;
+
+/// Freed blocks are pushed to the front of their list and reused first
+pub const STRATEGY_LIFO: u32 = 0;
+/// Freed blocks are appended to the back of their list and reused last
+pub const STRATEGY_FIFO: u32 = 1;
+/// Like LIFO, but when a block has to be split the most recently freed larger
+/// block is split instead of the smallest one, see buddy_set_policy
+pub const STRATEGY_CACHE_HOT: u32 = 2;
+/// Freed blocks are kept sorted by address so the lowest free block is reused
+/// first, at the cost of walking the list on every insert, see buddy_set_policy
+pub const STRATEGY_ADDRESS: u32 = 3;
+
+/// Whether failures of the backing memory panic or are returned as error codes
+static ABORT_ON_ERROR: AtomicBool = AtomicBool::new(true);
+
+/// Address of the handler for invariant violations, 0 when none is installed
+static ASSERT_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Byte fresh allocations are filled with when the poison feature is enabled
+#[cfg(feature = "poison")]
+const POISON_ALLOC: u8 = 0xAB;
+
+/// Byte freed blocks are filled with when the poison feature is enabled
+#[cfg(feature = "poison")]
+const POISON_FREE: u8 = 0xDD;
+
+/// errno of the last failure, for builds without libc
+#[cfg(not(feature = "std"))]
+static ERRNO: AtomicI32 = AtomicI32::new(0);
+
+// Windows has no mmap, the region comes from VirtualAlloc instead. The POSIX
+// protection and flags are kept so the pool records the same values everywhere.
+#[cfg(all(windows, feature = "std"))]
+const PROT_READ: i32 = 0x1;
+#[cfg(all(windows, feature = "std"))]
+const PROT_WRITE: i32 = 0x2;
+#[cfg(all(windows, feature = "std"))]
+const MAP_SHARED: i32 = 0x01;
+#[cfg(all(windows, feature = "std"))]
+const MAP_PRIVATE: i32 = 0x02;
+#[cfg(all(windows, feature = "std"))]
+const MAP_ANONYMOUS: i32 = 0x20;
 
-/// Struct to represent the table of all available blocks do not reorder members 
+#[cfg(all(windows, feature = "std"))]
+const MEM_COMMIT: u32 = 0x1000;
+#[cfg(all(windows, feature = "std"))]
+const MEM_RESERVE: u32 = 0x2000;
+#[cfg(all(windows, feature = "std"))]
+const MEM_DECOMMIT: u32 = 0x4000;
+#[cfg(all(windows, feature = "std"))]
+const MEM_RELEASE: u32 = 0x8000;
+#[cfg(all(windows, feature = "std"))]
+const PAGE_READWRITE: u32 = 0x04;
+
+/// SYSTEM_INFO as filled in by GetSystemInfo
+#[cfg(all(windows, feature = "std"))]
+#[repr(C)]
+struct SystemInfo {
+    processor_architecture: u16,
+    reserved: u16,
+    page_size: u32,
+    minimum_application_address: *mut c_void,
+    maximum_application_address: *mut c_void,
+    active_processor_mask: usize,
+    number_of_processors: u32,
+    processor_type: u32,
+    allocation_granularity: u32,
+    processor_level: u16,
+    processor_revision: u16,
+}
+
+#[cfg(all(windows, feature = "std"))]
+extern "system" {
+    fn VirtualAlloc(address: *mut c_void, size: usize, allocation_type: u32, protect: u32) -> *mut c_void;
+    fn VirtualFree(address: *mut c_void, size: usize, free_type: u32) -> i32;
+    fn GetSystemInfo(info: *mut SystemInfo);
+}
+
+#[cfg(all(windows, feature = "std"))]
+extern "C" {
+    #[link_name = "_errno"]
+    fn __errno_location() -> *mut i32;
+}
+
+/// Struct to represent the table of all available blocks do not reorder members
 /// of this struct because internal calculations depend on the ordering.
 #[repr(C)]
 #[derive(Debug)]
 pub struct Avail {
-    pub tag: u16,    // Block status: BLOCK_AVAIL, BLOCK_RESERVED
+    pub tag: u16,    // Block status: BLOCK_AVAIL, BLOCK_RESERVED, BLOCK_ALIGNED
     pub kval: u16,   // kval of this block
+    pub owner: u32,  // Owner tag of a reserved block, see buddy_malloc_tagged
     pub next: *mut Avail,
     pub prev: *mut Avail,
+    pub stamp: u64,  // Allocation clock when this block was reserved, free clock when it was freed
+}
+
+/// Options for buddy_init_with
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BuddyInitOptions {
+    pub flags: u32,          // Combination of INIT_* flags
+    pub map_retries: u32,    // Number of times a transiently failing mmap is retried
+    pub retry_delay_us: u32, // Delay before the first retry, doubled for every further one
+}
+
+/// The tuning knobs of a pool that can be changed at any time
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuddyPolicy {
+    pub flags: u32,    // Combination of POLICY_* flags
+    pub strategy: u32, // Free list strategy: STRATEGY_LIFO, STRATEGY_FIFO, STRATEGY_CACHE_HOT, STRATEGY_ADDRESS
+}
+
+/// How the memory of a pool is backed
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingInfo {
+    pub fd: i32,          // File descriptor backing the mapping, -1 if anonymous
+    pub flags: i32,       // MAP_* flags the region was mapped with, 0 if not mapped by the pool
+    pub prot: i32,        // PROT_* protection of the region, 0 if not mapped by the pool
+    pub owns_mapping: u8, // 1 if the pool maps and unmaps the region itself
+}
+
+/// Running totals of the work done by a pool
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BuddyCounters {
+    pub allocs: u64,           // Number of allocations made
+    pub frees: u64,            // Number of allocations freed
+    pub live: u64,             // Number of allocations not freed yet
+    pub splits: u64,           // Number of blocks split in half
+    pub coalesce_blocked: u64, // Number of frees whose merging stopped at a reserved buddy
+}
+
+/// Snapshot of how the memory of a pool is split between free and used blocks
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BuddyStats {
+    pub total_bytes: usize,        // Size of the pool, 2^kval_m
+    pub free_bytes: usize,         // Bytes in blocks on the free lists
+    pub used_bytes: usize,         // Bytes in blocks that are not free, headers included
+    pub largest_free_block: usize, // Size of the largest free block, 0 if there is none
+    pub num_free_blocks: usize,    // Number of blocks on the free lists
+}
+
+/// Why the pool could not hand out or take back memory
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuddyError {
+    Ok = 0,             // Nothing went wrong
+    NullPool = 1,       // The pool was NULL
+    NullPtr = 2,        // The pointer to free was NULL
+    InvalidPointer = 3, // The pointer to free does not belong to the pool
+    DoubleFree = 4,     // The pointer to free was freed already
+    OutOfMemory = 5,    // No free block is large enough right now
+    ZeroSize = 6,       // Zero bytes were requested
+    Throttled = 7,      // The rate callback or split budget turned the request down, try again later
+    TooLarge = 8,       // The request does not fit even into an empty pool
+}
+
+impl BuddyError {
+    /// Helper function.
+    ///
+    /// Converts the errno left by a failed allocation.
+    fn from_errno() -> BuddyError {
+        match errno() {
+            EAGAIN => BuddyError::Throttled,
+            _ => BuddyError::OutOfMemory,
+        }
+    }
 }
 
+impl core::fmt::Display for BuddyError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            BuddyError::Ok => "no error",
+            BuddyError::NullPool => "null buddy pool",
+            BuddyError::NullPtr => "null pointer freed",
+            BuddyError::InvalidPointer => "pointer not allocated from the buddy pool",
+            BuddyError::DoubleFree => "buddy block freed twice",
+            BuddyError::OutOfMemory => "buddy pool out of memory",
+            BuddyError::ZeroSize => "zero sized buddy allocation",
+            BuddyError::Throttled => "buddy allocation throttled",
+            BuddyError::TooLarge => "buddy allocation larger than the pool",
+        })
+    }
+}
+
+impl core::error::Error for BuddyError {}
+
 /// The Buddy Memory Pool
 #[repr(C)]
 #[derive(Debug)]
@@ -30,10 +271,39 @@ pub struct BuddyPool {
     pub numbytes: usize,       // Number of bytes in this pool
     pub base: *mut c_void,     // Base address for memory calculations
     pub avail: [Avail; MAX_K], // Array of available memory blocks
+    pub owns_mapping: bool,    // Whether base was mmapped by the pool and must be unmapped
+    pub policy: BuddyPolicy,   // Coalescing and free list behavior
+    pub sentinel: *mut Avail,  // Record marking the end of the region, NULL if there is none
+    pub alloc_clock: u64,      // Number of allocations made from this pool
+    pub free_clock: u64,       // Number of frees made to this pool
+    pub rate_callback: Option<extern "C" fn(count: u64, user: *mut c_void) -> bool>, // Called when a rate window is exceeded
+    pub rate_threshold: u64,   // Number of allocations allowed per rate window
+    pub rate_count: u64,       // Number of allocations in the current window
+    pub rate_user: *mut c_void, // User data handed to rate_callback
+    pub max_split_depth: u16,  // Most splits a single allocation ever needed
+    pub split_count: u64,      // Number of blocks split in half so far
+    pub live_count: usize,     // Number of allocations that have not been freed yet
+    pub free_mask: u64,        // Bit k is set while avail[k] is not empty
+    pub highest_free_k: usize, // Largest class with a free block, 0 if there is none
+    pub map_fd: i32,           // File descriptor the region is mapped from, -1 if anonymous
+    pub map_flags: i32,        // MAP_* flags the region was mapped with
+    pub map_prot: i32,         // PROT_* protection the region was mapped with
+    pub coalesce_blocked: u64, // Number of frees whose merging stopped at a reserved buddy
+    pub split_limit: u64,      // Most splits the pool may ever perform, 0 for no limit
+    pub trailing_header: bool, // Whether reserved blocks keep their header in their last bytes
+    pub oom_errno: i32,        // errno set when the pool runs out of memory, 0 for ENOMEM
+    pub free_bytes: usize,     // Number of bytes in blocks on the free lists
+    pub headerless_count: usize, // Number of live allocations made without a header
+    pub free_bits: *mut u64,   // Bit per block of every class, set while it is on its free list, NULL if not kept
+    pub on_alloc: Option<extern "C" fn(size: usize, ptr: *mut c_void)>, // Called after every successful allocation
+    pub on_free: Option<extern "C" fn(ptr: *mut c_void)>, // Called after every successful buddy_free
 }
 
 /// Converts bytes to its equivalent K value defined as bytes <= 2^K
 ///
+/// Sizes above the largest power of two a usize can hold saturate to
+/// usize::BITS - 1 instead of overflowing.
+///
 /// ## Parameters
 ///
 /// - bytes `usize` The number of bytes needed
@@ -48,6 +318,12 @@ pub extern "C" fn btok(bytes: usize) -> usize {
         return 0;
     }
 
+    // Past the largest power of two the shift below would overflow
+    const LIMIT: usize = 1 << (usize::BITS - 1);
+    if bytes > LIMIT {
+        return usize::BITS as usize - 1;
+    }
+
     // Initialize k to the smallest block size
     let mut k = 0;
 
@@ -62,6 +338,10 @@ pub extern "C" fn btok(bytes: usize) -> usize {
 
 /// Find the buddy of a given pointer and kval relative to the base address we got from mmap
 ///
+/// A block of class kval_m spans the whole pool and has no buddy, for such a
+/// block the block itself is returned. Use buddy_has_buddy to tell the cases
+/// apart.
+///
 /// ## Parameters
 /// - pool `*mut BuddyPool` The memory pool to work on (needed for the base addresses)
 /// - buddy `*mut Avail` The memory block that we want to find the buddy for
@@ -71,6 +351,10 @@ pub extern "C" fn btok(bytes: usize) -> usize {
 ///  - A pointer to the buddy. Type = `*mut Avail`
 #[no_mangle]
 pub extern "C" fn buddy_calc(pool: *mut BuddyPool, buddy: *mut Avail) -> *mut Avail {
+    if !buddy_has_buddy(pool, buddy) {
+        return buddy;
+    }
+
     unsafe {
         // Calculate the offset of the current block from the base of the pool
         let offset = (buddy as usize) - ((*pool).base as usize);
@@ -88,497 +372,9583 @@ pub extern "C" fn buddy_calc(pool: *mut BuddyPool, buddy: *mut Avail) -> *mut Av
     }
 }
 
+/// Returns whether block has a buddy, i.e. whether it is smaller than the
+/// whole pool. Only a block of class kval_m has none, and buddy_calc returns
+/// such a block unchanged.
+///
+/// If pool or block is NULL, the return value will be false
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool the block belongs to
+/// - block `*mut Avail` The block to check
+///
+/// ## Returns
+///
+/// - True if the block has a buddy. Type = `bool`
+#[no_mangle]
+pub extern "C" fn buddy_has_buddy(pool: *mut BuddyPool, block: *mut Avail) -> bool {
+    if pool.is_null() || block.is_null() {
+        return false;
+    }
+
+    unsafe { ((*block).kval as usize) < (*pool).kval_m }
+}
+
 /// Helper function.
 ///
-/// Removes a block from the free list.
+/// Removes a block from the free list and returns whether the list is empty
+/// afterwards, so callers can keep track of empty classes without looking at
+/// the list again.
+///
+/// # Safety
+///
+/// `block` must point to a valid block that is currently linked into a free
+/// list. It must not be one of the avail sentinels (tagged BLOCK_UNUSED),
+/// unlinking a sentinel cuts its whole list loose, debug builds abort on it.
 #[no_mangle]
-pub unsafe extern "C" fn remove_block(block: *mut Avail) {
+pub unsafe extern "C" fn remove_block(block: *mut Avail) -> bool {
+    debug_assert!((*block).tag != BLOCK_UNUSED, "remove_block called on an avail sentinel");
+
     // Update the previous pointer of the block's next block
     (*(*block).prev).next = (*block).next;
-    //
+
     // Update the next pointer of the block's previous block
     (*(*block).next).prev = (*block).prev;
+
+    // Only the sentinel is left if it was both neighbours
+    (*block).prev == (*block).next
 }
 
-/// Allocates a block of size bytes of memory, returning a pointer to
-/// the beginning of the block. The content of the newly allocated block
-/// of memory is not initialized, remaining with indeterminate values.
+/// Removes a free block from its avail list like remove_block, after making
+/// sure the block is a free block inside the pool. The avail sentinels in
+/// particular are refused, unlinking one of them would cut its whole list
+/// loose for good.
 ///
-/// If size is zero, the return value will be NULL
-/// If pool is NULL, the return value will be NULL
+/// The block is left marked available but is on no list anymore, it is up
+/// to the caller to use it or hand it back.
 ///
 /// ## Parameters
 ///
-/// - pool `*mut BuddyPool` The memory pool to alloc from
-/// - size  `usize` The size of the user requested memory block in bytes
+/// - pool `*mut BuddyPool` The memory pool the block belongs to
+/// - block `*mut Avail` The free block to unlink
 ///
 /// ## Returns
 ///
-/// - A pointer to the memory block. Type = `*mut c_void`
+/// - 0 if the block was unlinked, EINVAL if pool or block is NULL or block is not a free block of the pool. Type = `i32`
 #[no_mangle]
-pub extern "C" fn buddy_malloc(pool: *mut BuddyPool, size: usize) -> *mut c_void {
-    // Return null pointer if pool is null or size is 0
-    if pool.is_null() || size == 0 {
-        return ptr::null_mut();
+pub extern "C" fn buddy_remove_block(pool: *mut BuddyPool, block: *mut Avail) -> i32 {
+    if pool.is_null() || block.is_null() {
+        return EINVAL;
     }
 
     unsafe {
-        // Calculate the required block size (including space for the header)
-        let mut req_k = btok(size + std::mem::size_of::<Avail>());
-        if req_k < SMALLEST_K {
-            req_k = SMALLEST_K;
+        // The sentinels live in the pool itself, never inside the region
+        let offset = (block as usize).wrapping_sub((*pool).base as usize);
+        if offset >= (*pool).numbytes || offset & ((1 << SMALLEST_K) - 1) != 0 {
+            return EINVAL;
         }
 
-        // Search for the first available block of sufficient size
-        let mut k = req_k; 
-        while k <= (*pool).kval_m && (*pool).avail[k].next == &mut (*pool).avail[k] {
-            k += 1;
+        if !is_free_block(pool, block) || (*block).kval as usize > (*pool).kval_m {
+            return EINVAL;
         }
 
-        // If no block is found, set errno and return null (memory not available)
-        if k > (*pool).kval_m {
-            // Set errno to ENOMEM
-            (*__errno_location()) = ENOMEM;
-
-            return ptr::null_mut();
-        }
+        unlink_block(pool, block);
+    }
 
-        let block = (*pool).avail[k].next;
-        remove_block(block);
+    0
+}
 
-        // Split blocks down to the required size (req_k)
-        while k > req_k {
-            k -= 1;
-            let buddy = (block as usize + (1 << k)) as *mut Avail;
+/// Helper function.
+///
+/// Links a free block into the avail list matching its kval, at the position
+/// chosen by the pool's strategy, and marks it available.
+unsafe fn insert_block(pool: *mut BuddyPool, block: *mut Avail) {
+    let head: *mut Avail = &mut (*pool).avail[(*block).kval as usize];
 
-            (*buddy).kval = k as u16;
-            (*buddy).tag = BLOCK_AVAIL;
-            (*buddy).next = (*pool).avail[k].next;
-            (*buddy).prev = &mut (*pool).avail[k];
+    // Insert after the sentinel for LIFO or before it, at the tail, for FIFO
+    let mut prev = if (*pool).policy.strategy == STRATEGY_FIFO { (*head).prev } else { head };
 
-            (*(*pool).avail[k].next).prev = buddy;
-            (*pool).avail[k].next = buddy;
+    // Or after the last block at a lower address to keep the list sorted
+    if (*pool).policy.strategy == STRATEGY_ADDRESS {
+        while (*prev).next != head && ((*prev).next as usize) < block as usize {
+            prev = (*prev).next;
         }
+    }
 
-        // Mark the block as reserved
-        (*block).tag = BLOCK_RESERVED;
-        (*block).kval = k as u16;
+    (*block).next = (*prev).next;
+    (*block).prev = prev;
+
+    (*(*prev).next).prev = block;
+    (*prev).next = block;
+
+    (*block).tag = BLOCK_AVAIL;
 
-        // Return the memory location after the block header (pointer to the user data)
-        (block as *mut u8).add(std::mem::size_of::<Avail>()) as *mut c_void
+    let k = (*block).kval as usize;
+    set_free_bit(pool, block, k, true);
+    (*pool).free_bytes += 1 << k;
+    (*pool).free_mask |= 1 << k;
+    if k > (*pool).highest_free_k {
+        (*pool).highest_free_k = k;
     }
 }
 
-/// A block of memory previously allocated by a call to malloc,
-/// calloc or realloc is deallocated, making it available again
-/// for further allocations.
+/// Helper function.
 ///
-/// If ptr does not point to a block of memory allocated with
-/// the above functions, it causes undefined behavior.
+/// Returns the number of words of the free bitmap of the pool. Class k has
+/// numbytes >> k blocks, the bits of class k start at bit numbytes >> k, so
+/// all classes together take twice numbytes >> SMALLEST_K bits.
+fn free_bits_words(pool: &BuddyPool) -> usize {
+    (pool.numbytes >> (SMALLEST_K - 1)).div_ceil(u64::BITS as usize)
+}
+
+/// Helper function.
 ///
-/// If ptr is a null pointer, the function does nothing.
-/// Notice that this function does not change the value of ptr itself,
-/// hence it still points to the same (now invalid) location.
+/// Returns the word and mask of the bit of the class k block at block.
+unsafe fn free_bit(pool: *mut BuddyPool, block: *mut Avail, k: usize) -> (*mut u64, u64) {
+    let index = ((*pool).numbytes >> k) + ((block as usize - (*pool).base as usize) >> k);
+
+    ((*pool).free_bits.add(index / u64::BITS as usize), 1 << (index % u64::BITS as usize))
+}
+
+/// Helper function.
 ///
-/// ## Parameters
+/// Records in the free bitmap, if the pool keeps one, whether the class k
+/// block at block is on its free list.
+unsafe fn set_free_bit(pool: *mut BuddyPool, block: *mut Avail, k: usize, free: bool) {
+    if (*pool).free_bits.is_null() {
+        return;
+    }
+
+    let (word, mask) = free_bit(pool, block, k);
+    if free {
+        *word |= mask;
+    } else {
+        *word &= !mask;
+    }
+}
+
+/// Helper function.
 ///
-/// - pool `*mut BuddyPool` The memory pool
-/// - ptr `*mut c_void` Pointer to the memory block to free
-#[no_mangle]
-pub extern "C" fn buddy_free(pool: *mut BuddyPool, ptr: *mut c_void) -> u8 {
-    // Return early if the pointer is null or the pool is null
-    if ptr.is_null() || pool.is_null() {
-        return 1;
+/// Returns whether a whole free class k block starts at block. With a free
+/// bitmap this is a bit test, which spares coalescing a cache miss on the
+/// header of a cold buddy.
+unsafe fn is_free_at(pool: *mut BuddyPool, block: *mut Avail, k: usize) -> bool {
+    if (*pool).free_bits.is_null() {
+        return is_free_block(pool, block) && (*block).kval as usize == k;
     }
 
-    unsafe {
-        // Get the block header by subtracting the size of Avail from the pointer
-        let mut block = (ptr as *mut u8).sub(std::mem::size_of::<Avail>()) as *mut Avail;
+    let (word, mask) = free_bit(pool, block, k);
+    *word & mask != 0
+}
 
-        (*block).tag = BLOCK_AVAIL;
+/// Helper function.
+///
+/// Maps the free bitmap of the pool, or clears it if it already has one.
+/// The bitmap is mapped rather than taken from the Rust heap because the
+/// pool may itself be the Rust heap, see BuddyGlobal. If it can not be
+/// mapped the pool does without.
+unsafe fn init_free_bits(pool: *mut BuddyPool) {
+    let len = free_bits_words(&*pool) * core::mem::size_of::<u64>();
 
-        // Try to coalesce the block with its buddy if they are both available
-        while ((*block).kval as usize) < (*pool).kval_m {
-            let buddy = buddy_calc(pool, block);
+    if !(*pool).free_bits.is_null() {
+        ptr::write_bytes((*pool).free_bits as *mut u8, 0, len);
 
-            // If the buddy is available or has a different size, break out of the loop
-            if (*buddy).tag != BLOCK_AVAIL || (*buddy).kval != (*block).kval {
-                break;
-            }
+        return;
+    }
 
-            // Remove the buddy from the available list
-            remove_block(buddy);
+    (*pool).free_bits = map_pages(len) as *mut u64;
+}
 
-            // If the buddy is smaller in address, update block to point to it
-            if buddy < block {
-                block = buddy;
-            }
+/// Helper function.
+///
+/// Unmaps the free bitmap of the pool.
+unsafe fn drop_free_bits(pool: *mut BuddyPool) {
+    if (*pool).free_bits.is_null() {
+        return;
+    }
 
-            // Increase the kval (combine blocks into a larger one)
-            (*block).kval += 1;
-        }
+    unmap_pages((*pool).free_bits as *mut c_void, free_bits_words(&*pool) * core::mem::size_of::<u64>());
+    (*pool).free_bits = ptr::null_mut();
+}
+
+/// Helper function.
+///
+/// Removes a free block from its avail list like remove_block, keeping the
+/// free byte count and the index of non-empty classes up to date.
+unsafe fn unlink_block(pool: *mut BuddyPool, block: *mut Avail) {
+    let empty = remove_block(block);
 
-        (*block).next = (*pool).avail[(*block).kval as usize].next;
-        (*block).prev = &mut (*pool).avail[(*block).kval as usize];
+    let k = (*block).kval as usize;
+    set_free_bit(pool, block, k, false);
+    (*pool).free_bytes = (*pool).free_bytes.saturating_sub(1 << k);
 
-        (*(*pool).avail[(*block).kval as usize].next).prev = block;
-        (*pool).avail[(*block).kval as usize].next = block;
+    if !empty {
+        return;
     }
 
-
-    0
+    (*pool).free_mask &= !(1 << k);
+    if k == (*pool).highest_free_k {
+        (*pool).highest_free_k = match (*pool).free_mask {
+            0 => 0,
+            mask => (u64::BITS - 1 - mask.leading_zeros()) as usize,
+        };
+    }
 }
 
-/// Initialize a new memory pool using the buddy algorithm. Internally,
-/// this function uses mmap to get a block of memory to manage so should be
-/// portable to any system that implements mmap. This function will round
-/// up to the nearest power of two. So if the user requests 503MiB
-/// it will be rounded up to 512MiB.
-///
-/// Note that if a 0 is passed as an argument then it initializes
-/// the memory pool to be of the default size of DEFAULT_K. If the caller
-/// specifies an unreasonably small size, then the buddy system may
-/// not be able to satisfy any requests.
-///
-/// NOTE: Memory pools returned by this function can not be intermingled.
-/// Calling buddy_malloc with pool A and then calling buddy_free with
-/// pool B will result in undefined behavior.
-///
-/// ## Parameters
+/// Helper function.
 ///
-/// - pool `*mut BuddyPool` A pointer to the pool to initialize
-/// - size `usize` The size of the pool in bytes.
-#[no_mangle]
-pub extern "C" fn buddy_init(pool: *mut BuddyPool, size: usize) {
-   unsafe {
-        let kval = if size == 0 { DEFAULT_K } else { btok(size) };
-        let kval = kval.clamp(MIN_K, MAX_K - 1);
-        
-        memset(pool as *mut _, 0, std::mem::size_of::<BuddyPool>());
-        (*pool).kval_m = kval;
-        (*pool).numbytes = 1 << kval;
-        
-        (*pool).base = mmap(
-            ptr::null_mut(),
-            (*pool).numbytes,
-            PROT_READ | PROT_WRITE,
-            MAP_PRIVATE | MAP_ANONYMOUS,
-            -1,
-            0,
-        );
-        
-        if (*pool).base == MAP_FAILED {
-            panic!("buddy_init avail array mmap failed");
-        }
-        
-        for i in 0..=kval {
-            (*pool).avail[i].next = &mut (*pool).avail[i];
-            (*pool).avail[i].prev = &mut (*pool).avail[i];
-            (*pool).avail[i].kval = i as u16;
-            (*pool).avail[i].tag = BLOCK_UNUSED;
-        }
-        
-        let m = (*pool).base as *mut Avail;
-        (*pool).avail[kval].next = m;
-        (*pool).avail[kval].prev = m;
-        (*m).tag = BLOCK_AVAIL;
-        (*m).kval = kval as u16;
-        (*m).next = &mut (*pool).avail[kval];
-        (*m).prev = &mut (*pool).avail[kval];
-    } 
+/// Returns the kval of the block needed to hold size bytes of user data plus
+/// the block header. Sizes so large that adding the header overflows get a
+/// class no pool has.
+fn request_class(size: usize) -> usize {
+    btok(size.saturating_add(core::mem::size_of::<Avail>())).max(SMALLEST_K)
 }
 
-/// Inverse of buddy_init.
-///
-/// Notice that this function does not change the value of pool itself,
-/// hence it still points to the same (now invalid) location.
-///
-/// ## Parameters
+/// Helper function.
 ///
-/// - pool `*mut BuddyPool` The memory pool to destroy
-#[no_mangle]
-pub extern "C" fn buddy_destroy(pool: *mut BuddyPool) {
-    unsafe {
-        if munmap((*pool).base as *mut _, (*pool).numbytes) == -1 {
-            panic!("buddy_destroy avail array");
-        }
-
-        memset(pool as *mut _, 0, std::mem::size_of::<BuddyPool>());
+/// Returns the smallest k >= req_k whose avail list is not empty, or a value
+/// greater than kval_m if there is none.
+unsafe fn find_free_class(pool: *mut BuddyPool, req_k: usize) -> usize {
+    let mut k = req_k;
+    while k <= (*pool).kval_m && (*pool).avail[k].next == &mut (*pool).avail[k] {
+        k += 1;
     }
+
+    k
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::mem::MaybeUninit;
+/// Helper function.
+///
+/// Splits the class k block, already taken off its list, in half until it is
+/// of class req_k. Every upper half is put on the free list of its class. The
+/// header of the block itself is left for the caller to fill in.
+unsafe fn split_block(pool: *mut BuddyPool, block: *mut Avail, mut k: usize, req_k: usize) {
+    while k > req_k {
+        k -= 1;
+        let buddy = (block as usize + (1 << k)) as *mut Avail;
 
-    fn check_buddy_pool_full(pool: &mut BuddyPool) {
-        for i in 0..pool.kval_m {
-            let avail = &pool.avail[i];
-            assert_eq!(avail.next as *const _, avail as *const _);
-            assert_eq!(avail.prev as *const _, avail as *const _);
-            assert_eq!(avail.tag, BLOCK_UNUSED);
-            assert_eq!(avail.kval as usize, i);
-        }
+        // The split off half is exactly as recently freed as the block it came from
+        (*buddy).kval = k as u16;
+        (*buddy).stamp = (*block).stamp;
+        insert_block(pool, buddy);
 
-        let top = &pool.avail[pool.kval_m];
-        unsafe {
-            assert_eq!((*top.next).tag, BLOCK_AVAIL);
-            assert_eq!((*top.next).next, top as *const _ as *mut _);
-            assert_eq!((*top.prev).prev, top as *const _ as *mut _);
-            assert_eq!(top.next, pool.base as *mut Avail);
-        }
+        (*pool).split_count += 1;
     }
+}
 
-    fn check_buddy_pool_empty(pool: &mut BuddyPool) {
-        // All avail lists should be empty
-        for i in 0..=pool.kval_m {
-            let avail = &pool.avail[i];
-            assert_eq!(avail.next as *const _, avail as *const _);
-            assert_eq!(avail.prev as *const _, avail as *const _);
-            assert_eq!(avail.tag, BLOCK_UNUSED);
-            assert_eq!(avail.kval as usize, i);
+/// Helper function.
+///
+/// Returns the class, from min_k up, whose first free block was freed most
+/// recently. With head insertion the first block of each list is the most
+/// recently freed one of its class, so only the heads have to be compared.
+unsafe fn hottest_class(pool: *mut BuddyPool, min_k: usize) -> usize {
+    let mut best = min_k;
+
+    for k in min_k + 1..=(*pool).kval_m {
+        let head: *mut Avail = &mut (*pool).avail[k];
+        if (*head).next != head && (*(*head).next).stamp > (*(*pool).avail[best].next).stamp {
+            best = k;
         }
     }
 
-    #[test]
-    fn test_buddy_malloc_one_byte() {
-        let kval = MIN_K as usize;
-        let size = 1 << kval;
+    best
+}
 
-        let mut pool = MaybeUninit::<BuddyPool>::uninit();
-        let pool_ptr = pool.as_mut_ptr();
+/// Helper function.
+///
+/// Merges every pair of free buddies in a single bottom-up pass over the avail
+/// lists, producing blocks up to class top. Blocks merged into class k + 1 are
+/// considered again when that list is visited, so with top at kval_m the pool
+/// ends up maximally coalesced.
+unsafe fn coalesce_sweep(pool: *mut BuddyPool, top: usize) {
+    for k in SMALLEST_K..top {
+        let head: *mut Avail = &mut (*pool).avail[k];
 
-        unsafe {
-            buddy_init(pool_ptr, size);
-            let pool_ref = &mut *pool_ptr;
+        let mut block = (*head).next;
+        while block != head {
+            let mut next = (*block).next;
+            let buddy = buddy_calc(pool, block);
 
-            let mem = buddy_malloc(pool_ref, 1);
-            assert!(!mem.is_null());
+            if is_free_at(pool, buddy, k) {
+                // The buddy may be the node we were about to visit
+                if buddy == next {
+                    next = (*buddy).next;
+                }
 
-            assert_eq!(buddy_free(pool_ref, mem), 0);
-            check_buddy_pool_full(pool_ref);
+                unlink_block(pool, block);
+                unlink_block(pool, buddy);
 
-            buddy_destroy(pool_ref);
+                let merged = if buddy < block { buddy } else { block };
+                (*merged).kval = (k + 1) as u16;
+                insert_block(pool, merged);
+            }
+
+            block = next;
         }
     }
+}
+
+/// Merges every pair of free buddies in the pool in a single sweep from the
+/// smallest class upward, whatever the policy. Pools that free with
+/// coalescing switched off or deferred end up as coalesced as their live
+/// allocations allow, at the cost of one pass over the free lists rather than
+/// a merge loop per freed block.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to coalesce
+///
+/// ## Returns
+///
+/// - The largest class with a free block afterwards, 0 if there is none or pool is NULL. Type = `u16`
+#[no_mangle]
+pub extern "C" fn buddy_coalesce_all(pool: *mut BuddyPool) -> u16 {
+    if pool.is_null() {
+        return 0;
+    }
+
+    unsafe {
+        coalesce_sweep(pool, (*pool).kval_m);
+
+        (*pool).highest_free_k as u16
+    }
+}
+
+/// Returns the current policy of the pool.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - The policy of the pool, or the default policy if pool is NULL. Type = `BuddyPolicy`
+#[no_mangle]
+pub extern "C" fn buddy_get_policy(pool: *mut BuddyPool) -> BuddyPolicy {
+    if pool.is_null() {
+        return BuddyPolicy { flags: POLICY_DEFAULT, strategy: STRATEGY_LIFO };
+    }
+
+    unsafe { (*pool).policy }
+}
+
+/// Changes the policy of the pool. The new policy applies to every following
+/// call to buddy_malloc and buddy_free, blocks already on the free lists are
+/// left where they are.
+///
+/// When coalescing is switched off (POLICY_COALESCE cleared) freed blocks are
+/// never merged. With POLICY_DEFERRED freed blocks are merged in one sweep the
+/// next time an allocation would otherwise fail, and with POLICY_EAGER as well
+/// also the classes below one that would have to be split from a larger
+/// block, in case merging them yields a block of the class. POLICY_SMALL_FAST
+/// keeps freed
+/// blocks of the smallest class unmerged so they can be handed out again
+/// without splitting.
+///
+/// STRATEGY_CACHE_HOT reuses the most recently freed memory first, even when
+/// that means splitting a larger block than necessary. A block freed a few
+/// operations ago is likely still in the L1 or L2 cache, where a hit costs
+/// a few nanoseconds against roughly 100 for a trip to main memory, so for
+/// workloads that free and soon allocate again most first touches of the
+/// returned memory avoid a cache miss.
+///
+/// STRATEGY_ADDRESS keeps every free list sorted by address, so allocations
+/// pack towards the start of the pool and free blocks stay clustered where
+/// they can merge. Inserting walks the list, making frees O(n) in the length
+/// of the list instead of O(1), which pays off for long lived pools that
+/// fragment under LIFO reuse.
+///
+/// POLICY_CHECKED verifies the headers of blocks taken
+/// from the free lists and of pointers being freed, and that freeing the last
+/// allocation merges the pool back into one block, see buddy_set_assert_handler.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to configure
+/// - policy `BuddyPolicy` The new policy
+#[no_mangle]
+pub extern "C" fn buddy_set_policy(pool: *mut BuddyPool, policy: BuddyPolicy) {
+    if pool.is_null() {
+        return;
+    }
+
+    unsafe {
+        (*pool).policy = policy;
+    }
+}
+
+/// Selects the errno value allocations from the pool set when it runs out of
+/// memory, for callers whose error handling expects something other than
+/// ENOMEM, e.g. EAGAIN for a pool that is worth retrying later. Passing 0
+/// restores the default of ENOMEM.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to configure
+/// - errno_value `i32` The errno to report, 0 for ENOMEM
+#[no_mangle]
+pub extern "C" fn buddy_set_oom_errno(pool: *mut BuddyPool, errno_value: i32) {
+    if pool.is_null() {
+        return;
+    }
+
+    unsafe {
+        (*pool).oom_errno = errno_value;
+    }
+}
+
+/// Helper function.
+///
+/// Returns the errno to set when an allocation from the pool runs out of memory.
+unsafe fn oom_errno(pool: *mut BuddyPool) -> i32 {
+    match (*pool).oom_errno {
+        0 => ENOMEM,
+        errno => errno,
+    }
+}
+
+/// Allocates a block of size bytes of memory, returning a pointer to
+/// the beginning of the block. The content of the newly allocated block
+/// of memory is not initialized, remaining with indeterminate values.
+///
+/// If size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
+/// If the block would be larger than the pool, errno is set to ENOMEM and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - size  `usize` The size of the user requested memory block in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the memory block. Type = `*mut c_void`
+#[no_mangle]
+pub extern "C" fn buddy_malloc(pool: *mut BuddyPool, size: usize) -> *mut c_void {
+    buddy_malloc_budget(pool, size, usize::MAX)
+}
+
+/// Allocates a block of size bytes of memory like buddy_malloc, and stores
+/// in reason why it could not when it returns NULL, or BuddyError::Ok when
+/// it does not. Unlike errno this tells a NULL pool, a zero size and a
+/// request the pool could never hold apart from the pool being full.
+///
+/// A destroyed pool, or one whose initialization failed, holds nothing and
+/// reports BuddyError::TooLarge for every size.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - size `usize` The size of the user requested memory block in bytes
+/// - reason `*mut BuddyError` Receives the outcome, may be NULL
+///
+/// ## Returns
+///
+/// - A pointer to the memory block. Type = `*mut c_void`
+#[no_mangle]
+pub extern "C" fn buddy_malloc_explained(pool: *mut BuddyPool, size: usize, reason: *mut BuddyError) -> *mut c_void {
+    let (mem, why) = if pool.is_null() {
+        (ptr::null_mut(), BuddyError::NullPool)
+    } else if size == 0 {
+        (ptr::null_mut(), BuddyError::ZeroSize)
+    } else if size > unsafe { (*pool).numbytes }.saturating_sub(core::mem::size_of::<Avail>()) {
+        (ptr::null_mut(), BuddyError::TooLarge)
+    } else {
+        match buddy_malloc(pool, size) {
+            mem if mem.is_null() => (mem, BuddyError::from_errno()),
+            mem => (mem, BuddyError::Ok),
+        }
+    };
+
+    if !reason.is_null() {
+        unsafe { *reason = why };
+    }
+
+    mem
+}
+
+/// Allocates a block of size bytes of memory like buddy_malloc and records
+/// owner in its header, so the memory held by each subsystem can be added up
+/// with buddy_bytes_by_tag. Blocks from every other allocation function have
+/// owner 0.
+///
+/// If size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - size `usize` The size of the user requested memory block in bytes
+/// - owner `u32` The tag to attribute the block to
+///
+/// ## Returns
+///
+/// - A pointer to the memory block. Type = `*mut c_void`
+#[no_mangle]
+pub extern "C" fn buddy_malloc_tagged(pool: *mut BuddyPool, size: usize, owner: u32) -> *mut c_void {
+    let mem = buddy_malloc(pool, size);
+
+    if !mem.is_null() {
+        unsafe { (*block_of(pool, mem)).owner = owner };
+    }
+
+    mem
+}
+
+/// Allocates a block of size bytes of memory like buddy_malloc, but only if
+/// it takes at most max_splits splits to carve it out of the free blocks.
+/// Every split is work on the allocation path, so this bounds the latency of
+/// the call and lets the caller fall back to memory it set aside instead.
+///
+/// If size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
+/// If the allocation would need more than max_splits splits, errno is set to EAGAIN and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - size `usize` The size of the user requested memory block in bytes
+/// - max_splits `usize` The largest number of splits the allocation may perform
+///
+/// ## Returns
+///
+/// - A pointer to the memory block. Type = `*mut c_void`
+#[no_mangle]
+pub extern "C" fn buddy_malloc_budget(pool: *mut BuddyPool, size: usize, max_splits: usize) -> *mut c_void {
+    // Return null pointer if pool is null or size is 0
+    if pool.is_null() || size == 0 {
+        return ptr::null_mut();
+    }
+
+    unsafe {
+        let mem = malloc_block(pool, size, max_splits);
+        report_alloc(pool, size, mem);
+
+        mem
+    }
+}
+
+/// Helper function.
+///
+/// Does the work of buddy_malloc_budget without reporting the allocation to
+/// the on_alloc hook, for callers that hand out a different pointer or size
+/// and report it themselves.
+unsafe fn malloc_block(pool: *mut BuddyPool, size: usize, max_splits: usize) -> *mut c_void {
+    // Calculate the required block size (including space for the header)
+    let req_k = request_class(size);
+
+    // No amount of freeing or coalescing makes a block this large
+    if req_k > (*pool).kval_m {
+        set_errno(ENOMEM);
+
+        return ptr::null_mut();
+    }
+
+    let block = take_block(pool, req_k, max_splits);
+    if block.is_null() {
+        return ptr::null_mut();
+    }
+
+    reserve_block(pool, block, req_k)
+}
+
+/// Helper function.
+///
+/// Calls the on_alloc hook of the pool, if it has one, for a successful
+/// allocation of size bytes at mem. Every public allocation function calls
+/// this exactly once with the size it was asked for and the pointer it
+/// returns.
+unsafe fn report_alloc(pool: *mut BuddyPool, size: usize, mem: *mut c_void) {
+    if mem.is_null() {
+        return;
+    }
+
+    if let Some(on_alloc) = (*pool).on_alloc {
+        on_alloc(size, mem);
+    }
+}
+
+/// Helper function.
+///
+/// Calls the on_free hook of the pool, if it has one, for the freed
+/// allocation at mem.
+unsafe fn report_free(pool: *mut BuddyPool, mem: *mut c_void) {
+    if let Some(on_free) = (*pool).on_free {
+        on_free(mem);
+    }
+}
+
+/// Allocates a block of exactly class k, splitting a larger free block down
+/// to k if needed. Unlike buddy_malloc the class is chosen by the caller
+/// rather than derived from a size, so allocations of the same class always
+/// sit on the same grid of 1 << k byte blocks, e.g. for slabs. The caller may
+/// use buddy_usable_size bytes of the block, its size minus the header.
+///
+/// k is clamped to the classes of the pool, SMALLEST_K up to its kval_m.
+///
+/// If pool is NULL, the return value will be NULL
+/// If the pool has no free block of class k or larger, errno is set to ENOMEM and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - k `usize` The class of the block to allocate
+///
+/// ## Returns
+///
+/// - A pointer to the memory block. Type = `*mut c_void`
+#[no_mangle]
+pub extern "C" fn buddy_alloc_class(pool: *mut BuddyPool, k: usize) -> *mut c_void {
+    if pool.is_null() {
+        return ptr::null_mut();
+    }
+
+    unsafe {
+        let k = k.clamp(SMALLEST_K, (*pool).kval_m);
+
+        let block = take_block(pool, k, usize::MAX);
+        if block.is_null() {
+            return ptr::null_mut();
+        }
+
+        // The caller asked for the whole block
+        let mem = reserve_block(pool, block, k);
+        report_alloc(pool, 1 << k, mem);
+
+        mem
+    }
+}
+
+/// Helper function.
+///
+/// Takes a free block of class req_k off the free lists, splitting a larger
+/// one if needed but with no more than max_splits splits, and returns its
+/// start. The block is neither marked nor counted as reserved. Returns NULL
+/// with errno set if the rate callback, the split budget or the free memory
+/// do not allow it.
+unsafe fn take_block(pool: *mut BuddyPool, req_k: usize, max_splits: usize) -> *mut Avail {
+    // Let the rate callback throttle allocation storms
+    if let Some(callback) = (*pool).rate_callback {
+        (*pool).rate_count += 1;
+
+        if (*pool).rate_count > (*pool).rate_threshold {
+            if !callback((*pool).rate_count, (*pool).rate_user) {
+                set_errno(EAGAIN);
+
+                return ptr::null_mut();
+            }
+
+            // The storm was allowed, start a new window
+            (*pool).rate_count = 0;
+        }
+    }
+
+    // The lifetime split limit caps the budget of every allocation
+    let max_splits = match (*pool).split_limit {
+        0 => max_splits,
+        limit => max_splits.min(limit.saturating_sub((*pool).split_count) as usize),
+    };
+
+    merge_postponed(pool, req_k);
+    let k = select_class(pool, req_k, max_splits);
+
+    // If no block is found, set errno and return null (memory not available)
+    if k > (*pool).kval_m {
+        // Set errno to ENOMEM, or what the pool was configured to report instead
+        set_errno(oom_errno(pool));
+
+        return ptr::null_mut();
+    }
+
+    if k - req_k > max_splits {
+        set_errno(EAGAIN);
+
+        return ptr::null_mut();
+    }
+
+    let block = (*pool).avail[k].next;
+
+    if (*pool).policy.flags & POLICY_CHECKED != 0 && ((*block).tag != BLOCK_AVAIL || (*block).kval as usize != k) {
+        assert_failed(format_args!(
+            "buddy_malloc: block at offset {:#x} on free list {} has tag {} and kval {}",
+            block as usize - (*pool).base as usize,
+            k,
+            (*block).tag,
+            (*block).kval
+        ));
+
+        return ptr::null_mut();
+    }
+
+    unlink_block(pool, block);
+
+    // Remember the longest split chain for worst case latency reports
+    let depth = (k - req_k) as u16;
+    if depth > (*pool).max_split_depth {
+        (*pool).max_split_depth = depth;
+    }
+
+    // Split blocks down to the required size (req_k)
+    split_block(pool, block, k, req_k);
+
+    block
+}
+
+/// Helper function.
+///
+/// Merges the free blocks whose coalescing the policy postponed when an
+/// allocation of class req_k needs it: when no block is large enough, or
+/// with POLICY_EAGER when a larger block would have to be split. Merging
+/// only joins free buddies, so no memory changes hands.
+unsafe fn merge_postponed(pool: *mut BuddyPool, req_k: usize) {
+    let flags = (*pool).policy.flags;
+    if flags & POLICY_DEFERRED == 0 {
+        return;
+    }
+
+    // Coalescing may have been postponed, merge what we can and look again
+    let mut k = find_free_class(pool, req_k);
+    if k > (*pool).kval_m {
+        coalesce_sweep(pool, (*pool).kval_m);
+        k = find_free_class(pool, req_k);
+    }
+
+    // Postponed merges may add up to the class, which spares the larger block a split
+    if k > req_k && k <= (*pool).kval_m && flags & POLICY_EAGER != 0 {
+        coalesce_sweep(pool, req_k);
+    }
+}
+
+/// Helper function.
+///
+/// Returns the class whose first free block an allocation of class req_k
+/// takes, with no more than max_splits splits, or a value greater than
+/// kval_m if there is none. The pool is not modified, so this is also how
+/// the block is predicted without allocating it.
+unsafe fn select_class(pool: *mut BuddyPool, req_k: usize, max_splits: usize) -> usize {
+    // Search for the first available block of sufficient size
+    let k = find_free_class(pool, req_k);
+
+    // Cache-hot pools split the most recently freed block instead of the smallest one
+    if k > req_k && k <= (*pool).kval_m && (*pool).policy.strategy == STRATEGY_CACHE_HOT {
+        let hot = hottest_class(pool, k);
+
+        // The hottest block is not worth blowing the split budget
+        if hot - req_k <= max_splits {
+            return hot;
+        }
+    }
+
+    k
+}
+
+/// Helper function.
+///
+/// Marks the class k block starting at block, already off the free lists, as
+/// reserved, stamps it with the allocation clock and returns the pointer to
+/// hand out for it.
+unsafe fn reserve_block(pool: *mut BuddyPool, block: *mut Avail, k: usize) -> *mut c_void {
+    let header = reserved_header(pool, block, k);
+    (*header).tag = BLOCK_RESERVED;
+    (*header).kval = k as u16;
+    (*header).owner = 0;
+
+    // Trailing headers link back to the start of their block so they can be told from user data
+    if (*pool).trailing_header {
+        (*header).next = block;
+        (*header).prev = ptr::null_mut();
+    }
+
+    (*pool).alloc_clock += 1;
+    (*header).stamp = (*pool).alloc_clock;
+    (*pool).live_count += 1;
+
+    // Return the memory location of the user data
+    let mem = user_ptr(pool, header);
+
+    // Reads of memory the caller never wrote show up as 0xAB bytes
+    #[cfg(feature = "poison")]
+    ptr::write_bytes(mem as *mut u8, POISON_ALLOC, user_end(pool, header) - mem as usize);
+
+    mem
+}
+
+/// Allocates the block of the class fitting size bytes that starts exactly
+/// offset bytes from base, so callers can control where allocations land,
+/// e.g. to lay out a memory mapped format the same way on every run. The
+/// free block covering offset is split down until the requested block is
+/// carved out, with every other half going back to the free lists.
+///
+/// The returned pointer is the user pointer of that block, for pools without
+/// INIT_TRAILING_HEADER it is offset + size_of::<Avail>() bytes from base.
+///
+/// If pool is NULL or size is zero, the return value will be NULL
+/// If offset is not a multiple of the block size or the block would not fit in the pool, errno is set to EINVAL and the return value will be NULL
+/// If any part of the block is not free, errno is set to ENOMEM and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - offset `usize` The offset of the block from base in bytes
+/// - size `usize` The size of the user requested memory block in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the memory block. Type = `*mut c_void`
+#[no_mangle]
+pub extern "C" fn buddy_malloc_at(pool: *mut BuddyPool, offset: usize, size: usize) -> *mut c_void {
+    if pool.is_null() || size == 0 {
+        return ptr::null_mut();
+    }
+
+    unsafe {
+        let req_k = request_class(size);
+        if req_k > (*pool).kval_m || offset & ((1 << req_k) - 1) != 0 || offset >= (*pool).numbytes {
+            set_errno(EINVAL);
+
+            return ptr::null_mut();
+        }
+
+        // Descend from the top block through the halves covering offset until a whole block is found
+        let base = (*pool).base as usize;
+        let mut found = None;
+        for k in (req_k..=(*pool).kval_m).rev() {
+            let start = (base + (offset & !((1 << k) - 1))) as *mut Avail;
+            let header = header_at(pool, start as usize);
+
+            // Smaller blocks mean this one was split, keep descending
+            if (*header).kval as usize != k {
+                continue;
+            }
+
+            if (*header).tag == BLOCK_AVAIL {
+                found = Some((start, k));
+            }
+
+            break;
+        }
+
+        let Some((mut block, mut k)) = found else {
+            set_errno(oom_errno(pool));
+
+            return ptr::null_mut();
+        };
+
+        unlink_block(pool, block);
+
+        // Keep the half holding offset, the other one goes back to the free lists
+        while k > req_k {
+            k -= 1;
+            let upper = (block as usize + (1 << k)) as *mut Avail;
+            let (keep, spare) = if base + offset >= upper as usize { (upper, block) } else { (block, upper) };
+
+            (*spare).kval = k as u16;
+            (*spare).stamp = (*block).stamp;
+            insert_block(pool, spare);
+            (*pool).split_count += 1;
+
+            block = keep;
+        }
+
+        let mem = reserve_block(pool, block, req_k);
+        report_alloc(pool, size, mem);
+
+        mem
+    }
+}
+
+/// Registers a callback that watches the allocation rate of the pool. Every
+/// call to buddy_malloc counts towards the current window and once more than
+/// threshold allocations were made in it the callback is called with that
+/// count. If the callback returns false the allocation is denied, buddy_malloc
+/// sets errno to EAGAIN and returns NULL, and the callback keeps being asked
+/// on every following allocation. If it returns true a new window starts.
+///
+/// Windows also restart on buddy_reset_rate_window, so calling that on a timer
+/// turns the threshold into an allocations-per-interval limit. Passing a NULL
+/// callback disables rate tracking.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to watch
+/// - callback `Option<extern "C" fn(u64, *mut c_void) -> bool>` The callback, or NULL to disable it
+/// - threshold `u64` The number of allocations allowed per window
+/// - user `*mut c_void` User data passed to the callback
+#[no_mangle]
+pub extern "C" fn buddy_set_rate_callback(
+    pool: *mut BuddyPool,
+    callback: Option<extern "C" fn(count: u64, user: *mut c_void) -> bool>,
+    threshold: u64,
+    user: *mut c_void,
+) {
+    if pool.is_null() {
+        return;
+    }
+
+    unsafe {
+        (*pool).rate_callback = callback;
+        (*pool).rate_threshold = threshold;
+        (*pool).rate_count = 0;
+        (*pool).rate_user = user;
+    }
+}
+
+/// Registers hooks that observe every allocation and free of the pool, e.g.
+/// to build a histogram of allocation sizes without touching the call sites.
+/// on_alloc is called once for every successful allocation, whichever
+/// function made it, with the size that function was asked for and the
+/// pointer it returned, so aligned allocations report the aligned pointer.
+/// buddy_calloc reports the product of its arguments and buddy_alloc_class
+/// the size of the block. on_free is called with the pointer after every
+/// successful buddy_free and buddy_free_headerless. buddy_realloc reports
+/// the old pointer as freed and the new one as allocated, also when the block
+/// is resized in place. Failed calls are not reported. Without hooks the
+/// cost is a single branch per call.
+///
+/// Hooks run inside the allocator, so they must not allocate from or free to
+/// the same pool. Passing NULL removes a hook.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to observe
+/// - on_alloc `Option<extern "C" fn(usize, *mut c_void)>` Called after each allocation, or NULL
+/// - on_free `Option<extern "C" fn(*mut c_void)>` Called after each free, or NULL
+#[no_mangle]
+pub extern "C" fn buddy_set_hooks(
+    pool: *mut BuddyPool,
+    on_alloc: Option<extern "C" fn(size: usize, ptr: *mut c_void)>,
+    on_free: Option<extern "C" fn(ptr: *mut c_void)>,
+) {
+    if pool.is_null() {
+        return;
+    }
+
+    unsafe {
+        (*pool).on_alloc = on_alloc;
+        (*pool).on_free = on_free;
+    }
+}
+
+/// Starts a new rate window, see buddy_set_rate_callback.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool
+#[no_mangle]
+pub extern "C" fn buddy_reset_rate_window(pool: *mut BuddyPool) {
+    if pool.is_null() {
+        return;
+    }
+
+    unsafe {
+        (*pool).rate_count = 0;
+    }
+}
+
+/// A block of memory previously allocated by a call to malloc,
+/// calloc or realloc is deallocated, making it available again
+/// for further allocations.
+///
+/// If ptr does not point to a block of memory allocated with
+/// the above functions, it causes undefined behavior.
+///
+/// If ptr is a null pointer, the function does nothing.
+/// Notice that this function does not change the value of ptr itself,
+/// hence it still points to the same (now invalid) location.
+///
+/// A pointer outside the pool and a block that is already free are caught
+/// and reported instead of corrupting the free lists. So is a pointer into
+/// the middle of a block, as long as the bytes in front of it do not happen
+/// to look like the header of a block aligned to its own size. A block that
+/// was freed and handed out again can not be told from a live one, though.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool
+/// - ptr `*mut c_void` Pointer to the memory block to free
+///
+/// ## Returns
+///
+/// - BuddyError::Ok if the block was freed, why it was not otherwise. Type = `BuddyError`
+#[no_mangle]
+pub extern "C" fn buddy_free(pool: *mut BuddyPool, ptr: *mut c_void) -> BuddyError {
+    // Return early if the pointer is null or the pool is null
+    if pool.is_null() {
+        return BuddyError::NullPool;
+    }
+
+    if ptr.is_null() {
+        return BuddyError::NullPtr;
+    }
+
+    unsafe {
+        if (*pool).policy.flags & POLICY_CHECKED != 0 && !check_free(pool, ptr) {
+            return if in_pool(pool, ptr) && is_freed(pool, ptr) { BuddyError::DoubleFree } else { BuddyError::InvalidPointer };
+        }
+
+        let header = match live_header(pool, ptr) {
+            Ok(header) => header,
+            Err(err) => return err,
+        };
+
+        let block = block_start(pool, header);
+
+        // Reads through dangling pointers show up as 0xDD bytes, headers are left for coalescing
+        #[cfg(feature = "poison")]
+        ptr::write_bytes(ptr as *mut u8, POISON_FREE, user_end(pool, header) - ptr as usize);
+
+        // Free blocks always keep their header at the start, wipe the trailing one
+        if block != header {
+            (*block).kval = (*header).kval;
+            (*header).tag = BLOCK_UNUSED;
+            (*header).next = ptr::null_mut();
+        }
+
+        release_block(pool, block);
+        report_free(pool, ptr);
+    }
+
+    BuddyError::Ok
+}
+
+/// Helper function.
+///
+/// Returns whether ptr could have been handed out by the pool, i.e. whether
+/// it lies inside the region with room for a header in front of it where
+/// the pool keeps one.
+unsafe fn in_pool(pool: *mut BuddyPool, ptr: *mut c_void) -> bool {
+    let base = (*pool).base as usize;
+
+    // Only trailing headers let user data start right at a block
+    let first = if (*pool).trailing_header { base } else { base + core::mem::size_of::<Avail>() };
+
+    (first..base + (*pool).numbytes).contains(&(ptr as usize))
+}
+
+/// Helper function.
+///
+/// Returns the header of the live allocation at ptr, following the link of
+/// aligned allocations, or why ptr is not one: it lies outside the pool, was
+/// freed already or has no valid header in front of it.
+unsafe fn live_header(pool: *mut BuddyPool, ptr: *mut c_void) -> Result<*mut Avail, BuddyError> {
+    if !in_pool(pool, ptr) {
+        return Err(BuddyError::InvalidPointer);
+    }
+
+    if is_freed(pool, ptr) {
+        return Err(BuddyError::DoubleFree);
+    }
+
+    let header = block_of(pool, ptr);
+    if !is_valid_header(pool, header) {
+        return Err(BuddyError::InvalidPointer);
+    }
+
+    Ok(header)
+}
+
+/// Helper function.
+///
+/// Returns whether header, found in front of a pointer given to buddy_free,
+/// can be the header of a reserved block: its kval is a class of the pool and
+/// the block it describes is aligned to its size and inside the region. The
+/// buddy math relies on that alignment, so a header that fails this check
+/// belongs to a pointer the pool never handed out, e.g. one into the middle
+/// of a block.
+unsafe fn is_valid_header(pool: *mut BuddyPool, header: *mut Avail) -> bool {
+    let base = (*pool).base as usize;
+    let kval = (*header).kval as usize;
+
+    if (*header).tag != BLOCK_RESERVED || !(SMALLEST_K..=(*pool).kval_m).contains(&kval) {
+        return false;
+    }
+
+    let offset = (block_start(pool, header) as usize).wrapping_sub(base);
+
+    offset & ((1 << kval) - 1) == 0 && offset + (1 << kval) <= (*pool).numbytes
+}
+
+/// Helper function.
+///
+/// Returns whether the allocation at ptr, a pointer inside the pool, has been
+/// freed already. Freed blocks keep their header at their start, with
+/// trailing headers that is at ptr itself for plain allocations, while the
+/// record in front of an aligned one links to the wiped trailing header.
+unsafe fn is_freed(pool: *mut BuddyPool, ptr: *mut c_void) -> bool {
+    if !(*pool).trailing_header {
+        return (*block_of(pool, ptr)).tag == BLOCK_AVAIL;
+    }
+
+    if !find_trailing(pool, ptr as usize).is_null() {
+        return false;
+    }
+
+    if (*(ptr as *mut Avail)).tag == BLOCK_AVAIL {
+        return true;
+    }
+
+    let record = (ptr as usize).wrapping_sub(core::mem::size_of::<Avail>()) as *mut Avail;
+    if (record as usize) < (*pool).base as usize || (*record).tag != BLOCK_ALIGNED {
+        return false;
+    }
+
+    let block = aligned_link(pool, record, ptr);
+    !block.is_null() && (*block).tag != BLOCK_RESERVED
+}
+
+/// Frees a block like buddy_free and records caller_id, any number the
+/// caller picks to identify the call site such as a return address, in the
+/// freed block. Freeing the block again through buddy_free_tagged returns
+/// BuddyError::DoubleFree and leaves the block alone, and if an assert
+/// handler is installed it is told the caller_id of both frees. The id of
+/// the first one can also be looked up with buddy_freed_by.
+///
+/// The caller_id lives in the free memory right behind the header, so the
+/// report is only as good as that memory: once the block has been handed
+/// out again a second free can no longer be told apart from a valid one.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool
+/// - ptr `*mut c_void` Pointer to the memory block to free
+/// - caller_id `u64` The id of the code freeing the block
+///
+/// ## Returns
+///
+/// - BuddyError::Ok if the block was freed, why it was not otherwise. Type = `BuddyError`
+#[no_mangle]
+pub extern "C" fn buddy_free_tagged(pool: *mut BuddyPool, ptr: *mut c_void, caller_id: u64) -> BuddyError {
+    if pool.is_null() {
+        return BuddyError::NullPool;
+    }
+
+    if ptr.is_null() {
+        return BuddyError::NullPtr;
+    }
+
+    unsafe {
+        if in_pool(pool, ptr) && is_freed(pool, ptr) {
+            // Without a handler the error code is report enough, panicking would abort the caller
+            if ASSERT_HANDLER.load(Ordering::Relaxed) != 0 {
+                assert_failed(format_args!(
+                    "buddy_free: block at offset {:#x} was already freed by caller {:#x}, freed again by caller {:#x}",
+                    freed_header(pool, ptr) as usize - (*pool).base as usize,
+                    buddy_freed_by(pool, ptr),
+                    caller_id
+                ));
+            }
+
+            return BuddyError::DoubleFree;
+        }
+
+        let block = block_start(pool, block_of(pool, ptr));
+        let status = buddy_free(pool, ptr);
+        if status != BuddyError::Ok {
+            return status;
+        }
+
+        *(block.add(1) as *mut u64) = caller_id;
+    }
+
+    BuddyError::Ok
+}
+
+/// Returns the caller_id buddy_free_tagged recorded when it freed the
+/// allocation at ptr, e.g. to find out who freed a block that is freed twice.
+/// Like the id itself this is only meaningful until the block is handed out
+/// again.
+///
+/// If pool or ptr is NULL, or ptr is not a freed allocation of the pool, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool ptr was allocated from
+/// - ptr `*mut c_void` Pointer to the freed allocation
+///
+/// ## Returns
+///
+/// - The caller_id of the free. Type = `u64`
+#[no_mangle]
+pub extern "C" fn buddy_freed_by(pool: *mut BuddyPool, ptr: *mut c_void) -> u64 {
+    if pool.is_null() || ptr.is_null() {
+        return 0;
+    }
+
+    unsafe {
+        if !in_pool(pool, ptr) || !is_freed(pool, ptr) {
+            return 0;
+        }
+
+        *(freed_header(pool, ptr).add(1) as *const u64)
+    }
+}
+
+/// Helper function.
+///
+/// Returns the header of the freed allocation at ptr, which a freed block
+/// always keeps at its start.
+unsafe fn freed_header(pool: *mut BuddyPool, ptr: *mut c_void) -> *mut Avail {
+    if (*pool).trailing_header { ptr as *mut Avail } else { block_of(pool, ptr) }
+}
+
+/// Helper function.
+///
+/// Puts a block that is no longer reserved, with its kval set in the header at
+/// its start, back on the free lists, coalescing it with its buddies as the
+/// policy allows.
+unsafe fn release_block(pool: *mut BuddyPool, mut block: *mut Avail) {
+    (*block).tag = BLOCK_AVAIL;
+
+    let flags = (*pool).policy.flags;
+    let coalesce = flags & POLICY_COALESCE != 0
+        && flags & POLICY_DEFERRED == 0
+        && !(flags & POLICY_SMALL_FAST != 0 && (*block).kval as usize == SMALLEST_K);
+
+    // Try to coalesce the block with its buddy if they are both available
+    while coalesce && ((*block).kval as usize) < (*pool).kval_m {
+        let buddy = buddy_calc(pool, block);
+
+        // If the buddy is available or has a different size, break out of the loop
+        if !is_free_at(pool, buddy, (*block).kval as usize) {
+            // A live allocation is what keeps this block from growing
+            if is_reserved_block(pool, buddy) {
+                (*pool).coalesce_blocked += 1;
+            }
+
+            break;
+        }
+
+        // Remove the buddy from the available list
+        unlink_block(pool, buddy);
+
+        // If the buddy is smaller in address, update block to point to it
+        if buddy < block {
+            block = buddy;
+        }
+
+        // Increase the kval (combine blocks into a larger one). Both halves
+        // are tagged BLOCK_AVAIL with the same kval, so the merged block is a
+        // consistent free block for the next round
+        (*block).kval += 1;
+    }
+
+    (*pool).free_clock += 1;
+    (*block).stamp = (*pool).free_clock;
+    (*pool).live_count = (*pool).live_count.saturating_sub(1);
+
+    // With nothing left allocated, merging must have rebuilt the whole pool
+    let merges_all = coalesce && flags & POLICY_SMALL_FAST == 0 && (*pool).headerless_count == 0;
+    if flags & POLICY_CHECKED != 0 && merges_all && (*pool).live_count == 0 && ((*block).kval as usize) < (*pool).kval_m {
+        assert_failed(format_args!(
+            "buddy_free: last allocation freed but block at offset {:#x} only merged up to kval {} of {}",
+            block as usize - (*pool).base as usize,
+            (*block).kval,
+            (*pool).kval_m
+        ));
+    }
+
+    insert_block(pool, block);
+}
+
+/// Allocates a block of at least size bytes without keeping a header in it,
+/// so all 2^k bytes of the block belong to the caller. This suits power of
+/// two sized buffers, which would otherwise need a block twice their size to
+/// fit the header. The pointer returned is the start of the block.
+///
+/// Since nothing in the block records its size, the caller has to keep track
+/// of it and hand the same size to buddy_free_headerless, the block must not
+/// be freed with buddy_free or resized with buddy_realloc. Functions walking
+/// the blocks in address order, such as buddy_check_layout, cannot tell a
+/// live headerless block apart from the data in it, so they should not be
+/// used while headerless blocks are live.
+///
+/// If size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - size `usize` The size of the user requested memory block in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the start of the memory block. Type = `*mut c_void`
+#[no_mangle]
+pub extern "C" fn buddy_malloc_headerless(pool: *mut BuddyPool, size: usize) -> *mut c_void {
+    if pool.is_null() || size == 0 {
+        return ptr::null_mut();
+    }
+
+    unsafe {
+        let block = take_block(pool, btok(size).max(SMALLEST_K), usize::MAX);
+        if block.is_null() {
+            return ptr::null_mut();
+        }
+
+        // The tag is only there until the caller writes to the block
+        (*block).tag = BLOCK_RESERVED;
+
+        (*pool).alloc_clock += 1;
+        (*pool).live_count += 1;
+        (*pool).headerless_count += 1;
+
+        report_alloc(pool, size, block as *mut c_void);
+
+        block as *mut c_void
+    }
+}
+
+/// Frees a block allocated by buddy_malloc_headerless, coalescing it with
+/// its buddies like buddy_free does.
+///
+/// If ptr is not in the pool, or is not aligned to a block of size bytes that fits it, BuddyError::InvalidPointer is returned
+/// If the block is free already, or no headerless block is live, BuddyError::DoubleFree is returned
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool
+/// - ptr `*mut c_void` Pointer returned by buddy_malloc_headerless
+/// - size `usize` The size the block was allocated with
+///
+/// ## Returns
+///
+/// - BuddyError::Ok if the block was freed, why it was not otherwise. Type = `BuddyError`
+#[no_mangle]
+pub extern "C" fn buddy_free_headerless(pool: *mut BuddyPool, ptr: *mut c_void, size: usize) -> BuddyError {
+    if pool.is_null() {
+        return BuddyError::NullPool;
+    }
+
+    if ptr.is_null() {
+        return BuddyError::NullPtr;
+    }
+
+    unsafe {
+        let offset = (ptr as usize).wrapping_sub((*pool).base as usize);
+        if offset >= (*pool).numbytes {
+            return BuddyError::InvalidPointer;
+        }
+
+        // A block of that size must fit the pool and start on a multiple of its size
+        let k = btok(size).max(SMALLEST_K);
+        if k > (*pool).kval_m || offset & ((1 << k) - 1) != 0 {
+            return BuddyError::InvalidPointer;
+        }
+
+        // The block, or a larger one holding it, being free means it was freed already
+        let base = (*pool).base as usize;
+        let freed = (k..=(*pool).kval_m).any(|j| is_free_at(pool, (base + (offset & !((1 << j) - 1))) as *mut Avail, j));
+        if (*pool).headerless_count == 0 || freed {
+            return BuddyError::DoubleFree;
+        }
+
+        let block = ptr as *mut Avail;
+        (*block).kval = k as u16;
+        (*pool).headerless_count -= 1;
+
+        release_block(pool, block);
+        report_free(pool, ptr);
+    }
+
+    BuddyError::Ok
+}
+
+/// Helper function.
+///
+/// Returns the header of the block a user pointer belongs to. Pointers handed
+/// out by the aligned allocation path are preceded by a BLOCK_ALIGNED record
+/// whose next field links back to the real block header.
+unsafe fn block_of(pool: *mut BuddyPool, ptr: *mut c_void) -> *mut Avail {
+    if (*pool).trailing_header {
+        let header = find_trailing(pool, ptr as usize);
+        if !header.is_null() {
+            return header;
+        }
+    }
+
+    let header = (ptr as *mut u8).sub(core::mem::size_of::<Avail>()) as *mut Avail;
+
+    if (*header).tag == BLOCK_ALIGNED {
+        let block = aligned_link(pool, header, ptr);
+        if !block.is_null() {
+            return block;
+        }
+    }
+
+    header
+}
+
+/// Helper function.
+///
+/// Returns the block header the BLOCK_ALIGNED record in front of ptr links
+/// to, or NULL if the link can not be one. The record may just as well be
+/// user data in front of an interior pointer, so the link is only trusted if
+/// it lies in the pool where a header of a block holding ptr could be: at a
+/// multiple of the smallest block size before ptr, or with trailing headers
+/// in the last bytes of such a block after it.
+unsafe fn aligned_link(pool: *mut BuddyPool, record: *mut Avail, ptr: *mut c_void) -> *mut Avail {
+    let base = (*pool).base as usize;
+    let link = (*record).next as usize;
+    if link < base || link >= base + (*pool).numbytes {
+        return ptr::null_mut();
+    }
+
+    let valid = if (*pool).trailing_header {
+        (link + core::mem::size_of::<Avail>() - base) & ((1 << SMALLEST_K) - 1) == 0 && link > ptr as usize
+    } else {
+        (link - base) & ((1 << SMALLEST_K) - 1) == 0 && link < ptr as usize
+    };
+
+    if valid { link as *mut Avail } else { ptr::null_mut() }
+}
+
+/// Helper function.
+///
+/// Returns the header of a reserved class k block starting at block, which is
+/// the block itself unless the pool keeps headers at the end of blocks.
+unsafe fn reserved_header(pool: *mut BuddyPool, block: *mut Avail, k: usize) -> *mut Avail {
+    if !(*pool).trailing_header {
+        return block;
+    }
+
+    (block as usize + (1 << k) - core::mem::size_of::<Avail>()) as *mut Avail
+}
+
+/// Helper function.
+///
+/// Returns the first byte of the block a header belongs to.
+unsafe fn block_start(pool: *mut BuddyPool, header: *mut Avail) -> *mut Avail {
+    if !(*pool).trailing_header || (*header).tag != BLOCK_RESERVED {
+        return header;
+    }
+
+    (header as usize + core::mem::size_of::<Avail>() - (1 << (*header).kval)) as *mut Avail
+}
+
+/// Helper function.
+///
+/// Returns the pointer handed out for the plain allocation with this header.
+unsafe fn user_ptr(pool: *mut BuddyPool, header: *mut Avail) -> *mut c_void {
+    if (*pool).trailing_header {
+        return block_start(pool, header) as *mut c_void;
+    }
+
+    (header as *mut u8).add(core::mem::size_of::<Avail>()) as *mut c_void
+}
+
+/// Helper function.
+///
+/// Returns the address right past the last usable byte of the reserved block
+/// with this header.
+unsafe fn user_end(pool: *mut BuddyPool, header: *mut Avail) -> usize {
+    let end = block_start(pool, header) as usize + (1 << (*header).kval);
+
+    if (*pool).trailing_header {
+        return end - core::mem::size_of::<Avail>();
+    }
+
+    end
+}
+
+/// Helper function.
+///
+/// In pools with trailing headers, looks for the header of a reserved block
+/// starting at start. Every class the address is aligned to is tried from the
+/// smallest up, and a header only matches if it is reserved, has that class
+/// and links back to start. Returns NULL if there is no such block.
+unsafe fn find_trailing(pool: *mut BuddyPool, start: usize) -> *mut Avail {
+    let base = (*pool).base as usize;
+    let end = base + (*pool).numbytes;
+    if start < base || start >= end {
+        return ptr::null_mut();
+    }
+
+    for k in SMALLEST_K..=(*pool).kval_m {
+        if (start - base) & ((1 << k) - 1) != 0 || start + (1 << k) > end {
+            break;
+        }
+
+        let header = (start + (1 << k) - core::mem::size_of::<Avail>()) as *mut Avail;
+        if (*header).tag == BLOCK_RESERVED && (*header).kval as usize == k && (*header).next as usize == start {
+            return header;
+        }
+    }
+
+    ptr::null_mut()
+}
+
+/// Helper function.
+///
+/// Returns the header of the block starting at start. Reserved blocks may
+/// keep their header at their end.
+unsafe fn header_at(pool: *mut BuddyPool, start: usize) -> *mut Avail {
+    if (*pool).trailing_header {
+        let header = find_trailing(pool, start);
+        if !header.is_null() {
+            return header;
+        }
+    }
+
+    start as *mut Avail
+}
+
+/// Helper function.
+///
+/// Returns whether the block starting at block is reserved.
+unsafe fn is_reserved_block(pool: *mut BuddyPool, block: *mut Avail) -> bool {
+    if (*pool).trailing_header {
+        return !find_trailing(pool, block as usize).is_null();
+    }
+
+    (*block).tag == BLOCK_RESERVED
+}
+
+/// Helper function.
+///
+/// Returns whether the block starting at block is free. With trailing
+/// headers the start of a reserved block is user data, which could look like
+/// a free header, so reserved blocks are ruled out first. The same goes for
+/// headerless blocks, which is why the free list links are followed back
+/// while there are any.
+unsafe fn is_free_block(pool: *mut BuddyPool, block: *mut Avail) -> bool {
+    if (*pool).trailing_header && is_reserved_block(pool, block) {
+        return false;
+    }
+
+    if (*block).tag != BLOCK_AVAIL {
+        return false;
+    }
+
+    if (*pool).headerless_count == 0 {
+        return true;
+    }
+
+    // Headerless blocks are all user data, only a free block is linked in its list
+    let k = (*block).kval as usize;
+    if !(SMALLEST_K..=(*pool).kval_m).contains(&k) {
+        return false;
+    }
+
+    let prev = (*block).prev;
+    let head: *mut Avail = &mut (*pool).avail[k];
+    let offset = (prev as usize).wrapping_sub((*pool).base as usize);
+    if prev != head && (offset >= (*pool).numbytes || offset & ((1 << SMALLEST_K) - 1) != 0) {
+        return false;
+    }
+
+    (*prev).next == block
+}
+
+/// Helper function.
+///
+/// Verifies that ptr is the start of a reserved allocation of the pool before
+/// it is freed, reporting a violation to the assert handler if it is not.
+unsafe fn check_free(pool: *mut BuddyPool, ptr: *mut c_void) -> bool {
+    let base = (*pool).base as usize;
+    let end = base + (*pool).numbytes;
+
+    // Only trailing headers let user data start right at a block
+    let first = if (*pool).trailing_header { base } else { base + core::mem::size_of::<Avail>() };
+    if (ptr as usize) < first || ptr as usize >= end {
+        assert_failed(format_args!("buddy_free: pointer {:p} is outside the pool", ptr));
+
+        return false;
+    }
+
+    let block = block_of(pool, ptr);
+    if (block as usize) < base || block as usize >= end {
+        assert_failed(format_args!("buddy_free: aligned pointer {:p} links outside the pool", ptr));
+
+        return false;
+    }
+
+    if (*block).tag != BLOCK_RESERVED {
+        assert_failed(format_args!(
+            "buddy_free: block at offset {:#x} has tag {} instead of being reserved, double free?",
+            block as usize - base,
+            (*block).tag
+        ));
+
+        return false;
+    }
+
+    true
+}
+
+/// Returns whether ptr is the start of a live allocation of the pool, as
+/// returned by buddy_malloc or one of its variants, rather than a pointer into
+/// the middle of one or into free memory. The header in front of ptr must be
+/// reserved and sit at a multiple of its own block size from base, which only
+/// a real block header does.
+///
+/// If pool or ptr is NULL, the return value will be false
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to check against
+/// - ptr `*mut c_void` The pointer to check
+///
+/// ## Returns
+///
+/// - True if ptr starts an allocation of the pool. Type = `bool`
+#[no_mangle]
+pub extern "C" fn buddy_is_block_start(pool: *mut BuddyPool, ptr: *mut c_void) -> bool {
+    if pool.is_null() || ptr.is_null() {
+        return false;
+    }
+
+    unsafe {
+        // With trailing headers plain allocations start right at their block
+        if (*pool).trailing_header && !find_trailing(pool, ptr as usize).is_null() {
+            return true;
+        }
+
+        let header = (ptr as usize).wrapping_sub(core::mem::size_of::<Avail>());
+        if !is_block_header(pool, header) {
+            return false;
+        }
+
+        let record = header as *mut Avail;
+        match (*record).tag {
+            BLOCK_RESERVED => !(*pool).trailing_header,
+            // Aligned pointers must link back to the reserved block that holds them
+            BLOCK_ALIGNED => {
+                let block = (*record).next;
+                is_reserved_header(pool, block as usize)
+                    && block_start(pool, block) as usize <= header
+                    && (ptr as usize) < user_end(pool, block)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Helper function.
+///
+/// Returns whether addr is the header of a reserved block of the pool.
+unsafe fn is_reserved_header(pool: *mut BuddyPool, addr: usize) -> bool {
+    if !(*pool).trailing_header {
+        return is_block_header(pool, addr) && (*(addr as *mut Avail)).tag == BLOCK_RESERVED;
+    }
+
+    // A trailing header sits in the last bytes of its block and links back to its start
+    let base = (*pool).base as usize;
+    if addr < base || addr >= base + (*pool).numbytes || addr & (core::mem::align_of::<Avail>() - 1) != 0 {
+        return false;
+    }
+
+    let kval = (*(addr as *mut Avail)).kval as usize;
+    if !(SMALLEST_K..=(*pool).kval_m).contains(&kval) || addr + core::mem::size_of::<Avail>() < base + (1 << kval) {
+        return false;
+    }
+
+    find_trailing(pool, addr + core::mem::size_of::<Avail>() - (1 << kval)) as usize == addr
+}
+
+/// Helper function.
+///
+/// Returns whether addr can be read as a header of the pool: it lies in the
+/// region, is suitably aligned and, unless it is an aligned record, holds a
+/// valid kval and sits at a multiple of that block size from base.
+unsafe fn is_block_header(pool: *mut BuddyPool, addr: usize) -> bool {
+    let base = (*pool).base as usize;
+    if addr < base || addr >= base + (*pool).numbytes || addr & (core::mem::align_of::<Avail>() - 1) != 0 {
+        return false;
+    }
+
+    let record = addr as *mut Avail;
+    if (*record).tag == BLOCK_ALIGNED {
+        return true;
+    }
+
+    let kval = (*record).kval as usize;
+    (SMALLEST_K..=(*pool).kval_m).contains(&kval) && (addr - base) & ((1 << kval) - 1) == 0
+}
+
+/// Installs a handler that is called with a description of any invariant
+/// violation found by the checks of POLICY_CHECKED, instead of panicking.
+/// The message is not NUL terminated, its length is passed along with it.
+/// The handler may log and return, in which case the failing call returns an
+/// error, or it may never return (abort, longjmp).
+///
+/// Without a handler, or after passing NULL, violations panic, which aborts
+/// the process when it happens inside an extern function. The handler is
+/// shared by every pool in the process.
+///
+/// ## Parameters
+///
+/// - handler `Option<extern "C" fn(*const u8, usize)>` The handler, or NULL for the default
+#[no_mangle]
+pub extern "C" fn buddy_set_assert_handler(handler: Option<extern "C" fn(msg: *const u8, len: usize)>) {
+    ASSERT_HANDLER.store(handler.map_or(0, |h| h as usize), Ordering::Relaxed);
+}
+
+/// Helper function.
+///
+/// Reports an invariant violation to the assert handler or panics if none is
+/// installed. The message is formatted on the stack so no heap is needed,
+/// and cut short if it does not fit.
+fn assert_failed(args: core::fmt::Arguments) {
+    let handler = ASSERT_HANDLER.load(Ordering::Relaxed);
+    if handler == 0 {
+        panic!("{}", args);
+    }
+
+    let mut msg = AssertMessage { bytes: [0; 256], len: 0 };
+    let _ = core::fmt::write(&mut msg, args);
+
+    let handler: extern "C" fn(*const u8, usize) = unsafe { core::mem::transmute(handler) };
+    handler(msg.bytes.as_ptr(), msg.len);
+}
+
+/// Buffer assert_failed formats its message into
+struct AssertMessage {
+    bytes: [u8; 256],
+    len: usize, // Number of bytes written so far
+}
+
+impl core::fmt::Write for AssertMessage {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+
+        Ok(())
+    }
+}
+
+/// Frees every allocation of the pool for which pred returns true and returns
+/// how many were freed. Reserved blocks are visited in address order and pred
+/// is called with the user pointer of the block, the number of usable bytes
+/// in it and user. All matching blocks are collected before any of them is
+/// freed, so pred always sees the pool as it was when the call started.
+///
+/// For allocations made through the aligned path pred receives the start of
+/// the block's user data rather than the aligned pointer.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool
+/// - pred `extern "C" fn(*mut c_void, usize, *mut c_void) -> bool` Returns true for blocks to free
+/// - user `*mut c_void` User data passed to pred
+///
+/// ## Returns
+///
+/// - The number of allocations freed. Type = `usize`
+#[cfg(feature = "std")]
+#[no_mangle]
+pub extern "C" fn buddy_free_matching(
+    pool: *mut BuddyPool,
+    pred: extern "C" fn(ptr: *mut c_void, usable: usize, user: *mut c_void) -> bool,
+    user: *mut c_void,
+) -> usize {
+    if pool.is_null() {
+        return 0;
+    }
+
+    let header = core::mem::size_of::<Avail>();
+    let mut matching = Vec::new();
+
+    unsafe {
+        walk_blocks(pool, |block| {
+            if (*block).tag != BLOCK_RESERVED {
+                return;
+            }
+
+            let mem = user_ptr(pool, block);
+            if pred(mem, (1 << (*block).kval) - header, user) {
+                matching.push(mem);
+            }
+        });
+    }
+
+    for &mem in &matching {
+        buddy_free(pool, mem);
+    }
+
+    matching.len()
+}
+
+/// Helper function.
+///
+/// Changes the class of the reserved block with this header to req_k without
+/// moving its start. Shrinking splits off the halves that are no longer
+/// needed, growing merges the block with the free buddies above it, which is
+/// only possible if the block is the lower half at every level up to req_k
+/// and each of those buddies is free and whole. Returns false, leaving the
+/// block alone, if the block can not grow.
+unsafe fn resize_in_place(pool: *mut BuddyPool, header: *mut Avail, req_k: usize) -> bool {
+    let block = block_start(pool, header);
+    let k = (*header).kval as usize;
+    let stamp = (*header).stamp;
+
+    if req_k > k {
+        if req_k > (*pool).kval_m {
+            return false;
+        }
+
+        let offset = block as usize - (*pool).base as usize;
+        for j in k..req_k {
+            let buddy = (block as usize + (1 << j)) as *mut Avail;
+            if offset & (1 << j) != 0 || !is_free_at(pool, buddy, j) {
+                return false;
+            }
+        }
+    }
+
+    // A trailing header is about to end up in the middle of the block or in a freed half
+    if header != block {
+        (*header).tag = BLOCK_UNUSED;
+        (*header).next = ptr::null_mut();
+    }
+
+    if req_k > k {
+        for j in k..req_k {
+            unlink_block(pool, (block as usize + (1 << j)) as *mut Avail);
+        }
+    } else {
+        split_block(pool, block, k, req_k);
+    }
+
+    let header = reserved_header(pool, block, req_k);
+    (*header).tag = BLOCK_RESERVED;
+    (*header).kval = req_k as u16;
+    (*header).stamp = stamp;
+
+    if (*pool).trailing_header {
+        (*header).next = block;
+        (*header).prev = ptr::null_mut();
+    }
+
+    true
+}
+
+/// Allocates memory for an array of nmemb elements of size bytes each and
+/// sets every usable byte of the block to zero. The header of the block is
+/// left alone.
+///
+/// Memory returned by this function is released with buddy_free.
+///
+/// If nmemb or size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
+/// If nmemb * size overflows or the pool is exhausted, errno is set to ENOMEM and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - nmemb `usize` The number of elements
+/// - size `usize` The size of each element in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the zeroed memory. Type = `*mut c_void`
+#[no_mangle]
+pub extern "C" fn buddy_calloc(pool: *mut BuddyPool, nmemb: usize, size: usize) -> *mut c_void {
+    if pool.is_null() || nmemb == 0 || size == 0 {
+        return ptr::null_mut();
+    }
+
+    unsafe {
+        let bytes = match nmemb.checked_mul(size) {
+            Some(bytes) => bytes,
+            None => {
+                set_errno(oom_errno(pool));
+
+                return ptr::null_mut();
+            }
+        };
+
+        let mem = buddy_malloc(pool, bytes);
+        if mem.is_null() {
+            return ptr::null_mut();
+        }
+
+        let end = user_end(pool, block_of(pool, mem));
+        ptr::write_bytes(mem as *mut u8, 0, end - mem as usize);
+
+        mem
+    }
+}
+
+/// Allocates size bytes for a single object and sets every usable byte of
+/// the block to zero, including the slack left by rounding size up to a
+/// power of two. Unlike buddy_calloc there is no element count to multiply.
+///
+/// Memory returned by this function is released with buddy_free.
+///
+/// If size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
+/// If the pool is exhausted, errno is set to ENOMEM and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - size `usize` The size of the user requested memory block in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the zeroed memory. Type = `*mut c_void`
+#[no_mangle]
+pub extern "C" fn buddy_alloc_zeroed(pool: *mut BuddyPool, size: usize) -> *mut c_void {
+    if pool.is_null() || size == 0 {
+        return ptr::null_mut();
+    }
+
+    let mem = buddy_malloc(pool, size);
+    if !mem.is_null() {
+        unsafe { ptr::write_bytes(mem as *mut u8, 0, buddy_usable_size(pool, mem)) };
+    }
+
+    mem
+}
+
+/// Changes the size of the memory block pointed to by ptr to size bytes,
+/// returning a pointer to the resized block. The contents are preserved up
+/// to the lesser of the old and new sizes. If the block already has the right
+/// size class ptr is returned unchanged. A block that shrinks is split in
+/// place, handing the halves it no longer needs back to the free lists, and
+/// a block that grows absorbs the free buddies above it if they add up to
+/// the new class. Only when that is not possible is a new block allocated,
+/// the contents copied and the old block freed.
+///
+/// Allocations made through the aligned path, such as those of
+/// buddy_aligned_alloc, buddy_aligned_calloc and buddy_posix_memalign, keep
+/// their alignment, the new block is allocated with the same alignment as
+/// the old one.
+///
+/// If ptr is NULL this behaves like buddy_malloc(pool, size)
+/// If size is zero the block is freed and the return value will be NULL
+/// If the new block can not be allocated, NULL is returned and the old block is left untouched
+/// If ptr is not a live allocation of the pool, errno is set to EINVAL, NULL is returned and nothing is changed
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool ptr was allocated from
+/// - ptr `*mut c_void` Pointer to the memory block to resize
+/// - size `usize` The new size of the memory block in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the resized memory block. Type = `*mut c_void`
+#[no_mangle]
+pub extern "C" fn buddy_realloc(pool: *mut BuddyPool, ptr: *mut c_void, size: usize) -> *mut c_void {
+    if pool.is_null() {
+        return ptr::null_mut();
+    }
+
+    if ptr.is_null() {
+        return buddy_malloc(pool, size);
+    }
+
+    if size == 0 {
+        buddy_free(pool, ptr);
+
+        return ptr::null_mut();
+    }
+
+    unsafe {
+        // Only live allocations have a header to resize
+        let block = match live_header(pool, ptr) {
+            Ok(block) => block,
+            Err(_) => {
+                set_errno(EINVAL);
+
+                return ptr::null_mut();
+            }
+        };
+
+        let usable = user_end(pool, block) - ptr as usize;
+        let plain = user_ptr(pool, block) == ptr;
+
+        // Plain allocations that already have the right class stay where they are
+        let req_k = request_class(size);
+        if plain && (req_k == (*block).kval as usize || resize_in_place(pool, block, req_k)) {
+            // The hooks see a resize as the old allocation going and the new one coming
+            report_free(pool, ptr);
+            report_alloc(pool, size, ptr);
+
+            return ptr;
+        }
+
+        // Aligned allocations carry their alignment in the record right before ptr
+        let mem = if plain {
+            malloc_block(pool, size, usize::MAX)
+        } else {
+            let record = (ptr as *mut u8).sub(core::mem::size_of::<Avail>()) as *mut Avail;
+            alloc_aligned(pool, 1 << (*record).kval, size)
+        };
+
+        if mem.is_null() {
+            return ptr::null_mut();
+        }
+
+        ptr::copy_nonoverlapping(ptr as *const u8, mem as *mut u8, usable.min(size));
+        report_alloc(pool, size, mem);
+        buddy_free(pool, ptr);
+
+        mem
+    }
+}
+
+/// Resizes the array at ptr to nmemb elements of size bytes each, like
+/// reallocarray from the BSDs and glibc. The multiplication is checked, so a
+/// count that would wrap around fails instead of shrinking the array.
+///
+/// If nmemb * size overflows, errno is set to ENOMEM, NULL is returned and the old block is left untouched
+/// Otherwise this behaves like buddy_realloc(pool, ptr, nmemb * size)
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool ptr was allocated from
+/// - ptr `*mut c_void` Pointer to the array to resize, may be NULL
+/// - nmemb `usize` The new number of elements
+/// - size `usize` The size of each element in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the resized array. Type = `*mut c_void`
+#[no_mangle]
+pub extern "C" fn buddy_reallocarray(pool: *mut BuddyPool, ptr: *mut c_void, nmemb: usize, size: usize) -> *mut c_void {
+    if pool.is_null() {
+        return ptr::null_mut();
+    }
+
+    match nmemb.checked_mul(size) {
+        Some(bytes) => buddy_realloc(pool, ptr, bytes),
+        None => {
+            unsafe { set_errno(oom_errno(pool)) };
+
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Helper function.
+///
+/// Allocates size bytes whose address is a multiple of alignment. The block is
+/// over-allocated so that an aligned address can always be found with room for
+/// a BLOCK_ALIGNED record right before it. That record stores a link to the
+/// real block header, so buddy_free can recover it, and log2(alignment) in its
+/// kval.
+///
+/// Returns NULL and sets errno to EINVAL if alignment is not a power of two,
+/// or ENOMEM if the request can not be satisfied.
+unsafe fn alloc_aligned(pool: *mut BuddyPool, alignment: usize, size: usize) -> *mut c_void {
+    if !alignment.is_power_of_two() {
+        set_errno(EINVAL);
+
+        return ptr::null_mut();
+    }
+
+    let header = core::mem::size_of::<Avail>();
+
+    // Room for the aligned record plus the worst case padding to reach alignment
+    let total = match size.checked_add(header + alignment - 1) {
+        Some(total) if total <= (*pool).numbytes => total,
+        _ => {
+            set_errno(oom_errno(pool));
+
+            return ptr::null_mut();
+        }
+    };
+
+    let mem = malloc_block(pool, total, usize::MAX);
+    if mem.is_null() {
+        return ptr::null_mut();
+    }
+
+    // Leave room for the aligned record and round up to the alignment
+    let aligned = ((mem as usize) + header + alignment - 1) & !(alignment - 1);
+
+    let record = (aligned - header) as *mut Avail;
+    (*record).tag = BLOCK_ALIGNED;
+    (*record).kval = alignment.trailing_zeros() as u16;
+    (*record).next = block_of(pool, mem);
+    (*record).prev = ptr::null_mut();
+
+    aligned as *mut c_void
+}
+
+/// Allocates size bytes whose address is a multiple of alignment, such as a
+/// cache line for SIMD data or a page for DMA. Blocks are only aligned to
+/// their own size, with the header in front of the user data, so the block
+/// is over-allocated and a record right before the aligned pointer links
+/// back to the block header.
+///
+/// Memory returned by this function is released with buddy_free and resized
+/// with buddy_realloc like any other allocation.
+///
+/// If size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
+/// If alignment is not a power of two, errno is set to EINVAL and the return value will be NULL
+/// If the pool is exhausted, errno is set to ENOMEM and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - alignment `usize` The required alignment of the returned pointer, a power of two
+/// - size `usize` The size of the user requested memory block in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the aligned memory block. Type = `*mut c_void`
+#[no_mangle]
+pub extern "C" fn buddy_aligned_alloc(pool: *mut BuddyPool, alignment: usize, size: usize) -> *mut c_void {
+    if pool.is_null() || size == 0 {
+        return ptr::null_mut();
+    }
+
+    unsafe {
+        let mem = alloc_aligned(pool, alignment, size);
+        report_alloc(pool, size, mem);
+
+        mem
+    }
+}
+
+/// Allocates size bytes whose address is a multiple of alignment and stores
+/// the pointer in *memptr, with the signature of posix_memalign so C code
+/// written against it can use the pool. Errors are returned instead of set in
+/// errno, and on failure *memptr is not modified.
+///
+/// Memory returned by this function is released with buddy_free.
+///
+/// If size is zero, *memptr is set to NULL and the return value will be 0
+/// If pool or memptr is NULL, the return value will be EINVAL
+/// If alignment is not a power of two multiple of size_of::<*mut c_void>(), the return value will be EINVAL
+/// If the pool is exhausted, the return value will be ENOMEM
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - memptr `*mut *mut c_void` Where to store the pointer to the aligned memory block
+/// - alignment `usize` The required alignment of the returned pointer
+/// - size `usize` The size of the user requested memory block in bytes
+///
+/// ## Returns
+///
+/// - 0 on success, EINVAL or ENOMEM on failure. Type = `i32`
+#[no_mangle]
+pub extern "C" fn buddy_posix_memalign(pool: *mut BuddyPool, memptr: *mut *mut c_void, alignment: usize, size: usize) -> i32 {
+    if pool.is_null() || memptr.is_null() {
+        return EINVAL;
+    }
+
+    // A power of two is a multiple of the pointer size once it is at least that large
+    if !alignment.is_power_of_two() || alignment < core::mem::size_of::<*mut c_void>() {
+        return EINVAL;
+    }
+
+    unsafe {
+        if size == 0 {
+            *memptr = ptr::null_mut();
+
+            return 0;
+        }
+
+        let mem = alloc_aligned(pool, alignment, size);
+        if mem.is_null() {
+            return ENOMEM;
+        }
+
+        report_alloc(pool, size, mem);
+        *memptr = mem;
+    }
+
+    0
+}
+
+/// Allocates memory for an array of nmemb elements of size bytes each whose
+/// address is a multiple of alignment, and zeroes every usable byte of it.
+/// This is meant for buffers that must be both aligned and clean, like DMA or
+/// SIMD buffers.
+///
+/// Memory returned by this function is released with buddy_free.
+///
+/// If nmemb or size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
+/// If alignment is not a power of two, errno is set to EINVAL and the return value will be NULL
+/// If nmemb * size overflows or the pool is exhausted, errno is set to ENOMEM and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - alignment `usize` The required alignment of the returned pointer, a power of two
+/// - nmemb `usize` The number of elements
+/// - size `usize` The size of each element in bytes
+///
+/// ## Returns
+///
+/// - A pointer to the zeroed memory. Type = `*mut c_void`
+#[no_mangle]
+pub extern "C" fn buddy_aligned_calloc(pool: *mut BuddyPool, alignment: usize, nmemb: usize, size: usize) -> *mut c_void {
+    if pool.is_null() || nmemb == 0 || size == 0 {
+        return ptr::null_mut();
+    }
+
+    unsafe {
+        let bytes = match nmemb.checked_mul(size) {
+            Some(bytes) => bytes,
+            None => {
+                set_errno(oom_errno(pool));
+
+                return ptr::null_mut();
+            }
+        };
+
+        let mem = alloc_aligned(pool, alignment, bytes);
+        if mem.is_null() {
+            return ptr::null_mut();
+        }
+
+        // Zero everything from the aligned pointer up to the end of the block
+        let end = user_end(pool, block_of(pool, mem));
+        ptr::write_bytes(mem as *mut u8, 0, end - mem as usize);
+
+        report_alloc(pool, bytes, mem);
+
+        mem
+    }
+}
+
+/// Returns whether buddy_malloc(pool, size) could be satisfied right now,
+/// i.e. whether any avail list at or above the class of the request holds a
+/// block. Nothing is removed or split and errno is left alone, so a scheduler
+/// can check before committing to a large allocation.
+///
+/// The pool is not changed, so blocks a POLICY_DEFERRED pool has yet to merge
+/// do not count, and the rate callback and split limits are not consulted.
+///
+/// If pool is NULL or size is zero, the return value will be false
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+/// - size `usize` The size of the user requested memory block in bytes
+///
+/// ## Returns
+///
+/// - True if the allocation would succeed. Type = `bool`
+#[no_mangle]
+pub extern "C" fn buddy_can_alloc(pool: *mut BuddyPool, size: usize) -> bool {
+    if pool.is_null() || size == 0 {
+        return false;
+    }
+
+    unsafe { find_free_class(pool, request_class(size)) <= (*pool).kval_m }
+}
+
+/// Returns whether an allocation of size bytes aligned to alignment, as made
+/// by buddy_aligned_calloc, could be satisfied right now. The aligned path
+/// over-allocates to find an aligned address with room for its record, so
+/// it can need a block twice the size a plain allocation of size bytes would
+/// use, and fail where buddy_malloc would not.
+///
+/// The pool is not changed, so blocks a POLICY_DEFERRED pool has yet to merge
+/// do not count, and the rate callback and split limits are not consulted.
+///
+/// If pool is NULL, size is zero or alignment is not a power of two, the return value will be false
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+/// - size `usize` The size of the user requested memory block in bytes
+/// - alignment `usize` The required alignment of the memory, a power of two
+///
+/// ## Returns
+///
+/// - True if the aligned allocation would succeed. Type = `bool`
+#[no_mangle]
+pub extern "C" fn buddy_can_alloc_aligned(pool: *mut BuddyPool, size: usize, alignment: usize) -> bool {
+    if pool.is_null() || size == 0 || !alignment.is_power_of_two() {
+        return false;
+    }
+
+    unsafe {
+        // The same inflated size alloc_aligned asks buddy_malloc for
+        match size.checked_add(core::mem::size_of::<Avail>() + alignment - 1) {
+            Some(total) if total <= (*pool).numbytes => find_free_class(pool, request_class(total)) <= (*pool).kval_m,
+            _ => false,
+        }
+    }
+}
+
+/// Allocates size bytes that are guaranteed not to cross a multiple of
+/// boundary, i.e. [ptr, ptr + size) lies within a single boundary aligned
+/// window. This is meant for devices that can not DMA across such boundaries.
+///
+/// Blocks of the pool are naturally aligned to their size, so any block no
+/// larger than boundary stays inside one window. When the header pushes the
+/// request over boundary the aligned path is used instead, with the pointer
+/// aligned to boundary itself.
+///
+/// Memory returned by this function is released with buddy_free.
+///
+/// If size is zero, the return value will be NULL
+/// If pool is NULL, the return value will be NULL
+/// If boundary is not a power of two or is smaller than size, errno is set to EINVAL and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - size `usize` The size of the user requested memory block in bytes
+/// - boundary `usize` The boundary the memory must not cross, a power of two
+///
+/// ## Returns
+///
+/// - A pointer to the memory block. Type = `*mut c_void`
+#[no_mangle]
+pub extern "C" fn buddy_malloc_noboundary(pool: *mut BuddyPool, size: usize, boundary: usize) -> *mut c_void {
+    if pool.is_null() || size == 0 {
+        return ptr::null_mut();
+    }
+
+    unsafe {
+        if !boundary.is_power_of_two() || size > boundary {
+            set_errno(EINVAL);
+
+            return ptr::null_mut();
+        }
+
+        // A block that fits inside the boundary can not straddle it
+        if size + core::mem::size_of::<Avail>() <= boundary || boundary >= (*pool).numbytes {
+            return buddy_malloc(pool, size);
+        }
+
+        let mem = alloc_aligned(pool, boundary, size);
+        report_alloc(pool, size, mem);
+
+        mem
+    }
+}
+
+/// Reports whether an allocation of size bytes made right now would hand out
+/// memory that is not resident, i.e. whether touching it would page fault.
+/// The block buddy_malloc would pick is looked up without allocating it and
+/// the residency of its pages is checked with mincore. This lets latency
+/// sensitive callers prefault memory or take another path first. Free blocks
+/// whose merging the policy postponed are merged first, as the allocation
+/// would do.
+///
+/// If the allocation could not be satisfied at all false is returned. On
+/// platforms without mincore the answer is always true.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+/// - size `usize` The size of the allocation in bytes
+///
+/// ## Returns
+///
+/// - true if the allocation would touch non-resident pages. Type = `bool`
+#[no_mangle]
+pub extern "C" fn buddy_would_fault(pool: *mut BuddyPool, size: usize) -> bool {
+    if pool.is_null() || size == 0 {
+        return false;
+    }
+
+    unsafe {
+        let req_k = request_class(size);
+        if req_k > (*pool).kval_m {
+            return false;
+        }
+
+        // Pick the block exactly like buddy_malloc, after the merges it would do first
+        merge_postponed(pool, req_k);
+        let k = select_class(pool, req_k, usize::MAX);
+        if k > (*pool).kval_m {
+            return false;
+        }
+
+        // The allocation would be carved from the front of this block
+        let block = (*pool).avail[k].next;
+
+        !is_resident(block as *mut c_void, 1 << req_k)
+    }
+}
+
+/// Prepares the pool for allocations of the given classes so the first ones
+/// made at each of them neither split a block nor fault a page in. For every
+/// class whose free list is empty a larger block is split down ahead of time,
+/// then the pages of the first free block of the class are touched so they
+/// are backed by memory.
+///
+/// Classes are block classes as returned by btok, a class that is already
+/// warm is left alone.
+///
+/// If pool or classes is NULL, EINVAL is returned
+/// If a class is outside of SMALLEST_K..=kval_m, EINVAL is returned and no class is warmed
+/// If there is no free block left to split for a class, ENOMEM is returned
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to warm
+/// - classes `*const usize` The classes to warm
+/// - count `usize` The number of entries in classes
+///
+/// ## Returns
+///
+/// - 0 on success, an errno value otherwise. Type = `i32`
+#[no_mangle]
+pub extern "C" fn buddy_warm_classes(pool: *mut BuddyPool, classes: *const usize, count: usize) -> i32 {
+    if pool.is_null() || classes.is_null() {
+        return EINVAL;
+    }
+
+    unsafe {
+        let classes = core::slice::from_raw_parts(classes, count);
+
+        if classes.iter().any(|&k| !(SMALLEST_K..=(*pool).kval_m).contains(&k)) {
+            return EINVAL;
+        }
+
+        for &k in classes {
+            let found = find_free_class(pool, k);
+            if found > (*pool).kval_m {
+                return ENOMEM;
+            }
+
+            if found > k {
+                let block = (*pool).avail[found].next;
+                unlink_block(pool, block);
+                split_block(pool, block, found, k);
+
+                (*block).kval = k as u16;
+                insert_block(pool, block);
+            }
+
+            touch_pages((*pool).avail[k].next as *mut u8, 1 << k);
+        }
+    }
+
+    0
+}
+
+/// Splits free blocks ahead of time until the free list of every class k
+/// holds at least counts[k] blocks, so the first allocations of each class
+/// are served without splitting. Classes are handled from the largest down,
+/// and a class is only split into smaller ones while it holds more blocks
+/// than asked for, always taking the smallest such class.
+///
+/// The splits are planned before any is made, so the pool is only changed if
+/// the whole distribution fits. The blocks stay split until a freed buddy
+/// coalesces through them, set a policy without POLICY_COALESCE to keep the
+/// shape for good.
+///
+/// If pool or counts is NULL, EINVAL is returned
+/// If a class outside of SMALLEST_K..=kval_m asks for blocks, EINVAL is returned
+/// If the free blocks can not be split into the distribution, ENOMEM is returned and the pool is left untouched
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to shape
+/// - counts `*const usize` The number of free blocks wanted for each class, indexed by class
+/// - len `usize` The number of entries in counts
+///
+/// ## Returns
+///
+/// - 0 on success, an errno value otherwise. Type = `i32`
+#[no_mangle]
+pub extern "C" fn buddy_prewarm(pool: *mut BuddyPool, counts: *const usize, len: usize) -> i32 {
+    if pool.is_null() || counts.is_null() {
+        return EINVAL;
+    }
+
+    unsafe {
+        let counts = core::slice::from_raw_parts(counts, len);
+        let range = SMALLEST_K..=(*pool).kval_m;
+
+        if counts.iter().enumerate().any(|(k, &n)| n > 0 && !range.contains(&k)) {
+            return EINVAL;
+        }
+
+        if !prewarm_pass(pool, counts, false) {
+            return ENOMEM;
+        }
+
+        prewarm_pass(pool, counts, true);
+    }
+
+    0
+}
+
+/// Helper function.
+///
+/// Works out the splits buddy_prewarm needs on a tally of the free blocks of
+/// every class, making them only if apply is set. Returns whether the counts
+/// can be met.
+unsafe fn prewarm_pass(pool: *mut BuddyPool, counts: &[usize], apply: bool) -> bool {
+    let kval_m = (*pool).kval_m;
+    let wanted = |k: usize| counts.get(k).copied().unwrap_or(0);
+
+    let mut have = [0; MAX_K];
+    for (k, count) in have.iter_mut().enumerate().take(kval_m + 1).skip(SMALLEST_K) {
+        *count = free_blocks(pool, k);
+    }
+
+    for k in (SMALLEST_K..=kval_m).rev() {
+        while have[k] < wanted(k) {
+            let Some(j) = (k + 1..=kval_m).find(|&j| have[j] > wanted(j)) else {
+                return false;
+            };
+
+            if apply {
+                let block = (*pool).avail[j].next;
+                unlink_block(pool, block);
+                split_block(pool, block, j, k);
+
+                (*block).kval = k as u16;
+                insert_block(pool, block);
+            }
+
+            // One spare half on every class in between, both halves of the last split on k
+            have[j] -= 1;
+            for count in &mut have[k..j] {
+                *count += 1;
+            }
+            have[k] += 1;
+        }
+    }
+
+    true
+}
+
+/// Splits every free block of the pool down to the class that fits
+/// object_size, turning the free memory into a grid of equal slots on a
+/// single free list, and returns how many slots that list holds. Allocations
+/// of object_size are then served straight off the list without splitting,
+/// which makes the pool an object pool for objects of that size.
+///
+/// Freed slots are coalesced again as usual, set a policy without
+/// POLICY_COALESCE to keep the grid.
+///
+/// If pool is NULL, object_size is zero or no block fits it, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to split up
+/// - object_size `usize` The size of the objects the slots hold in bytes
+///
+/// ## Returns
+///
+/// - The number of free slots of the class. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_slabify(pool: *mut BuddyPool, object_size: usize) -> usize {
+    if pool.is_null() || object_size == 0 {
+        return 0;
+    }
+
+    unsafe {
+        let req_k = request_class(object_size);
+        if req_k > (*pool).kval_m {
+            return 0;
+        }
+
+        // Top down, so the buddies split off land on lists still to be visited
+        for k in (req_k + 1..=(*pool).kval_m).rev() {
+            let head: *mut Avail = &mut (*pool).avail[k];
+
+            loop {
+                let block = (*head).next;
+                if block == head {
+                    break;
+                }
+
+                unlink_block(pool, block);
+                split_block(pool, block, k, req_k);
+
+                (*block).kval = req_k as u16;
+                insert_block(pool, block);
+            }
+        }
+
+        free_blocks(pool, req_k)
+    }
+}
+
+/// Returns how many bytes the pool could hand out in total if all of it were
+/// carved into blocks of class assumed_block_k. Every block loses its header,
+/// so this is less than numbytes and shrinks as the blocks get smaller,
+/// which makes it the capacity to plan with for a workload of uniform
+/// allocations.
+///
+/// If pool is NULL or assumed_block_k is not a class of the pool, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+/// - assumed_block_k `usize` The class every block is assumed to have
+///
+/// ## Returns
+///
+/// - The number of usable bytes. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_usable_capacity(pool: *mut BuddyPool, assumed_block_k: usize) -> usize {
+    if pool.is_null() {
+        return 0;
+    }
+
+    unsafe {
+        if !(SMALLEST_K..=(*pool).kval_m).contains(&assumed_block_k) {
+            return 0;
+        }
+
+        ((*pool).numbytes >> assumed_block_k) * ((1 << assumed_block_k) - core::mem::size_of::<Avail>())
+    }
+}
+
+/// Helper function.
+///
+/// Writes to every page of [addr, addr + len) so the kernel backs it. The
+/// first byte of each page is written back unchanged.
+unsafe fn touch_pages(addr: *mut u8, len: usize) {
+    let page = page_size();
+
+    let mut at = 0;
+    while at < len {
+        let byte = addr.add(at);
+        ptr::write_volatile(byte, ptr::read_volatile(byte));
+
+        at += page;
+    }
+}
+
+/// Hands the pages of free blocks back to the system until at least
+/// target_bytes have been released, for instance when the process is under
+/// memory pressure. Free blocks are visited from the largest class down and
+/// every whole page behind their header is advised away with MADV_DONTNEED,
+/// or decommitted on Windows.
+/// The blocks stay on the free lists, their pages are faulted back in, zeroed
+/// for private mappings, when they are used again.
+///
+/// Pages are counted whether they were resident or not, so the bytes released
+/// are an upper bound on the memory actually returned.
+///
+/// If pool is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to shrink
+/// - target_bytes `usize` The number of bytes to release
+///
+/// ## Returns
+///
+/// - The number of bytes released. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_reclaim(pool: *mut BuddyPool, target_bytes: usize) -> usize {
+    if pool.is_null() {
+        return 0;
+    }
+
+    let mut reclaimed = 0;
+
+    unsafe {
+        let page = page_size();
+
+        for k in (SMALLEST_K..=(*pool).kval_m).rev() {
+            let head: *mut Avail = &mut (*pool).avail[k];
+
+            let mut block = (*head).next;
+            while block != head && reclaimed < target_bytes {
+                // The header has to survive, so the first page of every block is kept
+                let start = (block as usize + core::mem::size_of::<Avail>() + page - 1) & !(page - 1);
+                let end = (block as usize + (1 << k)) & !(page - 1);
+
+                if end > start && discard_pages(start as *mut c_void, end - start) {
+                    reclaimed += end - start;
+                }
+
+                block = (*block).next;
+            }
+        }
+    }
+
+    reclaimed
+}
+
+/// Hands every whole page of every free block back to the system, like
+/// buddy_reclaim without a target, so a pool that once peaked high stops
+/// holding on to memory it no longer uses. The first page of each free block
+/// is kept for its header and the mapping itself stays in place, so the pool
+/// works as before and trimmed pages are faulted back in when reused.
+///
+/// If pool is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to trim
+///
+/// ## Returns
+///
+/// - The number of bytes released. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_trim(pool: *mut BuddyPool) -> usize {
+    buddy_reclaim(pool, usize::MAX)
+}
+
+/// Helper function.
+///
+/// Returns whether every page overlapping [addr, addr + len) is resident.
+#[cfg(all(target_os = "linux", feature = "std"))]
+unsafe fn is_resident(addr: *mut c_void, len: usize) -> bool {
+    let page = page_size();
+    let start = addr as usize & !(page - 1);
+    let end = (addr as usize + len + page - 1) & !(page - 1);
+
+    // Ask about a bounded number of pages at a time
+    let mut pages = [0u8; 64];
+    let mut at = start;
+    while at < end {
+        let count = ((end - at) / page).min(pages.len());
+
+        if mincore(at as *mut c_void, count * page, pages.as_mut_ptr()) != 0 {
+            return false;
+        }
+
+        if pages[..count].iter().any(|p| p & 1 == 0) {
+            return false;
+        }
+
+        at += count * page;
+    }
+
+    true
+}
+
+/// Helper function.
+///
+/// Residency can not be queried here, so memory is assumed to fault.
+#[cfg(not(all(target_os = "linux", feature = "std")))]
+unsafe fn is_resident(_addr: *mut c_void, _len: usize) -> bool {
+    false
+}
+
+/// Returns the largest number of blocks of class k the pool could ever hold,
+/// which is the number of blocks left if the whole pool were split down to
+/// that class. Comparing it with the number of free blocks of the class gives
+/// its utilization.
+///
+/// If pool is NULL or k is not a valid class of the pool, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+/// - k `usize` The class to query
+///
+/// ## Returns
+///
+/// - The number of class k blocks that fit in the pool. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_capacity(pool: *mut BuddyPool, k: usize) -> usize {
+    if pool.is_null() {
+        return 0;
+    }
+
+    unsafe {
+        if !(SMALLEST_K..=(*pool).kval_m).contains(&k) {
+            return 0;
+        }
+
+        (*pool).numbytes >> k
+    }
+}
+
+/// Returns the number of usable bytes of the largest free block of the pool,
+/// the largest allocation buddy_malloc can currently satisfy. The answer is
+/// read from an index of non-empty classes, so it takes constant time no
+/// matter how many classes the pool has.
+///
+/// If pool is NULL or it has no free block, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - The usable size of the largest free block in bytes. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_largest_available(pool: *mut BuddyPool) -> usize {
+    if pool.is_null() {
+        return 0;
+    }
+
+    unsafe {
+        if (*pool).free_mask == 0 {
+            return 0;
+        }
+
+        (1 << (*pool).highest_free_k) - core::mem::size_of::<Avail>()
+    }
+}
+
+/// Same as buddy_largest_available: the usable size of the largest free
+/// block, (1 << k) - size_of::<Avail>() for the highest class k with a free
+/// block, or 0 if the pool is fully allocated. The pool is not changed.
+///
+/// If pool is NULL or it has no free block, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - The usable size of the largest free block in bytes. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_largest_free(pool: *mut BuddyPool) -> usize {
+    buddy_largest_available(pool)
+}
+
+/// Returns how many classes, from SMALLEST_K to kval_m, currently have at
+/// least one free block. A fresh pool has a single one, its top block, and the
+/// more classes the free memory is spread over the more fragmented it tends
+/// to be. This is read from the index of non-empty classes, in constant time.
+///
+/// If pool is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - The number of classes with a free block. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_active_classes(pool: *mut BuddyPool) -> usize {
+    if pool.is_null() {
+        return 0;
+    }
+
+    unsafe { ((*pool).free_mask >> SMALLEST_K).count_ones() as usize }
+}
+
+/// Allocates the largest free block of the pool whole and stores its usable
+/// size in size. This hands out as much contiguous memory as the pool can
+/// give without splitting anything.
+///
+/// If pool or size is NULL, the return value will be NULL
+/// If the pool has no free block, errno is set to ENOMEM and the return value will be NULL
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to alloc from
+/// - size `*mut usize` Receives the usable size of the returned block
+///
+/// ## Returns
+///
+/// - A pointer to the memory block. Type = `*mut c_void`
+#[no_mangle]
+pub extern "C" fn buddy_malloc_largest(pool: *mut BuddyPool, size: *mut usize) -> *mut c_void {
+    if pool.is_null() || size.is_null() {
+        return ptr::null_mut();
+    }
+
+    let largest = buddy_largest_available(pool);
+    if largest == 0 {
+        unsafe { set_errno(oom_errno(pool)) };
+
+        return ptr::null_mut();
+    }
+
+    let mem = buddy_malloc(pool, largest);
+    if !mem.is_null() {
+        unsafe { *size = largest };
+    }
+
+    mem
+}
+
+/// Describes how the memory of the pool is backed: the file descriptor, the
+/// mmap flags and the protection of its region and whether the pool maps it
+/// itself. Pools on a caller supplied buffer report an fd of -1 and no flags
+/// or protection, since they never map anything.
+///
+/// If pool or info is NULL, EINVAL is returned
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+/// - info `*mut MappingInfo` Receives the description of the mapping
+///
+/// ## Returns
+///
+/// - 0 on success, EINVAL otherwise. Type = `i32`
+#[no_mangle]
+pub extern "C" fn buddy_mapping_info(pool: *mut BuddyPool, info: *mut MappingInfo) -> i32 {
+    if pool.is_null() || info.is_null() {
+        return EINVAL;
+    }
+
+    unsafe {
+        *info = MappingInfo {
+            fd: (*pool).map_fd,
+            flags: (*pool).map_flags,
+            prot: (*pool).map_prot,
+            owns_mapping: (*pool).owns_mapping as u8,
+        };
+    }
+
+    0
+}
+
+/// Reads the running totals of the pool: allocations and frees made, live
+/// allocations, blocks split and frees whose merging was cut short by a
+/// reserved buddy. A coalesce_blocked count that keeps growing means long
+/// lived allocations are pinning the pool in a fragmented state.
+///
+/// If pool or counters is NULL, EINVAL is returned
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+/// - counters `*mut BuddyCounters` Receives the counters
+///
+/// ## Returns
+///
+/// - 0 on success, EINVAL otherwise. Type = `i32`
+#[no_mangle]
+pub extern "C" fn buddy_counters(pool: *mut BuddyPool, counters: *mut BuddyCounters) -> i32 {
+    if pool.is_null() || counters.is_null() {
+        return EINVAL;
+    }
+
+    unsafe {
+        *counters = BuddyCounters {
+            allocs: (*pool).alloc_clock,
+            frees: (*pool).free_clock,
+            live: (*pool).live_count as u64,
+            splits: (*pool).split_count,
+            coalesce_blocked: (*pool).coalesce_blocked,
+        };
+    }
+
+    0
+}
+
+/// Adds up the free lists of the pool into a BuddyStats: how many bytes and
+/// blocks are free, the largest free block, and the rest of the pool as used.
+/// Sizes are those of whole blocks, so the rounding of every allocation up to
+/// a power of two shows up as used memory. The pool is not modified.
+///
+/// If pool is NULL, every field of the result will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - The statistics of the pool. Type = `BuddyStats`
+#[no_mangle]
+pub extern "C" fn buddy_stats(pool: *mut BuddyPool) -> BuddyStats {
+    let mut stats = BuddyStats::default();
+    if pool.is_null() {
+        return stats;
+    }
+
+    unsafe {
+        stats.total_bytes = 1 << (*pool).kval_m;
+
+        for k in SMALLEST_K..=(*pool).kval_m {
+            let count = free_blocks(pool, k);
+            if count > 0 {
+                stats.free_bytes += count << k;
+                stats.num_free_blocks += count;
+                stats.largest_free_block = 1 << k;
+            }
+        }
+
+        stats.used_bytes = stats.total_bytes - stats.free_bytes;
+    }
+
+    stats
+}
+
+/// Returns the number of free blocks of class k, the length of a single
+/// avail list, without filling in a whole BuddyStats.
+///
+/// If pool is NULL or k is larger than the largest class of the pool, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+/// - k `usize` The class to count the free blocks of
+///
+/// ## Returns
+///
+/// - The number of free blocks of class k. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_free_count(pool: *mut BuddyPool, k: usize) -> usize {
+    if pool.is_null() {
+        return 0;
+    }
+
+    unsafe {
+        if k > (*pool).kval_m {
+            return 0;
+        }
+
+        free_blocks(pool, k)
+    }
+}
+
+/// Measures the external fragmentation of the pool as one minus the share of
+/// the free memory held by the largest free block. 0.0 means all free memory
+/// is a single block, values near 1.0 mean it is scattered over many small
+/// blocks and large requests fail even though the pool is far from full. A
+/// value that keeps rising over time means coalescing is not keeping up. The
+/// pool is not modified.
+///
+/// If pool is NULL or it has no free memory, the return value will be 0.0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - The fragmentation ratio between 0.0 and 1.0. Type = `f64`
+#[no_mangle]
+pub extern "C" fn buddy_fragmentation(pool: *mut BuddyPool) -> f64 {
+    let stats = buddy_stats(pool);
+    if stats.free_bytes == 0 {
+        return 0.0;
+    }
+
+    1.0 - stats.largest_free_block as f64 / stats.free_bytes as f64
+}
+
+/// Returns whether the pool can be destroyed without pulling memory out from
+/// under a caller, i.e. whether every allocation made from it was freed.
+///
+/// If pool is NULL, the return value will be false
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - True if no allocation of the pool is outstanding. Type = `bool`
+#[no_mangle]
+pub extern "C" fn buddy_can_destroy(pool: *mut BuddyPool) -> bool {
+    if pool.is_null() {
+        return false;
+    }
+
+    unsafe { (*pool).live_count == 0 }
+}
+
+/// Caps the number of splits the pool may perform over its whole lifetime.
+/// Once split_count has reached max, allocations that would have to split a
+/// block fail with EAGAIN, while those served straight from a free list still
+/// succeed. An allocation is also refused if its splits would take the pool
+/// past the limit. This bounds the structural work a tenant of the pool can
+/// cause.
+///
+/// Passing 0 removes the limit, which is the default.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to limit
+/// - max `u64` The largest split_count the pool may reach, 0 for no limit
+#[no_mangle]
+pub extern "C" fn buddy_set_lifetime_split_limit(pool: *mut BuddyPool, max: u64) {
+    if pool.is_null() {
+        return;
+    }
+
+    unsafe {
+        (*pool).split_limit = max;
+    }
+}
+
+/// Returns the number of levels of the buddy tree of the pool below its top
+/// block, kval_m - SMALLEST_K. This is the most splits one allocation or
+/// merges one free can ever take, and the number of classes below the top
+/// one that per class bookkeeping has to cover.
+///
+/// If pool is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - The depth of the buddy tree. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_depth(pool: *mut BuddyPool) -> usize {
+    if pool.is_null() {
+        return 0;
+    }
+
+    unsafe { (*pool).kval_m - SMALLEST_K }
+}
+
+/// Returns the largest number of splits a single buddy_malloc call has needed
+/// since the pool was initialized. Every split is one step of the allocation
+/// path, so this bounds the worst case allocation latency seen so far.
+///
+/// If pool is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to query
+///
+/// ## Returns
+///
+/// - The deepest split chain performed by one allocation. Type = `u16`
+#[no_mangle]
+pub extern "C" fn buddy_max_split_depth(pool: *mut BuddyPool) -> u16 {
+    if pool.is_null() {
+        return 0;
+    }
+
+    unsafe { (*pool).max_split_depth }
+}
+
+/// Returns the natural alignment of the block behind ptr, 1 << kval. Blocks
+/// of class k start at a multiple of 2^k, because the region itself is
+/// aligned to its size, so this is the alignment the block is guaranteed to
+/// have for free.
+///
+/// Notice that it is the block that has this alignment, not ptr: the user
+/// data starts after the block header, at block + size_of::<Avail>(). Callers
+/// that need the large alignment can round ptr up to it inside the block, or
+/// create the pool with INIT_TRAILING_HEADER so ptr is the block itself.
+///
+/// If pool or ptr is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool ptr was allocated from
+/// - ptr `*mut c_void` Pointer to a live allocation
+///
+/// ## Returns
+///
+/// - The alignment of the start of the block in bytes. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_natural_alignment(pool: *mut BuddyPool, ptr: *mut c_void) -> usize {
+    if pool.is_null() || ptr.is_null() {
+        return 0;
+    }
+
+    unsafe { 1 << (*block_of(pool, ptr)).kval }
+}
+
+/// Returns how many bytes starting at ptr the caller may use. Requests are
+/// rounded up to a power of two block, so this is usually more than was asked
+/// for, and the caller is free to grow into the rest of the block without
+/// calling buddy_realloc. This is the counterpart of malloc_usable_size.
+///
+/// If pool or ptr is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool ptr was allocated from
+/// - ptr `*mut c_void` Pointer to a live allocation
+///
+/// ## Returns
+///
+/// - The number of usable bytes of the allocation. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_usable_size(pool: *mut BuddyPool, ptr: *mut c_void) -> usize {
+    if pool.is_null() || ptr.is_null() {
+        return 0;
+    }
+
+    unsafe { user_end(pool, block_of(pool, ptr)) - ptr as usize }
+}
+
+/// Returns the offset from the start of the pool of the block holding the
+/// allocation at ptr. The offset stays the same for as long as the allocation
+/// lives, so it can key metadata kept outside of the pool.
+///
+/// If pool or ptr is NULL or ptr is not inside the pool, the return value will be usize::MAX
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool ptr was allocated from
+/// - ptr `*mut c_void` Pointer to a live allocation
+///
+/// ## Returns
+///
+/// - The offset of the block in bytes. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_offset_of(pool: *mut BuddyPool, ptr: *mut c_void) -> usize {
+    if pool.is_null() || ptr.is_null() {
+        return usize::MAX;
+    }
+
+    unsafe {
+        if !in_pool(pool, ptr) {
+            return usize::MAX;
+        }
+
+        block_start(pool, block_of(pool, ptr)) as usize - (*pool).base as usize
+    }
+}
+
+/// Returns the index of the block holding the allocation at ptr among the
+/// smallest blocks of the pool, i.e. its offset divided by the smallest block
+/// size. No two live allocations share an index and every index is below
+/// the pool size divided by the smallest block size, so a flat array of that
+/// length can hold per allocation metadata.
+///
+/// If pool or ptr is NULL or ptr is not inside the pool, the return value will be usize::MAX
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool ptr was allocated from
+/// - ptr `*mut c_void` Pointer to a live allocation
+///
+/// ## Returns
+///
+/// - The index of the block. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_block_index(pool: *mut BuddyPool, ptr: *mut c_void) -> usize {
+    match buddy_offset_of(pool, ptr) {
+        usize::MAX => usize::MAX,
+        offset => offset >> SMALLEST_K,
+    }
+}
+
+/// Returns how many allocations were made from the pool after the one that
+/// returned ptr. The most recent allocation has an age of 0, so comparing
+/// ages tells which of several live allocations is the oldest, e.g. for a
+/// cache deciding what to evict.
+///
+/// If pool or ptr is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool ptr was allocated from
+/// - ptr `*mut c_void` Pointer to a live allocation
+///
+/// ## Returns
+///
+/// - The age of the allocation. Type = `u64`
+#[no_mangle]
+pub extern "C" fn buddy_alloc_age(pool: *mut BuddyPool, ptr: *mut c_void) -> u64 {
+    if pool.is_null() || ptr.is_null() {
+        return 0;
+    }
+
+    unsafe { (*pool).alloc_clock - (*block_of(pool, ptr)).stamp }
+}
+
+/// Initialize a new memory pool using the buddy algorithm. Internally,
+/// this function uses mmap to get a block of memory to manage so should be
+/// portable to any system that implements mmap. On Windows the memory comes
+/// from VirtualAlloc instead. This function will round
+/// up to the nearest power of two. So if the user requests 503MiB
+/// it will be rounded up to 512MiB.
+///
+/// The region is placed at an address that is a multiple of its own size, so
+/// every block of the pool is naturally aligned to its size.
+///
+/// Note that if a 0 is passed as an argument then it initializes
+/// the memory pool to be of the default size of DEFAULT_K. If the caller
+/// specifies an unreasonably small size, then the buddy system may
+/// not be able to satisfy any requests.
+///
+/// If mmap fails this function panics, unless buddy_set_abort_on_error(false)
+/// was called, in which case the pool is left zeroed and the errno reported
+/// by mmap is returned.
+///
+/// NOTE: Memory pools returned by this function can not be intermingled.
+/// Calling buddy_malloc with pool A and then calling buddy_free with
+/// pool B will result in undefined behavior.
+/// BuddyRegistry finds the right pool for a pointer when several are in use.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` A pointer to the pool to initialize
+/// - size `usize` The size of the pool in bytes.
+///
+/// ## Returns
+///
+/// - 0 on success or the errno of the failed mapping. Type = `i32`
+#[cfg(feature = "std")]
+#[no_mangle]
+pub extern "C" fn buddy_init(pool: *mut BuddyPool, size: usize) -> i32 {
+    unsafe { init_mapped(pool, size, &BuddyInitOptions::default()) }
+}
+
+/// Same as buddy_init but with extra options for how the pool is set up.
+///
+/// With INIT_END_SENTINEL a few extra bytes are mapped past the end of the
+/// region to hold a BLOCK_SENTINEL record. Address-order walks over the pool
+/// then have to land exactly on that record, which buddy_check_layout uses to
+/// detect corrupted headers.
+///
+/// With INIT_SHARED the region is mapped MAP_SHARED | MAP_ANONYMOUS. A private
+/// region is copied on write into a child after fork, while a shared one stays
+/// the same memory in the parent and all of its children, so bytes written by
+/// one process are seen by the others. Only the region is shared, the
+/// BuddyPool itself is as private as the memory it lives in. Processes that
+/// allocate from the same pool must place it in shared memory too, use offsets
+/// from base rather than raw pointers if the region may be mapped elsewhere,
+/// and serialize their calls with a lock of their own. Windows has no fork,
+/// so there INIT_SHARED fails with EINVAL.
+///
+/// With INIT_TRAILING_HEADER reserved blocks keep their header in their last
+/// bytes instead of their first ones, so the pointer handed out is the start
+/// of the block and shares its natural alignment, e.g. a 64 byte block is
+/// cache line aligned. Free blocks still keep their header at the start for
+/// the free lists. To find the header again, buddy_free tries every class
+/// the pointer is aligned to and accepts the reserved header that links back
+/// to it, so user data must never be written past the usable size.
+///
+/// With INIT_HUGEPAGES the region is mapped with MAP_HUGETLB on Linux, backed
+/// by 2 MiB huge pages so a large pool needs far fewer TLB entries. Huge pages
+/// have to be reserved by the administrator first (vm.nr_hugepages), and when
+/// the mapping fails the pool falls back to normal pages instead. Whether huge
+/// pages were used shows in map_flags, see buddy_mapping_info. The size should
+/// be a multiple of the huge page size, a smaller pool still takes a whole
+/// huge page. Other systems ignore the flag.
+///
+/// If mmap fails with ENOMEM or EAGAIN it is retried up to map_retries times,
+/// sleeping retry_delay_us microseconds before the first retry and twice as
+/// long before each one after it. The error is only reported once every retry
+/// failed.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` A pointer to the pool to initialize
+/// - size `usize` The size of the pool in bytes.
+/// - options `*const BuddyInitOptions` The options to use, NULL for the defaults
+///
+/// ## Returns
+///
+/// - 0 on success or the errno of the failed mapping. Type = `i32`
+#[cfg(feature = "std")]
+#[no_mangle]
+pub extern "C" fn buddy_init_with(pool: *mut BuddyPool, size: usize, options: *const BuddyInitOptions) -> i32 {
+    let options = if options.is_null() { BuddyInitOptions::default() } else { unsafe { *options } };
+
+    unsafe { init_mapped(pool, size, &options) }
+}
+
+/// Helper function.
+///
+/// Does the work of buddy_init. Kept separate from the extern function so a
+/// failure panics through regular Rust frames.
+#[cfg(feature = "std")]
+unsafe fn init_mapped(pool: *mut BuddyPool, size: usize, options: &BuddyInitOptions) -> i32 {
+    let kval = if size == 0 { DEFAULT_K } else { btok(size) };
+    let kval = kval.clamp(MIN_K, MAX_K - 1);
+
+    ptr::write_bytes(pool, 0, 1);
+    (*pool).kval_m = kval;
+    (*pool).numbytes = 1 << kval;
+
+    let with_sentinel = options.flags & INIT_END_SENTINEL != 0;
+    let extra = if with_sentinel { core::mem::size_of::<Avail>() } else { 0 };
+
+    (*pool).map_fd = -1;
+    (*pool).map_flags = if options.flags & INIT_SHARED != 0 { MAP_SHARED } else { MAP_PRIVATE } | MAP_ANONYMOUS;
+    (*pool).map_prot = PROT_READ | PROT_WRITE;
+
+    // Huge pages may not be reserved on this system, then normal pages will do
+    #[cfg(target_os = "linux")]
+    if options.flags & INIT_HUGEPAGES != 0 {
+        let flags = (*pool).map_flags | MAP_HUGETLB;
+        (*pool).base = map_region((*pool).numbytes + extra, (*pool).numbytes, (*pool).map_prot, flags);
+        if !(*pool).base.is_null() {
+            (*pool).map_flags = flags;
+        }
+    }
+
+    if (*pool).base.is_null() {
+        (*pool).base = map_region((*pool).numbytes + extra, (*pool).numbytes, (*pool).map_prot, (*pool).map_flags);
+    }
+
+    // Memory pressure may only be momentary, back off and try again
+    let mut delay = options.retry_delay_us as u64;
+    for _ in 0..options.map_retries {
+        let errno = errno();
+        if !(*pool).base.is_null() || (errno != ENOMEM && errno != EAGAIN) {
+            break;
+        }
+
+        std::thread::sleep(Duration::from_micros(delay));
+        delay = delay.saturating_mul(2);
+
+        (*pool).base = map_region((*pool).numbytes + extra, (*pool).numbytes, (*pool).map_prot, (*pool).map_flags);
+    }
+
+    if (*pool).base.is_null() {
+        let err = backing_error("buddy_init avail array mmap failed");
+        ptr::write_bytes(pool, 0, 1);
+
+        return err;
+    }
+
+    (*pool).owns_mapping = true;
+    (*pool).policy = BuddyPolicy { flags: POLICY_DEFAULT, strategy: STRATEGY_LIFO };
+    (*pool).trailing_header = options.flags & INIT_TRAILING_HEADER != 0;
+
+    if with_sentinel {
+        place_sentinel(pool);
+    }
+
+    init_free_lists(pool);
+
+    0
+}
+
+/// Helper function.
+///
+/// Writes the record marking the end of the region right past its last byte.
+#[cfg(feature = "std")]
+unsafe fn place_sentinel(pool: *mut BuddyPool) {
+    let sentinel = ((*pool).base as usize + (*pool).numbytes) as *mut Avail;
+    (*sentinel).tag = BLOCK_SENTINEL;
+    (*sentinel).kval = (*pool).kval_m as u16;
+    (*sentinel).next = ptr::null_mut();
+    (*sentinel).prev = ptr::null_mut();
+
+    (*pool).sentinel = sentinel;
+}
+
+/// Helper function.
+///
+/// Returns the number of bytes mapped for the pool, the region plus its sentinel.
+unsafe fn mapped_bytes(pool: *mut BuddyPool) -> usize {
+    let extra = if (*pool).sentinel.is_null() { 0 } else { core::mem::size_of::<Avail>() };
+
+    // Huge page mappings can only be unmapped in whole huge pages
+    #[cfg(all(target_os = "linux", feature = "std"))]
+    if (*pool).map_flags & MAP_HUGETLB != 0 {
+        return ((*pool).numbytes + extra).next_multiple_of(HUGE_PAGE_SIZE);
+    }
+
+    (*pool).numbytes + extra
+}
+
+/// Helper function.
+///
+/// Visits the header of every block of the pool in address order by hopping
+/// 2^kval bytes from one block to the next. Returns false, stopping early, if a header
+/// holds a kval that is out of range or the walk does not end exactly at the
+/// end of the region, on its sentinel if the pool has one.
+unsafe fn walk_blocks(pool: *mut BuddyPool, mut visit: impl FnMut(*mut Avail)) -> bool {
+    let base = (*pool).base as usize;
+    let end = base + (*pool).numbytes;
+
+    let mut addr = base;
+    while addr < end {
+        let block = header_at(pool, addr);
+        let kval = (*block).kval as usize;
+
+        if !(SMALLEST_K..=(*pool).kval_m).contains(&kval) || addr + (1 << kval) > end {
+            return false;
+        }
+
+        visit(block);
+        addr += 1 << kval;
+    }
+
+    let sentinel = (*pool).sentinel;
+    sentinel.is_null() || (addr as *mut Avail == sentinel && (*sentinel).tag == BLOCK_SENTINEL)
+}
+
+/// Walks every block of the pool in address order and checks that the walk
+/// covers exactly the whole region. For pools created with INIT_END_SENTINEL
+/// the walk must also finish on the sentinel record. A false result means a
+/// block header was corrupted.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to check
+///
+/// ## Returns
+///
+/// - true if the layout of the pool is consistent. Type = `bool`
+#[no_mangle]
+pub extern "C" fn buddy_check_layout(pool: *mut BuddyPool) -> bool {
+    if pool.is_null() {
+        return false;
+    }
+
+    unsafe { walk_blocks(pool, |_| {}) }
+}
+
+/// Reports every allocation of the pool with its owner, to find out which
+/// subsystem holds memory that should have been released. Reserved blocks are
+/// visited in address order and visit is called with the user pointer of the
+/// block, the number of usable bytes in it, the owner recorded by
+/// buddy_malloc_tagged (0 for every other allocation) and user. Free blocks
+/// are skipped. visit must not allocate from or free to the pool.
+///
+/// For allocations made through the aligned path visit receives the start of
+/// the block's user data rather than the aligned pointer.
+///
+/// If pool is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to walk
+/// - visit `extern "C" fn(*mut c_void, usize, u32, *mut c_void)` Called for every allocation
+/// - user `*mut c_void` User data passed to visit
+///
+/// ## Returns
+///
+/// - The number of allocations reported. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_walk_allocated(
+    pool: *mut BuddyPool,
+    visit: extern "C" fn(ptr: *mut c_void, usable: usize, owner: u32, user: *mut c_void),
+    user: *mut c_void,
+) -> usize {
+    if pool.is_null() {
+        return 0;
+    }
+
+    let header = core::mem::size_of::<Avail>();
+    let mut count = 0;
+
+    unsafe {
+        walk_blocks(pool, |block| {
+            if (*block).tag == BLOCK_RESERVED {
+                visit(user_ptr(pool, block), (1 << (*block).kval) - header, (*block).owner, user);
+                count += 1;
+            }
+        });
+    }
+
+    count
+}
+
+/// Checks the structure of the avail lists, so tests and debug builds can
+/// assert that a pool is healthy. Every list must be a consistent doubly
+/// linked ring through its sentinel, with an empty list pointing at itself,
+/// and hold only BLOCK_AVAIL blocks of its class that lie inside the pool,
+/// aligned to their size. Lists below SMALLEST_K must be empty, free_mask
+/// must match which lists are not, and the kval_m list can hold at most one
+/// block, the whole pool at base.
+///
+/// Unlike buddy_check_layout the blocks are not walked in address order, so
+/// this also works on pools whose reserved headers were overwritten.
+///
+/// If pool is NULL, the return value will be false
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to check
+///
+/// ## Returns
+///
+/// - true if every avail list is consistent. Type = `bool`
+#[no_mangle]
+pub extern "C" fn buddy_validate(pool: *mut BuddyPool) -> bool {
+    if pool.is_null() {
+        return false;
+    }
+
+    unsafe {
+        let base = (*pool).base as usize;
+        let kval_m = (*pool).kval_m;
+
+        for k in 0..=kval_m {
+            let head: *mut Avail = &mut (*pool).avail[k];
+            if (*head).tag != BLOCK_UNUSED || (*head).kval as usize != k {
+                return false;
+            }
+
+            let empty = (*head).next == head;
+            if empty != ((*head).prev == head) || empty == ((*pool).free_mask & (1 << k) != 0) {
+                return false;
+            }
+
+            if !empty && (k < SMALLEST_K || (k == kval_m && ((*head).next as usize != base || (*head).next != (*head).prev))) {
+                return false;
+            }
+
+            // A list longer than the pool has room for must be a cycle that skips the sentinel
+            let mut count = 0;
+            let mut prev = head;
+            let mut block = (*head).next;
+            while block != head {
+                let offset = (block as usize).wrapping_sub(base);
+                if count == (*pool).numbytes >> k
+                    || offset >= (*pool).numbytes
+                    || offset & ((1 << k) - 1) != 0
+                    || (*block).tag != BLOCK_AVAIL
+                    || (*block).kval as usize != k
+                    || (*block).prev != prev
+                {
+                    return false;
+                }
+
+                count += 1;
+                prev = block;
+                block = (*block).next;
+            }
+
+            if (*head).prev != prev {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Finds the block of the pool that addr falls into, for instance the
+/// faulting address of a crash, and describes it in info. The blocks are
+/// walked in address order, so the answer is only as good as the headers in
+/// front of addr.
+///
+/// If pool or info is NULL or addr is outside the pool, the return value will be false
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to search
+/// - addr `*const c_void` The address to look up
+/// - info `*mut BlockInfo` Receives the block containing addr
+///
+/// ## Returns
+///
+/// - True if a block containing addr was found. Type = `bool`
+#[no_mangle]
+pub extern "C" fn buddy_block_at_address(pool: *mut BuddyPool, addr: *const c_void, info: *mut BlockInfo) -> bool {
+    if pool.is_null() || info.is_null() {
+        return false;
+    }
+
+    unsafe {
+        let base = (*pool).base as usize;
+        let offset = (addr as usize).wrapping_sub(base);
+        if offset >= (*pool).numbytes {
+            return false;
+        }
+
+        let mut found = None;
+        walk_blocks(pool, |header| {
+            let start = block_start(pool, header) as usize - base;
+            if found.is_none() && (start..start + (1 << (*header).kval)).contains(&offset) {
+                found = Some(BlockInfo { offset: start, kval: (*header).kval, tag: (*header).tag });
+            }
+        });
+
+        match found {
+            Some(block) => {
+                *info = block;
+
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Returns the total size of the reserved blocks whose owner is tag, walking
+/// the pool in address order. Whole blocks are counted, header included, so
+/// the totals of all tags add up to the memory in use. Tag 0 covers every
+/// allocation not made with buddy_malloc_tagged.
+///
+/// If pool is NULL, the return value will be 0
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to walk
+/// - tag `u32` The owner tag to add up
+///
+/// ## Returns
+///
+/// - The bytes held by blocks with this owner. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_bytes_by_tag(pool: *mut BuddyPool, tag: u32) -> usize {
+    if pool.is_null() {
+        return 0;
+    }
+
+    let mut bytes = 0;
+    unsafe {
+        walk_blocks(pool, |header| {
+            if (*header).tag == BLOCK_RESERVED && (*header).owner == tag {
+                bytes += 1 << (*header).kval;
+            }
+        });
+    }
+
+    bytes
+}
+
+/// Helper function.
+///
+/// Resets every avail list of the pool to empty and installs a single free
+/// block of size 2^kval_m at the base of the pool.
+unsafe fn init_free_lists(pool: *mut BuddyPool) {
+    let kval = (*pool).kval_m;
+
+    for i in 0..=kval {
+        (*pool).avail[i].next = &mut (*pool).avail[i];
+        (*pool).avail[i].prev = &mut (*pool).avail[i];
+        (*pool).avail[i].kval = i as u16;
+        (*pool).avail[i].tag = BLOCK_UNUSED;
+    }
+
+    init_free_bits(pool);
+
+    let m = (*pool).base as *mut Avail;
+    set_free_bit(pool, m, kval, true);
+    (*pool).avail[kval].next = m;
+    (*pool).avail[kval].prev = m;
+    (*m).tag = BLOCK_AVAIL;
+    (*m).kval = kval as u16;
+    (*m).next = &mut (*pool).avail[kval];
+    (*m).prev = &mut (*pool).avail[kval];
+    (*m).stamp = 0;
+
+    (*pool).free_bytes = 1 << kval;
+    (*pool).free_mask = 1 << kval;
+    (*pool).highest_free_k = kval;
+}
+
+/// Initialize a new memory pool using the buddy algorithm on top of a region
+/// of memory supplied by the caller instead of one obtained from mmap. This
+/// allows placing the pool in a static buffer or a shared memory segment.
+///
+/// The size is rounded down to the nearest power of two and only that many
+/// bytes from base are managed. The base must be aligned to that rounded size,
+/// i.e. the size of the largest block, so that every block of the pool is
+/// naturally aligned to its own size. If base is misaligned, or the region is
+/// too small to hold a block of size 2^SMALLEST_K, the pool is left untouched
+/// and EINVAL is returned. Such a block is the least that fits the header of
+/// the top block, which is checked when the crate is compiled.
+///
+/// The memory stays owned by the caller: buddy_destroy will not unmap it and
+/// it must outlive the pool.
+///
+/// The free lists hold absolute addresses, so to share a pool between
+/// processes the BuddyPool itself must be placed in the shared segment too and
+/// every process must map the segment at the same address.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` A pointer to the pool to initialize
+/// - base `*mut c_void` The start of the memory region to manage
+/// - size `usize` The size of the memory region in bytes
+///
+/// ## Returns
+///
+/// - 0 on success or EINVAL if the region can not be used. Type = `i32`
+#[no_mangle]
+pub extern "C" fn buddy_init_from_buffer(pool: *mut BuddyPool, base: *mut c_void, size: usize) -> i32 {
+    if pool.is_null() || base.is_null() || size < (1 << SMALLEST_K) {
+        return EINVAL;
+    }
+
+    // Round the size down to a power of two (bytes >= 2^kval)
+    let kval = ((usize::BITS - 1 - size.leading_zeros()) as usize).min(MAX_K - 1);
+
+    // The buddy math relies on blocks being aligned to their size
+    if (base as usize) & ((1 << kval) - 1) != 0 {
+        return EINVAL;
+    }
+
+    unsafe {
+        ptr::write_bytes(pool, 0, 1);
+        (*pool).kval_m = kval;
+        (*pool).numbytes = 1 << kval;
+        (*pool).base = base;
+        (*pool).owns_mapping = false;
+        (*pool).map_fd = -1;
+        (*pool).policy = BuddyPolicy { flags: POLICY_DEFAULT, strategy: STRATEGY_LIFO };
+
+        init_free_lists(pool);
+    }
+
+    0
+}
+
+/// Inverse of buddy_init.
+///
+/// Pools initialized with buddy_init_from_buffer do not own their memory, so
+/// it is left for the caller to release.
+///
+/// If munmap fails this function panics, unless buddy_set_abort_on_error(false)
+/// was called, in which case the pool is left intact and the errno reported by
+/// munmap is returned.
+///
+/// Notice that this function does not change the value of pool itself,
+/// hence it still points to the same (now invalid) location.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to destroy
+///
+/// ## Returns
+///
+/// - 0 on success or the errno of the failed unmapping. Type = `i32`
+#[no_mangle]
+pub extern "C" fn buddy_destroy(pool: *mut BuddyPool) -> i32 {
+    unsafe { destroy_mapped(pool) }
+}
+
+/// Helper function.
+///
+/// Does the work of buddy_destroy. Kept separate from the extern function so a
+/// failure panics through regular Rust frames.
+unsafe fn destroy_mapped(pool: *mut BuddyPool) -> i32 {
+    // Only pools from buddy_init own their mapping, and buddy_init needs std
+    #[cfg(feature = "std")]
+    if (*pool).owns_mapping && !unmap_region((*pool).base, mapped_bytes(pool)) {
+        return backing_error("buddy_destroy avail array");
+    }
+
+    drop_free_bits(pool);
+    ptr::write_bytes(pool, 0, 1);
+
+    0
+}
+
+/// Frees every allocation of the pool by handing its region back to the
+/// system and mapping a fresh one of the same size in its place. Unlike
+/// freeing everything by hand, the new pages are guaranteed to be zeroed and
+/// not resident until they are touched. The policy, callbacks and counters of
+/// the pool are kept.
+///
+/// The new region usually lives at a different address, so every pointer
+/// into the old one, including those held by the caller, is invalidated.
+///
+/// If pool is NULL or it does not own its mapping, EINVAL is returned
+/// If mapping the new region or unmapping the old one fails, the behavior
+/// follows buddy_set_abort_on_error. If the new region can not be mapped the
+/// pool is left untouched.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to recycle
+///
+/// ## Returns
+///
+/// - 0 on success, an errno value otherwise. Type = `i32`
+#[cfg(feature = "std")]
+#[no_mangle]
+pub extern "C" fn buddy_recycle(pool: *mut BuddyPool) -> i32 {
+    if pool.is_null() {
+        return EINVAL;
+    }
+
+    unsafe {
+        if !(*pool).owns_mapping {
+            return EINVAL;
+        }
+
+        let old = (*pool).base;
+        let len = mapped_bytes(pool);
+
+        // Map the replacement first so a failure leaves the pool usable
+        let base = map_region(len, (*pool).numbytes, (*pool).map_prot, (*pool).map_flags);
+        if base.is_null() {
+            return backing_error("buddy_recycle mmap failed");
+        }
+
+        (*pool).base = base;
+        if !(*pool).sentinel.is_null() {
+            place_sentinel(pool);
+        }
+
+        init_free_lists(pool);
+        (*pool).live_count = 0;
+        (*pool).headerless_count = 0;
+
+        if !unmap_region(old, len) {
+            return backing_error("buddy_recycle munmap failed");
+        }
+    }
+
+    0
+}
+
+/// Shrinks the pool to new_size bytes, rounded up to a power of two and to
+/// at least 2^MIN_K, and hands the memory past the new end back to the
+/// system for good. Unlike buddy_reclaim, which only drops the physical pages
+/// of free blocks, the address range is unmapped and kval_m and numbytes
+/// shrink with it. This only works if everything past the new end is free,
+/// i.e. the upper half of the pool, the upper half of the lower half and so
+/// on down to the new size can each merge into a single free block. Live
+/// allocations below the new end stay where they are.
+///
+/// On Windows the tail is decommitted rather than released, its address
+/// range stays reserved until the pool is destroyed. Pools registered with a
+/// BuddyRegistry have to be registered again to update their range.
+///
+/// If pool is NULL or it does not own its mapping, EINVAL is returned
+/// If new_size is not smaller than the pool, nothing happens and 0 is returned
+/// If any memory past the new end is in use, EBUSY is returned and the pool is left as it was
+/// If unmapping the tail fails, the behavior follows buddy_set_abort_on_error
+/// and the pool is left as it was.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to shrink
+/// - new_size `usize` The size to shrink the pool to in bytes
+///
+/// ## Returns
+///
+/// - 0 on success, an errno value otherwise. Type = `i32`
+#[cfg(feature = "std")]
+#[no_mangle]
+pub extern "C" fn buddy_shrink(pool: *mut BuddyPool, new_size: usize) -> i32 {
+    if pool.is_null() {
+        return EINVAL;
+    }
+
+    unsafe {
+        if !(*pool).owns_mapping {
+            return EINVAL;
+        }
+
+        let old_k = (*pool).kval_m;
+        let new_k = btok(new_size).max(MIN_K);
+        if new_k >= old_k {
+            return 0;
+        }
+
+        // Blocks freed without merging may add up to the halves we need
+        coalesce_sweep(pool, old_k);
+
+        let base = (*pool).base as *mut Avail;
+        let whole = is_free_at(pool, base, old_k);
+
+        // Each upper half from the new size up has to be one free block
+        let tail = |k: usize| (base as usize + (1 << k)) as *mut Avail;
+        if !whole && !(new_k..old_k).all(|k| is_free_at(pool, tail(k), k)) {
+            return EBUSY;
+        }
+
+        // Keep the page holding the new sentinel, if there is one
+        #[cfg(target_os = "linux")]
+        let page = if (*pool).map_flags & MAP_HUGETLB != 0 { HUGE_PAGE_SIZE } else { page_size() };
+        #[cfg(not(target_os = "linux"))]
+        let page = page_size();
+
+        let extra = if (*pool).sentinel.is_null() { 0 } else { core::mem::size_of::<Avail>() };
+        let keep = ((1 << new_k) + extra).next_multiple_of(page);
+        let mapped = mapped_bytes(pool).next_multiple_of(page);
+
+        // The headers of the tail blocks live in the pages about to be unmapped
+        if whole {
+            unlink_block(pool, base);
+        } else {
+            for k in new_k..old_k {
+                unlink_block(pool, tail(k));
+            }
+        }
+
+        if mapped > keep && !unmap_tail((base as usize + keep) as *mut c_void, mapped - keep) {
+            // Nothing was unmapped, put the blocks back where they were
+            if whole {
+                insert_block(pool, base);
+            } else {
+                for k in new_k..old_k {
+                    insert_block(pool, tail(k));
+                }
+            }
+
+            return backing_error("buddy_shrink munmap failed");
+        }
+
+        // The free bitmap is laid out by the size of the pool
+        drop_free_bits(pool);
+        (*pool).kval_m = new_k;
+        (*pool).numbytes = 1 << new_k;
+        init_free_bits(pool);
+
+        for k in SMALLEST_K..=new_k {
+            let head: *mut Avail = &mut (*pool).avail[k];
+
+            let mut block = (*head).next;
+            while block != head {
+                set_free_bit(pool, block, k, true);
+                block = (*block).next;
+            }
+        }
+
+        if whole {
+            (*base).kval = new_k as u16;
+            insert_block(pool, base);
+        }
+
+        if !(*pool).sentinel.is_null() {
+            place_sentinel(pool);
+        }
+    }
+
+    0
+}
+
+/// Frees every allocation of the pool at once by putting it back in the state
+/// buddy_init left it in, a single free block of size 2^kval_m at base. The
+/// region is reused as is, without a call to mmap or munmap, which makes this
+/// much cheaper than destroying and recreating the pool when it is used as an
+/// arena. The policy, callbacks and counters of the pool are kept and the
+/// contents of the memory are left as they were.
+///
+/// Every pointer previously returned by the pool is invalidated.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to reset
+#[no_mangle]
+pub extern "C" fn buddy_reset(pool: *mut BuddyPool) {
+    if pool.is_null() {
+        return;
+    }
+
+    unsafe {
+        init_free_lists(pool);
+        (*pool).live_count = 0;
+        (*pool).headerless_count = 0;
+    }
+}
+
+/// Exchanges everything two pools hold, their regions, free lists, policies
+/// and counters, so a caller holding a pointer to a keeps using it and now
+/// sees the memory of b, and the other way around. This lets a new pool be
+/// built and filled in the background and then swapped in place of a live
+/// one.
+///
+/// The pools must be separate, each with its own region, and no allocation
+/// or free may be in progress on either of them during the swap. The swap is
+/// not synchronized, guarding it against other threads is up to the caller.
+///
+/// If a or b is NULL, or they are the same pool, EINVAL is returned
+///
+/// ## Parameters
+///
+/// - a `*mut BuddyPool` The first pool
+/// - b `*mut BuddyPool` The second pool
+///
+/// ## Returns
+///
+/// - 0 on success, EINVAL otherwise. Type = `i32`
+#[no_mangle]
+pub extern "C" fn buddy_swap(a: *mut BuddyPool, b: *mut BuddyPool) -> i32 {
+    if a.is_null() || b.is_null() || a == b {
+        return EINVAL;
+    }
+
+    unsafe {
+        ptr::swap(a, b);
+
+        relink_heads(a, b);
+        relink_heads(b, a);
+    }
+
+    0
+}
+
+/// Helper function.
+///
+/// After the contents of two pools were swapped, points the free lists of
+/// pool back at its own avail sentinels instead of those of other.
+unsafe fn relink_heads(pool: *mut BuddyPool, other: *mut BuddyPool) {
+    for k in 0..=(*pool).kval_m {
+        let head: *mut Avail = &mut (*pool).avail[k];
+        let stale: *mut Avail = &mut (*other).avail[k];
+
+        // An empty list still points at the sentinel it was copied from
+        if (*head).next == stale {
+            (*head).next = head;
+            (*head).prev = head;
+        } else {
+            (*(*head).next).prev = head;
+            (*(*head).prev).next = head;
+        }
+    }
+}
+
+/// Reports what buddy_destroy would do to the pool without doing it: the
+/// number of bytes it would unmap and the number of bytes still held by live
+/// allocations, which would be pulled out from under their owners. Teardown
+/// code can log these or refuse to go ahead. The pool is not changed.
+///
+/// Pools initialized with buddy_init_from_buffer report 0 bytes to unmap,
+/// since buddy_destroy leaves their memory alone.
+///
+/// If pool, would_unmap_bytes or outstanding_bytes is NULL, EINVAL is returned
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to inspect
+/// - would_unmap_bytes `*mut usize` Receives the number of bytes buddy_destroy would unmap
+/// - outstanding_bytes `*mut usize` Receives the number of bytes in reserved blocks
+///
+/// ## Returns
+///
+/// - 0 on success, EINVAL otherwise. Type = `i32`
+#[no_mangle]
+pub extern "C" fn buddy_destroy_dry_run(pool: *mut BuddyPool, would_unmap_bytes: *mut usize, outstanding_bytes: *mut usize) -> i32 {
+    if pool.is_null() || would_unmap_bytes.is_null() || outstanding_bytes.is_null() {
+        return EINVAL;
+    }
+
+    unsafe {
+        let mut outstanding = 0;
+        walk_blocks(pool, |block| {
+            if (*block).tag == BLOCK_RESERVED {
+                outstanding += 1 << (*block).kval;
+            }
+        });
+
+        *would_unmap_bytes = if (*pool).owns_mapping { mapped_bytes(pool) } else { 0 };
+        *outstanding_bytes = outstanding;
+    }
+
+    0
+}
+
+/// Selects how failures of the backing memory (mmap and munmap) are handled
+/// by buddy_init, buddy_destroy and buddy_recycle. When abort is true, the
+/// default, they panic. When abort is false they return the errno of the
+/// failure instead, which is usually what a C caller wants.
+///
+/// This setting is shared by every pool in the process.
+///
+/// ## Parameters
+///
+/// - abort `bool` Whether failures should panic
+#[no_mangle]
+pub extern "C" fn buddy_set_abort_on_error(abort: bool) {
+    ABORT_ON_ERROR.store(abort, Ordering::Relaxed);
+}
+
+/// Returns whether failures of the backing memory panic, see buddy_set_abort_on_error.
+///
+/// ## Returns
+///
+/// - true if failures panic. Type = `bool`
+#[no_mangle]
+pub extern "C" fn buddy_get_abort_on_error() -> bool {
+    ABORT_ON_ERROR.load(Ordering::Relaxed)
+}
+
+/// Helper function.
+///
+/// Sets errno, the way every function here reports why it failed.
+#[cfg(feature = "std")]
+fn set_errno(value: i32) {
+    unsafe { *__errno_location() = value };
+}
+
+/// Helper function.
+///
+/// Sets errno, the way every function here reports why it failed. Without
+/// libc there is no errno, the value is kept for buddy_errno instead.
+#[cfg(not(feature = "std"))]
+fn set_errno(value: i32) {
+    ERRNO.store(value, Ordering::Relaxed);
+}
+
+/// Helper function.
+///
+/// Returns the value last stored by set_errno.
+#[cfg(feature = "std")]
+fn errno() -> i32 {
+    unsafe { *__errno_location() }
+}
+
+/// Helper function.
+///
+/// Returns the value last stored by set_errno.
+#[cfg(not(feature = "std"))]
+fn errno() -> i32 {
+    ERRNO.load(Ordering::Relaxed)
+}
+
+/// Returns the errno value left by the last call that failed. With the std
+/// feature this is errno of the calling thread. Without it there is no libc
+/// to keep errno, so the value is shared by the whole program and only
+/// meaningful right after the failed call.
+///
+/// ## Returns
+///
+/// - The errno of the last failure. Type = `i32`
+#[no_mangle]
+pub extern "C" fn buddy_errno() -> i32 {
+    errno()
+}
+
+/// Helper function.
+///
+/// Reports a failure of the backing memory according to the abort policy,
+/// either panicking with msg or returning the current errno.
+#[cfg(feature = "std")]
+fn backing_error(msg: &str) -> i32 {
+    if ABORT_ON_ERROR.load(Ordering::Relaxed) {
+        panic!("{}", msg);
+    }
+
+    errno()
+}
+
+/// Helper function.
+///
+/// Returns the size of a page of memory in bytes.
+#[cfg(all(unix, feature = "std"))]
+fn page_size() -> usize {
+    unsafe { sysconf(_SC_PAGESIZE) as usize }
+}
+
+/// Helper function.
+///
+/// Returns the size of a page of memory in bytes.
+#[cfg(all(windows, feature = "std"))]
+fn page_size() -> usize {
+    system_info().page_size as usize
+}
+
+/// Helper function.
+///
+/// Asks Windows for the page size and the allocation granularity.
+#[cfg(all(windows, feature = "std"))]
+fn system_info() -> SystemInfo {
+    let mut info = MaybeUninit::<SystemInfo>::zeroed();
+
+    unsafe {
+        GetSystemInfo(info.as_mut_ptr());
+        info.assume_init()
+    }
+}
+
+/// Helper function.
+///
+/// Maps len bytes of private read/write memory for the bookkeeping of the
+/// pool, returning NULL on failure. The Rust heap is not used so the pool can
+/// back the global allocator.
+#[cfg(all(unix, feature = "std"))]
+unsafe fn map_pages(len: usize) -> *mut c_void {
+    let pages = mmap(ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    if pages == MAP_FAILED {
+        return ptr::null_mut();
+    }
+
+    pages
+}
+
+/// Helper function.
+///
+/// Maps len bytes of private read/write memory for the bookkeeping of the
+/// pool, returning NULL on failure. The Rust heap is not used so the pool can
+/// back the global allocator.
+#[cfg(all(windows, feature = "std"))]
+unsafe fn map_pages(len: usize) -> *mut c_void {
+    VirtualAlloc(ptr::null_mut(), len, MEM_RESERVE | MEM_COMMIT, PAGE_READWRITE)
+}
+
+/// Helper function.
+///
+/// Gives back memory returned by map_pages.
+#[cfg(all(unix, feature = "std"))]
+unsafe fn unmap_pages(pages: *mut c_void, len: usize) {
+    munmap(pages, len);
+}
+
+/// Helper function.
+///
+/// Gives back memory returned by map_pages.
+#[cfg(all(windows, feature = "std"))]
+unsafe fn unmap_pages(pages: *mut c_void, _len: usize) {
+    VirtualFree(pages, 0, MEM_RELEASE);
+}
+
+/// Helper function.
+///
+/// Releases the physical memory behind the whole pages of [addr, addr + len)
+/// while keeping the range mapped. The pages read as zero when next touched.
+#[cfg(all(unix, feature = "std"))]
+unsafe fn discard_pages(addr: *mut c_void, len: usize) -> bool {
+    madvise(addr, len, MADV_DONTNEED) == 0
+}
+
+/// Helper function.
+///
+/// Releases the physical memory behind the whole pages of [addr, addr + len)
+/// while keeping the range mapped. The pages read as zero when next touched.
+#[cfg(all(windows, feature = "std"))]
+unsafe fn discard_pages(addr: *mut c_void, len: usize) -> bool {
+    VirtualFree(addr, len, MEM_DECOMMIT) != 0 && !VirtualAlloc(addr, len, MEM_COMMIT, PAGE_READWRITE).is_null()
+}
+
+/// Helper function.
+///
+/// Without an operating system to ask, pages are assumed to be 4 KiB.
+#[cfg(not(feature = "std"))]
+fn page_size() -> usize {
+    4096
+}
+
+/// Helper function.
+///
+/// Without an operating system there is nothing to map the bookkeeping from,
+/// pools then work without it.
+#[cfg(not(feature = "std"))]
+unsafe fn map_pages(_len: usize) -> *mut c_void {
+    ptr::null_mut()
+}
+
+/// Helper function.
+///
+/// Counterpart of map_pages, which never maps anything without std.
+#[cfg(not(feature = "std"))]
+unsafe fn unmap_pages(_pages: *mut c_void, _len: usize) {}
+
+/// Helper function.
+///
+/// Without an operating system pages can not be released.
+#[cfg(not(feature = "std"))]
+unsafe fn discard_pages(_addr: *mut c_void, _len: usize) -> bool {
+    false
+}
+
+/// Helper function.
+///
+/// Maps size bytes of anonymous memory with the given protection and flags
+/// starting at a multiple of align, returning NULL on failure. A larger region
+/// is mapped first and the pages before and after the aligned part are handed
+/// back to the system.
+#[cfg(all(unix, feature = "std"))]
+unsafe fn map_region(size: usize, align: usize, prot: i32, flags: i32) -> *mut c_void {
+    #[cfg(test)]
+    if tests::fail_backing() {
+        return ptr::null_mut();
+    }
+
+    // Huge page mappings are trimmed in whole huge pages
+    #[cfg(target_os = "linux")]
+    let page = if flags & MAP_HUGETLB != 0 { HUGE_PAGE_SIZE } else { page_size() };
+    #[cfg(not(target_os = "linux"))]
+    let page = page_size();
+    let align = align.max(page);
+    let len = size + align;
+
+    let raw = mmap(
+        ptr::null_mut(),
+        len,
+        prot,
+        flags,
+        -1,
+        0,
+    );
+
+    if raw == MAP_FAILED {
+        return ptr::null_mut();
+    }
+
+    // Trim the head up to the aligned start and everything past the pages we keep
+    let start = (raw as usize + align - 1) & !(align - 1);
+    let keep = (size + page - 1) & !(page - 1);
+    let end = raw as usize + len;
+
+    if start > raw as usize {
+        munmap(raw, start - raw as usize);
+    }
+
+    if end > start + keep {
+        munmap((start + keep) as *mut c_void, end - (start + keep));
+    }
+
+    start as *mut c_void
+}
+
+/// Helper function.
+///
+/// Reserves and commits size bytes of read/write memory with VirtualAlloc
+/// starting at a multiple of align, returning NULL with errno set on failure.
+/// A reservation can only be released whole, so unlike with mmap the excess
+/// can not be trimmed: an oversized range is reserved to find an aligned
+/// address, released, and reserved again at that address. Another thread may
+/// take the range in between, so this is retried a few times.
+///
+/// Memory from VirtualAlloc can not be shared with other processes, so
+/// MAP_SHARED in flags fails with EINVAL.
+#[cfg(all(windows, feature = "std"))]
+unsafe fn map_region(size: usize, align: usize, _prot: i32, flags: i32) -> *mut c_void {
+    #[cfg(test)]
+    if tests::fail_backing() {
+        return ptr::null_mut();
+    }
+
+    if flags & MAP_SHARED != 0 {
+        set_errno(EINVAL);
+
+        return ptr::null_mut();
+    }
+
+    // Every reservation starts on a multiple of the allocation granularity
+    if align <= system_info().allocation_granularity as usize {
+        let base = VirtualAlloc(ptr::null_mut(), size, MEM_RESERVE | MEM_COMMIT, PAGE_READWRITE);
+        if base.is_null() {
+            set_errno(ENOMEM);
+        }
+
+        return base;
+    }
+
+    for _ in 0..8 {
+        let raw = VirtualAlloc(ptr::null_mut(), size + align, MEM_RESERVE, PAGE_READWRITE);
+        if raw.is_null() {
+            break;
+        }
+
+        let start = (raw as usize + align - 1) & !(align - 1);
+        VirtualFree(raw, 0, MEM_RELEASE);
+
+        let base = VirtualAlloc(start as *mut c_void, size, MEM_RESERVE | MEM_COMMIT, PAGE_READWRITE);
+        if !base.is_null() {
+            return base;
+        }
+    }
+
+    set_errno(ENOMEM);
+
+    ptr::null_mut()
+}
+
+/// Helper function.
+///
+/// Unmaps a region previously returned by map_region, returning whether it succeeded.
+#[cfg(all(unix, feature = "std"))]
+unsafe fn unmap_region(base: *mut c_void, size: usize) -> bool {
+    #[cfg(test)]
+    if tests::fail_backing() {
+        return false;
+    }
+
+    #[cfg(test)]
+    tests::record_unmap(base);
+
+    munmap(base, size) == 0
+}
+
+/// Helper function.
+///
+/// Unmaps the last len bytes, starting at addr, of a region returned by
+/// map_region, returning whether it succeeded.
+#[cfg(all(unix, feature = "std"))]
+unsafe fn unmap_tail(addr: *mut c_void, len: usize) -> bool {
+    unmap_region(addr, len)
+}
+
+/// Helper function.
+///
+/// Decommits the last len bytes, starting at addr, of a region returned by
+/// map_region, returning whether it succeeded. A reservation can only be
+/// released whole, so the address range stays reserved. errno is set to
+/// EINVAL on failure.
+#[cfg(all(windows, feature = "std"))]
+unsafe fn unmap_tail(addr: *mut c_void, len: usize) -> bool {
+    #[cfg(test)]
+    if tests::fail_backing() {
+        return false;
+    }
+
+    if VirtualFree(addr, len, MEM_DECOMMIT) == 0 {
+        set_errno(EINVAL);
+
+        return false;
+    }
+
+    true
+}
+
+/// Helper function.
+///
+/// Releases a region previously returned by map_region, returning whether it
+/// succeeded. errno is set to EINVAL on failure.
+#[cfg(all(windows, feature = "std"))]
+unsafe fn unmap_region(base: *mut c_void, _size: usize) -> bool {
+    #[cfg(test)]
+    if tests::fail_backing() {
+        return false;
+    }
+
+    #[cfg(test)]
+    tests::record_unmap(base);
+
+    if VirtualFree(base, 0, MEM_RELEASE) == 0 {
+        set_errno(EINVAL);
+
+        return false;
+    }
+
+    true
+}
+
+/// Number of bytes used by a single block record in an exported layout.
+pub const LAYOUT_RECORD_SIZE: usize = 2;
+
+/// Describes a single block of the pool in address order.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockInfo {
+    pub offset: usize, // Offset of the block from the pool base
+    pub kval: u16,     // kval of this block
+    pub tag: u16,      // Block status: BLOCK_AVAIL, BLOCK_RESERVED
+}
+
+/// Iterator over every block of a pool in address order, see pool_blocks
+pub struct BlockIter<'a> {
+    pool: &'a BuddyPool,
+    addr: usize, // Address of the next block to visit
+    end: usize,  // End of the region
+}
+
+impl Iterator for BlockIter<'_> {
+    type Item = BlockInfo;
+
+    fn next(&mut self) -> Option<BlockInfo> {
+        if self.addr >= self.end {
+            return None;
+        }
+
+        let pool = self.pool as *const BuddyPool as *mut BuddyPool;
+
+        unsafe {
+            let header = header_at(pool, self.addr);
+            let kval = (*header).kval as usize;
+
+            // A corrupted kval would send the walk off the region, stop instead
+            if !(SMALLEST_K..=self.pool.kval_m).contains(&kval) || self.addr + (1 << kval) > self.end {
+                self.addr = self.end;
+
+                return None;
+            }
+
+            let info = BlockInfo { offset: self.addr - self.pool.base as usize, kval: kval as u16, tag: (*header).tag };
+            self.addr += 1 << kval;
+
+            Some(info)
+        }
+    }
+}
+
+/// Walks the pool linearly from base, hopping 2^kval bytes from one block to
+/// the next, and yields the offset, kval and tag of every block, free or
+/// reserved. Unlike the free lists this shows the whole layout of the pool.
+/// The walk stops early at a header whose kval is out of range.
+///
+/// ## Parameters
+///
+/// - pool `&BuddyPool` The memory pool to walk
+///
+/// ## Returns
+///
+/// - An iterator over the blocks of the pool. Type = `BlockIter`
+pub fn pool_blocks(pool: &BuddyPool) -> BlockIter<'_> {
+    let base = pool.base as usize;
+
+    BlockIter { pool, addr: base, end: base + pool.numbytes }
+}
+
+/// Helper function.
+///
+/// Decodes the layout record at index `i` of a layout produced by buddy_export_layout
+/// returning the (tag, kval) pair stored in it.
+unsafe fn layout_record(layout: *const u8, i: usize) -> (u16, u16) {
+    let record = layout.add(i * LAYOUT_RECORD_SIZE);
+
+    (*record as u16, *record.add(1) as u16)
+}
+
+/// Writes a compact description of the shape of the pool into the buffer out.
+/// Every block of the pool is visited in address order and recorded as a
+/// `LAYOUT_RECORD_SIZE` byte record holding its tag followed by its kval. The
+/// user data stored in the pool is never copied.
+///
+/// If out is NULL nothing is written and the number of bytes the layout needs
+/// is returned, so callers can size their buffer. If the layout does not fit
+/// in len bytes, or the walk finds a corrupted header, nothing is written and
+/// 0 is returned.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to capture
+/// - out `*mut u8` The buffer to write the layout into
+/// - len `usize` The size of the buffer in bytes
+///
+/// ## Returns
+///
+/// - The number of bytes written. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_export_layout(pool: *mut BuddyPool, out: *mut u8, len: usize) -> usize {
+    if pool.is_null() {
+        return 0;
+    }
+
+    unsafe {
+        // Count the blocks first so we never write a partial layout
+        let mut count = 0;
+        if !walk_blocks(pool, |_| count += 1) {
+            return 0;
+        }
+
+        let needed = count * LAYOUT_RECORD_SIZE;
+        if out.is_null() {
+            return needed;
+        }
+
+        if needed > len {
+            return 0;
+        }
+
+        // Walk the region again writing the tag and kval of every block
+        let mut record = out;
+        walk_blocks(pool, |block| {
+            *record = (*block).tag as u8;
+            *record.add(1) = (*block).kval as u8;
+            record = record.add(LAYOUT_RECORD_SIZE);
+        });
+
+        needed
+    }
+}
+
+/// Compares two layouts captured with buddy_export_layout from the same pool
+/// and reports every block of layout b that does not appear with the same
+/// offset, kval and tag in layout a, i.e. the blocks that changed between the
+/// two captures.
+///
+/// At most cap changes are written to out, but the total number of changed
+/// blocks is always returned. Passing a NULL out only counts the changes.
+///
+/// ## Parameters
+///
+/// - a `*const u8` The earlier layout
+/// - a_len `usize` The size of the earlier layout in bytes
+/// - b `*const u8` The later layout
+/// - b_len `usize` The size of the later layout in bytes
+/// - out `*mut BlockInfo` Buffer receiving the changed blocks of b
+/// - cap `usize` The number of entries out can hold
+///
+/// ## Returns
+///
+/// - The number of blocks that changed. Type = `usize`
+#[no_mangle]
+pub extern "C" fn buddy_layout_diff(
+    a: *const u8,
+    a_len: usize,
+    b: *const u8,
+    b_len: usize,
+    out: *mut BlockInfo,
+    cap: usize,
+) -> usize {
+    if a.is_null() || b.is_null() {
+        return 0;
+    }
+
+    let a_count = a_len / LAYOUT_RECORD_SIZE;
+    let b_count = b_len / LAYOUT_RECORD_SIZE;
+
+    let mut changes = 0;
+
+    unsafe {
+        // Walk both layouts in address order keeping track of each record's offset
+        let mut i = 0;
+        let mut a_offset = 0;
+        let mut b_offset = 0;
+        for j in 0..b_count {
+            let (b_tag, b_kval) = layout_record(b, j);
+
+            // Skip past the records of a that start before the current record of b
+            while i < a_count && a_offset < b_offset {
+                a_offset += 1 << layout_record(a, i).1;
+                i += 1;
+            }
+
+            let unchanged = i < a_count && a_offset == b_offset && layout_record(a, i) == (b_tag, b_kval);
+            if !unchanged {
+                if !out.is_null() && changes < cap {
+                    *out.add(changes) = BlockInfo { offset: b_offset, kval: b_kval, tag: b_tag };
+                }
+
+                changes += 1;
+            }
+
+            b_offset += 1 << b_kval;
+        }
+    }
+
+    changes
+}
+
+/// Helper function.
+///
+/// Returns the number of blocks on the avail list of class k. The walk stops
+/// after as many blocks as the pool can hold of the class, so a corrupted
+/// list that never returns to its sentinel can not hang the caller.
+unsafe fn free_blocks(pool: *mut BuddyPool, k: usize) -> usize {
+    let head: *mut Avail = &mut (*pool).avail[k];
+    let limit = (*pool).numbytes >> k;
+
+    let mut count = 0;
+    let mut block = (*head).next;
+    while block != head && count < limit {
+        count += 1;
+        block = (*block).next;
+    }
+
+    count
+}
+
+/// Recomputes the counters the pool keeps up to date incrementally, the free
+/// byte count, the live allocation count and the index of non-empty classes,
+/// from the free lists and a walk over the region, and compares them with the
+/// stored values. A mismatch means a bug or memory corruption made the
+/// accounting drift. If fix is true the stored values are replaced by the
+/// recomputed ones.
+///
+/// If pool is NULL, the return value will be false
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to audit
+/// - fix `bool` Whether to repair counters that drifted
+///
+/// ## Returns
+///
+/// - True if every counter matched. Type = `bool`
+#[no_mangle]
+pub extern "C" fn buddy_audit_counters(pool: *mut BuddyPool, fix: bool) -> bool {
+    if pool.is_null() {
+        return false;
+    }
+
+    unsafe {
+        let mut free_bytes = 0;
+        let mut free_mask = 0u64;
+        for k in 0..=(*pool).kval_m {
+            let count = free_blocks(pool, k);
+            free_bytes += count << k;
+
+            if count > 0 {
+                free_mask |= 1 << k;
+            }
+        }
+
+        let highest_free_k = match free_mask {
+            0 => 0,
+            mask => (u64::BITS - 1 - mask.leading_zeros()) as usize,
+        };
+
+        let mut live_count = 0;
+        walk_blocks(pool, |block| {
+            if (*block).tag == BLOCK_RESERVED {
+                live_count += 1;
+            }
+        });
+
+        let matched = free_bytes == (*pool).free_bytes
+            && live_count == (*pool).live_count
+            && free_mask == (*pool).free_mask
+            && highest_free_k == (*pool).highest_free_k;
+
+        if !matched && fix {
+            (*pool).free_bytes = free_bytes;
+            (*pool).live_count = live_count;
+            (*pool).free_mask = free_mask;
+            (*pool).highest_free_k = highest_free_k;
+        }
+
+        matched
+    }
+}
+
+/// Writes the statistics of the pool into the buffer out as text in the
+/// Prometheus exposition format, ready to be served to a scraper. The metrics
+/// are buddy_total_bytes, buddy_free_bytes, buddy_live_allocations,
+/// buddy_fragmentation_ratio and buddy_free_blocks, which has one sample per
+/// class labelled with its kval. The fragmentation ratio is one minus the
+/// share of free memory held by the largest free block. The text is not NUL
+/// terminated.
+///
+/// If out is NULL nothing is written and the number of bytes the text needs
+/// is returned, so callers can size their buffer. If the text does not fit in
+/// len bytes nothing is written and 0 is returned.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to report on
+/// - out `*mut u8` The buffer to write the metrics into
+/// - len `usize` The size of the buffer in bytes
+///
+/// ## Returns
+///
+/// - The number of bytes written. Type = `usize`
+#[cfg(feature = "std")]
+#[no_mangle]
+pub extern "C" fn buddy_export_prometheus(pool: *mut BuddyPool, out: *mut u8, len: usize) -> usize {
+    use core::fmt::Write;
+
+    if pool.is_null() {
+        return 0;
+    }
+
+    let mut text = String::new();
+
+    unsafe {
+        let free_bytes = buddy_stats(pool).free_bytes;
+        let fragmentation = buddy_fragmentation(pool);
+
+        let gauges = [
+            ("buddy_total_bytes", "Size of the pool in bytes", (*pool).numbytes.to_string()),
+            ("buddy_free_bytes", "Bytes held by free blocks", free_bytes.to_string()),
+            ("buddy_live_allocations", "Allocations not freed yet", (*pool).live_count.to_string()),
+            ("buddy_fragmentation_ratio", "One minus the largest free block over the free bytes", fragmentation.to_string()),
+        ];
+
+        for (name, help, value) in gauges {
+            let _ = write!(text, "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n");
+        }
+
+        text.push_str("# HELP buddy_free_blocks Number of free blocks of each class\n# TYPE buddy_free_blocks gauge\n");
+        for k in SMALLEST_K..=(*pool).kval_m {
+            let _ = writeln!(text, "buddy_free_blocks{{kval=\"{}\"}} {}", k, free_blocks(pool, k));
+        }
+
+        if out.is_null() {
+            return text.len();
+        }
+
+        if text.len() > len {
+            return 0;
+        }
+
+        ptr::copy_nonoverlapping(text.as_ptr(), out, text.len());
+    }
+
+    text.len()
+}
+
+/// Prints the free lists of the pool to stderr, see buddy_dump_to_string for
+/// the format. Going to stderr keeps the dump out of data written to stdout.
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to dump
+#[cfg(feature = "std")]
+#[no_mangle]
+pub extern "C" fn buddy_dump(pool: *mut BuddyPool) {
+    eprint!("{}", buddy_dump_to_string(pool));
+}
+
+/// Describes every free list of the pool, from SMALLEST_K up to kval_m, for
+/// debugging fragmentation. Each list gets a line with its kval and number of
+/// free blocks followed by one line per block with its offset from base and
+/// its tag.
+///
+/// A corrupted list must not hang the dump, so a list stops being followed
+/// at the first block outside the pool and after as many blocks as the class
+/// can hold, with a line saying so.
+///
+/// If pool is NULL, the return value will be empty
+///
+/// ## Parameters
+///
+/// - pool `*mut BuddyPool` The memory pool to dump
+///
+/// ## Returns
+///
+/// - The text of the dump. Type = `String`
+#[cfg(feature = "std")]
+pub fn buddy_dump_to_string(pool: *mut BuddyPool) -> String {
+    use core::fmt::Write;
+
+    let mut text = String::new();
+    if pool.is_null() {
+        return text;
+    }
+
+    unsafe {
+        let base = (*pool).base as usize;
+        let _ = writeln!(text, "buddy pool at {:#x}, kval_m {}", base, (*pool).kval_m);
+
+        for k in SMALLEST_K..=(*pool).kval_m {
+            let head: *mut Avail = &mut (*pool).avail[k];
+            let limit = (*pool).numbytes >> k;
+
+            let mut blocks = String::new();
+            let mut count = 0;
+            let mut block = (*head).next;
+            while block != head {
+                let offset = (block as usize).wrapping_sub(base);
+                if offset >= (*pool).numbytes {
+                    let _ = writeln!(blocks, "  block at {:#x} is outside the pool, list corrupted", block as usize);
+                    break;
+                }
+
+                if count == limit {
+                    let _ = writeln!(blocks, "  more than {} blocks, list corrupted", limit);
+                    break;
+                }
+
+                let _ = writeln!(blocks, "  offset {:#x} tag {}", offset, (*block).tag);
+                count += 1;
+                block = (*block).next;
+            }
+
+            let _ = writeln!(text, "k={}: {} free", k, count);
+            text.push_str(&blocks);
+        }
+    }
+
+    text
+}
+
+/// A range of a pool in which every block is in the same state, see
+/// Buddy::memory_map
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(feature = "std")]
+pub struct MapEntry {
+    pub offset: usize,  // Offset of the range from the pool base
+    pub len: usize,     // Length of the range in bytes
+    pub reserved: bool, // Whether the blocks of the range are allocated
+}
+
+/// Safe owner of a buddy pool for use from Rust. The pool lives on the heap
+/// because the avail sentinels point into the pool itself, so its address
+/// must never change. The pool is destroyed when the Buddy is dropped.
+///
+/// Like the extern functions a Buddy must not be shared between threads,
+/// see SyncBuddy for that.
+#[cfg(feature = "std")]
+pub struct Buddy {
+    pool: NonNull<BuddyPool>,
+}
+
+// The pool is not tied to the thread that created it, only concurrent use is a problem
+#[cfg(feature = "std")]
+unsafe impl Send for Buddy {}
+
+#[cfg(feature = "std")]
+impl Buddy {
+    /// Creates a pool of at least size bytes, see buddy_init.
+    ///
+    /// ## Panics
+    ///
+    /// If the memory for the pool can not be mapped.
+    pub fn new(size: usize) -> Buddy {
+        let pool = Box::into_raw(Box::<BuddyPool>::new_uninit()) as *mut BuddyPool;
+
+        if buddy_init(pool, size) != 0 {
+            drop(unsafe { Box::from_raw(pool as *mut MaybeUninit<BuddyPool>) });
+            panic!("Buddy::new failed to map the pool");
+        }
+
+        Buddy { pool: unsafe { NonNull::new_unchecked(pool) } }
+    }
+
+    /// Returns the raw pool, for use with the extern functions.
+    pub fn as_ptr(&self) -> *mut BuddyPool {
+        self.pool.as_ptr()
+    }
+
+    /// Allocates a block of at least size bytes, see buddy_malloc. Returns
+    /// None if size is zero or the pool is exhausted.
+    pub fn alloc(&self, size: usize) -> Option<NonNull<u8>> {
+        NonNull::new(buddy_malloc(self.as_ptr(), size) as *mut u8)
+    }
+
+    /// Returns the pool as a list of ranges in address order, with
+    /// neighbouring blocks in the same state merged into one range, much like
+    /// /proc/self/maps. The ranges cover the whole pool.
+    pub fn memory_map(&self) -> Vec<MapEntry> {
+        let pool = self.as_ptr();
+        let mut map: Vec<MapEntry> = Vec::new();
+
+        unsafe {
+            let base = (*pool).base as usize;
+
+            walk_blocks(pool, |header| {
+                let offset = block_start(pool, header) as usize - base;
+                let len = 1 << (*header).kval;
+                let reserved = (*header).tag == BLOCK_RESERVED;
+
+                match map.last_mut() {
+                    Some(last) if last.reserved == reserved => last.len += len,
+                    _ => map.push(MapEntry { offset, len, reserved }),
+                }
+            });
+        }
+
+        map
+    }
+
+    /// Frees a block, see buddy_free.
+    ///
+    /// # Safety
+    ///
+    /// ptr must have been returned by an allocation from this pool.
+    pub unsafe fn free(&self, ptr: NonNull<u8>) -> BuddyError {
+        buddy_free(self.as_ptr(), ptr.as_ptr() as *mut c_void)
+    }
+
+    /// Allocates nmemb elements of size bytes set to zero, see buddy_calloc.
+    /// Returns None if the total is zero, overflows or the pool is exhausted.
+    pub fn calloc(&self, nmemb: usize, size: usize) -> Option<NonNull<u8>> {
+        NonNull::new(buddy_calloc(self.as_ptr(), nmemb, size) as *mut u8)
+    }
+
+    /// Resizes a block to size bytes, see buddy_realloc. Returns None if the
+    /// block can not grow, leaving it untouched, or if size is zero, which
+    /// frees it.
+    ///
+    /// # Safety
+    ///
+    /// ptr must have been returned by an allocation from this pool and not
+    /// freed since. Unless None is returned for a non-zero size it must not
+    /// be used again.
+    pub unsafe fn realloc(&self, ptr: NonNull<u8>, size: usize) -> Option<NonNull<u8>> {
+        NonNull::new(buddy_realloc(self.as_ptr(), ptr.as_ptr() as *mut c_void, size) as *mut u8)
+    }
+
+    /// Allocates a slice of len bytes, or returns None if len is zero or the
+    /// pool is exhausted. The contents of the slice are indeterminate.
+    // Every call hands out a block no other slice refers to
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_bytes(&self, len: usize) -> Option<&mut [u8]> {
+        let mem = buddy_malloc(self.as_ptr(), len);
+        if mem.is_null() {
+            return None;
+        }
+
+        Some(unsafe { core::slice::from_raw_parts_mut(mem as *mut u8, len) })
+    }
+
+    /// Resizes a slice handed out by this pool to new_len bytes, preserving
+    /// the first min(old.len(), new_len) bytes, and returns the resized
+    /// slice. The block may move, so old is borrowed for as long as the
+    /// returned slice lives and must not be used again afterwards.
+    ///
+    /// An empty old slice allocates a new one. A new_len of zero frees the
+    /// block and returns an empty slice. If the pool can not satisfy the new
+    /// size, or old does not start at an allocation of this pool, None is
+    /// returned and old is left untouched.
+    pub fn realloc_bytes<'a>(&'a self, old: &'a mut [u8], new_len: usize) -> Option<&'a mut [u8]> {
+        let ptr = if old.is_empty() { ptr::null_mut() } else { old.as_mut_ptr() as *mut c_void };
+
+        if new_len == 0 {
+            if !ptr.is_null() && buddy_free(self.as_ptr(), ptr) != BuddyError::Ok {
+                return None;
+            }
+
+            return Some(&mut []);
+        }
+
+        let mem = buddy_realloc(self.as_ptr(), ptr, new_len);
+        if mem.is_null() {
+            return None;
+        }
+
+        Some(unsafe { core::slice::from_raw_parts_mut(mem as *mut u8, new_len) })
+    }
+    /// Allocates a block of at least size bytes, like buddy_malloc, but
+    /// reports failure as an error instead of a null pointer.
+    pub fn try_alloc(&self, size: usize) -> Result<NonNull<u8>, BuddyError> {
+        if size == 0 {
+            return Err(BuddyError::ZeroSize);
+        }
+
+        NonNull::new(buddy_malloc(self.as_ptr(), size) as *mut u8).ok_or_else(BuddyError::from_errno)
+    }
+
+    /// Allocates a block of at least size bytes like try_alloc, with the
+    /// first size bytes set to zero.
+    pub fn try_alloc_zeroed(&self, size: usize) -> Result<NonNull<u8>, BuddyError> {
+        let mem = self.try_alloc(size)?;
+
+        unsafe { ptr::write_bytes(mem.as_ptr(), 0, size) };
+
+        Ok(mem)
+    }
+
+    /// Resizes a block handed out by this pool to new_size bytes, like
+    /// buddy_realloc. On error the block is left untouched and still owned
+    /// by the caller, a new_size of zero is an error rather than a free.
+    ///
+    /// # Safety
+    ///
+    /// ptr must have been returned by an allocation from this pool and not
+    /// freed since. On success it must not be used again, the returned
+    /// pointer replaces it.
+    pub unsafe fn try_realloc(&self, ptr: NonNull<u8>, new_size: usize) -> Result<NonNull<u8>, BuddyError> {
+        if new_size == 0 {
+            return Err(BuddyError::ZeroSize);
+        }
+
+        NonNull::new(buddy_realloc(self.as_ptr(), ptr.as_ptr() as *mut c_void, new_size) as *mut u8)
+            .ok_or_else(BuddyError::from_errno)
+    }
+    /// Takes the whole pool as a scratch buffer, if nothing is allocated from
+    /// it, and returns a guard that hands it back when dropped. While the
+    /// guard lives every allocation fails, afterwards the pool is exactly as
+    /// it was before.
+    pub fn borrow_top(&self) -> Option<ScratchGuard<'_>> {
+        let pool = self.as_ptr();
+
+        unsafe {
+            let k = (*pool).kval_m;
+            let head: *mut Avail = &mut (*pool).avail[k];
+
+            let block = (*head).next;
+            if block == head {
+                return None;
+            }
+
+            unlink_block(pool, block);
+
+            Some(ScratchGuard { buddy: self, block, len: 1 << k })
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for Buddy {
+    fn drop(&mut self) {
+        buddy_destroy(self.as_ptr());
+
+        drop(unsafe { Box::from_raw(self.as_ptr() as *mut MaybeUninit<BuddyPool>) });
+    }
+}
+
+/// The whole memory of a Buddy, borrowed by Buddy::borrow_top. The bytes
+/// are indeterminate, the header of the block included. Dropping the guard
+/// returns the memory to the pool.
+#[cfg(feature = "std")]
+pub struct ScratchGuard<'a> {
+    buddy: &'a Buddy,
+    block: *mut Avail,
+    len: usize,
+}
+
+#[cfg(feature = "std")]
+impl core::ops::Deref for ScratchGuard<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.block as *const u8, self.len) }
+    }
+}
+
+#[cfg(feature = "std")]
+impl core::ops::DerefMut for ScratchGuard<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.block as *mut u8, self.len) }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for ScratchGuard<'_> {
+    fn drop(&mut self) {
+        let pool = self.buddy.as_ptr();
+
+        unsafe {
+            // The scratch data overwrote the header
+            (*self.block).kval = (*pool).kval_m as u16;
+            insert_block(pool, self.block);
+        }
+    }
+}
+
+/// A Buddy behind a lock, so one pool can be shared between threads. Every
+/// call takes the lock for its whole duration. The extern functions stay
+/// single threaded, calling them on the pool of a SyncBuddy bypasses the
+/// lock.
+#[cfg(feature = "std")]
+pub struct SyncBuddy {
+    buddy: Mutex<Buddy>,
+}
+
+#[cfg(feature = "std")]
+impl SyncBuddy {
+    /// Creates a pool of at least size bytes, see buddy_init.
+    ///
+    /// ## Panics
+    ///
+    /// If the memory for the pool can not be mapped.
+    pub fn new(size: usize) -> SyncBuddy {
+        SyncBuddy { buddy: Mutex::new(Buddy::new(size)) }
+    }
+
+    /// Helper function.
+    ///
+    /// Takes the lock. A panic while it was held can not have left the pool
+    /// half updated, the extern functions do not panic midway, so poisoning
+    /// is ignored.
+    fn lock(&self) -> MutexGuard<'_, Buddy> {
+        self.buddy.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the raw pool. Using it with the extern functions bypasses the
+    /// lock, so no other thread may use the SyncBuddy meanwhile.
+    pub fn as_ptr(&self) -> *mut BuddyPool {
+        self.lock().as_ptr()
+    }
+
+    /// Allocates a block of at least size bytes, see buddy_malloc. Returns
+    /// None if size is zero or the pool is exhausted.
+    pub fn alloc(&self, size: usize) -> Option<NonNull<u8>> {
+        self.lock().alloc(size)
+    }
+
+    /// Frees a block, see buddy_free.
+    ///
+    /// # Safety
+    ///
+    /// ptr must have been returned by an allocation from this pool.
+    pub unsafe fn free(&self, ptr: NonNull<u8>) -> BuddyError {
+        self.lock().free(ptr)
+    }
+
+    /// Allocates nmemb elements of size bytes set to zero, see Buddy::calloc.
+    pub fn calloc(&self, nmemb: usize, size: usize) -> Option<NonNull<u8>> {
+        self.lock().calloc(nmemb, size)
+    }
+
+    /// Resizes a block to size bytes, see Buddy::realloc.
+    ///
+    /// # Safety
+    ///
+    /// Same as Buddy::realloc.
+    pub unsafe fn realloc(&self, ptr: NonNull<u8>, size: usize) -> Option<NonNull<u8>> {
+        self.lock().realloc(ptr, size)
+    }
+}
+
+/// A SyncBuddy created on first use, so a pool can be declared as a global,
+/// `static POOL: LazyBuddy = LazyBuddy::new(1 << 20);`, without sequencing
+/// its initialization by hand.
+///
+/// However many threads race to use it first, the pool is initialized exactly
+/// once and all of them get the same pool.
+#[cfg(feature = "std")]
+pub struct LazyBuddy {
+    size: usize,
+    buddy: OnceLock<SyncBuddy>,
+}
+
+#[cfg(feature = "std")]
+impl LazyBuddy {
+    /// Declares a pool of at least size bytes, see buddy_init. Nothing is
+    /// mapped until the pool is first used.
+    pub const fn new(size: usize) -> LazyBuddy {
+        LazyBuddy { size, buddy: OnceLock::new() }
+    }
+
+    /// Returns the pool, creating it if this is the first use.
+    ///
+    /// ## Panics
+    ///
+    /// If the memory for the pool can not be mapped.
+    pub fn get(&self) -> &SyncBuddy {
+        self.buddy.get_or_init(|| SyncBuddy::new(self.size))
+    }
+}
+
+#[cfg(feature = "std")]
+impl core::ops::Deref for LazyBuddy {
+    type Target = SyncBuddy;
+
+    fn deref(&self) -> &SyncBuddy {
+        self.get()
+    }
+}
+
+/// Routes frees to the pool a pointer was allocated from, for programs with
+/// several pools (per thread, per arena) that would rather not remember where
+/// every allocation came from. Pools are registered with their region,
+/// [base, base + numbytes), and kept sorted by base so finding the owner of a
+/// pointer is a binary search.
+///
+/// The registry only keeps pointers to the pools, they must stay where they
+/// are while registered and be unregistered before they are destroyed. Like
+/// the extern functions it is single threaded.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct BuddyRegistry {
+    pools: Vec<(usize, usize, *mut BuddyPool)>, // Start, end and pool of every region, sorted by start
+}
+
+#[cfg(feature = "std")]
+impl BuddyRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> BuddyRegistry {
+        BuddyRegistry { pools: Vec::new() }
+    }
+
+    /// Adds an initialized pool. Returns false, leaving the registry as it
+    /// was, if pool is NULL or its region overlaps one already registered.
+    pub fn register(&mut self, pool: *mut BuddyPool) -> bool {
+        if pool.is_null() {
+            return false;
+        }
+
+        let (start, end) = unsafe { ((*pool).base as usize, (*pool).base as usize + (*pool).numbytes) };
+        let at = self.pools.partition_point(|&(base, _, _)| base < start);
+
+        let after = self.pools.get(at).is_some_and(|&(base, _, _)| base < end);
+        let before = at > 0 && self.pools[at - 1].1 > start;
+        if after || before {
+            return false;
+        }
+
+        self.pools.insert(at, (start, end, pool));
+
+        true
+    }
+
+    /// Removes a pool, returning whether it was registered.
+    pub fn unregister(&mut self, pool: *mut BuddyPool) -> bool {
+        match self.pools.iter().position(|&(_, _, registered)| registered == pool) {
+            Some(at) => {
+                self.pools.remove(at);
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the registered pool whose region contains ptr, or NULL if there is none.
+    pub fn pool_of(&self, ptr: *const c_void) -> *mut BuddyPool {
+        let addr = ptr as usize;
+        let at = self.pools.partition_point(|&(base, _, _)| base <= addr);
+
+        match at.checked_sub(1).map(|at| self.pools[at]) {
+            Some((_, end, pool)) if addr < end => pool,
+            _ => ptr::null_mut(),
+        }
+    }
+
+    /// Frees ptr to the pool it belongs to, see buddy_free. A pointer that
+    /// lies in no registered pool gives BuddyError::InvalidPointer.
+    ///
+    /// # Safety
+    ///
+    /// ptr must be NULL or point into one of the registered pools.
+    pub unsafe fn free(&self, ptr: *mut c_void) -> BuddyError {
+        if ptr.is_null() {
+            return BuddyError::NullPtr;
+        }
+
+        match self.pool_of(ptr) {
+            pool if pool.is_null() => BuddyError::InvalidPointer,
+            pool => buddy_free(pool, ptr),
+        }
+    }
+
+    /// Returns the number of registered pools.
+    pub fn len(&self) -> usize {
+        self.pools.len()
+    }
+
+    /// Returns whether no pool is registered.
+    pub fn is_empty(&self) -> bool {
+        self.pools.is_empty()
+    }
+}
+
+/// A pool to use as the Rust heap, `#[global_allocator] static HEAP:
+/// BuddyGlobal = BuddyGlobal::new(1 << 30);`. The pool is created on the
+/// first allocation and lives inside the BuddyGlobal, so a BuddyGlobal must
+/// not be moved once it was used, which a static never is. Every call takes
+/// a lock, so the heap can be used from any thread.
+///
+/// buddy_free does not need the Layout a block was allocated with, the block
+/// header records its class, and for blocks allocated through the aligned
+/// path the record right before the pointer links to that header.
+#[cfg(feature = "std")]
+pub struct BuddyGlobal {
+    size: usize,
+    ready: Mutex<bool>, // Whether pool was initialized, held while pool is used
+    pool: core::cell::UnsafeCell<MaybeUninit<BuddyPool>>,
+}
+
+// The pool is only ever touched with ready locked
+#[cfg(feature = "std")]
+unsafe impl Sync for BuddyGlobal {}
+
+#[cfg(feature = "std")]
+impl BuddyGlobal {
+    /// Declares a heap of at least size bytes, see buddy_init. Nothing is
+    /// mapped until the first allocation.
+    pub const fn new(size: usize) -> BuddyGlobal {
+        BuddyGlobal { size, ready: Mutex::new(false), pool: core::cell::UnsafeCell::new(MaybeUninit::uninit()) }
+    }
+
+    /// Returns the raw pool, or NULL if nothing was allocated yet. Using it
+    /// with the extern functions bypasses the lock.
+    pub fn as_ptr(&self) -> *mut BuddyPool {
+        match *self.ready.lock().unwrap_or_else(PoisonError::into_inner) {
+            true => self.pool.get() as *mut BuddyPool,
+            false => ptr::null_mut(),
+        }
+    }
+
+    /// Helper function.
+    ///
+    /// Runs f on the pool with the lock held, creating the pool first if
+    /// needed. Returns NULL if the pool can not be created.
+    fn with_pool(&self, f: impl FnOnce(*mut BuddyPool) -> *mut c_void) -> *mut u8 {
+        let mut ready = self.ready.lock().unwrap_or_else(PoisonError::into_inner);
+        let pool = self.pool.get() as *mut BuddyPool;
+
+        if !*ready {
+            if buddy_init(pool, self.size) != 0 {
+                return ptr::null_mut();
+            }
+
+            *ready = true;
+        }
+
+        f(pool) as *mut u8
+    }
+}
+
+/// Alignment of every pointer buddy_malloc returns, that of the header in
+/// front of it, as blocks are aligned to at least 2^SMALLEST_K.
+#[cfg(feature = "std")]
+const GLOBAL_ALIGN: usize = 1 << core::mem::size_of::<Avail>().trailing_zeros();
+
+#[cfg(feature = "std")]
+unsafe impl core::alloc::GlobalAlloc for BuddyGlobal {
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        self.with_pool(|pool| match layout.align() <= GLOBAL_ALIGN {
+            true => buddy_malloc(pool, layout.size()),
+            false => buddy_aligned_alloc(pool, layout.align(), layout.size()),
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: core::alloc::Layout) {
+        self.with_pool(|pool| {
+            buddy_free(pool, ptr as *mut c_void);
+
+            ptr::null_mut()
+        });
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, _layout: core::alloc::Layout, new_size: usize) -> *mut u8 {
+        // buddy_realloc keeps the alignment of aligned blocks as well
+        self.with_pool(|pool| buddy_realloc(pool, ptr as *mut c_void, new_size))
+    }
+}
+
+/// Allocator handing out the blocks of a Buddy, for collections such as
+/// `Vec::new_in`. Only available on nightly with the allocator_api feature.
+///
+/// Every allocation gets the whole usable part of its block, which is often
+/// more than was asked for, so collections can grow into the slack without
+/// reallocating.
+#[cfg(feature = "allocator_api")]
+#[derive(Clone, Copy)]
+pub struct BuddyAllocator<'a> {
+    buddy: &'a Buddy,
+}
+
+#[cfg(feature = "allocator_api")]
+impl<'a> BuddyAllocator<'a> {
+    /// Creates an allocator drawing from buddy.
+    pub fn new(buddy: &'a Buddy) -> BuddyAllocator<'a> {
+        BuddyAllocator { buddy }
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl core::alloc::Allocator for BuddyAllocator<'_> {
+    fn allocate(&self, layout: core::alloc::Layout) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        // Nothing is allocated for zero sized layouts, any aligned address will do
+        if layout.size() == 0 {
+            let dangling = unsafe { NonNull::new_unchecked(ptr::without_provenance_mut(layout.align())) };
+
+            return Ok(NonNull::slice_from_raw_parts(dangling, 0));
+        }
+
+        let pool = self.buddy.as_ptr();
+        let mem = match layout.align() <= GLOBAL_ALIGN {
+            true => buddy_malloc(pool, layout.size()),
+            false => buddy_aligned_alloc(pool, layout.align(), layout.size()),
+        };
+
+        let mem = NonNull::new(mem as *mut u8).ok_or(core::alloc::AllocError)?;
+        let usable = unsafe { user_end(pool, block_of(pool, mem.as_ptr() as *mut c_void)) } - mem.as_ptr() as usize;
+
+        Ok(NonNull::slice_from_raw_parts(mem, usable))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: core::alloc::Layout) {
+        if layout.size() != 0 {
+            buddy_free(self.buddy.as_ptr(), ptr.as_ptr() as *mut c_void);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        /// Number of upcoming backing operations (map or unmap) on this thread that should fail
+        static BACKING_FAILURES: Cell<usize> = const { Cell::new(0) };
+
+        /// Address of the last region unmapped on this thread
+        static LAST_UNMAP: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Helper function.
+    ///
+    /// Remembers that the region at base was unmapped.
+    pub(super) fn record_unmap(base: *mut c_void) {
+        LAST_UNMAP.with(|last| last.set(base as usize));
+    }
+
+    /// Helper function.
+    ///
+    /// Consumes one simulated backing failure, returning true if the current
+    /// backing operation should fail with ENOMEM.
+    pub(super) fn fail_backing() -> bool {
+        BACKING_FAILURES.with(|failures| {
+            if failures.get() == 0 {
+                return false;
+            }
+
+            failures.set(failures.get() - 1);
+            unsafe { *__errno_location() = ENOMEM };
+
+            true
+        })
+    }
+
+    fn check_buddy_pool_full(pool: &mut BuddyPool) {
+        assert!(buddy_validate(pool));
+
+        // Every list below the top one is empty, including those under SMALLEST_K
+        for i in 0..pool.kval_m {
+            let avail = &pool.avail[i];
+            assert_eq!(avail.next as *const _, avail as *const _);
+            assert_eq!(avail.prev as *const _, avail as *const _);
+            assert_eq!(avail.tag, BLOCK_UNUSED);
+            assert_eq!(avail.kval as usize, i);
+        }
+
+        let top = &pool.avail[pool.kval_m];
+        unsafe {
+            assert_eq!((*top.next).tag, BLOCK_AVAIL);
+            assert_eq!((*top.next).next, top as *const _ as *mut _);
+            assert_eq!((*top.prev).prev, top as *const _ as *mut _);
+            assert_eq!(top.next, pool.base as *mut Avail);
+        }
+    }
+
+    fn check_buddy_pool_empty(pool: &mut BuddyPool) {
+        // All avail lists should be empty
+        for i in 0..=pool.kval_m {
+            let avail = &pool.avail[i];
+            assert_eq!(avail.next as *const _, avail as *const _);
+            assert_eq!(avail.prev as *const _, avail as *const _);
+            assert_eq!(avail.tag, BLOCK_UNUSED);
+            assert_eq!(avail.kval as usize, i);
+        }
+    }
+
+    #[test]
+    fn test_buddy_malloc_one_byte() {
+        let kval = MIN_K;
+        let size = 1 << kval;
+
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, size);
+            let pool_ref = &mut *pool_ptr;
+
+            let mem = buddy_malloc(pool_ref, 1);
+            assert!(!mem.is_null());
+
+            assert_eq!(buddy_free(pool_ref, mem), BuddyError::Ok);
+            check_buddy_pool_full(pool_ref);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_malloc_one_large() {
+        let size = 1 << MIN_K;
+
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+        
+        unsafe {
+            buddy_init(pool_ptr, size);
+            let pool_ref = &mut *pool_ptr;
 
-    #[test]
-    fn test_buddy_malloc_one_large() {
-        let size = 1 << MIN_K;
-
-        let mut pool = MaybeUninit::<BuddyPool>::uninit();
-        let pool_ptr = pool.as_mut_ptr();
-        
-        unsafe {
-            buddy_init(pool_ptr, size);
-            let pool_ref = &mut *pool_ptr;
-
             let ask = size - std::mem::size_of::<Avail>();
             let mem = buddy_malloc(pool_ref, ask);
             assert!(!mem.is_null());
 
-            let block = (mem as *mut u8).offset(-(std::mem::size_of::<Avail>() as isize)) as *mut Avail;
-            assert_eq!((*block).kval as usize, MIN_K);
-            assert_eq!((*block).tag, BLOCK_RESERVED);
+            let block = (mem as *mut u8).offset(-(std::mem::size_of::<Avail>() as isize)) as *mut Avail;
+            assert_eq!((*block).kval as usize, MIN_K);
+            assert_eq!((*block).tag, BLOCK_RESERVED);
+
+            check_buddy_pool_empty(pool_ref);
+
+            let fail = buddy_malloc(pool_ref, 5);
+            assert!(fail.is_null());
+
+            assert_eq!(buddy_free(pool_ref, mem), BuddyError::Ok);
+            check_buddy_pool_full(pool_ref);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_init() {
+        for i in MIN_K..=DEFAULT_K {
+            let size = 1 << i;
+            
+            let mut pool = MaybeUninit::<BuddyPool>::uninit();
+            let pool_ptr = pool.as_mut_ptr();
+
+            unsafe {
+                buddy_init(pool_ptr, size);
+                let pool_ref = &mut *pool_ptr;
+
+                check_buddy_pool_full(pool_ref);
+                buddy_destroy(pool_ref);
+            }
+        }
+    }
+
+    #[test]
+    fn test_buddy_calc_basic_pairs() {
+        const TEST_K: usize = MIN_K + 2;
+        let pool_size = 1 << TEST_K;
+
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, pool_size);
+            let pool_ref = &mut *pool_ptr;
+
+            // Allocate 2 small blocks manually by splitting top-level block
+            let top_block = pool_ref.avail[TEST_K].next;
+            assert_eq!((*top_block).tag, BLOCK_AVAIL);
+
+            // Remove top block from the free list
+            remove_block(top_block);
+
+            // Split it into two buddies
+            let kval = TEST_K - 1;
+            let block1 = top_block;
+            let block2 = (block1 as usize + (1 << kval)) as *mut Avail;
+
+            (*block1).kval = kval as u16;
+            (*block2).kval = kval as u16;
+
+            // Calculate each other as buddy
+            let b1 = buddy_calc(pool_ref, block1);
+            let b2 = buddy_calc(pool_ref, block2);
+
+            assert_eq!(b1, block2, "Buddy of block1 should be block2");
+            assert_eq!(b2, block1, "Buddy of block2 should be block1");
+
+            // Check that buddy address is offset by correct power of two
+            let offset1 = (block1 as usize) - (pool_ref.base as usize);
+            let offset2 = (block2 as usize) - (pool_ref.base as usize);
+            assert_eq!(offset1 ^ offset2, 1 << kval);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    /// Helper function.
+    ///
+    /// Inserts a block into the free list at kval
+    unsafe fn insert_block(pool: *mut BuddyPool, block: *mut Avail, kval: usize) {
+        // Get the head of the linked list for blocks of size 2^k where k = kval
+        let head = &mut (*pool).avail[kval];
+    
+        // Insert the block at the head of the list
+        (*block).next = head.next;
+        (*block).prev = head;
+    
+        // Update the next pointer of the block's previous node
+        (*head.next).prev = block;
+    
+        // Update the head's next pointer to the new block
+        head.next = block;
+    
+        // Set the block's tag to indicate its available
+        (*block).tag = BLOCK_AVAIL;
+    }
+
+    #[test]
+    fn test_buddy_calc_recursive_coalescing() {
+        const BASE_K: usize = MIN_K + 3; // 2^7 = 128 bytes
+        let pool_size = 1 << BASE_K;
+    
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+    
+        unsafe {
+            buddy_init(pool_ptr, pool_size);
+            let pool_ref = &mut *pool_ptr;
+    
+            // Manually take the top block
+            let top_block = pool_ref.avail[BASE_K].next;
+            remove_block(top_block);
+    
+            // Split into two level BASE_K - 1 blocks
+            let k1 = BASE_K - 1;
+            let left1 = top_block;
+            let right1 = (left1 as usize + (1 << k1)) as *mut Avail;
+            (*left1).kval = k1 as u16;
+            (*right1).kval = k1 as u16;
+    
+            // Split left1 into two BASE_K - 2 blocks
+            let k2 = k1 - 1;
+            let left2 = left1;
+            let right2 = (left2 as usize + (1 << k2)) as *mut Avail;
+            (*left2).kval = k2 as u16;
+            (*right2).kval = k2 as u16;
+    
+            // Split left2 again
+            let k3 = k2 - 1;
+            let left3 = left2;
+            let right3 = (left3 as usize + (1 << k3)) as *mut Avail;
+            (*left3).kval = k3 as u16;
+            (*right3).kval = k3 as u16;
+    
+            // Now free right3 and left3 and ensure they coalesce into left2
+            insert_block(pool_ref, left3, k3);
+            insert_block(pool_ref, right3, k3);
+    
+            let buddy_of_left3 = buddy_calc(pool_ref, left3);
+            assert_eq!(buddy_of_left3, right3, "Buddy of left3 should be right3");
+    
+            // Remove both from free list to simulate coalescing
+            remove_block(left3);
+            remove_block(right3);
+    
+            // Merge into left2
+            let merged_kval = k3 + 1;
+            let merged_block = if left3 < right3 { left3 } else { right3 };
+            (*merged_block).kval = merged_kval as u16;
+    
+            // Check buddy of merged_block is still correct
+            let buddy = buddy_calc(pool_ref, merged_block);
+            let expected_offset = 1 << merged_kval;
+            let offset_diff = (buddy as usize).wrapping_sub(merged_block as usize);
+            assert_eq!(offset_diff, expected_offset, "Merged block buddy is offset correctly");
+    
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_btok_one() {
+        assert_eq!(0, btok(1));
+    }
+
+    #[test]
+    fn test_btok_range() {
+        assert_eq!(0, btok(1));
+        assert_eq!(1, btok(2));
+        assert_eq!(2, btok(3));
+        assert_eq!(2, btok(4));
+        assert_eq!(3, btok(5));
+        assert_eq!(3, btok(8));
+        assert_eq!(4, btok(9));
+        assert_eq!(4, btok(16));
+        assert_eq!(5, btok(17));
+        assert_eq!(5, btok(32));
+        assert_eq!(6, btok(33));
+        assert_eq!(6, btok(64));
+        assert_eq!(10, btok(1024));
+        assert_eq!(11, btok(1025));
+        assert_eq!(40, btok(1099511627776));
+    }
+
+    #[test]
+    fn test_btok_huge() {
+        assert_eq!(47, btok(1 << 47));
+        assert_eq!(48, btok((1 << 47) + 1));
+        assert_eq!(48, btok((1 << 47) + (1 << 46)));
+        assert_eq!(62, btok(1 << 62));
+        assert_eq!(63, btok(1 << 63));
+        assert_eq!(63, btok((1 << 63) + 1));
+        assert_eq!(63, btok(usize::MAX));
+    }
+
+    #[test]
+    fn test_double_free() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 128);
+            let pool_ref = &mut *pool_ptr;
+
+            let ptr = buddy_malloc(pool_ref, 64);
+            assert!(!ptr.is_null());
+
+            assert_eq!(buddy_free(pool_ref, ptr), BuddyError::Ok);
+
+            // The second free is caught instead of corrupting the free lists
+            assert_eq!(buddy_free(pool_ref, ptr), BuddyError::DoubleFree);
+            check_buddy_pool_full(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_double_free_split_block() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            // The live neighbour keeps the freed block from merging away
+            let ptr = buddy_malloc(pool_ref, 1);
+            let other = buddy_malloc(pool_ref, 1);
+            assert!(!ptr.is_null() && !other.is_null());
+
+            assert_eq!(buddy_free(pool_ref, ptr), BuddyError::Ok);
+            let counts: Vec<usize> = (0..=pool_ref.kval_m).map(|k| count_free(pool_ref, k)).collect();
+
+            // The second free leaves every list exactly as it was
+            assert_eq!(buddy_free(pool_ref, ptr), BuddyError::DoubleFree);
+            assert!(buddy_validate(pool_ref));
+            assert_eq!(counts, (0..=pool_ref.kval_m).map(|k| count_free(pool_ref, k)).collect::<Vec<_>>());
+
+            assert_eq!(buddy_free(pool_ref, other), BuddyError::Ok);
+            check_buddy_pool_full(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_layout_diff_one_allocation() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            // Split the pool down so that a free block of the smallest size exists
+            let first = buddy_malloc(pool_ref, 1);
+            assert!(!first.is_null());
+
+            let needed = buddy_export_layout(pool_ref, ptr::null_mut(), 0);
+            assert_eq!(needed, (MIN_K - SMALLEST_K + 1) * LAYOUT_RECORD_SIZE);
+
+            let mut before = vec![0u8; needed];
+            assert_eq!(buddy_export_layout(pool_ref, before.as_mut_ptr(), before.len()), needed);
+
+            // A buffer that is too small is left untouched
+            let mut small = vec![0u8; needed - 1];
+            assert_eq!(buddy_export_layout(pool_ref, small.as_mut_ptr(), small.len()), 0);
+
+            // This allocation takes the smallest free buddy without splitting anything
+            let second = buddy_malloc(pool_ref, 1);
+            assert!(!second.is_null());
+
+            let mut after = vec![0u8; needed];
+            assert_eq!(buddy_export_layout(pool_ref, after.as_mut_ptr(), after.len()), needed);
+
+            let mut changes = [BlockInfo { offset: 0, kval: 0, tag: 0 }; 4];
+            let count = buddy_layout_diff(
+                before.as_ptr(),
+                before.len(),
+                after.as_ptr(),
+                after.len(),
+                changes.as_mut_ptr(),
+                changes.len(),
+            );
+
+            assert_eq!(count, 1);
+            assert_eq!(changes[0], BlockInfo { offset: 1 << SMALLEST_K, kval: SMALLEST_K as u16, tag: BLOCK_RESERVED });
+
+            // Identical layouts have no changes
+            assert_eq!(buddy_layout_diff(after.as_ptr(), after.len(), after.as_ptr(), after.len(), ptr::null_mut(), 0), 0);
+
+            buddy_free(pool_ref, second);
+            buddy_free(pool_ref, first);
+            check_buddy_pool_full(pool_ref);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    /// Helper function.
+    ///
+    /// Returns a heap buffer along with a pointer into it that is aligned to size
+    /// and has at least size bytes after it.
+    fn aligned_buffer(size: usize) -> (Vec<u8>, *mut c_void) {
+        let mut buffer = vec![0u8; size * 2];
+        let start = buffer.as_mut_ptr() as usize;
+        let aligned = (start + size - 1) & !(size - 1);
+
+        (buffer, aligned as *mut c_void)
+    }
+
+    #[test]
+    fn test_buddy_init_from_buffer() {
+        let size = 1 << 16;
+        let (buffer, base) = aligned_buffer(size);
+
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            // Extra bytes past the power of two are ignored
+            assert_eq!(buddy_init_from_buffer(pool_ptr, base, size + 100), 0);
+            let pool_ref = &mut *pool_ptr;
+
+            assert_eq!(pool_ref.base, base);
+            assert_eq!(pool_ref.kval_m, 16);
+            assert!(!pool_ref.owns_mapping);
+            check_buddy_pool_full(pool_ref);
+
+            let mem = buddy_malloc(pool_ref, 100);
+            assert!(!mem.is_null());
+            assert!(mem as usize >= base as usize && (mem as usize) < base as usize + size);
+
+            assert_eq!(buddy_free(pool_ref, mem), BuddyError::Ok);
+            check_buddy_pool_full(pool_ref);
+
+            // Destroying the pool must leave the caller's buffer alone
+            LAST_UNMAP.with(|last| last.set(0));
+            buddy_destroy(pool_ref);
+            assert_eq!(LAST_UNMAP.with(Cell::get), 0);
+            ptr::write_bytes(base as *mut u8, 0xEE, size);
+        }
+
+        drop(buffer);
+    }
+
+    #[test]
+    fn test_buddy_init_from_buffer_misaligned() {
+        let size = 1 << 16;
+        let (_buffer, base) = aligned_buffer(size);
+        let misaligned = (base as usize + 64) as *mut c_void;
+
+        let mut pool = MaybeUninit::<BuddyPool>::zeroed();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            assert_eq!(buddy_init_from_buffer(pool_ptr, misaligned, size), EINVAL);
+
+            // The pool is left untouched
+            assert!((*pool_ptr).base.is_null());
+            assert_eq!((*pool_ptr).kval_m, 0);
+        }
+
+        // Too small to hold a single block
+        assert_eq!(buddy_init_from_buffer(pool_ptr, base, (1 << SMALLEST_K) - 1), EINVAL);
+        assert_eq!(buddy_init_from_buffer(ptr::null_mut(), base, size), EINVAL);
+    }
+
+    #[test]
+    fn test_abort_on_error_policy() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        assert!(buddy_get_abort_on_error());
+
+        unsafe {
+            // By default a failed mapping panics
+            BACKING_FAILURES.with(|f| f.set(1));
+            let result = std::panic::catch_unwind(|| init_mapped(pool_ptr, 1 << MIN_K, &BuddyInitOptions::default()));
+            assert!(result.is_err());
+
+            // Otherwise the error code is returned and the pool left zeroed
+            buddy_set_abort_on_error(false);
+            assert!(!buddy_get_abort_on_error());
+
+            BACKING_FAILURES.with(|f| f.set(1));
+            assert_eq!(buddy_init(pool_ptr, 1 << MIN_K), ENOMEM);
+            assert!((*pool_ptr).base.is_null());
+            assert!(!(*pool_ptr).owns_mapping);
+
+            // A failed unmap keeps the pool intact so it can be destroyed later
+            assert_eq!(buddy_init(pool_ptr, 1 << MIN_K), 0);
+            BACKING_FAILURES.with(|f| f.set(1));
+            assert_eq!(buddy_destroy(pool_ptr), ENOMEM);
+            check_buddy_pool_full(&mut *pool_ptr);
+
+            buddy_set_abort_on_error(true);
+
+            BACKING_FAILURES.with(|f| f.set(1));
+            let result = std::panic::catch_unwind(|| destroy_mapped(pool_ptr));
+            assert!(result.is_err());
+
+            assert_eq!(buddy_destroy(pool_ptr), 0);
+        }
+    }
+
+    #[test]
+    fn test_buddy_aligned_calloc() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            // Dirty the memory so zeroing is observable
+            let dirty = buddy_malloc(pool_ref, 4096);
+            ptr::write_bytes(dirty as *mut u8, 0xFF, 4096);
+            buddy_free(pool_ref, dirty);
+
+            let mem = buddy_aligned_calloc(pool_ref, 64, 16, 64);
+            assert!(!mem.is_null());
+            assert_eq!(mem as usize % 64, 0);
+
+            let block = block_of(pool_ref, mem);
+            let usable = block as usize + (1 << (*block).kval) - mem as usize;
+            assert!(usable >= 16 * 64);
+
+            let bytes = std::slice::from_raw_parts(mem as *const u8, usable);
+            assert!(bytes.iter().all(|&b| b == 0));
+
+            assert_eq!(buddy_free(pool_ref, mem), BuddyError::Ok);
+            check_buddy_pool_full(pool_ref);
+
+            // Invalid alignment
+            *__errno_location() = 0;
+            assert!(buddy_aligned_calloc(pool_ref, 48, 16, 64).is_null());
+            assert_eq!(*__errno_location(), EINVAL);
+
+            // nmemb * size overflows
+            *__errno_location() = 0;
+            assert!(buddy_aligned_calloc(pool_ref, 64, usize::MAX, 2).is_null());
+            assert_eq!(*__errno_location(), ENOMEM);
+
+            assert!(buddy_aligned_calloc(pool_ref, 64, 0, 64).is_null());
+            check_buddy_pool_full(pool_ref);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    /// Helper function.
+    ///
+    /// Counts the blocks on the avail list of class k.
+    fn count_free(pool: &mut BuddyPool, k: usize) -> usize {
+        let head = &mut pool.avail[k] as *mut Avail;
+        let mut count = 0;
+
+        unsafe {
+            let mut block = (*head).next;
+            while block != head {
+                count += 1;
+                block = (*block).next;
+            }
+        }
+
+        count
+    }
+
+    #[test]
+    fn test_buddy_policy() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            assert_eq!(buddy_get_policy(pool_ref), BuddyPolicy { flags: POLICY_DEFAULT, strategy: STRATEGY_LIFO });
+
+            let policy = BuddyPolicy { flags: POLICY_COALESCE | POLICY_SMALL_FAST, strategy: STRATEGY_FIFO };
+            buddy_set_policy(pool_ref, policy);
+            assert_eq!(buddy_get_policy(pool_ref), policy);
+
+            // Coalescing switched off leaves freed blocks split
+            buddy_set_policy(pool_ref, BuddyPolicy { flags: 0, strategy: STRATEGY_LIFO });
+            let mem = buddy_malloc(pool_ref, 1);
+            buddy_free(pool_ref, mem);
+            assert_eq!(count_free(pool_ref, SMALLEST_K), 2);
+            assert_eq!(count_free(pool_ref, MIN_K), 0);
+
+            // Without deferral a whole pool allocation can never succeed now
+            assert!(buddy_malloc(pool_ref, (1 << MIN_K) - std::mem::size_of::<Avail>()).is_null());
+
+            // Deferred coalescing merges everything once an allocation needs it
+            buddy_set_policy(pool_ref, BuddyPolicy { flags: POLICY_COALESCE | POLICY_DEFERRED, strategy: STRATEGY_LIFO });
+            let all = buddy_malloc(pool_ref, (1 << MIN_K) - std::mem::size_of::<Avail>());
+            assert!(!all.is_null());
+            buddy_free(pool_ref, all);
+            check_buddy_pool_full(pool_ref);
+
+            // Small blocks stay on their list while larger ones still coalesce
+            buddy_set_policy(pool_ref, BuddyPolicy { flags: POLICY_COALESCE | POLICY_SMALL_FAST, strategy: STRATEGY_LIFO });
+            let small = buddy_malloc(pool_ref, 1);
+            buddy_free(pool_ref, small);
+            assert_eq!(count_free(pool_ref, SMALLEST_K), 2);
+
+            buddy_set_policy(pool_ref, BuddyPolicy { flags: POLICY_COALESCE | POLICY_DEFERRED, strategy: STRATEGY_LIFO });
+            assert!(!buddy_malloc(pool_ref, (1 << MIN_K) - std::mem::size_of::<Avail>()).is_null());
+            buddy_destroy(pool_ref);
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            let large = buddy_malloc(pool_ref, 1000);
+            buddy_set_policy(pool_ref, BuddyPolicy { flags: POLICY_COALESCE | POLICY_SMALL_FAST, strategy: STRATEGY_LIFO });
+            buddy_free(pool_ref, large);
+            check_buddy_pool_full(pool_ref);
+
+            // FIFO hands out the block that was freed first, LIFO the one freed last
+            buddy_set_policy(pool_ref, BuddyPolicy { flags: 0, strategy: STRATEGY_FIFO });
+            let a = buddy_malloc(pool_ref, 1);
+            let b = buddy_malloc(pool_ref, 1);
+            let c = buddy_malloc(pool_ref, 1);
+            let d = buddy_malloc(pool_ref, 1);
+            assert_eq!(count_free(pool_ref, SMALLEST_K), 0);
+
+            buddy_free(pool_ref, a);
+            buddy_free(pool_ref, b);
+            assert_eq!(buddy_malloc(pool_ref, 1), a);
+
+            buddy_set_policy(pool_ref, BuddyPolicy { flags: 0, strategy: STRATEGY_LIFO });
+            buddy_free(pool_ref, c);
+            buddy_free(pool_ref, d);
+            assert_eq!(buddy_malloc(pool_ref, 1), d);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_end_sentinel() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            let options = BuddyInitOptions { flags: INIT_END_SENTINEL, ..Default::default() };
+            assert_eq!(buddy_init_with(pool_ptr, 1 << MIN_K, &options), 0);
+            let pool_ref = &mut *pool_ptr;
+
+            assert_eq!(pool_ref.sentinel as usize, pool_ref.base as usize + pool_ref.numbytes);
+            assert_eq!((*pool_ref.sentinel).tag, BLOCK_SENTINEL);
+            check_buddy_pool_full(pool_ref);
+            assert!(buddy_check_layout(pool_ref));
+
+            let mem = buddy_malloc(pool_ref, 1);
+            assert!(buddy_check_layout(pool_ref));
+
+            // Corrupt the kval of the free buddy right after the allocation
+            let buddy = (pool_ref.base as usize + (1 << SMALLEST_K)) as *mut Avail;
+            assert_eq!((*buddy).tag, BLOCK_AVAIL);
+            (*buddy).kval = 10;
+
+            assert!(!buddy_check_layout(pool_ref));
+            assert_eq!(buddy_export_layout(pool_ref, ptr::null_mut(), 0), 0);
+
+            (*buddy).kval = SMALLEST_K as u16;
+            assert!(buddy_check_layout(pool_ref));
+
+            buddy_free(pool_ref, mem);
+            check_buddy_pool_full(pool_ref);
+
+            assert_eq!(buddy_destroy(pool_ref), 0);
+        }
+    }
+
+    #[test]
+    fn test_buddy_alloc_age() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            let first = buddy_malloc(pool_ref, 100);
+            let second = buddy_malloc(pool_ref, 5000);
+            let third = buddy_aligned_calloc(pool_ref, 256, 1, 10);
+
+            assert_eq!(buddy_alloc_age(pool_ref, first), 2);
+            assert_eq!(buddy_alloc_age(pool_ref, second), 1);
+            assert_eq!(buddy_alloc_age(pool_ref, third), 0);
+
+            // Freeing does not advance the clock, allocating does
+            buddy_free(pool_ref, second);
+            assert_eq!(buddy_alloc_age(pool_ref, first), 2);
+
+            let fourth = buddy_malloc(pool_ref, 1);
+            assert_eq!(buddy_alloc_age(pool_ref, first), 3);
+            assert_eq!(buddy_alloc_age(pool_ref, third), 1);
+            assert_eq!(buddy_alloc_age(pool_ref, fourth), 0);
+
+            assert_eq!(buddy_alloc_age(pool_ref, ptr::null_mut()), 0);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_malloc_noboundary() {
+        let boundary = 1 << 16;
+
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            // The region itself is aligned to its size
+            assert_eq!(pool_ref.base as usize % pool_ref.numbytes, 0);
+
+            // Offset later allocations so they do not start on a boundary by luck
+            let filler = buddy_malloc(pool_ref, 3000);
+            assert!(!filler.is_null());
+
+            let mut mems = Vec::new();
+            for size in [1, 100, 40000, boundary - 64, boundary - 10, boundary] {
+                let mem = buddy_malloc_noboundary(pool_ref, size, boundary);
+                assert!(!mem.is_null());
+
+                let first = mem as usize;
+                let last = first + size - 1;
+                assert_eq!(first / boundary, last / boundary, "size {} crosses the boundary", size);
+
+                mems.push(mem);
+            }
+
+            // Not a power of two, or larger than the boundary
+            assert!(buddy_malloc_noboundary(pool_ref, 100, 3000).is_null());
+            assert_eq!(*__errno_location(), EINVAL);
+            assert!(buddy_malloc_noboundary(pool_ref, boundary + 1, boundary).is_null());
+            assert_eq!(*__errno_location(), EINVAL);
+
+            for mem in mems {
+                assert_eq!(buddy_free(pool_ref, mem), BuddyError::Ok);
+            }
+            buddy_free(pool_ref, filler);
+            check_buddy_pool_full(pool_ref);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    /// Rate callback that counts its calls in the u64 behind user and only
+    /// allows the first storm.
+    extern "C" fn deny_after_first(_count: u64, user: *mut c_void) -> bool {
+        let calls = unsafe { &mut *(user as *mut u64) };
+        *calls += 1;
+
+        *calls == 1
+    }
+
+    #[test]
+    fn test_buddy_rate_callback() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+        let mut calls: u64 = 0;
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            buddy_set_rate_callback(pool_ref, Some(deny_after_first), 4, &mut calls as *mut u64 as *mut c_void);
+
+            // The first window allows 4 allocations, the 5th asks the callback which allows it
+            for _ in 0..5 {
+                assert!(!buddy_malloc(pool_ref, 16).is_null());
+            }
+            assert_eq!(calls, 1);
+
+            // The next window is denied once it exceeds the threshold
+            for _ in 0..4 {
+                assert!(!buddy_malloc(pool_ref, 16).is_null());
+            }
+            assert!(buddy_malloc(pool_ref, 16).is_null());
+            assert_eq!(*__errno_location(), EAGAIN);
+            assert!(buddy_malloc(pool_ref, 16).is_null());
+            assert_eq!(calls, 3);
+
+            // A new window lets allocations through again
+            buddy_reset_rate_window(pool_ref);
+            assert!(!buddy_malloc(pool_ref, 16).is_null());
+
+            // Without a callback nothing is throttled
+            buddy_set_rate_callback(pool_ref, None, 0, ptr::null_mut());
+            for _ in 0..10 {
+                assert!(!buddy_malloc(pool_ref, 16).is_null());
+            }
+            assert_eq!(calls, 3);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_realloc_bytes() {
+        let buddy = Buddy::new(1 << MIN_K);
+
+        let bytes = buddy.alloc_bytes(20).unwrap();
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+
+        // Grow well past the block the bytes started in
+        let grown = buddy.realloc_bytes(bytes, 5000).unwrap();
+        assert_eq!(grown.len(), 5000);
+        assert!(grown[..20].iter().enumerate().all(|(i, &b)| b == i as u8));
+        grown[4999] = 0xAA;
+
+        // Shrink keeps the prefix as well
+        let shrunk = buddy.realloc_bytes(grown, 10).unwrap();
+        assert_eq!(shrunk.len(), 10);
+        assert!(shrunk.iter().enumerate().all(|(i, &b)| b == i as u8));
+
+        // Too large for the pool leaves the slice alone
+        assert!(buddy.realloc_bytes(shrunk, 1 << MIN_K).is_none());
+
+        let freed = buddy.realloc_bytes(shrunk, 0).unwrap();
+        assert!(freed.is_empty());
+
+        check_buddy_pool_full(unsafe { &mut *buddy.as_ptr() });
+    }
+
+    #[test]
+    fn test_buddy_realloc_bytes_foreign() {
+        let buddy = Buddy::new(1 << MIN_K);
+
+        // Memory the pool never handed out is refused, whatever the new length
+        let mut local = [0x5Au8; 256];
+        assert!(buddy.realloc_bytes(&mut local[32..], 100).is_none());
+        assert!(buddy.realloc_bytes(&mut local[32..], 0).is_none());
+        assert!(local.iter().all(|&b| b == 0x5A));
+
+        // As is a slice into the middle of an allocation
+        let bytes = buddy.alloc_bytes(400).unwrap();
+        bytes.fill(0x5A);
+        let before = buddy_stats(buddy.as_ptr());
+        assert!(buddy.realloc_bytes(&mut bytes[64..], 1000).is_none());
+        assert!(buddy.realloc_bytes(&mut bytes[64..], 0).is_none());
+        assert_eq!(buddy_stats(buddy.as_ptr()), before);
+
+        bytes.fill(0);
+        let interior = bytes[96..].as_mut_ptr() as *mut c_void;
+        assert!(buddy_realloc(buddy.as_ptr(), interior, 10).is_null());
+        assert_eq!(unsafe { *__errno_location() }, EINVAL);
+        assert_eq!(buddy_stats(buddy.as_ptr()), before);
+
+        assert!(buddy.realloc_bytes(bytes, 0).unwrap().is_empty());
+        check_buddy_pool_full(unsafe { &mut *buddy.as_ptr() });
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_buddy_would_fault() {
+        let size = 256 * 1024;
+
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            // Nothing past the first header has been touched in a fresh mapping
+            assert!(buddy_would_fault(pool_ref, size));
+
+            // Touch every page of the block the allocation landed in
+            let mem = buddy_malloc(pool_ref, size);
+            let block = block_of(pool_ref, mem);
+            let usable = (1 << (*block).kval) - std::mem::size_of::<Avail>();
+            ptr::write_bytes(mem as *mut u8, 0x5A, usable);
+            buddy_free(pool_ref, mem);
+
+            // The same block comes back and its pages are resident now
+            assert!(!buddy_would_fault(pool_ref, size));
+            let again = buddy_realloc(pool_ref, ptr::null_mut(), size);
+            assert_eq!(again, mem);
+
+            // A same-size realloc stays on the resident block
+            assert_eq!(buddy_realloc(pool_ref, again, size), mem);
+            assert!(is_resident(mem, usable));
+            buddy_free(pool_ref, mem);
+            assert!(!buddy_would_fault(pool_ref, size));
+
+            // Requests that can not be satisfied never fault
+            assert!(!buddy_would_fault(pool_ref, 1 << MIN_K));
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_buddy_would_fault_policies() {
+        let header = std::mem::size_of::<Avail>();
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            // Deferred merges are done first, as the allocation would
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+            buddy_set_policy(pool, BuddyPolicy { flags: POLICY_COALESCE | POLICY_DEFERRED, strategy: STRATEGY_LIFO });
+
+            let halves = [buddy_malloc(pool, (1 << (MIN_K - 1)) - header), buddy_malloc(pool, (1 << (MIN_K - 1)) - header)];
+            for half in halves {
+                buddy_free(pool, half);
+            }
+            // The halves are looked at as the whole pool they merge into
+            let faults = buddy_would_fault(pool, (1 << MIN_K) - header);
+            assert_eq!(faults, !is_resident(pool.base, 1 << MIN_K));
+            let whole = buddy_malloc(pool, (1 << MIN_K) - header);
+            assert!(!whole.is_null());
+            buddy_free(pool, whole);
+            buddy_destroy(pool);
+
+            // Cache-hot pools pick the recently freed, resident block over the smallest one
+            buddy_init(pool, 1 << MIN_K);
+            buddy_set_policy(pool, BuddyPolicy { flags: POLICY_DEFAULT, strategy: STRATEGY_CACHE_HOT });
+
+            let small = [buddy_malloc(pool, (1 << 14) - header), buddy_malloc(pool, (1 << 14) - header)];
+            let hot = buddy_malloc(pool, (1 << 16) - header);
+            ptr::write_bytes(hot as *mut u8, 0x5A, (1 << 16) - header);
+            buddy_free(pool, hot);
+
+            assert!(!buddy_would_fault(pool, (1 << 14) - header));
+            let mem = buddy_malloc(pool, (1 << 14) - header);
+            assert_eq!(block_start(pool, block_of(pool, mem)), block_start(pool, block_of(pool, hot)));
+
+            // Otherwise the untouched class 15 block would be split
+            buddy_set_policy(pool, BuddyPolicy { flags: POLICY_DEFAULT, strategy: STRATEGY_LIFO });
+            buddy_free(pool, mem);
+            assert!(buddy_would_fault(pool, (1 << 14) - header));
+
+            for block in small {
+                buddy_free(pool, block);
+            }
+            check_buddy_pool_full(pool);
+            buddy_destroy(pool);
+        }
+    }
+
+    /// Messages received by record_assert
+    static ASSERT_MESSAGES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    /// Held by tests installing record_assert, the handler is global
+    static ASSERT_SERIAL: Mutex<()> = Mutex::new(());
+
+    extern "C" fn record_assert(msg: *const u8, len: usize) {
+        let msg = unsafe { std::slice::from_raw_parts(msg, len) };
+        ASSERT_MESSAGES.lock().unwrap().push(String::from_utf8_lossy(msg).into_owned());
+    }
+
+    #[test]
+    fn test_buddy_assert_handler() {
+        let _serial = ASSERT_SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        ASSERT_MESSAGES.lock().unwrap().clear();
+
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            buddy_set_assert_handler(Some(record_assert));
+            buddy_set_policy(pool_ref, BuddyPolicy { flags: POLICY_DEFAULT | POLICY_CHECKED, strategy: STRATEGY_LIFO });
+
+            let mem = buddy_malloc(pool_ref, 100);
+            assert_eq!(buddy_free(pool_ref, mem), BuddyError::Ok);
+
+            // Freeing again is reported instead of corrupting the free lists
+            assert_eq!(buddy_free(pool_ref, mem), BuddyError::DoubleFree);
+            check_buddy_pool_full(pool_ref);
+
+            let mut local = 0u64;
+            assert_eq!(buddy_free(pool_ref, &mut local as *mut u64 as *mut c_void), BuddyError::InvalidPointer);
+
+            // A corrupted free list is caught by buddy_malloc
+            (*pool_ref.avail[MIN_K].next).kval = 3;
+            assert!(buddy_malloc(pool_ref, 100).is_null());
+            (*pool_ref.avail[MIN_K].next).kval = MIN_K as u16;
+
+            buddy_set_assert_handler(None);
+
+            let messages = ASSERT_MESSAGES.lock().unwrap();
+            assert_eq!(messages.len(), 3);
+            assert!(messages[0].contains("double free"), "{}", messages[0]);
+            assert!(messages[1].contains("outside the pool"), "{}", messages[1]);
+            assert!(messages[2].contains("free list 20"), "{}", messages[2]);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_capacity() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            assert_eq!(buddy_capacity(pool_ref, SMALLEST_K), 1 << (pool_ref.kval_m - SMALLEST_K));
+            assert_eq!(buddy_capacity(pool_ref, pool_ref.kval_m), 1);
+            assert_eq!(buddy_capacity(pool_ref, 10), 1 << (MIN_K - 10));
+
+            // Classes the pool can not have
+            assert_eq!(buddy_capacity(pool_ref, SMALLEST_K - 1), 0);
+            assert_eq!(buddy_capacity(pool_ref, pool_ref.kval_m + 1), 0);
+            assert_eq!(buddy_capacity(ptr::null_mut(), SMALLEST_K), 0);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_cache_hot_strategy() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        for strategy in [STRATEGY_CACHE_HOT, STRATEGY_LIFO] {
+            unsafe {
+                buddy_init(pool_ptr, 1 << MIN_K);
+                let pool_ref = &mut *pool_ptr;
+
+                // Keep the freed blocks at their own class
+                buddy_set_policy(pool_ref, BuddyPolicy { flags: 0, strategy });
+
+                // Free then malloc of the same size hands back the block just freed
+                let mem = buddy_malloc(pool_ref, 100);
+                buddy_free(pool_ref, mem);
+                assert_eq!(buddy_malloc(pool_ref, 100), mem);
+
+                // Free a small block and then a larger one
+                let small = buddy_malloc(pool_ref, 1000);
+                let large = buddy_malloc(pool_ref, 5000);
+                buddy_free(pool_ref, small);
+                buddy_free(pool_ref, large);
+
+                // Cache-hot splits the most recently freed block, LIFO the
+                // smaller but older remainder of the very first allocation
+                let split = buddy_malloc(pool_ref, 50);
+                if strategy == STRATEGY_CACHE_HOT {
+                    assert_eq!(split, large);
+                } else {
+                    let remainder = pool_ref.base as usize + 256 + std::mem::size_of::<Avail>();
+                    assert_eq!(split as usize, remainder);
+                }
+
+                buddy_destroy(pool_ref);
+            }
+        }
+    }
+
+    /// Predicate matching blocks with more usable bytes than the usize behind user
+    extern "C" fn larger_than(_ptr: *mut c_void, usable: usize, user: *mut c_void) -> bool {
+        usable > unsafe { *(user as *const usize) }
+    }
+
+    #[test]
+    fn test_buddy_free_matching() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            let small = [buddy_malloc(pool_ref, 100), buddy_malloc(pool_ref, 200), buddy_malloc(pool_ref, 900)];
+            let large = [buddy_malloc(pool_ref, 5000), buddy_malloc(pool_ref, 20000)];
+
+            let mut threshold: usize = 1000;
+            let freed = buddy_free_matching(pool_ref, larger_than, &mut threshold as *mut usize as *mut c_void);
+            assert_eq!(freed, 2);
+
+            for mem in large {
+                assert_eq!((*block_of(pool_ref, mem)).tag, BLOCK_AVAIL);
+            }
+
+            for mem in small {
+                assert_eq!((*block_of(pool_ref, mem)).tag, BLOCK_RESERVED);
+            }
+
+            // Nothing left above the threshold
+            assert_eq!(buddy_free_matching(pool_ref, larger_than, &mut threshold as *mut usize as *mut c_void), 0);
+
+            threshold = 0;
+            assert_eq!(buddy_free_matching(pool_ref, larger_than, &mut threshold as *mut usize as *mut c_void), 3);
+            check_buddy_pool_full(pool_ref);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_realloc_keeps_alignment() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            let mem = buddy_aligned_calloc(pool_ref, 256, 1, 100) as *mut u8;
+            assert!(!mem.is_null());
+            assert_eq!(mem as usize % 256, 0);
+
+            for i in 0..100 {
+                *mem.add(i) = i as u8;
+            }
+
+            let grown = buddy_realloc(pool_ref, mem as *mut c_void, 10000) as *mut u8;
+            assert!(!grown.is_null());
+            assert_ne!(grown, mem);
+            assert_eq!(grown as usize % 256, 0);
+
+            for i in 0..100 {
+                assert_eq!(*grown.add(i), i as u8);
+            }
+
+            buddy_free(pool_ref, grown as *mut c_void);
+            check_buddy_pool_full(pool_ref);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_max_split_depth() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+            assert_eq!(buddy_max_split_depth(pool_ref), 0);
+
+            let mem = buddy_malloc(pool_ref, 1);
+            let req_k = request_class(1);
+            assert_eq!(buddy_max_split_depth(pool_ref) as usize, pool_ref.kval_m - req_k);
+
+            // A shallower split later on does not lower the maximum
+            let other = buddy_malloc(pool_ref, 1 << (MIN_K - 2));
+            assert_eq!(buddy_max_split_depth(pool_ref) as usize, pool_ref.kval_m - req_k);
+
+            buddy_free(pool_ref, mem);
+            buddy_free(pool_ref, other);
+            buddy_destroy(pool_ref);
+
+            buddy_init(pool_ptr, 1 << MIN_K);
+            assert_eq!(buddy_max_split_depth(pool_ptr), 0);
+            buddy_destroy(pool_ptr);
+        }
+    }
+
+    #[test]
+    fn test_buddy_warm_classes() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            let classes = [SMALLEST_K + 2, MIN_K - 3];
+            assert_eq!(buddy_warm_classes(pool_ref, classes.as_ptr(), classes.len()), 0);
+
+            for k in classes {
+                assert!(count_free(pool_ref, k) > 0);
+
+                let size = (1 << k) - std::mem::size_of::<Avail>();
+                assert!(!buddy_would_fault(pool_ref, size));
+
+                let splits = pool_ref.split_count;
+                let mem = buddy_malloc(pool_ref, size);
+                assert!(!mem.is_null());
+                assert_eq!(pool_ref.split_count, splits);
+            }
+
+            let bad = [MIN_K + 1];
+            assert_eq!(buddy_warm_classes(pool_ref, bad.as_ptr(), bad.len()), EINVAL);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_can_destroy() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+            assert!(buddy_can_destroy(pool_ref));
+
+            let mem = buddy_malloc(pool_ref, 64);
+            let aligned = buddy_aligned_calloc(pool_ref, 512, 4, 16);
+            assert!(!buddy_can_destroy(pool_ref));
+
+            buddy_free(pool_ref, mem);
+            assert!(!buddy_can_destroy(pool_ref));
+
+            buddy_free(pool_ref, aligned);
+            assert!(buddy_can_destroy(pool_ref));
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_highest_free_k() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+        let header = std::mem::size_of::<Avail>();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+            assert_eq!(pool_ref.highest_free_k, MIN_K);
+            assert_eq!(buddy_largest_available(pool_ref), (1 << MIN_K) - header);
+
+            // Taking the top block empties every list
+            let mut size = 0;
+            let top = buddy_malloc_largest(pool_ref, &mut size);
+            assert!(!top.is_null());
+            assert_eq!(size, (1 << MIN_K) - header);
+            assert_eq!(pool_ref.free_mask, 0);
+            assert_eq!(buddy_largest_available(pool_ref), 0);
+            assert!(buddy_malloc_largest(pool_ref, &mut size).is_null());
+
+            buddy_free(pool_ref, top);
+            assert_eq!(pool_ref.highest_free_k, MIN_K);
+
+            // The largest free block is the upper half once the first split happens
+            let small = buddy_malloc(pool_ref, 1);
+            assert_eq!(pool_ref.highest_free_k, MIN_K - 1);
+
+            let half = buddy_malloc(pool_ref, (1 << (MIN_K - 1)) - header);
+            assert_eq!(pool_ref.highest_free_k, MIN_K - 2);
+            assert_eq!(pool_ref.free_mask.count_ones() as usize, MIN_K - 1 - request_class(1));
+
+            buddy_free(pool_ref, half);
+            assert_eq!(pool_ref.highest_free_k, MIN_K - 1);
+
+            buddy_free(pool_ref, small);
+            assert_eq!(pool_ref.highest_free_k, MIN_K);
+            assert_eq!(pool_ref.free_mask, 1 << MIN_K);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_malloc_budget() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+            let needed = pool_ref.kval_m - request_class(1);
+
+            *__errno_location() = 0;
+            assert!(buddy_malloc_budget(pool_ref, 1, needed - 1).is_null());
+            assert_eq!(*__errno_location(), EAGAIN);
+            check_buddy_pool_full(pool_ref);
+
+            let mem = buddy_malloc_budget(pool_ref, 1, needed);
+            assert!(!mem.is_null());
+
+            // The buddy left behind by the first split needs none
+            let other = buddy_malloc_budget(pool_ref, 1, 0);
+            assert!(!other.is_null());
+
+            buddy_free(pool_ref, mem);
+            buddy_free(pool_ref, other);
+            check_buddy_pool_full(pool_ref);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_mapping_info() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+        let mut info = MaybeUninit::<MappingInfo>::uninit();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            assert_eq!(buddy_mapping_info(pool_ptr, info.as_mut_ptr()), 0);
+
+            let info = info.assume_init();
+            assert_eq!(info.fd, -1);
+            assert_eq!(info.flags, MAP_PRIVATE | MAP_ANONYMOUS);
+            assert_eq!(info.prot, PROT_READ | PROT_WRITE);
+            assert_eq!(info.owns_mapping, 1);
+
+            assert_eq!(buddy_mapping_info(pool_ptr, ptr::null_mut()), EINVAL);
+            buddy_destroy(pool_ptr);
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "poison"))]
+    fn test_buddy_recycle() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+        let size = 4096;
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+            buddy_set_policy(pool_ref, BuddyPolicy { flags: POLICY_DEFAULT, strategy: STRATEGY_FIFO });
+
+            let mem = buddy_malloc(pool_ref, size) as *mut u8;
+            ptr::write_bytes(mem, 0xAB, size);
+            buddy_malloc(pool_ref, 100);
+
+            assert_eq!(buddy_recycle(pool_ref), 0);
+            check_buddy_pool_full(pool_ref);
+            assert!(buddy_can_destroy(pool_ref));
+            assert_eq!(pool_ref.policy.strategy, STRATEGY_FIFO);
+
+            let mem = buddy_malloc(pool_ref, size) as *mut u8;
+            assert!((0..size).all(|i| *mem.add(i) == 0));
+
+            buddy_destroy(pool_ref);
+
+            let (_buffer, base) = aligned_buffer(1 << 16);
+            buddy_init_from_buffer(pool_ptr, base, 1 << 16);
+            assert_eq!(buddy_recycle(pool_ptr), EINVAL);
+        }
+    }
+
+    #[test]
+    fn test_buddy_coalesce_blocked() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+        let mut counters = BuddyCounters::default();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            // Two buddies of the smallest class
+            let held = buddy_malloc(pool_ref, 1);
+            let freed = buddy_malloc(pool_ref, 1);
+            assert_eq!(buddy_calc(pool_ref, block_of(pool_ref, held)), block_of(pool_ref, freed));
+
+            buddy_free(pool_ref, freed);
+            assert_eq!(buddy_counters(pool_ref, &mut counters), 0);
+            assert_eq!(counters.coalesce_blocked, 1);
+            assert_eq!(counters.allocs, 2);
+            assert_eq!(counters.frees, 1);
+            assert_eq!(counters.live, 1);
+            assert_eq!(counters.splits as usize, pool_ref.kval_m - SMALLEST_K);
+
+            // Freeing the last block merges all the way up
+            buddy_free(pool_ref, held);
+            buddy_counters(pool_ref, &mut counters);
+            assert_eq!(counters.coalesce_blocked, 1);
+            assert_eq!(counters.live, 0);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", not(feature = "poison")))]
+    fn test_buddy_init_shared() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+        let options = BuddyInitOptions { flags: INIT_SHARED, ..Default::default() };
+
+        unsafe {
+            assert_eq!(buddy_init_with(pool_ptr, 1 << MIN_K, &options), 0);
+            assert_eq!((*pool_ptr).map_flags, MAP_SHARED | MAP_ANONYMOUS);
+
+            let pid = libc::fork();
+            assert!(pid >= 0);
+
+            if pid == 0 {
+                // The child allocates and fills a block, the parent never allocates it
+                let mem = buddy_malloc(pool_ptr, 64) as *mut u8;
+                ptr::write_bytes(mem, 0x5A, 64);
+                libc::_exit(0);
+            }
+
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+
+            // The parent's copy of the pool hands out the same block and sees the child's bytes
+            let mem = buddy_malloc(pool_ptr, 64) as *mut u8;
+            assert!((0..64).all(|i| *mem.add(i) == 0x5A));
+
+            buddy_destroy(pool_ptr);
+        }
+    }
+
+    #[test]
+    fn test_buddy_is_block_start() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            let mem = buddy_malloc(pool_ref, 1000) as *mut u8;
+            let aligned = buddy_aligned_calloc(pool_ref, 256, 1, 100) as *mut u8;
+            assert!(buddy_is_block_start(pool_ref, mem as *mut c_void));
+            assert!(buddy_is_block_start(pool_ref, aligned as *mut c_void));
+
+            // Interior pointers, even ones that look like a header is in front of them
+            assert!(!buddy_is_block_start(pool_ref, mem.add(8) as *mut c_void));
+            assert!(!buddy_is_block_start(pool_ref, mem.add(512) as *mut c_void));
+            assert!(!buddy_is_block_start(pool_ref, aligned.add(64) as *mut c_void));
+
+            // Freed blocks and memory outside of the pool
+            buddy_free(pool_ref, mem as *mut c_void);
+            assert!(!buddy_is_block_start(pool_ref, mem as *mut c_void));
+            assert!(!buddy_is_block_start(pool_ref, pool_ptr as *mut c_void));
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_init_map_retries() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+        let options = BuddyInitOptions { flags: 0, map_retries: 3, retry_delay_us: 10 };
+
+        unsafe {
+            // Failures within the retry budget are ridden out
+            BACKING_FAILURES.with(|f| f.set(3));
+            assert_eq!(buddy_init_with(pool_ptr, 1 << MIN_K, &options), 0);
+            assert_eq!(BACKING_FAILURES.with(|f| f.get()), 0);
+            check_buddy_pool_full(&mut *pool_ptr);
+            buddy_destroy(pool_ptr);
+
+            // One more than that is reported, and only after every retry was made
+            BACKING_FAILURES.with(|f| f.set(4));
+            let result = std::panic::catch_unwind(|| init_mapped(pool_ptr, 1 << MIN_K, &options));
+            assert!(!matches!(result, Ok(0)));
+            assert_eq!(BACKING_FAILURES.with(|f| f.get()), 0);
+        }
+    }
+
+    #[test]
+    fn test_buddy_export_prometheus() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+            buddy_malloc(pool_ref, 1);
+
+            let needed = buddy_export_prometheus(pool_ref, ptr::null_mut(), 0);
+            assert!(needed > 0);
+
+            let mut buffer = vec![0u8; needed];
+            assert_eq!(buddy_export_prometheus(pool_ref, buffer.as_mut_ptr(), needed - 1), 0);
+            assert_eq!(buddy_export_prometheus(pool_ref, buffer.as_mut_ptr(), needed), needed);
+
+            let text = String::from_utf8(buffer).unwrap();
+            let sample = |name: &str| -> f64 {
+                let line = text.lines().find(|line| line.starts_with(&format!("{name} "))).unwrap();
+                line[name.len() + 1..].parse().unwrap()
+            };
+
+            assert_eq!(sample("buddy_total_bytes"), (1 << MIN_K) as f64);
+            assert_eq!(sample("buddy_free_bytes"), ((1 << MIN_K) - (1 << SMALLEST_K)) as f64);
+            assert_eq!(sample("buddy_live_allocations"), 1.0);
+
+            let fragmentation = sample("buddy_fragmentation_ratio");
+            assert!(fragmentation > 0.0 && fragmentation < 1.0);
+
+            assert_eq!(sample(&format!("buddy_free_blocks{{kval=\"{}\"}}", SMALLEST_K)), 1.0);
+            assert_eq!(sample(&format!("buddy_free_blocks{{kval=\"{}\"}}", MIN_K)), 0.0);
+            assert!(text.contains("# TYPE buddy_free_blocks gauge"));
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_swap() {
+        let mut a = MaybeUninit::<BuddyPool>::uninit();
+        let mut b = MaybeUninit::<BuddyPool>::uninit();
+        let (a, b) = (a.as_mut_ptr(), b.as_mut_ptr());
+
+        unsafe {
+            buddy_init(a, 1 << MIN_K);
+            buddy_init(b, 1 << (MIN_K + 1));
+
+            let in_a = buddy_malloc(a, 100) as *mut u8;
+            *in_a = 0xA;
+            let in_b = buddy_malloc(b, 5000) as *mut u8;
+            *in_b = 0xB;
+            let (base_a, base_b) = ((*a).base, (*b).base);
+
+            assert_eq!(buddy_swap(a, b), 0);
+            assert_eq!(buddy_swap(a, a), EINVAL);
+
+            assert_eq!((*a).base, base_b);
+            assert_eq!((*a).kval_m, MIN_K + 1);
+            assert_eq!((*b).base, base_a);
+            assert_eq!((*b).kval_m, MIN_K);
+
+            // Allocations follow their memory and the lists are usable from the new owner
+            assert!(buddy_is_block_start(a, in_b as *mut c_void));
+            assert!(buddy_is_block_start(b, in_a as *mut c_void));
+            assert_eq!(*in_b, 0xB);
+
+            buddy_free(a, in_b as *mut c_void);
+            buddy_free(b, in_a as *mut c_void);
+            check_buddy_pool_full(&mut *a);
+            check_buddy_pool_full(&mut *b);
+
+            let mem = buddy_malloc(a, 1 << MIN_K);
+            assert!(!mem.is_null());
+            buddy_free(a, mem);
+
+            buddy_destroy(a);
+            buddy_destroy(b);
+        }
+    }
+
+    #[test]
+    fn test_buddy_lifetime_split_limit() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+            buddy_set_lifetime_split_limit(pool_ref, 3);
+
+            // Three splits carve a block of kval_m - 3 and leave its buddies free
+            let size = (1 << (MIN_K - 3)) - std::mem::size_of::<Avail>();
+            let first = buddy_malloc(pool_ref, size);
+            assert!(!first.is_null());
+            assert_eq!(pool_ref.split_count, 3);
+
+            *__errno_location() = 0;
+            assert!(buddy_malloc(pool_ref, 1).is_null());
+            assert_eq!(*__errno_location(), EAGAIN);
+
+            // The free buddy of the first block needs no split
+            let second = buddy_malloc(pool_ref, size);
+            assert!(!second.is_null());
+
+            buddy_set_lifetime_split_limit(pool_ref, 0);
+            assert!(!buddy_malloc(pool_ref, 1).is_null());
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_natural_alignment() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+        let header = std::mem::size_of::<Avail>();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            let small = buddy_malloc(pool_ref, 1);
+            let large = buddy_malloc(pool_ref, (1 << (MIN_K - 2)) - header);
+
+            assert_eq!(buddy_natural_alignment(pool_ref, small), 1 << SMALLEST_K);
+            assert_eq!(buddy_natural_alignment(pool_ref, large), 1 << (MIN_K - 2));
+
+            // The block, not the user pointer, carries the alignment
+            let block = large as usize - header;
+            assert_eq!(block % buddy_natural_alignment(pool_ref, large), 0);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_destroy_dry_run() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+        let (mut unmap, mut outstanding) = (0, 0);
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            let mem = buddy_malloc(pool_ref, 1000);
+            assert_eq!(buddy_destroy_dry_run(pool_ref, &mut unmap, &mut outstanding), 0);
+            assert_eq!(unmap, pool_ref.numbytes);
+            assert_eq!(outstanding, 1 << request_class(1000));
+
+            // Nothing was torn down
+            assert!(buddy_is_block_start(pool_ref, mem));
+            buddy_free(pool_ref, mem);
+
+            buddy_destroy_dry_run(pool_ref, &mut unmap, &mut outstanding);
+            assert_eq!(outstanding, 0);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_trailing_header() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+        let options = BuddyInitOptions { flags: INIT_TRAILING_HEADER, ..Default::default() };
+        let header = std::mem::size_of::<Avail>();
+
+        unsafe {
+            assert_eq!(buddy_init_with(pool_ptr, 1 << MIN_K, &options), 0);
+            let pool_ref = &mut *pool_ptr;
+
+            // User pointers are the blocks themselves, the first one is base
+            let sizes = [1, 100, (1 << 12) - header, 5000, 1];
+            let mut mems = Vec::new();
+            for size in sizes {
+                let mem = buddy_malloc(pool_ref, size) as *mut u8;
+                assert!(!mem.is_null());
+                assert_eq!(mem as usize % (1 << request_class(size)), 0);
+                assert!(buddy_is_block_start(pool_ref, mem as *mut c_void));
+
+                // Filling the whole usable area leaves the header intact
+                ptr::write_bytes(mem, 0xFF, size);
+                mems.push(mem as *mut c_void);
+            }
+            assert_eq!(mems[0], pool_ref.base);
+            assert!(buddy_check_layout(pool_ref));
+
+            // Aligned allocations and reallocation work on top of it
+            let aligned = buddy_aligned_calloc(pool_ref, 256, 1, 100);
+            assert_eq!(aligned as usize % 256, 0);
+            let grown = buddy_realloc(pool_ref, mems[1], 3000) as *mut u8;
+            assert_eq!(*grown.add(99), 0xFF);
+            mems[1] = grown as *mut c_void;
+
+            for mem in mems {
+                assert_eq!(buddy_free(pool_ref, mem), BuddyError::Ok);
+            }
+            buddy_free(pool_ref, aligned);
+
+            check_buddy_pool_full(pool_ref);
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_active_classes() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+            assert_eq!(buddy_active_classes(pool_ref), 1);
+
+            // Splitting down to the smallest class leaves one buddy in every class below kval_m
+            let mem = buddy_malloc(pool_ref, 1);
+            assert_eq!(buddy_active_classes(pool_ref), MIN_K - SMALLEST_K);
+
+            buddy_free(pool_ref, mem);
+            assert_eq!(buddy_active_classes(pool_ref), 1);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_oom_errno() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+            let all = buddy_malloc(pool_ref, (1 << MIN_K) - std::mem::size_of::<Avail>());
+
+            *__errno_location() = 0;
+            assert!(buddy_malloc(pool_ref, 1).is_null());
+            assert_eq!(*__errno_location(), ENOMEM);
+
+            buddy_set_oom_errno(pool_ref, EAGAIN);
+            *__errno_location() = 0;
+            assert!(buddy_malloc(pool_ref, 1).is_null());
+            assert_eq!(*__errno_location(), EAGAIN);
+
+            buddy_set_oom_errno(pool_ref, 0);
+            assert!(buddy_malloc(pool_ref, 1).is_null());
+            assert_eq!(*__errno_location(), ENOMEM);
+
+            buddy_free(pool_ref, all);
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_slabify() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            let slots = buddy_slabify(pool_ref, 128);
+            let slot_k = request_class(128);
+            assert_eq!(slots, 1 << (MIN_K - slot_k));
+            assert_eq!(count_free(pool_ref, slot_k), slots);
+            assert_eq!(buddy_active_classes(pool_ref), 1);
+
+            let splits = pool_ref.split_count;
+            for _ in 0..slots {
+                assert!(!buddy_malloc(pool_ref, 128).is_null());
+            }
+            assert_eq!(pool_ref.split_count, splits);
+            assert!(buddy_malloc(pool_ref, 128).is_null());
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_has_buddy() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            let top = pool_ref.base as *mut Avail;
+            assert!(!buddy_has_buddy(pool_ref, top));
+            assert_eq!(buddy_calc(pool_ref, top), top);
+
+            let mem = buddy_malloc(pool_ref, 1);
+            let block = block_of(pool_ref, mem);
+            assert!(buddy_has_buddy(pool_ref, block));
+            assert_eq!(buddy_calc(pool_ref, block) as usize, block as usize + (1 << SMALLEST_K));
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_malloc_at() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+        let header = std::mem::size_of::<Avail>();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+            let base = pool_ref.base as usize;
+
+            let offset = 3 << 12;
+            let mem = buddy_malloc_at(pool_ref, offset, 1000);
+            assert_eq!(mem as usize - base, offset + header);
+            assert!(buddy_check_layout(pool_ref));
+
+            // Blocks overlapping it, from below and from above, are refused
+            *__errno_location() = 0;
+            assert!(buddy_malloc_at(pool_ref, offset, 100).is_null());
+            assert_eq!(*__errno_location(), ENOMEM);
+            assert!(buddy_malloc_at(pool_ref, offset + 1024, 100).is_null());
+            assert!(buddy_malloc_at(pool_ref, 0, (1 << 14) - header).is_null());
+
+            // Misaligned offsets are rejected
+            assert!(buddy_malloc_at(pool_ref, 100, 100).is_null());
+            assert_eq!(*__errno_location(), EINVAL);
+
+            // The free neighbours are still available
+            let next = buddy_malloc_at(pool_ref, offset + 2048, 1000);
+            assert_eq!(next as usize - base, offset + 2048 + header);
+            let first = buddy_malloc_at(pool_ref, 0, 1);
+            assert_eq!(first as usize - base, header);
+
+            buddy_free(pool_ref, mem);
+            buddy_free(pool_ref, next);
+            buddy_free(pool_ref, first);
+            check_buddy_pool_full(pool_ref);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_audit_counters() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            let mem = buddy_malloc(pool_ref, 1000);
+            buddy_malloc(pool_ref, 1);
+            assert_eq!(pool_ref.free_bytes, (1 << MIN_K) - (1 << request_class(1000)) - (1 << SMALLEST_K));
+            assert!(buddy_audit_counters(pool_ref, false));
+
+            // A drifted counter is reported but left alone without fix
+            pool_ref.free_bytes += 4096;
+            assert!(!buddy_audit_counters(pool_ref, false));
+            assert!(!buddy_audit_counters(pool_ref, false));
+
+            // and repaired with it
+            assert!(!buddy_audit_counters(pool_ref, true));
+            assert!(buddy_audit_counters(pool_ref, false));
+
+            pool_ref.live_count = 0;
+            assert!(!buddy_audit_counters(pool_ref, true));
+            assert_eq!(pool_ref.live_count, 2);
+
+            buddy_free(pool_ref, mem);
+            assert!(buddy_audit_counters(pool_ref, false));
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_malloc_headerless() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let size = 1 << MIN_K;
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), size);
+            let pool = pool.assume_init_mut();
+
+            // A power of two needs no bigger block than itself
+            let block = buddy_malloc_headerless(pool, 1 << 12) as *mut u8;
+            assert!(!block.is_null());
+            assert_eq!(block as usize, pool.base as usize);
+            assert_eq!(pool.live_count, 1);
+
+            // The whole block belongs to the caller, header bytes included
+            ptr::write_bytes(block, 0xAB, 1 << 12);
+
+            // Data that looks like a free header must not be merged with its buddy
+            let fake = block as *mut Avail;
+            (*fake).tag = BLOCK_AVAIL;
+            (*fake).kval = 12;
+            (*fake).prev = &mut pool.avail[12];
+            let other = buddy_malloc(pool, 1 << 11);
+            assert!(!other.is_null());
+            buddy_free(pool, other);
+            assert_eq!((*fake).kval, 12);
+            assert_eq!(*block.add((1 << 12) - 1), 0xAB);
+
+            // A size larger than the pool or a pointer off the grid of its class is refused
+            assert_eq!(buddy_free_headerless(pool, block as *mut c_void, size * 2), BuddyError::InvalidPointer);
+            assert_eq!(buddy_free_headerless(pool, block.add(1 << 11) as *mut c_void, 1 << 12), BuddyError::InvalidPointer);
+            assert_eq!(buddy_free_headerless(pool, block.add(64) as *mut c_void, 1 << 12), BuddyError::InvalidPointer);
+            assert_eq!(pool.headerless_count, 1);
+
+            assert_eq!(buddy_free_headerless(pool, block as *mut c_void, 1 << 12), BuddyError::Ok);
+            assert_eq!(pool.headerless_count, 0);
+            check_buddy_pool_full(pool);
+
+            // Freeing it again, or a block never handed out headerless, leaves the lists alone
+            assert_eq!(buddy_free_headerless(pool, block as *mut c_void, 1 << 12), BuddyError::DoubleFree);
+            let live = buddy_malloc_headerless(pool, 1 << 12) as *mut u8;
+            assert_eq!(buddy_free_headerless(pool, live.add(1 << 12) as *mut c_void, 1 << 12), BuddyError::DoubleFree);
+            assert_eq!(buddy_free_headerless(pool, live.add(1 << 13) as *mut c_void, 1 << 12), BuddyError::DoubleFree);
+            assert_eq!(buddy_free_headerless(pool, live as *mut c_void, 1 << 12), BuddyError::Ok);
+            assert_eq!(buddy_free_headerless(pool, live as *mut c_void, 1 << 12), BuddyError::DoubleFree);
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_try_alloc() {
+        let buddy = Buddy::new(1 << MIN_K);
+
+        assert_eq!(buddy.try_alloc(0), Err(BuddyError::ZeroSize));
+
+        let mem = buddy.try_alloc(100).unwrap();
+        let zeroed = buddy.try_alloc_zeroed(300).unwrap();
+        assert!(unsafe { std::slice::from_raw_parts(zeroed.as_ptr(), 300) }.iter().all(|&b| b == 0));
+
+        unsafe {
+            *mem.as_ptr() = 7;
+            let grown = buddy.try_realloc(mem, 4000).unwrap();
+            assert_eq!(*grown.as_ptr(), 7);
+
+            // The whole pool can not be had while blocks are live
+            assert_eq!(buddy.try_alloc(1 << MIN_K), Err(BuddyError::OutOfMemory));
+            assert_eq!(buddy.try_realloc(grown, 1 << MIN_K), Err(BuddyError::OutOfMemory));
+            assert_eq!(buddy.try_realloc(grown, 0), Err(BuddyError::ZeroSize));
+            assert_eq!(*grown.as_ptr(), 7);
+
+            buddy_free(buddy.as_ptr(), grown.as_ptr() as *mut c_void);
+            buddy_free(buddy.as_ptr(), zeroed.as_ptr() as *mut c_void);
+        }
+
+        check_buddy_pool_full(unsafe { &mut *buddy.as_ptr() });
+    }
+
+    #[test]
+    fn test_buddy_coalesce_all() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            // Free a scattered pool without merging anything
+            buddy_set_policy(pool, BuddyPolicy { flags: 0, strategy: STRATEGY_LIFO });
+            let blocks: Vec<_> = (0..64).map(|i| buddy_malloc(pool, 1 + 100 * (i % 5))).collect();
+            for &block in blocks.iter().rev().step_by(2).chain(blocks.iter().step_by(2)) {
+                buddy_free(pool, block);
+            }
+            assert_eq!(count_free(pool, MIN_K), 0);
+
+            assert_eq!(buddy_coalesce_all(pool), MIN_K as u16);
+            check_buddy_pool_full(pool);
+
+            // Live blocks keep their buddies apart
+            let live = buddy_malloc(pool, 1);
+            buddy_free(pool, buddy_malloc(pool, 1));
+            assert_eq!(buddy_coalesce_all(pool), (MIN_K - 1) as u16);
+            assert_eq!(count_free(pool, SMALLEST_K), 1);
+
+            buddy_free(pool, live);
+            buddy_coalesce_all(pool);
+            check_buddy_pool_full(pool);
+
+            assert_eq!(buddy_coalesce_all(ptr::null_mut()), 0);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_remove_block() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            // The sentinels stay linked
+            let sentinel: *mut Avail = &mut pool.avail[MIN_K];
+            assert_eq!(buddy_remove_block(pool, sentinel), EINVAL);
+            assert_eq!(count_free(pool, MIN_K), 1);
+
+            assert_eq!(buddy_remove_block(pool, ptr::null_mut()), EINVAL);
+            assert_eq!(buddy_remove_block(ptr::null_mut(), pool.base as *mut Avail), EINVAL);
+
+            // Reserved blocks are not on any list
+            let mem = buddy_malloc(pool, 1);
+            let buddy = buddy_calc(pool, block_of(pool, mem));
+            assert_eq!(buddy_remove_block(pool, block_of(pool, mem)), EINVAL);
+
+            assert_eq!(buddy_remove_block(pool, buddy), 0);
+            assert_eq!(count_free(pool, SMALLEST_K), 0);
+
+            // Handing the block back restores the pool
+            super::insert_block(pool, buddy);
+            buddy_free(pool, mem);
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_usable_capacity() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let usable = 256 - std::mem::size_of::<Avail>();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            let slots = buddy_slabify(pool, usable);
+            assert_eq!(buddy_usable_capacity(pool, 8), slots * usable);
+            assert_eq!(buddy_usable_capacity(pool, MIN_K), (1 << MIN_K) - std::mem::size_of::<Avail>());
+
+            assert_eq!(buddy_usable_capacity(pool, SMALLEST_K - 1), 0);
+            assert_eq!(buddy_usable_capacity(pool, MIN_K + 1), 0);
+            assert_eq!(buddy_usable_capacity(ptr::null_mut(), 8), 0);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_free_tagged() {
+        let _serial = ASSERT_SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        ASSERT_MESSAGES.lock().unwrap().clear();
+
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            buddy_set_assert_handler(Some(record_assert));
+
+            let mem = buddy_malloc(pool, 100);
+            let other = buddy_malloc(pool, 100);
+            assert_eq!(buddy_free_tagged(pool, mem, 0x1111), BuddyError::Ok);
+
+            // The second free names the first one and changes nothing
+            assert_eq!(buddy_free_tagged(pool, mem, 0x2222), BuddyError::DoubleFree);
+            assert_eq!(buddy_free_tagged(pool, other, 0x3333), BuddyError::Ok);
+            check_buddy_pool_full(pool);
+
+            buddy_set_assert_handler(None);
+
+            let messages = ASSERT_MESSAGES.lock().unwrap();
+            assert_eq!(messages.len(), 1);
+            assert!(messages[0].contains("already freed by caller 0x1111"), "{}", messages[0]);
+            assert!(messages[0].contains("again by caller 0x2222"), "{}", messages[0]);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_free_tagged_no_handler() {
+        let _serial = ASSERT_SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            let mem = buddy_malloc(pool, 100);
+            let other = buddy_malloc(pool, 100);
+            assert_eq!(buddy_freed_by(pool, mem), 0);
+            assert_eq!(buddy_free_tagged(pool, mem, 0x1111), BuddyError::Ok);
+            assert_eq!(buddy_freed_by(pool, mem), 0x1111);
+
+            // Without a handler the second free is only an error code
+            assert_eq!(buddy_free_tagged(pool, mem, 0x2222), BuddyError::DoubleFree);
+            assert_eq!(buddy_freed_by(pool, mem), 0x1111);
+
+            assert_eq!(buddy_free_tagged(pool, other, 0x3333), BuddyError::Ok);
+            check_buddy_pool_full(pool);
+            assert_eq!(buddy_freed_by(ptr::null_mut(), mem), 0);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_can_alloc_aligned() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            assert!(buddy_can_alloc_aligned(pool, 100, 64));
+            assert!(!buddy_can_alloc_aligned(pool, 100, 48));
+            assert!(!buddy_can_alloc_aligned(pool, 0, 64));
+            assert!(!buddy_can_alloc_aligned(pool, usize::MAX, 64));
+
+            // Only half of the pool is left
+            let half = buddy_malloc(pool, 1);
+            assert_eq!(find_free_class(pool, MIN_K - 1), MIN_K - 1);
+
+            // A quarter of the pool fits as is, but not with a quarter of padding on top
+            let size = 1 << (MIN_K - 2);
+            assert!(!buddy_can_alloc_aligned(pool, size, size));
+            assert!(buddy_aligned_calloc(pool, size, 1, size).is_null());
+            assert!(buddy_can_alloc_aligned(pool, size, 64));
+
+            let plain = buddy_malloc(pool, size);
+            assert!(!plain.is_null());
+
+            buddy_free(pool, plain);
+            buddy_free(pool, half);
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_borrow_top() {
+        let buddy = Buddy::new(1 << MIN_K);
+
+        {
+            let mut scratch = buddy.borrow_top().unwrap();
+            assert_eq!(scratch.len(), 1 << MIN_K);
+            scratch.fill(0xCD);
+
+            // Nothing else is left while the scratch memory is out
+            assert!(buddy.borrow_top().is_none());
+            assert_eq!(buddy.try_alloc(1), Err(BuddyError::OutOfMemory));
+        }
+
+        check_buddy_pool_full(unsafe { &mut *buddy.as_ptr() });
+
+        // A split pool has no top block to lend
+        let mem = buddy.try_alloc(1).unwrap();
+        assert!(buddy.borrow_top().is_none());
+        buddy_free(buddy.as_ptr(), mem.as_ptr() as *mut c_void);
+        assert!(buddy.borrow_top().is_some());
+    }
+
+    #[test]
+    fn test_buddy_block_at_address() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let mut info = BlockInfo { offset: 0, kval: 0, tag: 0 };
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            let first = buddy_malloc(pool, 1);
+            let mem = buddy_malloc(pool, 1000) as *mut u8;
+            let block = block_of(pool, mem as *mut c_void) as usize - pool.base as usize;
+
+            assert!(buddy_block_at_address(pool, mem.add(500) as *const c_void, &mut info));
+            assert_eq!(info, BlockInfo { offset: block, kval: 11, tag: BLOCK_RESERVED });
+
+            // The header belongs to the block as well
+            assert!(buddy_block_at_address(pool, (pool.base as *const u8).add(block) as *const c_void, &mut info));
+            assert_eq!(info.offset, block);
+
+            // Free memory is reported as such
+            assert!(buddy_block_at_address(pool, (pool.base as *const u8).add((1 << MIN_K) - 1) as *const c_void, &mut info));
+            assert_eq!(info, BlockInfo { offset: 1 << (MIN_K - 1), kval: (MIN_K - 1) as u16, tag: BLOCK_AVAIL });
+
+            assert!(!buddy_block_at_address(pool, (pool.base as *const u8).add(1 << MIN_K) as *const c_void, &mut info));
+            assert!(!buddy_block_at_address(pool, ptr::null(), &mut info));
+
+            buddy_free(pool, mem as *mut c_void);
+            buddy_free(pool, first);
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_eager_policy() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            buddy_set_policy(pool, BuddyPolicy { flags: POLICY_COALESCE | POLICY_DEFERRED | POLICY_EAGER, strategy: STRATEGY_LIFO });
+
+            // Two small blocks freed unmerged, buddies of each other
+            let a = buddy_malloc(pool, 1);
+            let b = buddy_malloc(pool, 1);
+            buddy_free(pool, a);
+            buddy_free(pool, b);
+            assert_eq!(count_free(pool, SMALLEST_K), 2);
+            assert_eq!(count_free(pool, SMALLEST_K + 1), 1);
+
+            // Taking both the merged pair and the free block leaves the larger blocks whole
+            let splits = pool.split_count;
+            let first = buddy_malloc(pool, 64);
+            let second = buddy_malloc(pool, 64);
+            assert_eq!(pool.split_count, splits);
+            assert_eq!(count_free(pool, SMALLEST_K), 0);
+            assert_eq!(count_free(pool, SMALLEST_K + 1), 0);
+
+            buddy_free(pool, first);
+            buddy_free(pool, second);
+            buddy_coalesce_all(pool);
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_calloc() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            // Leave garbage behind for calloc to clean up
+            let dirty = buddy_malloc(pool, 4000) as *mut u8;
+            ptr::write_bytes(dirty, 0xEE, 4000);
+            buddy_free(pool, dirty as *mut c_void);
+
+            let mem = buddy_calloc(pool, 100, 40) as *mut u8;
+            assert_eq!(mem, dirty);
+            let header = block_of(pool, mem as *mut c_void);
+            assert_eq!((*header).tag, BLOCK_RESERVED);
+
+            let usable = user_end(pool, header) - mem as usize;
+            assert!(std::slice::from_raw_parts(mem, usable).iter().all(|&b| b == 0));
+
+            assert!(buddy_calloc(pool, usize::MAX, 2).is_null());
+            assert_eq!(*__errno_location(), ENOMEM);
+            assert!(buddy_calloc(pool, 0, 8).is_null());
+            assert!(buddy_calloc(pool, 8, 0).is_null());
+            assert!(buddy_calloc(ptr::null_mut(), 8, 8).is_null());
+
+            buddy_free(pool, mem as *mut c_void);
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_depth() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 0);
+            let pool = pool.assume_init_mut();
+            assert_eq!(buddy_depth(pool), DEFAULT_K - SMALLEST_K);
+            buddy_destroy(pool);
+
+            buddy_init(pool, 1 << MIN_K);
+
+            // No allocation can split more often than that
+            buddy_free(pool, buddy_malloc(pool, 1));
+            assert_eq!(buddy_depth(pool), MIN_K - SMALLEST_K);
+            assert_eq!(buddy_max_split_depth(pool) as usize, buddy_depth(pool));
+            buddy_destroy(pool);
+
+            assert_eq!(buddy_depth(ptr::null_mut()), 0);
+        }
+    }
+
+    #[test]
+    fn test_buddy_realloc_in_place() {
+        for flags in [0, INIT_TRAILING_HEADER] {
+            let mut pool = MaybeUninit::<BuddyPool>::uninit();
+            let options = BuddyInitOptions { flags, ..Default::default() };
+
+            unsafe {
+                assert_eq!(buddy_init_with(pool.as_mut_ptr(), 1 << MIN_K, &options), 0);
+                let pool = pool.assume_init_mut();
+
+                let mem = buddy_malloc(pool, 4000) as *mut u8;
+                for i in 0..100 {
+                    *mem.add(i) = i as u8;
+                }
+
+                // Same class
+                assert_eq!(buddy_realloc(pool, mem as *mut c_void, 3000), mem as *mut c_void);
+
+                // Shrinking hands the upper halves back
+                assert_eq!(buddy_realloc(pool, mem as *mut c_void, 100), mem as *mut c_void);
+                assert_eq!(count_free(pool, 8), 1);
+                assert_eq!(count_free(pool, 11), 1);
+                assert_eq!((*block_of(pool, mem as *mut c_void)).kval, 8);
+
+                // Growing takes the free buddies above
+                let splits = pool.split_count;
+                assert_eq!(buddy_realloc(pool, mem as *mut c_void, 16000), mem as *mut c_void);
+                assert_eq!(pool.split_count, splits);
+                assert_eq!((*block_of(pool, mem as *mut c_void)).kval, 14);
+                assert!((0..100).all(|i| *mem.add(i) == i as u8));
+
+                // A live buddy above forces a move
+                let next = buddy_malloc(pool, 16000);
+                assert_eq!(next as usize, mem as usize + (1 << 14));
+                let moved = buddy_realloc(pool, mem as *mut c_void, 20000) as *mut u8;
+                assert!(!moved.is_null() && moved != mem);
+                assert!((0..100).all(|i| *moved.add(i) == i as u8));
+
+                buddy_free(pool, next);
+                buddy_free(pool, moved as *mut c_void);
+                check_buddy_pool_full(pool);
+
+                buddy_destroy(pool);
+            }
+        }
+    }
+
+    #[test]
+    fn test_buddy_aligned_alloc() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            // Knock the pool off its natural alignment first
+            let first = buddy_malloc(pool, 1);
+
+            let mut blocks = Vec::new();
+            for alignment in [8, 64, 4096, 1 << 16] {
+                let mem = buddy_aligned_alloc(pool, alignment, 100);
+                assert!(!mem.is_null());
+                assert_eq!(mem as usize & (alignment - 1), 0);
+                ptr::write_bytes(mem as *mut u8, 0xA5, 100);
+                blocks.push(mem);
+            }
+
+            assert!(buddy_aligned_alloc(pool, 48, 100).is_null());
+            assert_eq!(*__errno_location(), EINVAL);
+            assert!(buddy_aligned_alloc(pool, 64, 0).is_null());
+
+            for mem in blocks {
+                assert_eq!(buddy_free(pool, mem), BuddyError::Ok);
+            }
+            buddy_free(pool, first);
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_free_bits() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+            assert!(!pool.free_bits.is_null());
+
+            let mut live: Vec<_> = (0..40).map(|i| buddy_malloc(pool, 1 + 300 * (i % 7))).collect();
+            for i in (0..live.len()).rev().step_by(3) {
+                buddy_free(pool, live.remove(i));
+            }
+
+            let base = pool.base as usize;
+            for k in SMALLEST_K..=MIN_K {
+                let mut on_list = vec![false; pool.numbytes >> k];
+                let head: *mut Avail = &mut pool.avail[k];
+                let mut block = (*head).next;
+                while block != head {
+                    on_list[(block as usize - base) >> k] = true;
+                    block = (*block).next;
+                }
+
+                for (i, &free) in on_list.iter().enumerate() {
+                    let block = (base + (i << k)) as *mut Avail;
+                    assert_eq!(is_free_at(pool, block, k), free, "class {} block {}", k, i);
+
+                    // Every free block the bits know of has a free header as well
+                    let bits = std::mem::replace(&mut pool.free_bits, ptr::null_mut());
+                    let by_header = is_free_at(pool, block, k);
+                    pool.free_bits = bits;
+                    if free {
+                        assert!(by_header, "class {} block {}", k, i);
+                    }
+                }
+            }
+
+            for mem in live {
+                buddy_free(pool, mem);
+            }
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+            assert!(pool.free_bits.is_null());
+        }
+    }
+
+    #[test]
+    fn test_buddy_malloc_explained() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let mut reason = BuddyError::Ok;
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            assert!(buddy_malloc_explained(ptr::null_mut(), 10, &mut reason).is_null());
+            assert_eq!(reason, BuddyError::NullPool);
+
+            assert!(buddy_malloc_explained(pool, 0, &mut reason).is_null());
+            assert_eq!(reason, BuddyError::ZeroSize);
+
+            assert!(buddy_malloc_explained(pool, usize::MAX, &mut reason).is_null());
+            assert_eq!(reason, BuddyError::TooLarge);
+
+            let mem = buddy_malloc_explained(pool, 10, &mut reason);
+            assert!(!mem.is_null());
+            assert_eq!(reason, BuddyError::Ok);
+
+            // Would fit an empty pool, but not this one
+            let whole = (1 << MIN_K) - std::mem::size_of::<Avail>();
+            assert!(buddy_malloc_explained(pool, whole, &mut reason).is_null());
+            assert_eq!(reason, BuddyError::OutOfMemory);
+
+            // With the free class 11 block gone, the next one needs a split the limit forbids
+            let taken = buddy_malloc(pool, 2000);
+            buddy_set_lifetime_split_limit(pool, pool.split_count);
+            assert!(buddy_malloc_explained(pool, 2000, &mut reason).is_null());
+            assert_eq!(reason, BuddyError::Throttled);
+            buddy_set_lifetime_split_limit(pool, 0);
+
+            buddy_free(pool, taken);
+            buddy_free(pool, mem);
+            check_buddy_pool_full(pool);
+
+            // A destroyed pool is zeroed and can not hold anything
+            buddy_destroy(pool);
+            assert!(buddy_malloc_explained(pool, 1, &mut reason).is_null());
+            assert_eq!(reason, BuddyError::TooLarge);
+        }
+    }
+
+    #[test]
+    fn test_buddy_free_errors() {
+        for flags in [0, INIT_TRAILING_HEADER] {
+            let mut pool = MaybeUninit::<BuddyPool>::uninit();
+            let options = BuddyInitOptions { flags, ..Default::default() };
+
+            unsafe {
+                assert_eq!(buddy_init_with(pool.as_mut_ptr(), 1 << MIN_K, &options), 0);
+                let pool = pool.assume_init_mut();
+
+                let mem = buddy_malloc(pool, 100);
+                let aligned = buddy_aligned_alloc(pool, 4096, 100);
+
+                assert_eq!(buddy_free(ptr::null_mut(), mem), BuddyError::NullPool);
+                assert_eq!(buddy_free(pool, ptr::null_mut()), BuddyError::NullPtr);
+
+                let mut local = 0u64;
+                assert_eq!(buddy_free(pool, &mut local as *mut u64 as *mut c_void), BuddyError::InvalidPointer);
+                assert_eq!(buddy_free(pool, (pool.base as *mut u8).add(1 << MIN_K) as *mut c_void), BuddyError::InvalidPointer);
+
+                assert_eq!(buddy_free(pool, mem), BuddyError::Ok);
+                assert_eq!(buddy_free(pool, mem), BuddyError::DoubleFree);
+                assert_eq!(buddy_free(pool, aligned), BuddyError::Ok);
+                assert_eq!(buddy_free(pool, aligned), BuddyError::DoubleFree);
+                check_buddy_pool_full(pool);
+
+                buddy_destroy(pool);
+            }
+        }
+    }
+
+    #[test]
+    fn test_lazy_buddy() {
+        static POOL: LazyBuddy = LazyBuddy::new(1 << MIN_K);
+
+        let barrier = std::sync::Barrier::new(8);
+        let pools: Vec<usize> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+
+                        let mem = POOL.alloc(100).unwrap();
+                        unsafe { assert_eq!(POOL.free(mem), BuddyError::Ok) };
+
+                        POOL.as_ptr() as usize
+                    })
+                })
+                .collect();
+
+            threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+        });
+
+        // Every thread got the one pool there is
+        assert!(pools.iter().all(|&pool| pool == pools[0]));
+        check_buddy_pool_full(unsafe { &mut *POOL.as_ptr() });
+    }
+
+    #[test]
+    fn test_buddy_wrapper() {
+        let base;
+
+        {
+            let buddy = Buddy::new(1 << MIN_K);
+            base = unsafe { (*buddy.as_ptr()).base } as usize;
+
+            let mem = buddy.alloc(100).unwrap();
+            unsafe { *mem.as_ptr() = 1 };
+            assert!(buddy.alloc(0).is_none());
+            assert!(buddy.alloc(1 << MIN_K).is_none());
+
+            assert_eq!(unsafe { buddy.free(mem) }, BuddyError::Ok);
+            check_buddy_pool_full(unsafe { &mut *buddy.as_ptr() });
+        }
+
+        // Dropping the Buddy gave the region back
+        assert_eq!(LAST_UNMAP.with(Cell::get), base);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", not(feature = "poison")))]
+    fn test_buddy_reclaim() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let page = page_size();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            // Keep the first block busy so the dirty one can not merge away
+            let first = buddy_malloc(pool, 1);
+            let size = 60000;
+            let dirty = buddy_malloc(pool, size) as *mut u8;
+            ptr::write_bytes(dirty, 0xAA, size);
+            buddy_free(pool, dirty as *mut c_void);
+
+            // A small target stops after the first large block
+            let some = buddy_reclaim(pool, page);
+            assert!(some >= page && some & (page - 1) == 0);
+            assert!(some < pool.free_bytes);
+
+            let all = buddy_reclaim(pool, usize::MAX);
+            assert!(all > some);
+
+            // The block comes back zeroed past its first page
+            let mem = buddy_malloc(pool, size) as *mut u8;
+            assert_eq!(mem, dirty);
+            let skip = ((mem as usize + page - 1) & !(page - 1)) - mem as usize;
+            assert!(std::slice::from_raw_parts(mem.add(skip), size - skip).iter().all(|&b| b == 0));
+
+            buddy_free(pool, mem as *mut c_void);
+            buddy_free(pool, first);
+            check_buddy_pool_full(pool);
+
+            assert_eq!(buddy_reclaim(ptr::null_mut(), page), 0);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_memory_map() {
+        let buddy = Buddy::new(1 << MIN_K);
+        let size = 1 << MIN_K;
+
+        assert_eq!(buddy.memory_map(), [MapEntry { offset: 0, len: size, reserved: false }]);
+
+        // Two neighbouring blocks show as one range, a gap splits them
+        let a = buddy.alloc(100).unwrap();
+        let b = buddy.alloc(100).unwrap();
+        let gap = buddy.alloc(1000).unwrap();
+        let c = buddy.alloc(1000).unwrap();
+        unsafe { buddy.free(gap) };
+
+        assert_eq!(
+            buddy.memory_map(),
+            [
+                MapEntry { offset: 0, len: 512, reserved: true },
+                MapEntry { offset: 512, len: 512 + 1024 + 2048, reserved: false },
+                MapEntry { offset: 4096, len: 2048, reserved: true },
+                MapEntry { offset: 6144, len: size - 6144, reserved: false },
+            ]
+        );
+
+        unsafe {
+            for mem in [a, b, c] {
+                buddy.free(mem);
+            }
+        }
+
+        let all = buddy.alloc(size - std::mem::size_of::<Avail>()).unwrap();
+        assert_eq!(buddy.memory_map(), [MapEntry { offset: 0, len: size, reserved: true }]);
+        unsafe { buddy.free(all) };
+    }
+
+    #[test]
+    #[cfg(feature = "allocator_api")]
+    fn test_buddy_allocator() {
+        let buddy = Buddy::new(1 << MIN_K);
+        let alloc = BuddyAllocator::new(&buddy);
+
+        {
+            let mut numbers = Vec::with_capacity_in(10, alloc);
+            numbers.extend(0..10u64);
+
+            // The block is larger than asked for and the Vec may use all of it
+            assert!(numbers.capacity() >= 10);
+            assert_eq!(numbers.iter().sum::<u64>(), 45);
+
+            let boxed = Box::new_in([1u8; 5000], alloc);
+            assert_eq!(boxed.iter().map(|&b| b as usize).sum::<usize>(), 5000);
+
+            #[repr(align(4096))]
+            struct Page([u8; 64]);
+            let page = Box::new_in(Page([3; 64]), alloc);
+            assert_eq!(&*page as *const Page as usize % 4096, 0);
+            assert_eq!(page.0[63], 3);
+
+            let empty = Box::new_in((), alloc);
+            assert_eq!(&*empty as *const () as usize, 1);
+        }
+
+        check_buddy_pool_full(unsafe { &mut *buddy.as_ptr() });
+    }
+
+    #[test]
+    fn test_buddy_usable_size() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+        let header = std::mem::size_of::<Avail>();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            for size in [1, 31, 32, 33, 100, 4000, 5000, 1 << 16] {
+                let mem = buddy_malloc(pool_ref, size);
+                let block = mem as usize - header;
+                let usable = buddy_usable_size(pool_ref, mem);
+
+                assert!(usable >= size);
+                assert_eq!(usable, (1 << (*(block as *mut Avail)).kval) - header);
+
+                // Every usable byte can be written
+                ptr::write_bytes(mem as *mut u8, 0xA5, usable);
+                buddy_free(pool_ref, mem);
+            }
+
+            assert_eq!(buddy_usable_size(pool_ref, ptr::null_mut()), 0);
+            assert_eq!(buddy_usable_size(ptr::null_mut(), pool_ref.base), 0);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_bytes_by_tag() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+        let header = std::mem::size_of::<Avail>();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            let net = [
+                buddy_malloc_tagged(pool_ref, 100, 7),
+                buddy_malloc_tagged(pool_ref, 4096 - header, 7),
+            ];
+            let disk = [
+                buddy_malloc_tagged(pool_ref, 1, 9),
+                buddy_malloc_tagged(pool_ref, 5000, 9),
+                buddy_malloc_tagged(pool_ref, 70000, 9),
+            ];
+            let plain = buddy_malloc(pool_ref, 10);
+
+            assert_eq!(buddy_bytes_by_tag(pool_ref, 7), 256 + 4096);
+            assert_eq!(buddy_bytes_by_tag(pool_ref, 9), 64 + 8192 + 131072);
+            assert_eq!(buddy_bytes_by_tag(pool_ref, 0), 64);
+            assert_eq!(buddy_bytes_by_tag(pool_ref, 3), 0);
+
+            // Freed blocks no longer count and reused ones lose the tag
+            buddy_free(pool_ref, disk[2]);
+            assert_eq!(buddy_bytes_by_tag(pool_ref, 9), 64 + 8192);
+            let reused = buddy_malloc(pool_ref, 70000);
+            assert_eq!(buddy_bytes_by_tag(pool_ref, 9), 64 + 8192);
+
+            for mem in net.into_iter().chain(disk[..2].iter().copied()) {
+                buddy_free(pool_ref, mem);
+            }
+            buddy_free(pool_ref, plain);
+            buddy_free(pool_ref, reused);
+            assert_eq!(buddy_bytes_by_tag(pool_ref, 7), 0);
+            assert_eq!(buddy_bytes_by_tag(ptr::null_mut(), 0), 0);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_stats() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            let fresh = buddy_stats(pool_ref);
+            assert_eq!(fresh.total_bytes, 1 << MIN_K);
+            assert_eq!(fresh.free_bytes, 1 << MIN_K);
+            assert_eq!(fresh.used_bytes, 0);
+            assert_eq!(fresh.largest_free_block, 1 << MIN_K);
+            assert_eq!(fresh.num_free_blocks, 1);
+
+            // Each request is charged its whole power of two block
+            let mut ptrs = Vec::new();
+            let mut used = 0;
+            for (size, block) in [(1, 64), (100, 256), (4000, 4096), (5000, 8192)] {
+                ptrs.push(buddy_malloc(pool_ref, size));
+                used += block;
+
+                let stats = buddy_stats(pool_ref);
+                assert_eq!(stats.free_bytes, (1 << MIN_K) - used);
+                assert_eq!(stats.used_bytes, used);
+                assert_eq!(stats.largest_free_block, 1 << (MIN_K - 1));
+            }
+
+            // The first split left a free half in every class, three were handed out since
+            let stats = buddy_stats(pool_ref);
+            assert_eq!(stats.num_free_blocks, MIN_K - SMALLEST_K - 3);
+            assert_eq!(stats, buddy_stats(pool_ref));
+
+            for mem in ptrs {
+                buddy_free(pool_ref, mem);
+            }
+            assert_eq!(buddy_stats(pool_ref), fresh);
+            assert_eq!(buddy_stats(ptr::null_mut()), BuddyStats::default());
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_fragmentation() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+        let header = std::mem::size_of::<Avail>();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+
+            // A single free block is not fragmented at all
+            assert_eq!(buddy_fragmentation(pool_ref), 0.0);
+
+            // Fill the pool with small blocks and free every other one, no two free blocks are buddies
+            let ptrs: Vec<_> = (0..(1 << (MIN_K - 10))).map(|_| buddy_malloc(pool_ref, 1024 - header)).collect();
+            assert_eq!(buddy_fragmentation(pool_ref), 0.0);
+            for mem in ptrs.iter().step_by(2) {
+                buddy_free(pool_ref, *mem);
+            }
+
+            let fragmentation = buddy_fragmentation(pool_ref);
+            assert!(fragmentation > 0.99, "{fragmentation}");
+            assert_eq!(buddy_fragmentation(pool_ref), fragmentation);
+
+            // Freeing the rest merges everything back into the top block
+            for mem in ptrs.iter().skip(1).step_by(2) {
+                buddy_free(pool_ref, *mem);
+            }
+            assert_eq!(buddy_fragmentation(pool_ref), 0.0);
+            assert_eq!(buddy_fragmentation(ptr::null_mut()), 0.0);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_reset() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool_ref = &mut *pool_ptr;
+            let base = pool_ref.base;
+
+            for size in [1, 100, 4096, 70000] {
+                assert!(!buddy_malloc(pool_ref, size).is_null());
+            }
+            assert!(!buddy_malloc_headerless(pool_ref, 512).is_null());
+
+            buddy_reset(pool_ref);
+            check_buddy_pool_full(pool_ref);
+            assert!(buddy_can_destroy(pool_ref));
+            assert_eq!(pool_ref.base, base);
+
+            // The whole pool can be handed out again
+            let mem = buddy_malloc(pool_ref, (1 << MIN_K) - std::mem::size_of::<Avail>());
+            assert_eq!(mem as usize - std::mem::size_of::<Avail>(), base as usize);
+            buddy_free(pool_ref, mem);
+
+            buddy_destroy(pool_ref);
+            buddy_reset(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_buddy_init_destroy_round_trip() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        assert!(page_size().is_power_of_two());
+
+        unsafe {
+            for _ in 0..3 {
+                assert_eq!(buddy_init(pool_ptr, 1 << MIN_K), 0);
+                let pool_ref = &mut *pool_ptr;
+                assert_eq!(pool_ref.base as usize & ((1 << MIN_K) - 1), 0);
+                check_buddy_pool_full(pool_ref);
+
+                // The whole region is backed by writable memory
+                ptr::write_bytes(pool_ref.base as *mut u8, 0x5A, 1 << MIN_K);
+
+                assert_eq!(buddy_destroy(pool_ref), 0);
+                assert!(pool_ref.base.is_null());
+            }
+        }
+    }
+
+    #[test]
+    fn test_buddy_free_interior_pointer() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            let mem = buddy_malloc(pool, 4000) as *mut u8;
+            ptr::write_bytes(mem, 0, 4000);
+            let before = buddy_stats(pool);
+
+            // The bytes in front of an interior pointer are user data, not a header
+            let mut local = 0u64;
+            assert_eq!(buddy_free(pool, &mut local as *mut u64 as *mut c_void), BuddyError::InvalidPointer);
+            assert_eq!(buddy_free(pool, mem.add(200) as *mut c_void), BuddyError::InvalidPointer);
+
+            // A forged header is caught when its block would not be aligned to its size
+            let fake = mem.add(64).sub(std::mem::size_of::<Avail>()) as *mut Avail;
+            (*fake).tag = BLOCK_RESERVED;
+            (*fake).kval = 7;
+            assert_eq!(buddy_free(pool, mem.add(64) as *mut c_void), BuddyError::InvalidPointer);
+
+            // Or when it claims more than the pool holds
+            (*fake).kval = (MIN_K + 1) as u16;
+            assert_eq!(buddy_free(pool, mem.add(64) as *mut c_void), BuddyError::InvalidPointer);
+
+            // Data that looks like an aligned record links nowhere the pool would put a header
+            for link in [ptr::null_mut(), 0x10 as *mut Avail, mem.add(4096) as *mut Avail, pool.base.add(1 << MIN_K) as *mut Avail] {
+                (*fake).tag = BLOCK_ALIGNED;
+                (*fake).next = link;
+                assert_eq!(buddy_free(pool, mem.add(64) as *mut c_void), BuddyError::InvalidPointer);
+            }
+
+            // The free lists were left alone
+            assert_eq!(buddy_stats(pool), before);
+            assert_eq!(buddy_free(pool, mem as *mut c_void), BuddyError::Ok);
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "poison")]
+    fn test_poison() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let header = std::mem::size_of::<Avail>();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            let mem = buddy_malloc(pool, 900) as *mut u8;
+            assert!((0..1024 - header).all(|i| *mem.add(i) == POISON_ALLOC));
+
+            // Keep the buddy reserved so the freed block stays whole on its list
+            let buddy = buddy_malloc(pool, 900);
+            ptr::write_bytes(mem, 0x11, 900);
+            buddy_free(pool, mem as *mut c_void);
+
+            let node = pool.avail[10].next;
+            assert_eq!(node as usize, mem as usize - header);
+            assert_eq!((*node).tag, BLOCK_AVAIL);
+            assert_eq!((*node).kval, 10);
+
+            let bytes = std::slice::from_raw_parts(node.add(1) as *const u8, 1024 - header);
+            assert!(bytes.iter().all(|&b| b == POISON_FREE));
+
+            buddy_free(pool, buddy);
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_dump() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            let dump = buddy_dump_to_string(pool);
+            assert!(dump.contains(&format!("k={}: 1 free\n  offset 0x0 tag {}\n", MIN_K, BLOCK_AVAIL)));
+            assert!(dump.contains(&format!("k={}: 0 free\n", SMALLEST_K)));
+
+            // Splitting leaves the upper half of every class free
+            let mem = buddy_malloc(pool, 1);
+            let dump = buddy_dump_to_string(pool);
+            for k in SMALLEST_K..MIN_K {
+                assert!(dump.contains(&format!("k={}: 1 free\n  offset {:#x} tag 1\n", k, 1 << k)));
+            }
+            buddy_dump(pool);
+
+            // A list looping on itself is cut short
+            let block = pool.avail[SMALLEST_K].next;
+            (*block).next = block;
+            let dump = buddy_dump_to_string(pool);
+            assert!(dump.contains(&format!("more than {} blocks, list corrupted", 1 << (MIN_K - SMALLEST_K))));
+
+            // And so is one leading out of the pool
+            (*block).next = 0x1000 as *mut Avail;
+            assert!(buddy_dump_to_string(pool).contains("block at 0x1000 is outside the pool"));
+
+            (*block).next = &mut pool.avail[SMALLEST_K];
+            buddy_free(pool, mem);
+            check_buddy_pool_full(pool);
+            assert!(buddy_dump_to_string(ptr::null_mut()).is_empty());
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_pool_blocks() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            let whole = BlockInfo { offset: 0, kval: MIN_K as u16, tag: BLOCK_AVAIL };
+            assert_eq!(pool_blocks(pool).collect::<Vec<_>>(), [whole]);
+
+            let small = buddy_malloc(pool, 1);
+            let medium = buddy_malloc(pool, 100);
+
+            // The first allocation split the pool down to the smallest class, the second took the free class 8 half
+            let mut expected = vec![
+                BlockInfo { offset: 0, kval: 6, tag: BLOCK_RESERVED },
+                BlockInfo { offset: 64, kval: 6, tag: BLOCK_AVAIL },
+                BlockInfo { offset: 128, kval: 7, tag: BLOCK_AVAIL },
+                BlockInfo { offset: 256, kval: 8, tag: BLOCK_RESERVED },
+            ];
+            expected.extend((9..MIN_K).map(|k| BlockInfo { offset: 1 << k, kval: k as u16, tag: BLOCK_AVAIL }));
+            assert_eq!(pool_blocks(pool).collect::<Vec<_>>(), expected);
+
+            buddy_free(pool, small);
+            buddy_free(pool, medium);
+            assert_eq!(pool_blocks(pool).collect::<Vec<_>>(), [whole]);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_malloc_too_large() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            // Even an oom_errno override is bypassed, the request can never succeed
+            buddy_set_oom_errno(pool, EAGAIN);
+            let splits = pool.split_count;
+
+            for size in [pool.numbytes + 1, pool.numbytes - std::mem::size_of::<Avail>() + 1, usize::MAX] {
+                *__errno_location() = 0;
+                assert!(buddy_malloc(pool, size).is_null());
+                assert_eq!(*__errno_location(), ENOMEM);
+            }
+
+            assert_eq!(pool.split_count, splits);
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_sync_buddy_stress() {
+        let pool = SyncBuddy::new(1 << MIN_K);
+
+        std::thread::scope(|scope| {
+            for thread in 0..8u8 {
+                let pool = &pool;
+
+                scope.spawn(move || {
+                    let mut seed = thread as usize + 1;
+                    let mut live = Vec::new();
+
+                    for i in 0..2000 {
+                        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                        let size = (seed >> 33) % 2000 + 1;
+
+                        // Every block is filled with the id of its thread, another thread writing to it would show
+                        let mem = match i % 3 {
+                            0 => pool.alloc(size),
+                            1 => pool.calloc(size, 1).inspect(|mem| unsafe {
+                                assert!((0..size).all(|j| *mem.as_ptr().add(j) == 0));
+                            }),
+                            _ => match live.pop() {
+                                Some((old, old_size)) => match unsafe { pool.realloc(old, size) } {
+                                    Some(mem) => unsafe {
+                                        assert!((0..size.min(old_size)).all(|j| *mem.as_ptr().add(j) == thread));
+                                        Some(mem)
+                                    },
+                                    None => {
+                                        live.push((old, old_size));
+                                        None
+                                    }
+                                },
+                                None => pool.alloc(size),
+                            },
+                        };
+
+                        if let Some(mem) = mem {
+                            unsafe { ptr::write_bytes(mem.as_ptr(), thread, size) };
+                            live.push((mem, size));
+                        }
+
+                        if live.len() > 16 {
+                            let (mem, size) = live.swap_remove((seed >> 40) % live.len());
+                            unsafe {
+                                assert!((0..size).all(|j| *mem.as_ptr().add(j) == thread));
+                                assert_eq!(pool.free(mem), BuddyError::Ok);
+                            }
+                        }
+                    }
+
+                    for (mem, size) in live {
+                        unsafe {
+                            assert!((0..size).all(|j| *mem.as_ptr().add(j) == thread));
+                            assert_eq!(pool.free(mem), BuddyError::Ok);
+                        }
+                    }
+                });
+            }
+        });
+
+        check_buddy_pool_full(unsafe { &mut *pool.as_ptr() });
+    }
+
+    #[test]
+    fn test_buddy_alloc_zeroed() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            // 100 bytes round up to a 256 byte block, leaving slack past the request
+            for size in [1, 100, 224, 1000, 5000] {
+                // Leave garbage behind in the block the next allocation gets
+                let dirty = buddy_malloc(pool, size);
+                ptr::write_bytes(dirty as *mut u8, 0xEE, buddy_usable_size(pool, dirty));
+                buddy_free(pool, dirty);
+
+                let mem = buddy_alloc_zeroed(pool, size);
+                assert_eq!(mem, dirty);
+
+                let usable = buddy_usable_size(pool, mem);
+                assert!(usable >= size);
+                assert!(std::slice::from_raw_parts(mem as *const u8, usable).iter().all(|&b| b == 0));
+
+                buddy_free(pool, mem);
+            }
+
+            assert!(buddy_alloc_zeroed(pool, 0).is_null());
+            assert!(buddy_alloc_zeroed(ptr::null_mut(), 8).is_null());
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_posix_memalign() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let untouched = 0x1000 as *mut c_void;
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+            let mut mem = untouched;
+
+            // Alignments that are not a power of two multiple of a pointer
+            for alignment in [0, 1, 4, 24, 48] {
+                assert_eq!(buddy_posix_memalign(pool, &mut mem, alignment, 100), EINVAL);
+                assert_eq!(mem, untouched);
+            }
+            assert_eq!(buddy_posix_memalign(pool, ptr::null_mut(), 64, 100), EINVAL);
+            assert_eq!(buddy_posix_memalign(ptr::null_mut(), &mut mem, 64, 100), EINVAL);
+
+            assert_eq!(buddy_posix_memalign(pool, &mut mem, 1 << MIN_K, 1 << MIN_K), ENOMEM);
+            assert_eq!(mem, untouched);
+
+            let mut blocks = Vec::new();
+            for alignment in [8, 64, 4096] {
+                assert_eq!(buddy_posix_memalign(pool, &mut mem, alignment, 100), 0);
+                assert_eq!(mem as usize & (alignment - 1), 0);
+                ptr::write_bytes(mem as *mut u8, 0xA5, 100);
+                blocks.push(mem);
+            }
+
+            for mem in blocks {
+                assert_eq!(buddy_free(pool, mem), BuddyError::Ok);
+            }
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_validate() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+            assert!(buddy_validate(pool));
+
+            let live: Vec<_> = (0..20).map(|i| buddy_malloc(pool, 1 + 200 * (i % 5))).collect();
+            for &mem in live.iter().step_by(2) {
+                buddy_free(pool, mem);
+            }
+            assert!(buddy_validate(pool));
+
+            // A next pointer into the middle of another block
+            let k = (SMALLEST_K..MIN_K).find(|&k| pool.free_mask & (1 << k) != 0).unwrap();
+            let block = pool.avail[k].next;
+            let saved = (*block).next;
+            (*block).next = (pool.base as usize + 8) as *mut Avail;
+            assert!(!buddy_validate(pool));
+
+            // A next pointer that loops back on the block skips the sentinel forever
+            (*block).next = block;
+            assert!(!buddy_validate(pool));
+
+            (*block).next = saved;
+            assert!(buddy_validate(pool));
+
+            // A list marked empty in free_mask while it still holds a block
+            pool.free_mask &= !(1 << k);
+            assert!(!buddy_validate(pool));
+            pool.free_mask |= 1 << k;
+
+            assert!(!buddy_validate(ptr::null_mut()));
+
+            for &mem in live.iter().skip(1).step_by(2) {
+                buddy_free(pool, mem);
+            }
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_free_halves_coalesce() {
+        let _serial = ASSERT_SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        ASSERT_MESSAGES.lock().unwrap().clear();
+
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let half = (1 << (MIN_K - 1)) - std::mem::size_of::<Avail>();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            buddy_set_assert_handler(Some(record_assert));
+            buddy_set_policy(pool, BuddyPolicy { flags: POLICY_DEFAULT | POLICY_CHECKED, strategy: STRATEGY_LIFO });
+
+            // Free the two halves of the pool in both orders
+            for lower_first in [true, false] {
+                let lower = buddy_malloc(pool, half);
+                let upper = buddy_malloc(pool, half);
+                assert!(!lower.is_null() && !upper.is_null());
+                assert!(buddy_malloc(pool, 1).is_null());
+
+                let (first, second) = if lower_first { (lower, upper) } else { (upper, lower) };
+                assert_eq!(buddy_free(pool, first), BuddyError::Ok);
+                assert_eq!(buddy_free(pool, second), BuddyError::Ok);
+                check_buddy_pool_full(pool);
+            }
+            assert!(ASSERT_MESSAGES.lock().unwrap().is_empty());
+
+            // A half whose header was clobbered while free can not be merged
+            // back, the bitmap is set aside so the header is what gets read
+            let lower = buddy_malloc(pool, half);
+            let upper = buddy_malloc(pool, half);
+            buddy_free(pool, lower);
+            (*block_of(pool, lower)).kval -= 1;
+            let bits = std::mem::replace(&mut pool.free_bits, ptr::null_mut());
+            buddy_free(pool, upper);
+            pool.free_bits = bits;
+
+            buddy_set_assert_handler(None);
+
+            let messages = ASSERT_MESSAGES.lock().unwrap();
+            assert_eq!(messages.len(), 1);
+            assert!(messages[0].contains("only merged up to kval"), "{}", messages[0]);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_can_alloc() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            assert!(buddy_can_alloc(pool, 1));
+            assert!(buddy_can_alloc(pool, (1 << MIN_K) - std::mem::size_of::<Avail>()));
+            assert!(!buddy_can_alloc(pool, 1 << MIN_K));
+            assert!(!buddy_can_alloc(pool, usize::MAX));
+            assert!(!buddy_can_alloc(pool, 0));
+            assert!(!buddy_can_alloc(ptr::null_mut(), 1));
+
+            // Asking does not split anything
+            check_buddy_pool_full(pool);
+
+            // Use up the pool with real allocations
+            let mut live = Vec::new();
+            while buddy_can_alloc(pool, 1000) {
+                let mem = buddy_malloc(pool, 1000);
+                assert!(!mem.is_null());
+                live.push(mem);
+            }
+            assert_eq!(live.len(), 1 << (MIN_K - 11));
+
+            *__errno_location() = 0;
+            assert!(!buddy_can_alloc(pool, 1));
+            assert!(buddy_malloc(pool, 1000).is_null());
+            assert_eq!(*__errno_location(), ENOMEM);
+
+            // Once a block is back, so is the answer, and errno is left as it was
+            buddy_free(pool, live.pop().unwrap());
+            assert!(buddy_can_alloc(pool, 1000));
+            assert!(!buddy_can_alloc(pool, 3000));
+            assert_eq!(*__errno_location(), ENOMEM);
+
+            for mem in live {
+                buddy_free(pool, mem);
+            }
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_buddy_init_hugepages() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let options = BuddyInitOptions { flags: INIT_HUGEPAGES, ..Default::default() };
+
+        unsafe {
+            // Most machines reserve no huge pages, then the pool falls back to normal ones
+            assert_eq!(buddy_init_with(pool.as_mut_ptr(), 1 << 22, &options), 0);
+            let pool = pool.assume_init_mut();
+            assert_eq!(pool.numbytes, 1 << 22);
+            assert_eq!(pool.base as usize & ((1 << 22) - 1), 0);
+
+            let huge = pool.map_flags & MAP_HUGETLB != 0;
+            assert_eq!(pool.map_flags & !MAP_HUGETLB, MAP_PRIVATE | MAP_ANONYMOUS);
+            let mut info = MaybeUninit::<MappingInfo>::uninit();
+            assert_eq!(buddy_mapping_info(pool, info.as_mut_ptr()), 0);
+            assert_eq!(info.assume_init().flags, pool.map_flags);
+            if huge {
+                assert_eq!(mapped_bytes(pool), 1 << 22);
+            }
+
+            let mem = buddy_malloc(pool, 1 << 20);
+            assert!(!mem.is_null());
+            ptr::write_bytes(mem as *mut u8, 0xA5, 1 << 20);
+            assert_eq!(buddy_free(pool, mem), BuddyError::Ok);
+            check_buddy_pool_full(pool);
+
+            assert_eq!(buddy_destroy(pool), 0);
+        }
+    }
+
+    /// Number of calls and bytes seen by the hooks of test_buddy_hooks
+    static HOOK_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+    static HOOK_BYTES: AtomicUsize = AtomicUsize::new(0);
+    static HOOK_FREES: AtomicUsize = AtomicUsize::new(0);
+    static HOOK_LAST_ALLOC: AtomicUsize = AtomicUsize::new(0);
+    static HOOK_LAST_FREE: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn hook_alloc(size: usize, ptr: *mut c_void) {
+        assert!(!ptr.is_null());
+        HOOK_ALLOCS.fetch_add(1, Ordering::Relaxed);
+        HOOK_BYTES.fetch_add(size, Ordering::Relaxed);
+        HOOK_LAST_ALLOC.store(ptr as usize, Ordering::Relaxed);
+    }
+
+    extern "C" fn hook_free(ptr: *mut c_void) {
+        assert!(!ptr.is_null());
+        HOOK_FREES.fetch_add(1, Ordering::Relaxed);
+        HOOK_LAST_FREE.store(ptr as usize, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn test_buddy_hooks() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+            buddy_set_hooks(pool, Some(hook_alloc), Some(hook_free));
+
+            let live: Vec<_> = (1..=10).map(|i| buddy_malloc(pool, 100 * i)).collect();
+            assert_eq!(HOOK_ALLOCS.load(Ordering::Relaxed), 10);
+            assert_eq!(HOOK_BYTES.load(Ordering::Relaxed), 5500);
+
+            // Failed calls are not reported
+            assert!(buddy_malloc(pool, 1 << MIN_K).is_null());
+            assert!(buddy_malloc(pool, 0).is_null());
+            assert_eq!(buddy_free(pool, live[0]), BuddyError::Ok);
+            assert_eq!(buddy_free(pool, live[0]), BuddyError::DoubleFree);
+            assert_eq!(HOOK_ALLOCS.load(Ordering::Relaxed), 10);
+            assert_eq!(HOOK_FREES.load(Ordering::Relaxed), 1);
+
+            for &mem in &live[1..] {
+                buddy_free(pool, mem);
+            }
+            assert_eq!(HOOK_FREES.load(Ordering::Relaxed), 10);
+
+            // Aligned allocations report the pointer the caller gets, so frees pair up with them
+            let aligned = buddy_aligned_alloc(pool, 4096, 100);
+            assert_eq!(HOOK_ALLOCS.load(Ordering::Relaxed), 11);
+            assert_eq!(HOOK_BYTES.load(Ordering::Relaxed), 5600);
+            assert_eq!(HOOK_LAST_ALLOC.load(Ordering::Relaxed), aligned as usize);
+
+            let mut memptr = ptr::null_mut();
+            assert_eq!(buddy_posix_memalign(pool, &mut memptr, 256, 50), 0);
+            assert_eq!(HOOK_ALLOCS.load(Ordering::Relaxed), 12);
+            assert_eq!(HOOK_BYTES.load(Ordering::Relaxed), 5650);
+            assert_eq!(HOOK_LAST_ALLOC.load(Ordering::Relaxed), memptr as usize);
+
+            // A realloc is a free of the old pointer and an allocation of the new one, in place or not
+            let mem = buddy_malloc(pool, 100);
+            let same = buddy_realloc(pool, mem, 90);
+            assert_eq!(same, mem);
+            assert_eq!(HOOK_ALLOCS.load(Ordering::Relaxed), 14);
+            assert_eq!(HOOK_BYTES.load(Ordering::Relaxed), 5840);
+            assert_eq!(HOOK_FREES.load(Ordering::Relaxed), 11);
+            assert_eq!(HOOK_LAST_FREE.load(Ordering::Relaxed), mem as usize);
+
+            let grown = buddy_realloc(pool, same, 5000);
+            assert_eq!(HOOK_ALLOCS.load(Ordering::Relaxed), 15);
+            assert_eq!(HOOK_FREES.load(Ordering::Relaxed), 12);
+            assert_eq!(HOOK_LAST_ALLOC.load(Ordering::Relaxed), grown as usize);
+            assert_eq!(HOOK_LAST_FREE.load(Ordering::Relaxed), same as usize);
+
+            let moved = buddy_realloc(pool, aligned, 3000);
+            assert_ne!(moved, aligned);
+            assert_eq!(HOOK_ALLOCS.load(Ordering::Relaxed), 16);
+            assert_eq!(HOOK_FREES.load(Ordering::Relaxed), 13);
+            assert_eq!(HOOK_LAST_ALLOC.load(Ordering::Relaxed), moved as usize);
+            assert_eq!(HOOK_LAST_FREE.load(Ordering::Relaxed), aligned as usize);
+
+            for mem in [moved, memptr, grown] {
+                assert_eq!(buddy_free(pool, mem), BuddyError::Ok);
+                assert_eq!(HOOK_LAST_FREE.load(Ordering::Relaxed), mem as usize);
+            }
+            assert_eq!(HOOK_FREES.load(Ordering::Relaxed), 16);
+
+            // Once removed the hooks stay quiet
+            buddy_set_hooks(pool, None, None);
+            buddy_free(pool, buddy_malloc(pool, 100));
+            assert_eq!(HOOK_ALLOCS.load(Ordering::Relaxed), 16);
+            assert_eq!(HOOK_FREES.load(Ordering::Relaxed), 16);
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_largest_free() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let header = std::mem::size_of::<Avail>();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+            assert_eq!(buddy_largest_free(pool), (1 << MIN_K) - header);
+
+            // Scanning the lists from the top finds the same class
+            let scan = |pool: &mut BuddyPool| {
+                (SMALLEST_K..=pool.kval_m)
+                    .rev()
+                    .find(|&k| count_free(pool, k) > 0)
+                    .map_or(0, |k| (1 << k) - header)
+            };
+
+            let mut live: Vec<_> = (0..64).map(|i| buddy_malloc(pool, 1 + 700 * (i % 9))).collect();
+            for i in (0..live.len()).rev().step_by(3) {
+                buddy_free(pool, live.remove(i));
+                assert_eq!(buddy_largest_free(pool), scan(pool));
+            }
+            assert!(buddy_largest_free(pool) < (1 << MIN_K) - header);
+
+            // What it reports can be allocated, and nothing larger
+            let size = buddy_largest_free(pool);
+            assert!(!buddy_can_alloc(pool, size + 1));
+            let mem = buddy_malloc(pool, size);
+            assert!(!mem.is_null());
+            buddy_free(pool, mem);
+
+            // A fully allocated pool has nothing left
+            let mut rest = Vec::new();
+            loop {
+                let mem = buddy_malloc(pool, 1);
+                if mem.is_null() {
+                    break;
+                }
+                rest.push(mem);
+            }
+            assert_eq!(buddy_largest_free(pool), 0);
+            assert_eq!(buddy_largest_free(ptr::null_mut()), 0);
+
+            for mem in live.into_iter().chain(rest) {
+                buddy_free(pool, mem);
+            }
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_init_tiny() {
+        let mut pool = MaybeUninit::<BuddyPool>::zeroed();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            // Mapped pools never go below MIN_K, however small the request
+            for size in [1, std::mem::size_of::<Avail>() - 1, 1 << SMALLEST_K, (1 << MIN_K) - 1] {
+                assert_eq!(buddy_init(pool_ptr, size), 0);
+                let pool_ref = &mut *pool_ptr;
+                assert_eq!(pool_ref.kval_m, MIN_K);
+                check_buddy_pool_full(pool_ref);
+                buddy_destroy(pool_ref);
+            }
+
+            // Buffers that can not hold a single block are refused outright
+            let (_buffer, base) = aligned_buffer(1 << SMALLEST_K);
+            for size in [0, 1, std::mem::size_of::<Avail>(), (1 << SMALLEST_K) - 1] {
+                assert_eq!(buddy_init_from_buffer(pool_ptr, base, size), EINVAL);
+                assert!((*pool_ptr).base.is_null());
+            }
+
+            // The smallest one is a single block with room for its header
+            assert_eq!(buddy_init_from_buffer(pool_ptr, base, 1 << SMALLEST_K), 0);
+            let pool_ref = &mut *pool_ptr;
+            assert_eq!(pool_ref.kval_m, SMALLEST_K);
+            check_buddy_pool_full(pool_ref);
+
+            let mem = buddy_malloc(pool_ref, 1);
+            assert!(!mem.is_null());
+            assert_eq!(buddy_usable_size(pool_ref, mem), (1 << SMALLEST_K) - std::mem::size_of::<Avail>());
+            assert!(buddy_malloc(pool_ref, 1).is_null());
+            buddy_free(pool_ref, mem);
+            check_buddy_pool_full(pool_ref);
+
+            buddy_destroy(pool_ref);
+        }
+    }
+
+    #[test]
+    fn test_buddy_reallocarray() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            let array = buddy_reallocarray(pool, ptr::null_mut(), 10, 8) as *mut u64;
+            assert!(!array.is_null());
+            for i in 0..10 {
+                *array.add(i) = i as u64;
+            }
+
+            // An overflowing count fails and leaves the array alone
+            assert!(buddy_reallocarray(pool, array as *mut c_void, usize::MAX / 4, 8).is_null());
+            assert_eq!(*__errno_location(), ENOMEM);
+            assert_eq!((*block_of(pool, array as *mut c_void)).tag, BLOCK_RESERVED);
+            assert!((0..10).all(|i| *array.add(i) == i as u64));
+
+            // Growing keeps the elements
+            let grown = buddy_reallocarray(pool, array as *mut c_void, 1000, 8) as *mut u64;
+            assert!(!grown.is_null());
+            assert!(buddy_usable_size(pool, grown as *mut c_void) >= 8000);
+            assert!((0..10).all(|i| *grown.add(i) == i as u64));
+
+            assert!(buddy_reallocarray(pool, grown as *mut c_void, 0, 8).is_null());
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    extern "C" fn collect_allocated(ptr: *mut c_void, usable: usize, owner: u32, user: *mut c_void) {
+        let seen = unsafe { &mut *(user as *mut Vec<(*mut c_void, usize, u32)>) };
+        seen.push((ptr, usable, owner));
+    }
+
+    #[test]
+    fn test_buddy_walk_allocated() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let header = std::mem::size_of::<Avail>();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            let mut live: Vec<_> = (0..12u32)
+                .map(|i| {
+                    let size = 50 + 400 * i as usize;
+                    let mem = if i % 4 == 0 { buddy_malloc(pool, size) } else { buddy_malloc_tagged(pool, size, i % 4) };
+                    (mem, if i % 4 == 0 { 0 } else { i % 4 })
+                })
+                .collect();
+
+            // Freed blocks must not show up
+            for i in (0..live.len()).rev().step_by(3) {
+                buddy_free(pool, live.remove(i).0);
+            }
+
+            let mut seen: Vec<(*mut c_void, usize, u32)> = Vec::new();
+            let count = buddy_walk_allocated(pool, collect_allocated, &mut seen as *mut _ as *mut c_void);
+            assert_eq!(count, live.len());
+            assert_eq!(seen.len(), live.len());
+
+            // Reported in address order, each with its owner and usable size
+            assert!(seen.windows(2).all(|pair| pair[0].0 < pair[1].0));
+            for &(mem, owner) in &live {
+                let &(_, usable, seen_owner) = seen.iter().find(|entry| entry.0 == mem).unwrap();
+                assert_eq!(seen_owner, owner);
+                assert_eq!(usable, (1 << (*block_of(pool, mem)).kval) - header);
+                assert_eq!(usable, buddy_usable_size(pool, mem));
+            }
+
+            for (mem, _) in live {
+                buddy_free(pool, mem);
+            }
+            seen.clear();
+            assert_eq!(buddy_walk_allocated(pool, collect_allocated, &mut seen as *mut _ as *mut c_void), 0);
+            assert!(seen.is_empty());
+            assert_eq!(buddy_walk_allocated(ptr::null_mut(), collect_allocated, ptr::null_mut()), 0);
+
+            buddy_destroy(pool);
+        }
+    }
+
+    #[test]
+    fn test_buddy_prewarm() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
 
-            check_buddy_pool_empty(pool_ref);
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
 
-            let fail = buddy_malloc(pool_ref, 5);
-            assert!(fail.is_null());
+            let mut counts = [0; MIN_K + 1];
+            counts[8] = 3;
+            counts[10] = 2;
+            counts[14] = 1;
+            counts[MIN_K - 1] = 1;
+            assert_eq!(buddy_prewarm(pool, counts.as_ptr(), counts.len()), 0);
 
-            assert_eq!(buddy_free(pool_ref, mem), 0);
-            check_buddy_pool_full(pool_ref);
+            // The smallest class with blocks to spare is split each time
+            let expected = [(8, 4), (9, 0), (10, 3), (11, 0), (12, 1), (13, 1), (14, 1), (MIN_K - 1, 1), (MIN_K, 0)];
+            for (k, n) in expected {
+                assert_eq!(count_free(pool, k), n, "class {}", k);
+            }
+            assert!((15..MIN_K).all(|k| count_free(pool, k) == 1));
+            assert_eq!(pool.free_bytes, 1 << MIN_K);
+            assert!(buddy_validate(pool));
 
-            buddy_destroy(pool_ref);
+            // Asking again for what is already there changes nothing
+            let splits = pool.split_count;
+            assert_eq!(buddy_prewarm(pool, counts.as_ptr(), counts.len()), 0);
+            assert_eq!(pool.split_count, splits);
+
+            // The first allocations of a warmed class do not split
+            let small: Vec<_> = (0..3).map(|_| buddy_malloc(pool, 256 - std::mem::size_of::<Avail>())).collect();
+            assert_eq!(pool.split_count, splits);
+
+            // A distribution that does not fit leaves the pool as it was
+            let mut too_many = [0; MIN_K + 1];
+            too_many[8] = 1;
+            too_many[MIN_K - 1] = 2;
+            assert_eq!(buddy_prewarm(pool, too_many.as_ptr(), too_many.len()), ENOMEM);
+            assert_eq!(count_free(pool, 8), 1);
+            assert_eq!(pool.split_count, splits);
+
+            let mut bad = [0; MIN_K + 2];
+            bad[MIN_K + 1] = 1;
+            assert_eq!(buddy_prewarm(pool, bad.as_ptr(), bad.len()), EINVAL);
+            bad[MIN_K + 1] = 0;
+            bad[SMALLEST_K - 1] = 1;
+            assert_eq!(buddy_prewarm(pool, bad.as_ptr(), bad.len()), EINVAL);
+            assert_eq!(buddy_prewarm(pool, ptr::null(), 0), EINVAL);
+
+            // Blocks split ahead of time stay split unless a freed buddy merges through them
+            for mem in small {
+                buddy_free(pool, mem);
+            }
+            assert_eq!(pool.free_bytes, 1 << MIN_K);
+            assert_eq!(count_free(pool, 14), 1);
+            assert!(buddy_validate(pool));
+
+            buddy_destroy(pool);
         }
     }
 
     #[test]
-    fn test_buddy_init() {
-        for i in MIN_K as usize..=DEFAULT_K as usize {
-            let size = 1 << i;
-            
-            let mut pool = MaybeUninit::<BuddyPool>::uninit();
-            let pool_ptr = pool.as_mut_ptr();
+    fn test_remove_block_until_empty() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
 
-            unsafe {
-                buddy_init(pool_ptr, size);
-                let pool_ref = &mut *pool_ptr;
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
 
-                check_buddy_pool_full(pool_ref);
-                buddy_destroy(pool_ref);
+            // Four free blocks of the smallest class
+            let mut counts = [0; MIN_K + 1];
+            counts[SMALLEST_K] = 4;
+            assert_eq!(buddy_prewarm(pool, counts.as_ptr(), counts.len()), 0);
+            let head: *mut Avail = &mut pool.avail[SMALLEST_K];
+
+            // Only the removal of the last block reports the list as empty
+            let mut removed = Vec::new();
+            for left in (0..4).rev() {
+                let block = (*head).next;
+                assert_eq!(remove_block(block), left == 0);
+                removed.push(block);
+            }
+            assert_eq!((*head).next, head);
+            assert_eq!((*head).prev, head);
+
+            // Relink them so the pool is consistent again
+            for block in removed {
+                (*block).next = (*head).next;
+                (*block).prev = head;
+                (*(*head).next).prev = block;
+                (*head).next = block;
             }
+            assert!(buddy_validate(pool));
+
+            buddy_destroy(pool);
         }
     }
 
     #[test]
-    fn test_buddy_calc_basic_pairs() {
-        const TEST_K: usize = MIN_K + 2;
-        let pool_size = 1 << TEST_K;
+    fn test_buddy_registry() {
+        let mut first = MaybeUninit::<BuddyPool>::uninit();
+        let mut second = MaybeUninit::<BuddyPool>::uninit();
+
+        unsafe {
+            buddy_init(first.as_mut_ptr(), 1 << MIN_K);
+            buddy_init(second.as_mut_ptr(), 1 << (MIN_K + 1));
+            let first = first.assume_init_mut();
+            let second = second.assume_init_mut();
+
+            let mut registry = BuddyRegistry::new();
+            assert!(registry.register(first));
+            assert!(registry.register(second));
+            assert!(!registry.register(first));
+            assert!(!registry.register(ptr::null_mut()));
+            assert_eq!(registry.len(), 2);
+
+            // Allocate from both pools in turn and free in another order
+            let mut live: Vec<_> = (0..40)
+                .map(|i| {
+                    let pool: *mut BuddyPool = if i % 2 == 0 { &mut *first } else { &mut *second };
+                    (buddy_malloc(pool, 100 + 97 * i), pool)
+                })
+                .collect();
+            live.sort_by_key(|&(mem, _)| mem as usize % 7919);
+
+            for (mem, pool) in live {
+                assert_eq!(registry.pool_of(mem), pool);
+                assert_eq!(registry.free(mem), BuddyError::Ok);
+            }
+            check_buddy_pool_full(first);
+            check_buddy_pool_full(second);
+
+            // Pointers outside every pool are refused
+            let mut local = 0u64;
+            assert_eq!(registry.free(&mut local as *mut u64 as *mut c_void), BuddyError::InvalidPointer);
+            assert_eq!(registry.free(ptr::null_mut()), BuddyError::NullPtr);
+            let past = (second.base as usize + second.numbytes) as *const c_void;
+            assert!(!ptr::eq(registry.pool_of(past), second));
+
+            assert!(registry.unregister(first));
+            assert!(!registry.unregister(first));
+            let mem = buddy_malloc(first, 100);
+            assert!(registry.pool_of(mem).is_null());
+            buddy_free(first, mem);
+            assert!(registry.unregister(second));
+            assert!(registry.is_empty());
+
+            buddy_destroy(first);
+            buddy_destroy(second);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_buddy_trim() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let page = page_size();
+
+        unsafe {
+            buddy_init(pool.as_mut_ptr(), 1 << MIN_K);
+            let pool = pool.assume_init_mut();
+
+            // Peak: fault in most of the pool, then let it go again
+            let live: Vec<_> = (0..12).map(|i| buddy_malloc(pool, 20000 + 3000 * i)).collect();
+            for &mem in &live {
+                ptr::write_bytes(mem as *mut u8, 0x5A, buddy_usable_size(pool, mem));
+            }
+            for mem in live {
+                buddy_free(pool, mem);
+            }
+            check_buddy_pool_full(pool);
+
+            // Only the first page of the single free block is kept
+            let trimmed = buddy_trim(pool);
+            assert_eq!(trimmed, (1 << MIN_K) - page);
+            let base = pool.base as usize;
+            assert!(!is_resident((base + page) as *mut c_void, (1 << MIN_K) - page));
+
+            // The pool still works and the trimmed memory can be used again
+            let mem = buddy_malloc(pool, 500000);
+            assert!(!mem.is_null());
+            ptr::write_bytes(mem as *mut u8, 0xA5, 500000);
+            assert!(is_resident(mem, 500000));
+            let small = buddy_malloc(pool, 100);
+            assert!(!small.is_null());
+
+            // Only pages of free blocks are released
+            let again = buddy_trim(pool);
+            assert!(again < trimmed);
+            assert_eq!(*(mem as *mut u8).add(499999), 0xA5);
+
+            buddy_free(pool, small);
+            buddy_free(pool, mem);
+            check_buddy_pool_full(pool);
+            assert_eq!(buddy_trim(ptr::null_mut()), 0);
+
+            buddy_destroy(pool);
+        }
+    }
 
+    #[test]
+    fn test_strategy_address_order() {
         let mut pool = MaybeUninit::<BuddyPool>::uninit();
         let pool_ptr = pool.as_mut_ptr();
 
         unsafe {
-            buddy_init(pool_ptr, pool_size);
-            let pool_ref = &mut *pool_ptr;
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool = &mut *pool_ptr;
 
-            // Allocate 2 small blocks manually by splitting top-level block
-            let top_block = pool_ref.avail[TEST_K].next;
-            assert_eq!((*top_block).tag, BLOCK_AVAIL);
+            // Without coalescing the freed blocks stay on the smallest list
+            buddy_set_policy(pool, BuddyPolicy { flags: 0, strategy: STRATEGY_ADDRESS });
 
-            // Remove top block from the free list
-            remove_block(top_block);
+            let mut mem = [ptr::null_mut(); 8];
+            for slot in mem.iter_mut() {
+                *slot = buddy_malloc(pool, 1);
+                assert!(!slot.is_null());
+            }
 
-            // Split it into two buddies
-            let kval = TEST_K - 1;
-            let block1 = top_block;
-            let block2 = (block1 as usize + (1 << kval)) as *mut Avail;
+            for i in [5, 1, 7, 0, 3, 6, 2, 4] {
+                assert_eq!(buddy_free(pool, mem[i]), BuddyError::Ok);
+            }
 
-            (*block1).kval = kval as u16;
-            (*block2).kval = kval as u16;
+            for k in 0..=pool.kval_m {
+                let head = &mut pool.avail[k] as *mut Avail;
+                let mut block = (*head).next;
+                while (*block).next != head {
+                    assert!((block as usize) < (*block).next as usize);
+                    block = (*block).next;
+                }
+            }
 
-            // Calculate each other as buddy
-            let b1 = buddy_calc(pool_ref, block1);
-            let b2 = buddy_calc(pool_ref, block2);
+            // The lowest free block is handed out first
+            let lowest = *mem.iter().min().unwrap();
+            assert_eq!(buddy_malloc(pool, 1), lowest);
 
-            assert_eq!(b1, block2, "Buddy of block1 should be block2");
-            assert_eq!(b2, block1, "Buddy of block2 should be block1");
+            assert!(buddy_validate(pool));
+            buddy_destroy(pool);
+        }
+    }
 
-            // Check that buddy address is offset by correct power of two
-            let offset1 = (block1 as usize) - (pool_ref.base as usize);
-            let offset2 = (block2 as usize) - (pool_ref.base as usize);
-            assert_eq!(offset1 ^ offset2, 1 << kval);
+    #[test]
+    fn test_buddy_offset_of() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
 
-            buddy_destroy(pool_ref);
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool = &mut *pool_ptr;
+
+            let mem = buddy_malloc(pool, 1);
+            let other = buddy_malloc(pool, 100);
+            assert!(!mem.is_null() && !other.is_null());
+
+            let offset = buddy_offset_of(pool, mem);
+            assert_eq!(offset, mem as usize - size_of::<Avail>() - pool.base as usize);
+            assert_eq!(buddy_block_index(pool, mem), offset >> SMALLEST_K);
+            assert_ne!(buddy_block_index(pool, mem), buddy_block_index(pool, other));
+
+            // The same block comes back with the same offset and index
+            let index = buddy_block_index(pool, other);
+            assert_eq!(buddy_free(pool, other), BuddyError::Ok);
+            let again = buddy_malloc(pool, 100);
+            assert_eq!(again, other);
+            assert_eq!(buddy_block_index(pool, again), index);
+            assert!(index < pool.numbytes >> SMALLEST_K);
+
+            let mut outside = 0u64;
+            assert_eq!(buddy_offset_of(pool, &mut outside as *mut u64 as *mut c_void), usize::MAX);
+            assert_eq!(buddy_block_index(pool, &mut outside as *mut u64 as *mut c_void), usize::MAX);
+            assert_eq!(buddy_offset_of(ptr::null_mut(), mem), usize::MAX);
+            assert_eq!(buddy_block_index(pool, ptr::null_mut()), usize::MAX);
+
+            buddy_destroy(pool);
         }
     }
 
-    /// Helper function.
-    ///
-    /// Inserts a block into the free list at kval
-    unsafe fn insert_block(pool: *mut BuddyPool, block: *mut Avail, kval: usize) {
-        // Get the head of the linked list for blocks of size 2^k where k = kval
-        let head = &mut (*pool).avail[kval];
-    
-        // Insert the block at the head of the list
-        (*block).next = head.next;
-        (*block).prev = head;
-    
-        // Update the next pointer of the block's previous node
-        (*head.next).prev = block;
-    
-        // Update the head's next pointer to the new block
-        (*head).next = block;
-    
-        // Set the block's tag to indicate its available
-        (*block).tag = BLOCK_AVAIL;
+    #[test]
+    fn test_buddy_alloc_class() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool = &mut *pool_ptr;
+
+            // Every block of the class lands on the same grid
+            let k = SMALLEST_K + 2;
+            let mut mem = Vec::new();
+            for _ in 0..4 {
+                let block = buddy_alloc_class(pool, k);
+                assert!(!block.is_null());
+                assert_eq!((*block_of(pool, block)).kval as usize, k);
+                assert_eq!(buddy_offset_of(pool, block) % (1 << k), 0);
+                mem.push(block);
+            }
+
+            // Out of range classes are clamped
+            let small = buddy_alloc_class(pool, 0);
+            assert_eq!((*block_of(pool, small)).kval as usize, SMALLEST_K);
+            mem.push(small);
+
+            for block in mem {
+                assert_eq!(buddy_free(pool, block), BuddyError::Ok);
+            }
+            check_buddy_pool_full(pool);
+
+            let top = buddy_alloc_class(pool, usize::MAX);
+            assert_eq!((*block_of(pool, top)).kval as usize, pool.kval_m);
+            assert!(buddy_alloc_class(pool, SMALLEST_K).is_null());
+            assert!(buddy_alloc_class(ptr::null_mut(), SMALLEST_K).is_null());
+
+            buddy_destroy(pool);
+        }
     }
 
     #[test]
-    fn test_buddy_calc_recursive_coalescing() {
-        const BASE_K: usize = MIN_K + 3; // 2^7 = 128 bytes
-        let pool_size = 1 << BASE_K;
-    
+    fn test_buddy_free_count() {
         let mut pool = MaybeUninit::<BuddyPool>::uninit();
         let pool_ptr = pool.as_mut_ptr();
-    
+
         unsafe {
-            buddy_init(pool_ptr, pool_size);
-            let pool_ref = &mut *pool_ptr;
-    
-            // Manually take the top block
-            let top_block = pool_ref.avail[BASE_K].next;
-            remove_block(top_block);
-    
-            // Split into two level BASE_K - 1 blocks
-            let k1 = BASE_K - 1;
-            let left1 = top_block;
-            let right1 = (left1 as usize + (1 << k1)) as *mut Avail;
-            (*left1).kval = k1 as u16;
-            (*right1).kval = k1 as u16;
-    
-            // Split left1 into two BASE_K - 2 blocks
-            let k2 = k1 - 1;
-            let left2 = left1;
-            let right2 = (left2 as usize + (1 << k2)) as *mut Avail;
-            (*left2).kval = k2 as u16;
-            (*right2).kval = k2 as u16;
-    
-            // Split left2 again
-            let k3 = k2 - 1;
-            let left3 = left2;
-            let right3 = (left3 as usize + (1 << k3)) as *mut Avail;
-            (*left3).kval = k3 as u16;
-            (*right3).kval = k3 as u16;
-    
-            // Now free right3 and left3 and ensure they coalesce into left2
-            insert_block(pool_ref, left3, k3);
-            insert_block(pool_ref, right3, k3);
-    
-            let buddy_of_left3 = buddy_calc(pool_ref, left3);
-            assert_eq!(buddy_of_left3, right3, "Buddy of left3 should be right3");
-    
-            // Remove both from free list to simulate coalescing
-            remove_block(left3);
-            remove_block(right3);
-    
-            // Merge into left2
-            let merged_kval = k3 + 1;
-            let merged_block = if left3 < right3 { left3 } else { right3 };
-            (*merged_block).kval = merged_kval as u16;
-    
-            // Check buddy of merged_block is still correct
-            let buddy = buddy_calc(pool_ref, merged_block);
-            let expected_offset = 1 << merged_kval;
-            let offset_diff = (buddy as usize).wrapping_sub(merged_block as usize);
-            assert_eq!(offset_diff, expected_offset, "Merged block buddy is offset correctly");
-    
-            buddy_destroy(pool_ref);
+            buddy_init(pool_ptr, 1 << MIN_K);
+            let pool = &mut *pool_ptr;
+            assert_eq!(buddy_free_count(pool, pool.kval_m), 1);
+
+            // Splitting the top block down leaves one free buddy on every level below it
+            let mem = buddy_malloc(pool, 1);
+            assert!(!mem.is_null());
+            assert_eq!(buddy_free_count(pool, pool.kval_m), 0);
+            for k in SMALLEST_K..pool.kval_m {
+                assert_eq!(buddy_free_count(pool, k), 1);
+            }
+            for k in 0..SMALLEST_K {
+                assert_eq!(buddy_free_count(pool, k), 0);
+            }
+
+            // A second smallest block takes the buddy of the first
+            let other = buddy_malloc(pool, 1);
+            assert_eq!(buddy_free_count(pool, SMALLEST_K), 0);
+            assert_eq!(buddy_free_count(pool, SMALLEST_K + 1), 1);
+
+            assert_eq!(buddy_free_count(pool, pool.kval_m + 1), 0);
+            assert_eq!(buddy_free_count(pool, usize::MAX), 0);
+            assert_eq!(buddy_free_count(ptr::null_mut(), SMALLEST_K), 0);
+
+            assert_eq!(buddy_free(pool, other), BuddyError::Ok);
+            assert_eq!(buddy_free(pool, mem), BuddyError::Ok);
+            check_buddy_pool_full(pool);
+
+            buddy_destroy(pool);
         }
     }
 
     #[test]
-    fn test_btok_one() {
-        assert_eq!(0, btok(1));
+    fn test_buddy_shrink() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            let options = BuddyInitOptions { flags: INIT_END_SENTINEL, ..Default::default() };
+            assert_eq!(buddy_init_with(pool_ptr, 1 << (MIN_K + 2), &options), 0);
+            let pool = &mut *pool_ptr;
+
+            // An empty pool gives up everything past the new size
+            assert_eq!(buddy_shrink(pool, 1 << (MIN_K + 1)), 0);
+            assert_eq!(pool.kval_m, MIN_K + 1);
+            assert_eq!(pool.numbytes, 1 << (MIN_K + 1));
+            assert!(buddy_check_layout(pool));
+            check_buddy_pool_full(pool);
+
+            // Live memory below the new end stays put
+            let low = buddy_malloc(pool, 100);
+            assert!(!low.is_null());
+            ptr::write_bytes(low as *mut u8, 0x5A, 100);
+            assert_eq!(buddy_shrink(pool, 1), 0);
+            assert_eq!(pool.kval_m, MIN_K);
+            assert_eq!(*(low as *const u8).add(99), 0x5A);
+            assert!(buddy_validate(pool));
+            assert!(buddy_check_layout(pool));
+
+            assert_eq!(buddy_free(pool, low), BuddyError::Ok);
+            check_buddy_pool_full(pool);
+
+            // Growing is not shrinking
+            assert_eq!(buddy_shrink(pool, 1 << (MIN_K + 3)), 0);
+            assert_eq!(pool.kval_m, MIN_K);
+            assert_eq!(buddy_shrink(ptr::null_mut(), 0), EINVAL);
+
+            assert_eq!(buddy_destroy(pool), 0);
+        }
     }
 
     #[test]
-    fn test_btok_range() {
-        assert_eq!(0, btok(1));
-        assert_eq!(1, btok(2));
-        assert_eq!(2, btok(3));
-        assert_eq!(2, btok(4));
-        assert_eq!(3, btok(5));
-        assert_eq!(3, btok(8));
-        assert_eq!(4, btok(9));
-        assert_eq!(4, btok(16));
-        assert_eq!(5, btok(17));
-        assert_eq!(5, btok(32));
-        assert_eq!(6, btok(33));
-        assert_eq!(6, btok(64));
-        assert_eq!(10, btok(1024));
-        assert_eq!(11, btok(1025));
-        assert_eq!(40, btok(1099511627776));
+    fn test_buddy_shrink_tail_in_use() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let pool_ptr = pool.as_mut_ptr();
+
+        unsafe {
+            buddy_init(pool_ptr, 1 << (MIN_K + 1));
+            let pool = &mut *pool_ptr;
+
+            // The second half of the pool holds a live allocation
+            let half = (1 << MIN_K) - size_of::<Avail>();
+            let low = buddy_malloc(pool, half);
+            let high = buddy_malloc(pool, half);
+            assert!(!low.is_null() && !high.is_null());
+            assert_eq!(buddy_offset_of(pool, high), 1 << MIN_K);
+
+            assert_eq!(buddy_free(pool, low), BuddyError::Ok);
+            let free_bytes = pool.free_bytes;
+            assert_eq!(buddy_shrink(pool, 1 << MIN_K), EBUSY);
+            assert_eq!(pool.kval_m, MIN_K + 1);
+            assert_eq!(pool.free_bytes, free_bytes);
+            assert!(buddy_validate(pool));
+
+            assert_eq!(buddy_free(pool, high), BuddyError::Ok);
+            check_buddy_pool_full(pool);
+
+            // A pool over a caller's buffer has nothing to unmap
+            let mut other = MaybeUninit::<BuddyPool>::uninit();
+            let (_buffer, base) = aligned_buffer(1 << (MIN_K + 1));
+            assert_eq!(buddy_init_from_buffer(other.as_mut_ptr(), base, 1 << (MIN_K + 1)), 0);
+            assert_eq!(buddy_shrink(other.as_mut_ptr(), 1 << MIN_K), EINVAL);
+
+            buddy_destroy(pool);
+        }
     }
 
     #[test]
-    fn test_double_free() {
+    fn test_buddy_shrink_several_classes() {
         let mut pool = MaybeUninit::<BuddyPool>::uninit();
         let pool_ptr = pool.as_mut_ptr();
 
         unsafe {
-            buddy_init(pool_ptr, 128);
-            let pool_ref = &mut *pool_ptr;
+            // Without a sentinel page every tail header is unmapped
+            buddy_init(pool_ptr, 1 << (MIN_K + 2));
+            let pool = &mut *pool_ptr;
 
-            let ptr = buddy_malloc(pool_ref, 64);
-            assert!(!ptr.is_null());
+            let low = buddy_malloc(pool, 100);
+            assert!(!low.is_null());
+            ptr::write_bytes(low as *mut u8, 0x5A, 100);
+
+            assert_eq!(buddy_shrink(pool, 1 << MIN_K), 0);
+            assert_eq!(pool.kval_m, MIN_K);
+            assert_eq!(pool.numbytes, 1 << MIN_K);
+            assert_eq!(*(low as *const u8).add(99), 0x5A);
+            assert!(buddy_validate(pool));
+
+            assert_eq!(buddy_free(pool, low), BuddyError::Ok);
+            check_buddy_pool_full(pool);
+
+            assert_eq!(buddy_destroy(pool), 0);
+        }
+    }
+
+    #[test]
+    fn test_buddy_free_interior_pointer_trailing() {
+        let mut pool = MaybeUninit::<BuddyPool>::uninit();
+        let options = BuddyInitOptions { flags: INIT_TRAILING_HEADER, ..Default::default() };
+
+        unsafe {
+            assert_eq!(buddy_init_with(pool.as_mut_ptr(), 1 << MIN_K, &options), 0);
+            let pool = pool.assume_init_mut();
+
+            let mem = buddy_malloc(pool, 900) as *mut u8;
+            ptr::write_bytes(mem, 0, 900);
+            let before = buddy_stats(pool);
+
+            // An interior pointer off the block grid, behind bytes that look like an aligned record
+            let fake = mem.add(64) as *mut Avail;
+            for link in [ptr::null_mut(), 0x10 as *mut Avail, mem as *mut Avail] {
+                (*fake).tag = BLOCK_ALIGNED;
+                (*fake).next = link;
+                assert_eq!(buddy_free(pool, mem.add(96) as *mut c_void), BuddyError::InvalidPointer);
+            }
 
-            assert_eq!(buddy_free(pool_ref, ptr), 0);
+            assert_eq!(buddy_stats(pool), before);
+            assert_eq!(buddy_free(pool, mem as *mut c_void), BuddyError::Ok);
+            check_buddy_pool_full(pool);
 
-            // This free is undefined behavior and shouldn't fail
-            assert_eq!(buddy_free(pool_ref, ptr), 0);
+            buddy_destroy(pool);
         }
     }
 }
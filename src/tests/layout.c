@@ -0,0 +1,53 @@
+#include <assert.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+#include "../buddy_memory_manager.h"
+
+// Linked against the static library. Every check reads a struct the library
+// filled in, so a header whose layout drifted from the #[repr(C)] structs
+// fails here.
+
+void test_pool_layout() {
+    BuddyPool pool;
+    assert(buddy_init(&pool, 1 << MIN_K) == 0);
+
+    assert(pool.kval_m == MIN_K);
+    assert(pool.numbytes == (size_t)1 << MIN_K);
+    assert(pool.owns_mapping);
+
+    Avail* top = pool.avail[MIN_K].next;
+    assert(top == (Avail*)pool.base);
+    assert(top->tag == BLOCK_AVAIL);
+    assert(top->kval == MIN_K);
+    assert(top->next == &pool.avail[MIN_K]);
+
+    buddy_destroy(&pool);
+}
+
+void test_block_layout() {
+    BuddyPool pool;
+    buddy_init(&pool, 1 << MIN_K);
+
+    void* mem = buddy_malloc_tagged(&pool, 100, 7);
+    Avail* header = (Avail*)((char*)mem - sizeof(Avail));
+    assert(header->tag == BLOCK_RESERVED);
+    assert(header->owner == 7);
+    assert(buddy_usable_size(&pool, mem) == ((size_t)1 << header->kval) - sizeof(Avail));
+
+    BuddyStats stats = buddy_stats(&pool);
+    assert(stats.total_bytes == pool.numbytes);
+    assert(stats.used_bytes == (size_t)1 << header->kval);
+    assert(stats.free_bytes + stats.used_bytes == stats.total_bytes);
+
+    assert(buddy_free(&pool, mem) == BuddyError_Ok);
+    buddy_destroy(&pool);
+}
+
+int main() {
+    test_pool_layout();
+    test_block_layout();
+
+    printf("C header matches the static library!\n");
+    return 0;
+}
@@ -198,8 +198,8 @@ void test_double_free() {
     void* ptr = buddy_malloc(&pool, 64);
     assert(ptr != NULL);
 
-    assert(buddy_free(&pool, ptr) == 0);
-    assert(buddy_free(&pool, ptr) == 0);  // Should be okay even if undefined
+    assert(buddy_free(&pool, ptr) == BuddyError_Ok);
+    assert(buddy_free(&pool, ptr) == BuddyError_DoubleFree);  // Caught instead of corrupting the free lists
 
     buddy_destroy(&pool);
 }
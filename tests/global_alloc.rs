@@ -0,0 +1,42 @@
+use buddy_memory_manager::{BuddyGlobal, BuddyPool};
+
+#[global_allocator]
+static HEAP: BuddyGlobal = BuddyGlobal::new(1 << 28);
+
+/// Returns whether ptr lies inside the region of the heap's pool.
+fn in_heap(ptr: *const u8) -> bool {
+    let pool: *mut BuddyPool = HEAP.as_ptr();
+    assert!(!pool.is_null());
+
+    let (base, len) = unsafe { ((*pool).base as usize, (*pool).numbytes) };
+    (base..base + len).contains(&(ptr as usize))
+}
+
+#[test]
+fn test_buddy_global_alloc() {
+    let boxed = Box::new(42u64);
+    assert!(in_heap(&*boxed as *const u64 as *const u8));
+
+    let mut numbers = Vec::new();
+    for i in 0..10_000u32 {
+        numbers.push(i);
+    }
+    assert!(in_heap(numbers.as_ptr() as *const u8));
+    assert_eq!(numbers.iter().map(|&i| i as u64).sum::<u64>(), 49_995_000);
+
+    let text: String = (0..1000).map(|i| char::from(b'a' + (i % 26) as u8)).collect();
+    assert_eq!(text.len(), 1000);
+
+    // Over-aligned types go through the aligned path
+    #[repr(align(4096))]
+    struct Page([u8; 4096]);
+
+    let page = Box::new(Page([7; 4096]));
+    assert_eq!(&*page as *const Page as usize % 4096, 0);
+    assert!(page.0.iter().all(|&b| b == 7));
+
+    let threads: Vec<_> = (0..4).map(|i| std::thread::spawn(move || vec![i; 1000].iter().sum::<usize>())).collect();
+    for (i, thread) in threads.into_iter().enumerate() {
+        assert_eq!(thread.join().unwrap(), i * 1000);
+    }
+}
@@ -0,0 +1,35 @@
+//! Runs against the library built with --no-default-features, where it is
+//! no_std and errno is kept by the crate itself.
+#![cfg(not(feature = "std"))]
+
+use buddy_memory_manager::{buddy_destroy, buddy_errno, buddy_free, buddy_init_from_buffer, buddy_malloc, buddy_stats, BuddyError, BuddyPool};
+use core::ffi::c_void;
+use core::mem::MaybeUninit;
+
+/// Pool memory for the tests, aligned to its size like buddy_init_from_buffer wants
+#[repr(C, align(65536))]
+struct Buffer([u8; 1 << 16]);
+
+#[test]
+fn test_buddy_init_from_buffer_no_std() {
+    let mut buffer = Box::new(Buffer([0; 1 << 16]));
+    let base = buffer.0.as_mut_ptr() as *mut c_void;
+    let mut pool = MaybeUninit::<BuddyPool>::uninit();
+
+    unsafe {
+        assert_eq!(buddy_init_from_buffer(pool.as_mut_ptr(), base, 1 << 16), 0);
+        let pool = &mut *pool.as_mut_ptr();
+
+        let mem = buddy_malloc(pool, 100);
+        assert!(!mem.is_null());
+        core::ptr::write_bytes(mem as *mut u8, 0x5A, 100);
+
+        // Failures still leave an errno behind, ENOMEM as Linux numbers it
+        assert!(buddy_malloc(pool, 1 << 17).is_null());
+        assert_eq!(buddy_errno(), 12);
+
+        assert_eq!(buddy_free(pool, mem), BuddyError::Ok);
+        assert_eq!(buddy_stats(pool).free_bytes, 1 << 16);
+        assert_eq!(buddy_destroy(pool), 0);
+    }
+}